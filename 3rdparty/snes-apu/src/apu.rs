@@ -3,7 +3,7 @@ use crate::dsp::dsp::BUFFER_LEN;
 use super::smp::Smp;
 use super::dsp::dsp::Dsp;
 use super::timer::Timer;
-use spc::{Spc, RAM_LEN, IPL_ROM_LEN};
+use spc::{Spc, RAM_LEN, IPL_ROM_LEN, VERSION_MINOR};
 
 static DEFAULT_IPL_ROM: [u8; IPL_ROM_LEN] = [
     0xcd, 0xef, 0xbd, 0xe8, 0x00, 0xc6, 0x1d, 0xd0,
@@ -83,6 +83,45 @@ impl Apu {
         ret
     }
 
+    /// Captures the current state as an SPC file with no tags.
+    pub fn to_spc(&self) -> Spc {
+        let smp = self.smp.as_ref().unwrap();
+        let dsp = self.dsp.as_ref().unwrap();
+
+        let mut ram = [0; RAM_LEN];
+        ram.copy_from_slice(&self.ram);
+        let regs = dsp.get_state();
+
+        // These I/O registers aren't backed by RAM, so store them where
+        //  from_spc() reads them from. $f3 matches the saved registers, which
+        //  hold the voices keyed on rather than the last KON written.
+        ram[0xf1] = self.get_control_reg();
+        ram[0xf2] = self.dsp_reg_address;
+        ram[0xf3] = regs[(self.dsp_reg_address & 0x7f) as usize];
+        for i in 0..3 {
+            ram[0xfa + i] = self.timers[i].get_target();
+            ram[0xfd + i] = self.timers[i].peek_counter();
+        }
+
+        let mut ipl_rom = [0; IPL_ROM_LEN];
+        ipl_rom.copy_from_slice(&self.ipl_rom);
+
+        Spc {
+            version_minor: VERSION_MINOR,
+            pc: smp.reg_pc,
+            a: smp.reg_a,
+            x: smp.reg_x,
+            y: smp.reg_y,
+            psw: smp.get_psw(),
+            sp: smp.reg_sp,
+            id666_tag: None,
+            xid6_tag: None,
+            ram,
+            regs,
+            ipl_rom,
+        }
+    }
+
     pub fn render(&mut self, left_buffer: &mut [i16], right_buffer: &mut [i16]) {
         assert!(left_buffer.len() == right_buffer.len());
         let num_samples = left_buffer.len();
@@ -117,6 +156,25 @@ impl Apu {
         dsp.output_buffer.read_interleaved(out);
     }
 
+    /// Like render_interleaved(), but runs the DSP without the CPU, so that
+    ///  registers set directly aren't changed by the sound driver.
+    pub fn render_dsp_interleaved(&mut self, out: &mut [i16]) {
+        assert!(out.len() % 2 == 0);
+        let num_samples = out.len() / 2;
+
+        assert!(num_samples <= BUFFER_LEN);
+        let num_samples = num_samples as i32;
+
+        let dsp = self.dsp.as_mut().unwrap();
+        while dsp.output_buffer.get_sample_count() < num_samples {
+            let needed = num_samples - dsp.output_buffer.get_sample_count();
+            dsp.cycles_callback(needed * 64);
+            dsp.flush();
+        }
+
+        dsp.output_buffer.read_interleaved(out);
+    }
+
     pub fn cpu_cycles_callback(&mut self, num_cycles: i32) {
         self.dsp.as_mut().unwrap().cycles_callback(num_cycles);
         for timer in self.timers.iter_mut() {
@@ -124,6 +182,15 @@ impl Apu {
         }
     }
 
+    /// The 64KB of RAM, without the I/O registers or IPL ROM mapped over it.
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
     pub fn read_u8(&mut self, address: u32) -> u8 {
         let address = address & 0xffff;
         if address >= 0xf0 && address < 0x0100 {
@@ -187,6 +254,13 @@ impl Apu {
         panic!("Test reg not yet implemented");
     }
 
+    fn get_control_reg(&self) -> u8 {
+        (if self.is_ipl_rom_enabled { 0x80 } else { 0 }) |
+        (if self.timers[2].is_running() { 0x04 } else { 0 }) |
+        (if self.timers[1].is_running() { 0x02 } else { 0 }) |
+        (if self.timers[0].is_running() { 0x01 } else { 0 })
+    }
+
     fn set_control_reg(&mut self, value: u8) {
         self.is_ipl_rom_enabled = (value & 0x80) != 0;
         if (value & 0x20) != 0 {
@@ -204,3 +278,37 @@ impl Apu {
 }
 
 unsafe impl Send for Apu {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dsp::dsp::SAMPLE_RATE;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_to_spc_reloads() {
+        let spc = Spc::load("test/ferris-nu.spc").unwrap();
+        let mut apu = Apu::from_spc(&spc);
+        // One second at a time.
+        let mut out = vec![0; SAMPLE_RATE * 2];
+        for _ in 0..3 {
+            apu.render_interleaved(&mut out);
+        }
+
+        let saved = apu.to_spc();
+        let mut file = vec![];
+        saved.to_writer(&mut file).unwrap();
+        let mut reloaded = Apu::from_spc(&Spc::from_reader(Cursor::new(file)).unwrap());
+        let resaved = reloaded.to_spc();
+        assert_eq!(&resaved.ram[..], &saved.ram[..]);
+        assert_eq!(&resaved.regs[..], &saved.regs[..]);
+
+        // Voice positions and timer phases aren't saved, so the audio can
+        // only match a load of the state before it was written out.
+        let mut expected = vec![0; SAMPLE_RATE * 2];
+        Apu::from_spc(&saved).render_interleaved(&mut expected);
+        reloaded.render_interleaved(&mut out);
+        assert!(out == expected);
+        assert!(out.iter().any(|&sample| sample != 0));
+    }
+}
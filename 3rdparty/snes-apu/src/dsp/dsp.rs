@@ -8,10 +8,13 @@ use super::dsp_helpers;
 pub const SAMPLE_RATE: usize = 32000;
 pub const BUFFER_LEN: usize = SAMPLE_RATE * 2;
 
-const NUM_VOICES: usize = 8;
+pub const NUM_VOICES: usize = 8;
+/// Largest echo buffer, in samples.
+const MAX_ECHO_SAMPLES: usize = 0x0f * 0x800 / 4;
 
 const COUNTER_RANGE: i32 = 30720;
-static COUNTER_RATES: [i32; 32] = [
+/// Samples between envelope steps at each rate. Rate 0 never steps.
+pub static COUNTER_RATES: [i32; 32] = [
     COUNTER_RANGE + 1, // Never fires
     2048, 1536, 1280, 1024, 768, 640, 512, 384, 320, 256, 192, 160, 128, 96,
     80, 64, 48, 40, 32, 24, 20, 16, 12, 10, 8, 6, 5, 4, 3, 2, 1];
@@ -20,6 +23,49 @@ static COUNTER_OFFSETS: [i32; 32] = [
     1, 0, 1040, 536, 0, 1040, 536, 0, 1040, 536, 0, 1040, 536, 0, 1040,
     536, 0, 1040, 536, 0, 1040, 536, 0, 1040, 536, 0, 1040, 536, 0, 1040, 0, 0];
 
+/// A write to a DSP register by the SPC700, as logged when
+///  `Dsp::set_register_logging()` is enabled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RegisterWrite {
+    /// Number of samples rendered before the write.
+    pub sample: u64,
+    pub address: u8,
+    pub value: u8
+}
+
+/// One sample of each voice and the echo, as captured when
+///  `Dsp::set_stem_capture()` is enabled. Each is a (left, right) pair, after
+///  the voice's envelope and volume but before the main volume.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StemSample {
+    /// Each voice without echo.
+    pub voices: [(i16, i16); NUM_VOICES],
+    /// Each voice's share of the echo, as if it were the only voice echoed.
+    pub voice_echoes: [(i16, i16); NUM_VOICES],
+    /// All of the echo, after the echo volume, as mixed into the output.
+    pub echo: (i16, i16),
+}
+
+/// An echo buffer and filter for each voice, standing in for the shared
+///  echo buffer in RAM so each voice's echo can be heard alone.
+struct StemCapture {
+    samples: Vec<StemSample>,
+    echo_buffers: Vec<Box<[(i32, i32)]>>,
+    left_filters: Vec<Filter>,
+    right_filters: Vec<Filter>,
+}
+
+impl StemCapture {
+    fn new() -> StemCapture {
+        StemCapture {
+            samples: Vec::new(),
+            echo_buffers: (0..NUM_VOICES).map(|_| vec![(0, 0); MAX_ECHO_SAMPLES].into_boxed_slice()).collect(),
+            left_filters: (0..NUM_VOICES).map(|_| Filter::new()).collect(),
+            right_filters: (0..NUM_VOICES).map(|_| Filter::new()).collect(),
+        }
+    }
+}
+
 pub struct Dsp {
     apu: *mut Apu,
 
@@ -48,7 +94,15 @@ pub struct Dsp {
     echo_pos: i32,
     echo_length: i32,
 
-    resampling_mode: ResamplingMode
+    resampling_mode: ResamplingMode,
+    remove_surround: bool,
+
+    // Last value written to each register, for saving state.
+    regs: [u8; REG_LEN],
+
+    samples_rendered: u64,
+    register_log: Option<Vec<RegisterWrite>>,
+    stem_capture: Option<Box<StemCapture>>
 }
 
 impl Dsp {
@@ -83,6 +137,13 @@ impl Dsp {
             echo_length: 0,
 
             resampling_mode: resampling_mode,
+            remove_surround: false,
+
+            regs: [0; REG_LEN],
+
+            samples_rendered: 0,
+            register_log: None,
+            stem_capture: None,
         });
         let ret_ptr = &mut *ret as *mut _;
         for _ in 0..NUM_VOICES {
@@ -123,6 +184,27 @@ impl Dsp {
         }
     }
 
+    pub fn remove_surround(&self) -> bool {
+        self.remove_surround
+    }
+
+    /// If set, negative volumes are played as positive, rather than inverting
+    ///  the phase of a channel (which sounds unpleasant on headphones).
+    pub fn set_remove_surround(&mut self, remove_surround: bool) {
+        self.remove_surround = remove_surround;
+        for voice in self.voices.iter_mut() {
+            voice.remove_surround = remove_surround;
+        }
+    }
+
+    fn volume(&self, volume: u8) -> u8 {
+        if self.remove_surround {
+            dsp_helpers::remove_surround(volume)
+        } else {
+            volume
+        }
+    }
+
     fn calculate_echo_start_address(value: u8) -> u16 {
         (value as u16) << 8
     }
@@ -138,6 +220,74 @@ impl Dsp {
         self.set_kon(spc.regs[0x4c]);
     }
 
+    /// Returns register values suitable for `set_state()`. Voice playback
+    ///  positions can't be saved, so KON holds every voice that's currently
+    ///  keyed on, which restarts them on load.
+    pub fn get_state(&self) -> [u8; REG_LEN] {
+        let mut regs = self.regs;
+
+        let mut kon = 0;
+        for (i, voice) in self.voices.iter().enumerate() {
+            if !voice.envelope.is_releasing() {
+                kon |= 1 << i;
+            }
+        }
+        regs[0x4c] = kon;
+        regs[0x5c] = 0;
+
+        regs
+    }
+
+    pub fn get_register_value(&self, address: u8) -> u8 {
+        self.regs[(address & 0x7f) as usize]
+    }
+
+    /// Number of samples rendered since the DSP was created.
+    pub fn samples_rendered(&self) -> u64 {
+        self.samples_rendered
+    }
+
+    /// Starts or stops recording register writes, for analyzing what a song
+    ///  plays without listening to it.
+    pub fn set_register_logging(&mut self, enabled: bool) {
+        if enabled {
+            if self.register_log.is_none() {
+                self.register_log = Some(Vec::new());
+            }
+        } else {
+            self.register_log = None;
+        }
+    }
+
+    /// Returns the writes logged since the last call.
+    pub fn take_register_writes(&mut self) -> Vec<RegisterWrite> {
+        match self.register_log {
+            Some(ref mut log) => ::std::mem::replace(log, Vec::new()),
+            None => Vec::new()
+        }
+    }
+
+    /// Starts or stops recording each voice and the echo separately, for
+    ///  rendering stems.
+    pub fn set_stem_capture(&mut self, enabled: bool) {
+        if enabled {
+            if self.stem_capture.is_none() {
+                self.stem_capture = Some(Box::new(StemCapture::new()));
+            }
+        } else {
+            self.stem_capture = None;
+        }
+    }
+
+    /// Returns the stem samples captured since the last call, one for each
+    ///  sample rendered.
+    pub fn take_stem_samples(&mut self) -> Vec<StemSample> {
+        match self.stem_capture {
+            Some(ref mut capture) => ::std::mem::replace(&mut capture.samples, Vec::new()),
+            None => Vec::new()
+        }
+    }
+
     pub fn cycles_callback(&mut self, num_cycles: i32) {
         self.cycles_since_last_flush += num_cycles;
     }
@@ -186,8 +336,8 @@ impl Dsp {
                 last_voice_out = output.last_voice_out;
             }
 
-            left_out = dsp_helpers::multiply_volume(left_out, self.vol_left);
-            right_out = dsp_helpers::multiply_volume(right_out, self.vol_right);
+            left_out = dsp_helpers::multiply_volume(left_out, self.volume(self.vol_left));
+            right_out = dsp_helpers::multiply_volume(right_out, self.volume(self.vol_right));
 
             let echo_address = (self.echo_start_address + (self.echo_pos as u16)) as u32;
             let mut left_echo_in = (((((self.apu().read_u8(echo_address + 1) as i32) << 8) | (self.apu().read_u8(echo_address) as i32)) as i16) & !1) as i32;
@@ -196,8 +346,14 @@ impl Dsp {
             left_echo_in = dsp_helpers::clamp(self.left_filter.next(left_echo_in));
             right_echo_in = dsp_helpers::clamp(self.right_filter.next(right_echo_in));
 
-            let left_out = dsp_helpers::clamp(left_out + dsp_helpers::multiply_volume(left_echo_in, self.echo_vol_left)) as i16;
-            let right_out = dsp_helpers::clamp(right_out + dsp_helpers::multiply_volume(right_echo_in, self.echo_vol_right)) as i16;
+            let left_echo_return = dsp_helpers::multiply_volume(left_echo_in, self.volume(self.echo_vol_left));
+            let right_echo_return = dsp_helpers::multiply_volume(right_echo_in, self.volume(self.echo_vol_right));
+            if self.stem_capture.is_some() {
+                self.capture_stems(left_echo_return, right_echo_return);
+            }
+
+            let left_out = dsp_helpers::clamp(left_out + left_echo_return) as i16;
+            let right_out = dsp_helpers::clamp(right_out + right_echo_return) as i16;
             self.output_buffer.write_sample(left_out, right_out);
 
             if self.echo_write_enabled {
@@ -218,12 +374,49 @@ impl Dsp {
             }
 
             self.counter = (self.counter + 1) % COUNTER_RANGE;
+            self.samples_rendered += 1;
             self.cycles_since_last_flush -= 64;
         }
 
         self.is_flushing = false;
     }
 
+    /// Records the sample just rendered by each voice, and runs each voice's
+    ///  echo as the real echo runs on the mix of them.
+    fn capture_stems(&mut self, left_echo_return: i32, right_echo_return: i32) {
+        let echo_vol_left = self.volume(self.echo_vol_left);
+        let echo_vol_right = self.volume(self.echo_vol_right);
+        let feedback = (self.echo_feedback as i8) as i32;
+        let echo_index = (self.echo_pos / 4) as usize;
+        let capture = self.stem_capture.as_mut().unwrap();
+
+        let mut sample = StemSample::default();
+        sample.echo = (dsp_helpers::clamp(left_echo_return) as i16, dsp_helpers::clamp(right_echo_return) as i16);
+        for (i, voice) in self.voices.iter().enumerate() {
+            let output = voice.output_buffer.last();
+            sample.voices[i] = (output.left_out as i16, output.right_out as i16);
+
+            let left_filter = &mut capture.left_filters[i];
+            let right_filter = &mut capture.right_filters[i];
+            left_filter.coefficients = self.left_filter.coefficients;
+            right_filter.coefficients = self.right_filter.coefficients;
+            let (left_in, right_in) = capture.echo_buffers[i][echo_index];
+            let left_in = dsp_helpers::clamp(left_filter.next(left_in));
+            let right_in = dsp_helpers::clamp(right_filter.next(right_in));
+            sample.voice_echoes[i] = (
+                dsp_helpers::clamp(dsp_helpers::multiply_volume(left_in, echo_vol_left)) as i16,
+                dsp_helpers::clamp(dsp_helpers::multiply_volume(right_in, echo_vol_right)) as i16);
+
+            if self.echo_write_enabled {
+                let (left_out, right_out) = if voice.echo_on { (output.left_out, output.right_out) } else { (0, 0) };
+                capture.echo_buffers[i][echo_index] = (
+                    dsp_helpers::clamp(left_out + ((((left_in * feedback) >> 7) as i16) as i32)) & !1,
+                    dsp_helpers::clamp(right_out + ((((right_in * feedback) >> 7) as i16) as i32)) & !1);
+            }
+        }
+        capture.samples.push(sample);
+    }
+
     pub fn set_register(&mut self, address: u8, value: u8) {
         if (address & 0x80) != 0 {
             return;
@@ -233,6 +426,15 @@ impl Dsp {
             self.flush();
         }
 
+        self.regs[address as usize] = value;
+        if let Some(ref mut log) = self.register_log {
+            log.push(RegisterWrite {
+                sample: self.samples_rendered,
+                address: address,
+                value: value
+            });
+        }
+
         let voice_index = address >> 4;
         let voice_address = address & 0x0f;
         if voice_address < 0x0a {
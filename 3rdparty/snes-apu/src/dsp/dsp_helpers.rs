@@ -2,6 +2,16 @@ pub fn multiply_volume(value: i32, volume: u8) -> i32 {
     (value * (volume as i8 as i32)) >> 7
 }
 
+/// Replaces a negative (phase-inverting) volume with its magnitude.
+pub fn remove_surround(volume: u8) -> u8 {
+    let volume = volume as i8;
+    if volume < 0 {
+        volume.checked_neg().unwrap_or(i8::MAX) as u8
+    } else {
+        volume as u8
+    }
+}
+
 pub fn clamp(value: i32) -> i32 {
     if value < -32768 {
         return -32768;
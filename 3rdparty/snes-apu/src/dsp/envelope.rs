@@ -51,6 +51,13 @@ impl Envelope {
         self.mode = Mode::Release;
     }
 
+    pub fn is_releasing(&self) -> bool {
+        match self.mode {
+            Mode::Release => true,
+            _ => false
+        }
+    }
+
     pub fn tick(&mut self) {
         let mut env = self.level;
         match self.mode {
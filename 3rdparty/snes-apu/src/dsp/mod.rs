@@ -1,6 +1,6 @@
 mod dsp_helpers;
 mod envelope;
-mod brr_block_decoder;
+pub mod brr_block_decoder;
 mod gaussian;
 pub mod voice;
 mod filter;
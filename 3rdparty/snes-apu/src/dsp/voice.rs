@@ -7,7 +7,7 @@ use super::gaussian::{HALF_KERNEL_SIZE, HALF_KERNEL};
 
 const RESAMPLE_BUFFER_LEN: usize = 4;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum ResamplingMode {
     Linear,
     Gaussian,
@@ -49,6 +49,12 @@ impl VoiceBuffer {
         self.buffer[self.pos as usize] = value;
         self.pos = (self.pos + 1) % (VOICE_BUFFER_LEN as i32);
     }
+
+    /// Returns the most recently written sample.
+    pub fn last(&self) -> VoiceOutput {
+        let pos = (self.pos + (VOICE_BUFFER_LEN as i32) - 1) % (VOICE_BUFFER_LEN as i32);
+        self.buffer[pos as usize]
+    }
 }
 
 pub struct Voice {
@@ -73,6 +79,7 @@ pub struct Voice {
     sample_pos: i32,
 
     pub resampling_mode: ResamplingMode,
+    pub remove_surround: bool,
     resample_buffer: [i32; RESAMPLE_BUFFER_LEN],
     resample_buffer_pos: usize,
 
@@ -105,6 +112,7 @@ impl Voice {
             sample_pos: 0,
 
             resampling_mode: resampling_mode,
+            remove_surround: false,
             resample_buffer: [0; RESAMPLE_BUFFER_LEN],
             resample_buffer_pos: 0,
 
@@ -189,11 +197,17 @@ impl Voice {
             }
         }
 
+        let (vol_left, vol_right) = if self.remove_surround {
+            (dsp_helpers::remove_surround(self.vol_left), dsp_helpers::remove_surround(self.vol_right))
+        } else {
+            (self.vol_left, self.vol_right)
+        };
+
         let ret =
             if self.is_solod || (!self.is_muted && !are_any_voices_solod) {
                 VoiceOutput {
-                    left_out: dsp_helpers::multiply_volume(sample, self.vol_left),
-                    right_out: dsp_helpers::multiply_volume(sample, self.vol_right),
+                    left_out: dsp_helpers::multiply_volume(sample, vol_left),
+                    right_out: dsp_helpers::multiply_volume(sample, vol_right),
                     last_voice_out: sample
                 }
             } else {
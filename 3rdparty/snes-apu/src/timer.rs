@@ -52,6 +52,20 @@ impl Timer {
         };
     }
 
+    pub fn is_running(&self) -> bool {
+        self.is_running
+    }
+
+    pub fn get_target(&self) -> u8 {
+        self.target.unwrap_or(0)
+    }
+
+    /// Returns the counter value without resetting it like a read from the
+    ///  SMP would.
+    pub fn peek_counter(&self) -> u8 {
+        self.counter_high & 0x0f
+    }
+
     pub fn read_counter(&mut self) -> u8 {
         let ret = self.counter_high & 0x0f;
         self.counter_high = 0;
@@ -1,11 +1,14 @@
 mod binary_reader;
+mod xid6;
 
 use std::char;
-use std::io::{Read, Result, Error, ErrorKind, Seek, SeekFrom, BufReader};
+use std::io::{Read, Write, Result, Error, ErrorKind, Seek, SeekFrom, BufReader, BufWriter};
 use std::path::Path;
 use std::fs::File;
 use binary_reader::{ReadAll, BinaryRead, BinaryReader};
 
+pub use xid6::{Xid6Tag, TICKS_PER_SECOND};
+
 macro_rules! fail {
     ($expr:expr) => (return Err(Error::new(ErrorKind::Other, $expr)))
 }
@@ -18,6 +21,9 @@ const HEADER_LEN: usize = 33;
 const HEADER_BYTES: &'static [u8; HEADER_LEN] =
     b"SNES-SPC700 Sound File Data v0.30";
 
+pub const VERSION_MINOR: u8 = 30;
+
+#[derive(Clone)]
 pub struct Spc {
     pub version_minor: u8,
     pub pc: u16,
@@ -27,6 +33,7 @@ pub struct Spc {
     pub psw: u8,
     pub sp: u8,
     pub id666_tag: Option<Id666Tag>,
+    pub xid6_tag: Option<Xid6Tag>,
     pub ram: [u8; RAM_LEN],
     pub regs: [u8; REG_LEN],
     pub ipl_rom: [u8; IPL_ROM_LEN]
@@ -86,6 +93,10 @@ impl Spc {
         let mut ipl_rom = [0; IPL_ROM_LEN];
         r.read_all(&mut ipl_rom)?;
 
+        let mut extra = Vec::new();
+        r.read_to_end(&mut extra)?;
+        let xid6_tag = Xid6Tag::parse(&extra);
+
         Ok(Spc {
             version_minor: version_minor,
             pc: pc,
@@ -95,13 +106,52 @@ impl Spc {
             psw: psw,
             sp: sp,
             id666_tag: id666_tag,
+            xid6_tag: xid6_tag,
             ram: ram,
             regs: regs,
             ipl_rom: ipl_rom
         })
     }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let file = File::create(path)?;
+        let mut writer = BufWriter::new(file);
+        self.to_writer(&mut writer)?;
+        writer.flush()
+    }
+
+    pub fn to_writer<W: Write>(&self, mut w: W) -> Result<()> {
+        w.write_all(HEADER_BYTES)?;
+        w.write_all(&[0x1a, 0x1a])?;
+        w.write_all(&[if self.id666_tag.is_some() { 0x1a } else { 0x1b }])?;
+        w.write_all(&[self.version_minor])?;
+
+        w.write_all(&self.pc.to_le_bytes())?;
+        w.write_all(&[self.a, self.x, self.y, self.psw, self.sp])?;
+        // Reserved
+        w.write_all(&[0; 2])?;
+
+        // 0x2e..0x100
+        let mut tag = [0; 0xd2];
+        if let Some(ref id666_tag) = self.id666_tag {
+            id666_tag.write(&mut tag);
+        }
+        w.write_all(&tag)?;
+
+        w.write_all(&self.ram)?;
+        w.write_all(&self.regs)?;
+        // Unused
+        w.write_all(&[0; 0x40])?;
+        w.write_all(&self.ipl_rom)?;
+
+        if let Some(ref xid6_tag) = self.xid6_tag {
+            w.write_all(&xid6_tag.to_bytes())?;
+        }
+        Ok(())
+    }
 }
 
+#[derive(Clone)]
 pub struct Id666Tag {
     pub song_title: String,
     pub game_title: String,
@@ -115,6 +165,7 @@ pub struct Id666Tag {
     pub dumping_emulator: Emulator
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Emulator {
     Unknown,
     ZSnes,
@@ -158,29 +209,34 @@ impl Id666Tag {
 
                 (date_dumped, seconds_to_play_before_fading_out, fade_out_length)
             } else {
-                // TODO: Find SPC's to test this with
-                unimplemented!();
-
-                /*let year = try!(r.read_le_u16());
-                let month = try!(r.read_u8());
-                let day = try!(r.read_u8());
-                let date_dumped = format!("{}/{}/{}", month, day, year);
+                let day = r.read_u8()?;
+                let month = r.read_u8()?;
+                let year = r.read_le_u16()?;
+                let date_dumped = if year == 0 {
+                    "".to_string()
+                } else {
+                    format!("{:02}/{:02}/{}", month, day, year)
+                };
 
-                try!(r.seek(SeekFrom::Start(0xa9)));
-                let seconds_to_play_before_fading_out = try!(r.read_le_u16());
-                try!(r.read_u8());
-                let fade_out_length = try!(r.read_le_i32());
+                r.seek(SeekFrom::Start(0xa9))?;
+                let seconds_to_play_before_fading_out = r.read_le_u16()? as i32;
+                r.read_u8()?;
+                let fade_out_length = r.read_le_i32()?;
 
-                (date_dumped, seconds_to_play_before_fading_out, fade_out_length)*/
+                (date_dumped, seconds_to_play_before_fading_out, fade_out_length)
             };
 
         let artist_name = Id666Tag::read_string(r, 32)?;
 
         let default_channel_disables = r.read_u8()?;
 
-        let dumping_emulator = match Id666Tag::read_digit(r) {
-            Ok(1) => Emulator::ZSnes,
-            Ok(2) => Emulator::Snes9x,
+        // Binary tags usually store the emulator as a number, but some store a
+        //  digit like text tags do.
+        let dumping_emulator = match r.read_u8()? {
+            b'1' => Emulator::ZSnes,
+            b'2' => Emulator::Snes9x,
+            1 if !is_text_format => Emulator::ZSnes,
+            2 if !is_text_format => Emulator::Snes9x,
             _ => Emulator::Unknown
         };
 
@@ -198,6 +254,46 @@ impl Id666Tag {
         })
     }
 
+    /// Writes the tag in text format into `buf`, which covers the SPC file
+    /// from 0x2e up to the start of RAM.
+    fn write(&self, buf: &mut [u8]) {
+        const BASE: usize = 0x2e;
+        let mut field = |offset: usize, len: usize, value: &str| {
+            Id666Tag::write_string(&mut buf[offset - BASE..offset - BASE + len], value);
+        };
+
+        field(0x2e, 32, &self.song_title);
+        field(0x4e, 32, &self.game_title);
+        field(0x6e, 16, &self.dumper_name);
+        field(0x7e, 32, &self.comments);
+
+        // Anything other than digits and slashes here would make the reader
+        //  mistake the tag for binary format.
+        let date_dumped: String = self.date_dumped.chars()
+            .filter(|&c| c.is_digit(10) || c == '/')
+            .collect();
+        field(0x9e, 11, &date_dumped);
+        field(0xa9, 3, &self.seconds_to_play_before_fading_out.max(0).min(999).to_string());
+        field(0xac, 5, &self.fade_out_length.max(0).min(99999).to_string());
+        field(0xb1, 32, &self.artist_name);
+
+        buf[0xd1 - BASE] = self.default_channel_disables;
+        buf[0xd2 - BASE] = match self.dumping_emulator {
+            Emulator::Unknown => b'0',
+            Emulator::ZSnes => b'1',
+            Emulator::Snes9x => b'2'
+        };
+    }
+
+    fn write_string(buf: &mut [u8], value: &str) {
+        // Strings are read back one byte per char, so anything outside
+        //  Latin-1 can't be represented.
+        let bytes = value.chars().map(|c| if (c as u32) <= 0xff { c as u8 } else { b'?' });
+        for (dest, b) in buf.iter_mut().zip(bytes) {
+            *dest = b;
+        }
+    }
+
     fn read_string<R: BinaryRead>(r: &mut R, max_len: i32) -> Result<String> {
         // TODO: Reimplement as iterator or something similar
         let mut ret = "".to_string();
@@ -229,11 +325,6 @@ impl Id666Tag {
         Ok(true)
     }
 
-    fn read_digit<R: BinaryRead>(r: &mut R) -> Result<i32> {
-        let d = r.read_u8()?;
-        Id666Tag::digit(d)
-    }
-
     fn digit(d: u8) -> Result<i32> {
         match char::from_u32(d as u32) {
             Some(c) if c.is_digit(10) => Ok(c.to_digit(10).unwrap() as i32),
@@ -254,3 +345,83 @@ impl Id666Tag {
         Ok(ret)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// An SPC file of zeros, with `tag` at 0x2e.
+    fn spc_file(tag: &[(usize, &[u8])]) -> Vec<u8> {
+        let mut file = vec![0; 0x10200];
+        file[..HEADER_LEN].copy_from_slice(HEADER_BYTES);
+        file[0x21..0x24].copy_from_slice(&[0x1a, 0x1a, 0x1a]);
+        file[0x24] = VERSION_MINOR;
+        for &(offset, bytes) in tag {
+            file[offset..offset + bytes.len()].copy_from_slice(bytes);
+        }
+        file
+    }
+
+    #[test]
+    fn test_text_id666() {
+        let file = spc_file(&[
+            (0x2e, b"Song"),
+            (0x4e, b"Game"),
+            (0x9e, b"02/03/2001"),
+            (0xa9, b"180"),
+            (0xac, b"10000"),
+            (0xb1, b"Artist"),
+            (0xd2, b"2"),
+        ]);
+        let tag = Spc::from_reader(Cursor::new(file)).unwrap().id666_tag.unwrap();
+        assert_eq!(tag.song_title, "Song");
+        assert_eq!(tag.game_title, "Game");
+        assert_eq!(tag.date_dumped, "02/03/2001");
+        assert_eq!(tag.seconds_to_play_before_fading_out, 180);
+        assert_eq!(tag.fade_out_length, 10000);
+        assert_eq!(tag.artist_name, "Artist");
+        assert_eq!(tag.dumping_emulator, Emulator::Snes9x);
+    }
+
+    #[test]
+    fn test_binary_id666() {
+        let file = spc_file(&[
+            (0x2e, b"Song"),
+            // Day, month and year.
+            (0x9e, &[3, 2, 0xd1, 0x07]),
+            (0xa9, &180u16.to_le_bytes()),
+            (0xac, &10000i32.to_le_bytes()),
+            (0xb0, b"Artist"),
+            (0xd1, &[1]),
+        ]);
+        let tag = Spc::from_reader(Cursor::new(file)).unwrap().id666_tag.unwrap();
+        assert_eq!(tag.song_title, "Song");
+        assert_eq!(tag.date_dumped, "02/03/2001");
+        assert_eq!(tag.seconds_to_play_before_fading_out, 180);
+        assert_eq!(tag.fade_out_length, 10000);
+        assert_eq!(tag.artist_name, "Artist");
+        assert_eq!(tag.dumping_emulator, Emulator::ZSnes);
+    }
+
+    #[test]
+    fn test_id666_round_trip() {
+        let file = spc_file(&[(0x2e, b"Song"), (0x9e, &[3, 2, 0xd1, 0x07]), (0xa9, &[180, 0])]);
+        let mut spc = Spc::from_reader(Cursor::new(file)).unwrap();
+        spc.xid6_tag = Some(Xid6Tag {
+            loop_length: Some(TICKS_PER_SECOND),
+            ..Xid6Tag::default()
+        });
+
+        // Binary tags are written back as text.
+        let mut written = vec![];
+        spc.to_writer(&mut written).unwrap();
+        let reloaded = Spc::from_reader(Cursor::new(&written)).unwrap();
+        let tag = reloaded.id666_tag.unwrap();
+        assert_eq!(tag.song_title, "Song");
+        assert_eq!(tag.date_dumped, "02/03/2001");
+        assert_eq!(tag.seconds_to_play_before_fading_out, 180);
+        assert_eq!(reloaded.xid6_tag, spc.xid6_tag);
+        assert_eq!(&written[0x9e..0xa9], b"02/03/2001\0");
+    }
+}
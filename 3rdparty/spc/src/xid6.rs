@@ -0,0 +1,276 @@
+use std::char;
+
+/// xid6 lengths are measured in ticks of 1/64000 of a second.
+pub const TICKS_PER_SECOND: u32 = 64000;
+
+const CHUNK_ID: &'static [u8; 4] = b"xid6";
+
+// Sub-chunk types
+const TYPE_DATA: u8 = 0;
+const TYPE_STRING: u8 = 1;
+const TYPE_INTEGER: u8 = 4;
+
+/// The extended ID666 tag, stored after the end of the SPC data. Every field
+///  is optional, and overrides the corresponding ID666 field if present.
+#[derive(Clone, Default, Debug, PartialEq)]
+pub struct Xid6Tag {
+    pub song_title: Option<String>,
+    pub game_title: Option<String>,
+    pub artist_name: Option<String>,
+    pub dumper_name: Option<String>,
+    /// Stored as yyyymmdd.
+    pub date_dumped: Option<u32>,
+    pub dumping_emulator: Option<u8>,
+    pub comments: Option<String>,
+    pub ost_title: Option<String>,
+    pub ost_disc: Option<u8>,
+    /// Track number, and an optional letter suffix (like 3b).
+    pub ost_track: Option<(u8, Option<char>)>,
+    pub publisher: Option<String>,
+    pub copyright_year: Option<u16>,
+    pub intro_length: Option<u32>,
+    pub loop_length: Option<u32>,
+    pub end_length: Option<u32>,
+    pub fade_length: Option<u32>,
+    pub muted_voices: Option<u8>,
+    pub loop_count: Option<u8>,
+    /// Preamp level, where 0x10000 is unity gain.
+    pub amplification: Option<u32>,
+}
+
+impl Xid6Tag {
+    /// Parses the data following the end of an SPC file. Returns None if
+    ///  there's no xid6 chunk. Malformed or unknown sub-chunks are skipped.
+    pub fn parse(buf: &[u8]) -> Option<Xid6Tag> {
+        if buf.len() < 8 || &buf[0..4] != CHUNK_ID {
+            return None;
+        }
+        let chunk_len = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]) as usize;
+        let end = buf.len().min(8 + chunk_len);
+
+        let mut tag = Xid6Tag::default();
+        let mut pos = 8;
+        while pos + 4 <= end {
+            let id = buf[pos];
+            let ty = buf[pos + 1];
+            let data = u16::from_le_bytes([buf[pos + 2], buf[pos + 3]]);
+            pos += 4;
+
+            let payload: &[u8] = if ty == TYPE_DATA {
+                &[]
+            } else {
+                let len = data as usize;
+                if pos + len > end {
+                    break;
+                }
+                let payload = &buf[pos..pos + len];
+                // Payloads are padded to a multiple of 4 bytes.
+                pos += (len + 3) & !3;
+                payload
+            };
+
+            let string = || {
+                Some(payload.iter()
+                    .take_while(|&&b| b != 0)
+                    .filter_map(|&b| char::from_u32(b as u32))
+                    .collect::<String>())
+            };
+            let integer = || {
+                if ty == TYPE_INTEGER && payload.len() >= 4 {
+                    Some(u32::from_le_bytes([payload[0], payload[1], payload[2], payload[3]]))
+                } else {
+                    None
+                }
+            };
+
+            match id {
+                0x01 => tag.song_title = string(),
+                0x02 => tag.game_title = string(),
+                0x03 => tag.artist_name = string(),
+                0x04 => tag.dumper_name = string(),
+                0x05 => tag.date_dumped = integer(),
+                0x06 => tag.dumping_emulator = Some(data as u8),
+                0x07 => tag.comments = string(),
+                0x10 => tag.ost_title = string(),
+                0x11 => tag.ost_disc = Some(data as u8),
+                0x12 => {
+                    let suffix = (data & 0xff) as u8;
+                    let suffix = if suffix.is_ascii_alphanumeric() { Some(suffix as char) } else { None };
+                    tag.ost_track = Some(((data >> 8) as u8, suffix));
+                },
+                0x13 => tag.publisher = string(),
+                0x14 => tag.copyright_year = Some(data),
+                0x30 => tag.intro_length = integer(),
+                0x31 => tag.loop_length = integer(),
+                0x32 => tag.end_length = integer(),
+                0x33 => tag.fade_length = integer(),
+                0x34 => tag.muted_voices = Some(data as u8),
+                0x35 => tag.loop_count = Some(data as u8),
+                0x36 => tag.amplification = integer(),
+                _ => ()
+            }
+        }
+
+        Some(tag)
+    }
+
+    /// Serializes the tag, including the chunk header.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+
+        fn data(body: &mut Vec<u8>, id: u8, value: Option<u16>) {
+            if let Some(value) = value {
+                body.extend_from_slice(&[id, TYPE_DATA]);
+                body.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        fn string(body: &mut Vec<u8>, id: u8, value: &Option<String>) {
+            if let Some(value) = value {
+                let mut bytes: Vec<u8> = value.chars()
+                    .map(|c| if (c as u32) <= 0xff { c as u8 } else { b'?' })
+                    .take(255)
+                    .collect();
+                bytes.push(0);
+                body.extend_from_slice(&[id, TYPE_STRING]);
+                body.extend_from_slice(&(bytes.len() as u16).to_le_bytes());
+                let padded_len = (bytes.len() + 3) & !3;
+                bytes.resize(padded_len, 0);
+                body.extend_from_slice(&bytes);
+            }
+        }
+        fn integer(body: &mut Vec<u8>, id: u8, value: Option<u32>) {
+            if let Some(value) = value {
+                body.extend_from_slice(&[id, TYPE_INTEGER]);
+                body.extend_from_slice(&4u16.to_le_bytes());
+                body.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+
+        string(&mut body, 0x01, &self.song_title);
+        string(&mut body, 0x02, &self.game_title);
+        string(&mut body, 0x03, &self.artist_name);
+        string(&mut body, 0x04, &self.dumper_name);
+        integer(&mut body, 0x05, self.date_dumped);
+        data(&mut body, 0x06, self.dumping_emulator.map(u16::from));
+        string(&mut body, 0x07, &self.comments);
+        string(&mut body, 0x10, &self.ost_title);
+        data(&mut body, 0x11, self.ost_disc.map(u16::from));
+        data(&mut body, 0x12, self.ost_track.map(|(track, suffix)| {
+            ((track as u16) << 8) | suffix.map_or(0, |c| c as u16 & 0xff)
+        }));
+        string(&mut body, 0x13, &self.publisher);
+        data(&mut body, 0x14, self.copyright_year);
+        integer(&mut body, 0x30, self.intro_length);
+        integer(&mut body, 0x31, self.loop_length);
+        integer(&mut body, 0x32, self.end_length);
+        integer(&mut body, 0x33, self.fade_length);
+        data(&mut body, 0x34, self.muted_voices.map(u16::from));
+        data(&mut body, 0x35, self.loop_count.map(u16::from));
+        integer(&mut body, 0x36, self.amplification);
+
+        let mut ret = Vec::with_capacity(8 + body.len());
+        ret.extend_from_slice(CHUNK_ID);
+        ret.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        ret.extend_from_slice(&body);
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(id: u8, ty: u8, data: u16, payload: &[u8]) -> Vec<u8> {
+        let mut item = vec![id, ty];
+        item.extend_from_slice(&data.to_le_bytes());
+        item.extend_from_slice(payload);
+        item
+    }
+
+    fn chunk(items: &[Vec<u8>]) -> Vec<u8> {
+        let body = items.concat();
+        let mut chunk = CHUNK_ID.to_vec();
+        chunk.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(&body);
+        chunk
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let tag = Xid6Tag {
+            song_title: Some("Title".to_string()),
+            game_title: Some("Gamé".to_string()),
+            artist_name: Some("".to_string()),
+            date_dumped: Some(20010203),
+            dumping_emulator: Some(2),
+            ost_disc: Some(1),
+            ost_track: Some((3, Some('b'))),
+            copyright_year: Some(1995),
+            intro_length: Some(TICKS_PER_SECOND * 5),
+            loop_length: Some(TICKS_PER_SECOND * 30),
+            end_length: Some(0),
+            fade_length: Some(TICKS_PER_SECOND * 10),
+            muted_voices: Some(0x81),
+            loop_count: Some(2),
+            amplification: Some(0x18000),
+            ..Xid6Tag::default()
+        };
+        let bytes = tag.to_bytes();
+        assert_eq!(bytes.len() % 4, 0);
+        assert_eq!(Xid6Tag::parse(&bytes), Some(tag.clone()));
+
+        // Serializing what was parsed gives the same bytes.
+        assert_eq!(Xid6Tag::parse(&bytes).unwrap().to_bytes(), bytes);
+
+        assert_eq!(Xid6Tag::parse(&Xid6Tag::default().to_bytes()), Some(Xid6Tag::default()));
+        assert_eq!(Xid6Tag::parse(b""), None);
+        assert_eq!(Xid6Tag::parse(b"xid5\0\0\0\0"), None);
+    }
+
+    #[test]
+    fn test_odd_length_items() {
+        let bytes = chunk(&[
+            // 3 bytes, padded to 4.
+            item(0x01, TYPE_STRING, 3, b"abc\0"),
+            // A string missing its NUL.
+            item(0x02, TYPE_STRING, 5, b"Game!\0\0\0"),
+            // An integer too short to read.
+            item(0x30, TYPE_INTEGER, 2, b"\x01\x02\0\0"),
+            // Unknown items are skipped.
+            item(0x7f, TYPE_STRING, 1, b"x\0\0\0"),
+            item(0x14, TYPE_DATA, 1995, b""),
+        ]);
+        let tag = Xid6Tag::parse(&bytes).unwrap();
+        assert_eq!(tag.song_title.as_deref(), Some("abc"));
+        assert_eq!(tag.game_title.as_deref(), Some("Game!"));
+        assert_eq!(tag.intro_length, None);
+        assert_eq!(tag.copyright_year, Some(1995));
+    }
+
+    #[test]
+    fn test_truncated() {
+        let items = [
+            item(0x01, TYPE_STRING, 6, b"Title\0\0\0"),
+            item(0x31, TYPE_INTEGER, 4, &64000u32.to_le_bytes()),
+            item(0x02, TYPE_STRING, 8, b"Game\0\0\0\0"),
+        ];
+        let bytes = chunk(&items);
+
+        // Items cut off by the end of the file are dropped, keeping those
+        // before them.
+        let tag = Xid6Tag::parse(&bytes[..bytes.len() - 2]).unwrap();
+        assert_eq!(tag.song_title.as_deref(), Some("Title"));
+        assert_eq!(tag.loop_length, Some(64000));
+        assert_eq!(tag.game_title, None);
+
+        // As are items past the length in the chunk header.
+        let mut short = bytes.clone();
+        short[4..8].copy_from_slice(&((items[0].len() + items[1].len() + 3) as u32).to_le_bytes());
+        let tag = Xid6Tag::parse(&short).unwrap();
+        assert_eq!(tag.loop_length, Some(64000));
+        assert_eq!(tag.game_title, None);
+
+        // A header with no room for items.
+        assert_eq!(Xid6Tag::parse(&bytes[..10]), Some(Xid6Tag::default()));
+    }
+}
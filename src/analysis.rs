@@ -0,0 +1,107 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+use eframe::epi;
+use rusqlite::Connection;
+
+/// What the analysis threads are doing, for display while they run.
+#[derive(Clone, Default, Debug)]
+pub struct AnalysisProgress {
+    pub files_total: usize,
+    pub files_done: usize,
+}
+
+/// Analyzes a file and writes the results to the database.
+pub type AnalyzeFn = fn(&Connection, &Path) -> Result<()>;
+
+/// Slow per-file analysis (like loop detection) of a list of files, running
+/// on one background thread per CPU. Results are written to the database as
+/// each file finishes.
+pub struct Analysis {
+    progress: Arc<Mutex<AnalysisProgress>>,
+    result: Receiver<Vec<(PathBuf, String)>>,
+}
+
+impl Analysis {
+    pub fn start(
+        db_path: PathBuf,
+        paths: Vec<PathBuf>,
+        analyze_file: AnalyzeFn,
+        repaint_signal: Arc<dyn epi::RepaintSignal>,
+    ) -> Analysis {
+        let progress = Arc::new(Mutex::new(AnalysisProgress {
+            files_total: paths.len(),
+            files_done: 0,
+        }));
+        let (tx, result) = channel();
+
+        {
+            let progress = progress.clone();
+            thread::spawn(move || {
+                let queue = Mutex::new(paths.into_iter());
+                let errors = Mutex::new(vec![]);
+                let threads = thread::available_parallelism().map_or(1, |n| n.get());
+
+                thread::scope(|s| {
+                    for _ in 0..threads {
+                        s.spawn(|| {
+                            let conn = match open(&db_path) {
+                                Ok(conn) => conn,
+                                Err(err) => {
+                                    errors
+                                        .lock()
+                                        .unwrap()
+                                        .push((db_path.clone(), err.to_string()));
+                                    return;
+                                }
+                            };
+                            loop {
+                                let path = match queue.lock().unwrap().next() {
+                                    Some(path) => path,
+                                    None => break,
+                                };
+                                if let Err(err) = analyze_file(&conn, &path) {
+                                    errors.lock().unwrap().push((path, format!("{:#}", err)));
+                                }
+                                progress.lock().unwrap().files_done += 1;
+                                repaint_signal.request_repaint();
+                            }
+                        });
+                    }
+                });
+
+                let _ = tx.send(errors.into_inner().unwrap());
+                repaint_signal.request_repaint();
+            });
+        }
+
+        Analysis { progress, result }
+    }
+
+    pub fn progress(&self) -> AnalysisProgress {
+        self.progress.lock().unwrap().clone()
+    }
+
+    /// Returns the files which couldn't be analyzed, and why, once every file
+    /// has been processed.
+    pub fn try_finish(&self) -> Option<Vec<(PathBuf, String)>> {
+        match self.result.try_recv() {
+            Ok(errors) => Some(errors),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                Some(vec![(PathBuf::new(), "analysis crashed".to_owned())])
+            }
+        }
+    }
+}
+
+fn open(db_path: &Path) -> Result<Connection> {
+    let conn = Connection::open(db_path)?;
+    // Other threads may be writing at the same time.
+    conn.busy_timeout(Duration::from_secs(10))?;
+    Ok(conn)
+}
@@ -1,16 +1,75 @@
 use eframe::{egui, epi};
 
+use std::collections::HashSet;
 use std::fs::create_dir_all;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use directories::ProjectDirs;
 use rusqlite::Connection;
 
-use crate::spcplay::{AudioHandle, SpcPlayer};
+use crate::analysis::{Analysis, AnalyzeFn};
+use crate::archive;
+use crate::audition::{self, Sampler};
+use crate::brr::{self, BrrSample, SampleScan};
+use crate::db;
+use crate::export::{self, Export, ExportJob};
+use crate::library::{self, LibraryEntry, LibraryFilter, Scan, SpcTags, MAX_RATING};
+use crate::loops::{self, LoopInfo};
+use crate::loudness::{self, ReplayGain};
+use crate::m3u;
+use crate::playlist::{self, Playlist, PlaylistEntry, Repeat, Shuffle};
+use crate::settings::Settings;
+use crate::sf2;
+use crate::soundtrack::SoundtrackExport;
+use crate::spcplay::{output_device_names, AudioOutput, PlaybackEvent, SpcPlayer, TrackOverrides};
+use crate::wav;
+use snes_apu::dsp::voice::ResamplingMode;
 
 static SETTINGS_NAME: &str = "settings.sqlite3";
 
+/// Files which can be opened, including archives of SPCs.
+static OPEN_EXTENSIONS: &[&str] = &["spc", "rsn", "rar", "zip"];
+
+/// Computer keys which play notes in the Samples window, as semitones above
+/// the keyboard's lowest note, laid out like a piano as in trackers.
+static NOTE_KEYS: &[(egui::Key, u8)] = &[
+    (egui::Key::Z, 0),
+    (egui::Key::S, 1),
+    (egui::Key::X, 2),
+    (egui::Key::D, 3),
+    (egui::Key::C, 4),
+    (egui::Key::V, 5),
+    (egui::Key::G, 6),
+    (egui::Key::B, 7),
+    (egui::Key::H, 8),
+    (egui::Key::N, 9),
+    (egui::Key::J, 10),
+    (egui::Key::M, 11),
+    (egui::Key::Q, 12),
+    (egui::Key::Num2, 13),
+    (egui::Key::W, 14),
+    (egui::Key::Num3, 15),
+    (egui::Key::E, 16),
+    (egui::Key::R, 17),
+    (egui::Key::Num5, 18),
+    (egui::Key::T, 19),
+    (egui::Key::Num6, 20),
+    (egui::Key::Y, 21),
+    (egui::Key::Num7, 22),
+    (egui::Key::U, 23),
+    (egui::Key::I, 24),
+];
+/// Octaves shown by the on-screen keyboard, which also has the top C.
+const PIANO_OCTAVES: u8 = 2;
+const PIANO_KEY_SIZE: egui::Vec2 = egui::vec2(22.0, 80.0);
+/// Semitones above C of the white keys.
+const WHITE_KEYS: [u8; 7] = [0, 2, 4, 5, 7, 9, 11];
+/// The octave of middle C.
+const DEFAULT_OCTAVE: u8 = 4;
+const MAX_OCTAVE: u8 = 7;
+
 fn create_config_dir() -> Result<PathBuf> {
     // On windows, dirs's ProjectDirs::from("org", "username", "appname") creates the
     // path "username/appname". See https://github.com/dirs-dev/directories-rs/blob/main/src/win.rs#L94.
@@ -26,57 +85,356 @@ fn create_config_dir() -> Result<PathBuf> {
     Ok(config_dir.to_owned())
 }
 
-fn open_settings() -> Result<Connection> {
-    let config_dir = create_config_dir()?;
-    let settings_path = config_dir.join(SETTINGS_NAME);
+pub fn settings_path() -> Result<PathBuf> {
+    Ok(create_config_dir()?.join(SETTINGS_NAME))
+}
+
+/// Opens and migrates the settings database. If the existing database can't be
+/// used, it's backed up and replaced, and the returned message says so.
+fn open_settings(settings_path: &Path) -> Result<(Connection, Option<String>)> {
+    let mut conn = Connection::open(settings_path)?;
+    let mut message = None;
+
+    if let Some(problem) = db::check(&conn)? {
+        drop(conn);
+        let backup_path = db::back_up(settings_path)?;
+        message = Some(format!(
+            "The {}. It was moved to {}, and settings were reset.",
+            problem,
+            backup_path.display()
+        ));
+        conn = Connection::open(settings_path)?;
+    }
+
+    db::migrate(&mut conn)?;
+
+    Ok((conn, message))
+}
+
+/// An archive opened from the Open dialog, shown as a virtual folder.
+struct ArchiveBrowser {
+    archive: PathBuf,
+    /// Paths of the SPCs inside, as returned by `archive::list_spcs()`.
+    spcs: Vec<PathBuf>,
+}
+
+/// A playlist entry queued to play after the current song.
+struct QueuedSong {
+    index: usize,
+    /// The playlist generation `index` was chosen from.
+    generation: u64,
+    path: PathBuf,
+}
+
+/// The instrument samples of the song playing when the Samples window was
+/// opened.
+struct SamplesPanel {
+    path: PathBuf,
+    tags: SpcTags,
+    samples: Vec<BrrSample>,
+    /// Finds `samples` in the background, until it finishes.
+    scan: Option<SampleScan>,
+    /// Indexes of the samples replaced from WAV files.
+    replaced: HashSet<usize>,
+    /// Index of the sample the keyboard plays.
+    selected: usize,
+    /// Octave of the keyboard's lowest note, where 4 starts at middle C.
+    octave: u8,
+    /// Notes held on the keyboard.
+    sounding: HashSet<u8>,
+}
+
+impl SamplesPanel {
+    fn lowest_note(&self) -> u8 {
+        (self.octave + 1) * 12
+    }
+}
+
+/// Draws a piano keyboard from `lowest`, a C, with the notes in `sounding`
+/// highlighted. Returns the note the pointer is pressing, if any.
+fn piano(ui: &mut egui::Ui, lowest: u8, sounding: &HashSet<u8>) -> Option<u8> {
+    let white_count = PIANO_OCTAVES as usize * WHITE_KEYS.len() + 1;
+    let size = egui::vec2(PIANO_KEY_SIZE.x * white_count as f32, PIANO_KEY_SIZE.y);
+    let (rect, response) = ui.allocate_exact_size(size, egui::Sense::click_and_drag());
+
+    // Black keys sit over the right edge of C, D, F, G and A, and are drawn
+    // after the white keys so they're on top.
+    let mut keys = vec![];
+    let mut black_keys = vec![];
+    for i in 0..white_count {
+        let note = lowest + (i / WHITE_KEYS.len()) as u8 * 12 + WHITE_KEYS[i % WHITE_KEYS.len()];
+        let left = rect.left() + i as f32 * PIANO_KEY_SIZE.x;
+        let key = egui::Rect::from_min_size(egui::pos2(left, rect.top()), PIANO_KEY_SIZE);
+        keys.push((note, key, false));
+        let has_black = matches!(note % 12, 0 | 2 | 5 | 7 | 9);
+        if has_black && i + 1 < white_count {
+            let black = egui::Rect::from_center_size(
+                egui::pos2(key.right(), rect.top() + PIANO_KEY_SIZE.y * 0.3),
+                egui::vec2(PIANO_KEY_SIZE.x * 0.6, PIANO_KEY_SIZE.y * 0.6),
+            );
+            black_keys.push((note + 1, black, true));
+        }
+    }
+    keys.extend(black_keys);
+
+    let painter = ui.painter();
+    let highlight = ui.visuals().selection.bg_fill;
+    for &(note, key, black) in &keys {
+        let fill = if sounding.contains(&note) {
+            highlight
+        } else if black {
+            egui::Color32::BLACK
+        } else {
+            egui::Color32::WHITE
+        };
+        painter.rect_filled(key, 1.0, fill);
+        painter.rect_stroke(key, 1.0, (1.0, egui::Color32::GRAY));
+    }
+
+    if !response.is_pointer_button_down_on() {
+        return None;
+    }
+    let pos = response.interact_pointer_pos()?;
+    keys.iter()
+        .rev()
+        .find(|(_, key, _)| key.contains(pos))
+        .map(|&(note, _, _)| note)
+}
+
+/// State of the Preferences window while it's open.
+struct Preferences {
+    /// Edited copy of the settings, applied when OK is pressed.
+    draft: Settings,
+    device_names: Vec<String>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum LibraryColumn {
+    Title,
+    Artist,
+    Length,
+    Rating,
+}
+
+/// State of the library panel.
+struct LibraryView {
+    /// Every file in the library, ordered by game and title.
+    entries: Vec<LibraryEntry>,
+    /// Indices into `entries` of files matching `filter`, grouped by game,
+    /// with groups sorted by name.
+    groups: Vec<(String, Vec<usize>)>,
+    filter: LibraryFilter,
+    sort_column: LibraryColumn,
+    sort_ascending: bool,
+    scan: Option<Scan>,
+    /// Loop detection for files without a length.
+    loop_analysis: Option<Analysis>,
+    loudness_analysis: Option<Analysis>,
+}
+
+impl LibraryView {
+    fn new() -> LibraryView {
+        LibraryView {
+            entries: vec![],
+            groups: vec![],
+            filter: LibraryFilter::default(),
+            sort_column: LibraryColumn::Title,
+            sort_ascending: true,
+            scan: None,
+            loop_analysis: None,
+            loudness_analysis: None,
+        }
+    }
+
+    /// Reloads every file from the database, then reapplies the filter.
+    fn load(&mut self, conn: &Connection) -> Result<()> {
+        self.entries = library::load_entries(conn)?;
+        self.apply_filter(conn)
+    }
+
+    /// Recomputes which files are shown, after `filter` changes.
+    fn apply_filter(&mut self, conn: &Connection) -> Result<()> {
+        let matches = if self.filter == LibraryFilter::default() {
+            None
+        } else {
+            Some(library::search(conn, &self.filter)?)
+        };
+
+        self.groups.clear();
+        for (i, entry) in self.entries.iter().enumerate() {
+            if let Some(matches) = &matches {
+                if !matches.contains(&entry.id) {
+                    continue;
+                }
+            }
+            match self.groups.last_mut() {
+                Some((game, group)) if *game == entry.game => group.push(i),
+                _ => self.groups.push((entry.game.clone(), vec![i])),
+            }
+        }
+        self.sort();
+        Ok(())
+    }
+
+    fn match_count(&self) -> usize {
+        self.groups.iter().map(|(_, group)| group.len()).sum()
+    }
+
+    fn sort(&mut self) {
+        let column = self.sort_column;
+        let entries = &self.entries;
+        for (_, group) in &mut self.groups {
+            group.sort_by(|&a, &b| {
+                let (a, b) = (&entries[a], &entries[b]);
+                match column {
+                    LibraryColumn::Title => a.title.cmp(&b.title),
+                    LibraryColumn::Artist => a.artist.cmp(&b.artist),
+                    LibraryColumn::Length => a.length_ms.cmp(&b.length_ms),
+                    LibraryColumn::Rating => a.rating.cmp(&b.rating),
+                }
+            });
+            if !self.sort_ascending {
+                group.reverse();
+            }
+        }
+    }
+
+    /// Sorts by `column`, or reverses the order if already sorted by it.
+    fn sort_by(&mut self, column: LibraryColumn) {
+        if self.sort_column == column {
+            self.sort_ascending = !self.sort_ascending;
+        } else {
+            self.sort_column = column;
+            self.sort_ascending = true;
+        }
+        self.sort();
+    }
+}
 
-    let conn = Connection::open(&settings_path)?;
+/// Shows a combo box choosing between any value (None), `Some(true)` and
+/// `Some(false)`.
+fn tri_state_combo(ui: &mut egui::Ui, label: &str, value: &mut Option<bool>, yes: &str, no: &str) {
+    let text = |value| match value {
+        None => "Any",
+        Some(true) => yes,
+        Some(false) => no,
+    };
+    egui::ComboBox::from_label(label)
+        .selected_text(text(*value))
+        .show_ui(ui, |ui| {
+            for &option in &[None, Some(true), Some(false)] {
+                ui.selectable_value(value, option, text(option));
+            }
+        });
+}
 
-    Ok(conn)
+/// Shows a checkbox enabling a length limit, and the limit if enabled.
+fn length_limit(ui: &mut egui::Ui, label: &str, value: &mut Option<u32>, default: u32) {
+    let mut enabled = value.is_some();
+    ui.checkbox(&mut enabled, label);
+    if enabled {
+        let secs = value.get_or_insert(default);
+        ui.add(
+            egui::DragValue::new(secs)
+                .clamp_range(0..=3599)
+                .suffix(" s"),
+        );
+    } else {
+        *value = None;
+    }
 }
 
-// TODO call in response to menu item
-fn touch_sql(conn: &Connection) -> Result<()> {
-    conn.execute(
-        "create table if not exists cat_colors (
-             id integer primary key,
-             name text not null unique
-         )",
-        [],
-    )?;
-    conn.execute(
-        "create table if not exists cats (
-             id integer primary key,
-             name text not null,
-             color_id integer not null references cat_colors(id)
-         )",
-        [],
-    )?;
-    Ok(())
+fn format_length(length_ms: Option<u32>) -> String {
+    match length_ms {
+        Some(ms) => format!("{}:{:02}", ms / 60000, ms / 1000 % 60),
+        None => "".to_owned(),
+    }
 }
 
 pub struct SpcPlayApp {
     // Settings database connection
-    settings: Option<Connection>,
+    settings_db: Option<Connection>,
+    settings_path: Option<PathBuf>,
+    settings: Settings,
+
+    library: LibraryView,
 
     error_dialog: Option<String>,
+    preferences: Option<Preferences>,
+    archive_browser: Option<ArchiveBrowser>,
+    samples_panel: Option<SamplesPanel>,
+
+    playlist: Playlist,
+    /// Index of the playlist entry being dragged to a new position.
+    playlist_drag: Option<usize>,
+    /// Whether the playlist was edited since it was last saved.
+    playlist_dirty: bool,
+    /// Renders the playlist to files in the background.
+    export: Option<Export>,
+    /// Renders the playlist to one file in the background.
+    soundtrack_export: Option<SoundtrackExport>,
 
-    audio: Option<AudioHandle>,
+    /// Set in `setup()`, and used to handle playback events while the window
+    /// is idle.
+    repaint_signal: Option<Arc<dyn epi::RepaintSignal>>,
+    /// Opened when something is first played, and kept open between songs.
+    output: Option<AudioOutput>,
+    queued: Option<QueuedSong>,
     spc_info: String,
 }
 
 impl SpcPlayApp {
     pub fn new() -> Self {
-        let settings = open_settings();
-        let (settings, error_dialog) = match settings {
-            Ok(settings) => (Some(settings), None),
-            Err(err) => (None, Some(err.to_string())),
+        let settings_db = settings_path().and_then(|path| Ok((open_settings(&path)?, path)));
+        let (settings_db, settings_path, mut error_dialog) = match settings_db {
+            Ok(((settings_db, message), path)) => (Some(settings_db), Some(path), message),
+            Err(err) => (None, None, Some(err.to_string())),
+        };
+
+        let settings = match settings_db.as_ref().map(Settings::load) {
+            Some(Ok(settings)) => settings,
+            Some(Err(err)) => {
+                error_dialog.get_or_insert_with(|| format!("Error loading settings: {}", err));
+                Settings::default()
+            }
+            None => Settings::default(),
+        };
+
+        let mut library = LibraryView::new();
+        if let Some(settings_db) = &settings_db {
+            if let Err(err) = library.load(settings_db) {
+                error_dialog.get_or_insert_with(|| format!("Error loading library: {}", err));
+            }
+        }
+
+        let playlist_entries = match settings_db.as_ref().map(playlist::load) {
+            Some(Ok(entries)) => entries,
+            Some(Err(err)) => {
+                error_dialog.get_or_insert_with(|| format!("Error loading playlist: {}", err));
+                vec![]
+            }
+            None => vec![],
         };
+        let mut playlist = Playlist::new(playlist_entries, settings.repeat, settings.shuffle);
+        playlist.set_current(settings.playlist_current);
 
         Self {
+            settings_db,
+            settings_path,
             settings,
+            library,
             error_dialog,
-            audio: None,
+            preferences: None,
+            archive_browser: None,
+            samples_panel: None,
+            playlist,
+            playlist_drag: None,
+            playlist_dirty: false,
+            export: None,
+            soundtrack_export: None,
+            repaint_signal: None,
+            output: None,
+            queued: None,
             spc_info: "".to_owned(),
         }
     }
@@ -91,9 +449,14 @@ impl epi::App for SpcPlayApp {
     fn setup(
         &mut self,
         ctx: &egui::CtxRef,
-        _frame: &mut epi::Frame<'_>,
+        frame: &mut epi::Frame<'_>,
         _storage: Option<&dyn epi::Storage>,
     ) {
+        if let Some((width, height)) = self.settings.window_size {
+            frame.set_window_size(egui::vec2(width, height));
+        }
+        self.repaint_signal = Some(frame.repaint_signal());
+
         // Set fonts.
         {
             static PROPORTIONAL: &str = "B612";
@@ -141,6 +504,12 @@ impl epi::App for SpcPlayApp {
         }
     }
 
+    fn on_exit(&mut self) {
+        if let Err(err) = self.save_settings() {
+            eprintln!("Error saving settings: {}", err);
+        }
+    }
+
     /// Called each time the UI needs repainting, which may be many times per second.
     /// Put your widgets into a `SidePanel`, `TopPanel`, `CentralPanel`, `Window` or `Area`.
     fn update(&mut self, ctx: &egui::CtxRef, frame: &mut epi::Frame<'_>) {
@@ -158,14 +527,68 @@ impl epi::App for SpcPlayApp {
                             self.error_dialog = Some(err.to_string());
                         }
                     }
+                    if ui
+                        .add(egui::Button::new("Save state as SPC…").enabled(self.is_playing()))
+                        .clicked()
+                    {
+                        if let Err(err) = self.on_save_state_pressed() {
+                            self.error_dialog = Some(err.to_string());
+                        }
+                    }
 
-                    if let Some(ref settings) = &self.settings {
-                        if ui.button("Run SQL").clicked() {
-                            if let Err(err) = touch_sql(settings) {
-                                self.error_dialog = Some(err.to_string());
-                            }
+                    if ui
+                        .add(egui::Button::new("Instrument samples…").enabled(self.is_playing()))
+                        .on_hover_text("List and export the samples the song plays")
+                        .clicked()
+                    {
+                        self.open_samples_panel();
+                    }
+
+                    if ui.button("Add folder to library…").clicked() {
+                        if let Err(err) = self.on_add_folder_pressed(frame) {
+                            self.error_dialog = Some(err.to_string());
+                        }
+                    }
+                    if ui
+                        .add(
+                            egui::Button::new("Rescan library")
+                                .enabled(self.library.scan.is_none()),
+                        )
+                        .clicked()
+                    {
+                        if let Err(err) = self.start_scan(frame) {
+                            self.error_dialog = Some(err.to_string());
+                        }
+                    }
+                    if ui
+                        .add(
+                            egui::Button::new("Detect song loops")
+                                .enabled(self.library.loop_analysis.is_none()),
+                        )
+                        .on_hover_text("Find the loop of every song in the library")
+                        .clicked()
+                    {
+                        if let Err(err) = self.start_analysis(false) {
+                            self.error_dialog = Some(err.to_string());
+                        }
+                    }
+                    if ui
+                        .add(
+                            egui::Button::new("Measure loudness")
+                                .enabled(self.library.loudness_analysis.is_none()),
+                        )
+                        .on_hover_text(
+                            "Measure the loudness of every song in the library, for ReplayGain",
+                        )
+                        .clicked()
+                    {
+                        if let Err(err) = self.start_loudness_analysis() {
+                            self.error_dialog = Some(err.to_string());
                         }
                     }
+                    if ui.button("Preferences…").clicked() {
+                        self.open_preferences();
+                    }
                     if ui.button("Quit").clicked() {
                         frame.quit();
                     }
@@ -173,18 +596,37 @@ impl epi::App for SpcPlayApp {
             });
         });
 
+        let screen_size = ctx.input().screen_rect.size();
+        self.settings.window_size = Some((screen_size.x, screen_size.y));
+
+        self.poll_scan();
+        self.poll_analysis();
+        self.poll_export();
+        self.poll_soundtrack_export();
+        self.poll_samples_scan();
+        self.poll_playback();
+
+        egui::SidePanel::right("playlist").show(ctx, |ui| {
+            self.show_playlist(ui);
+        });
+
         egui::CentralPanel::default().show(ctx, |ui| {
             // The central panel the region left after adding TopPanel's and SidePanel's
 
-            ui.heading("egui template");
-            ui.hyperlink("https://github.com/emilk/egui_template");
-            ui.add(egui::github_link_file!(
-                "https://github.com/emilk/egui_template/blob/master/",
-                "Source code."
-            ));
             egui::warn_if_debug_build(ui);
+            self.show_library(ui);
         });
 
+        self.show_preferences(ctx);
+        self.show_archive_browser(ctx);
+        self.show_samples_panel(ctx);
+
+        if let Err(err) = self.save_playlist() {
+            self.error_dialog = Some(format!("Error saving playlist: {}", err));
+            // Don't show the error every frame.
+            self.playlist_dirty = false;
+        }
+
         if let Some(ref msg) = &self.error_dialog {
             // sigh... can't use a single variable for both
             let mut open = true;
@@ -206,18 +648,1647 @@ impl epi::App for SpcPlayApp {
 }
 
 impl SpcPlayApp {
+    fn save_settings(&mut self) -> Result<()> {
+        self.settings.repeat = self.playlist.repeat();
+        self.settings.shuffle = self.playlist.shuffle();
+        self.settings.playlist_current = self.playlist.current();
+
+        if let Some(settings_db) = &mut self.settings_db {
+            self.settings.save(settings_db)?;
+        }
+        self.save_playlist()
+    }
+
+    fn save_playlist(&mut self) -> Result<()> {
+        if let (Some(settings_db), true) = (&mut self.settings_db, self.playlist_dirty) {
+            playlist::save(settings_db, self.playlist.entries())?;
+            self.playlist_dirty = false;
+        }
+        Ok(())
+    }
+
     fn on_open_pressed(&mut self) -> Result<()> {
-        if let Some(path) = rfd::FileDialog::new().pick_file() {
-            let player = SpcPlayer::new(&path)?;
+        let mut dialog =
+            rfd::FileDialog::new().add_filter("SPC files and archives", OPEN_EXTENSIONS);
+        if let Some(folder) = &self.settings.last_folder {
+            dialog = dialog.set_directory(folder);
+        }
+
+        if let Some(path) = dialog.pick_file() {
+            self.settings.last_folder = path.parent().map(Path::to_owned);
+            if archive::is_archive(&path) {
+                let spcs = archive::list_spcs(&path)?;
+                self.archive_browser = Some(ArchiveBrowser {
+                    archive: path,
+                    spcs,
+                });
+                return Ok(());
+            }
+            let index = self.playlist.add(vec![PlaylistEntry::from_file(&path)?]);
+            self.playlist_dirty = true;
+            self.play_index(index)?;
+        }
+
+        Ok(())
+    }
+
+    /// Plays a playlist entry.
+    fn play_index(&mut self, index: usize) -> Result<()> {
+        self.playlist.set_current(Some(index));
+        let entry = &self.playlist.entries()[index];
+        let (path, overrides) = (entry.path.clone(), entry.overrides);
+        self.open_file(&path, &overrides)
+    }
+
+    fn is_playing(&self) -> bool {
+        matches!(&self.output, Some(output) if output.is_playing())
+    }
+
+    /// Returns the output stream, opening it if needed.
+    fn output(&mut self) -> Result<&AudioOutput> {
+        if self.output.is_none() {
+            let repaint_signal = self.repaint_signal.clone();
+            let notify = Box::new(move || {
+                if let Some(repaint_signal) = &repaint_signal {
+                    repaint_signal.request_repaint();
+                }
+            });
+            self.output = Some(AudioOutput::new(&self.settings, notify)?);
+        }
+        Ok(self.output.as_ref().unwrap())
+    }
+
+    /// Loads a song, with its detected loop and loudness from the database.
+    /// Files which haven't been analyzed are analyzed in the background, so
+    /// the results are known next time.
+    fn new_player(&mut self, path: &Path, overrides: &TrackOverrides) -> Result<SpcPlayer> {
+        let hash = library::file_hash(&archive::read(path)?);
+        let loop_info = self.detected_loop(path, &hash).unwrap_or_else(|err| {
+            eprintln!("Error looking up loop of {}: {:#}", path.display(), err);
+            None
+        });
+        let mut player = SpcPlayer::new(path, &self.settings, overrides, loop_info)?;
+
+        match self.replay_gain_db(path, &hash) {
+            Ok(Some(gain_db)) => player.set_replay_gain_db(gain_db),
+            Ok(None) => {}
+            Err(err) => eprintln!("Error looking up loudness of {}: {:#}", path.display(), err),
+        }
+        Ok(player)
+    }
+
+    fn detected_loop(&mut self, path: &Path, hash: &str) -> Result<Option<LoopInfo>> {
+        let settings_db = match &self.settings_db {
+            Some(settings_db) => settings_db,
+            None => return Ok(None),
+        };
+        match loops::lookup(settings_db, hash)? {
+            Some(loop_info) => Ok(loop_info),
+            None => {
+                if self.library.loop_analysis.is_none() {
+                    self.library.loop_analysis =
+                        Some(self.analyze(vec![path.to_owned()], loops::analyze_file)?);
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    /// Returns the gain which normalizes a song's loudness, or None if
+    /// normalization is off or the song hasn't been measured.
+    fn replay_gain_db(&mut self, path: &Path, hash: &str) -> Result<Option<f64>> {
+        let settings_db = match &self.settings_db {
+            Some(settings_db) if self.settings.replay_gain != ReplayGain::Off => settings_db,
+            _ => return Ok(None),
+        };
+        let track = match loudness::lookup(settings_db, hash)? {
+            Some(track) => track,
+            None => {
+                if self.library.loudness_analysis.is_none() {
+                    self.library.loudness_analysis =
+                        Some(self.analyze(vec![path.to_owned()], loudness::analyze_file)?);
+                }
+                return Ok(None);
+            }
+        };
+        let loudness = match self.settings.replay_gain {
+            ReplayGain::Album => loudness::album_loudness(settings_db, hash)?.unwrap_or(track),
+            _ => track,
+        };
+        Ok(Some(loudness::gain_db(&self.settings, &loudness)))
+    }
+
+    /// Plays a file immediately, cutting off the current song.
+    fn open_file(&mut self, path: &Path, overrides: &TrackOverrides) -> Result<()> {
+        let player = self.new_player(path, overrides)?;
+        self.spc_info = player.get_spc_info();
+
+        self.output()?.play(player);
+        self.queued = None;
+        self.on_song_started(path)
+    }
+
+    fn on_song_started(&mut self, path: &Path) -> Result<()> {
+        if let Some(settings_db) = &self.settings_db {
+            library::record_play(settings_db, path)?;
+            if self.library.filter.played.is_some() {
+                self.library.apply_filter(settings_db)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles events from the audio thread, queueing the next song before
+    /// the current one ends so there's no gap between them.
+    fn poll_playback(&mut self) {
+        let output = match &self.output {
+            Some(output) => output,
+            None => return,
+        };
+
+        // The queued song may no longer be next if the playlist was edited.
+        // If it already started, its `Started` event is handled below.
+        if let Some(queued) = &self.queued {
+            if queued.generation != self.playlist.generation() && output.clear_queue() {
+                self.queued = None;
+            }
+        }
+
+        for event in output.take_events() {
+            let result = match event {
+                PlaybackEvent::NeedNext => self.queue_next(),
+                PlaybackEvent::Started => self.on_queued_started(),
+                PlaybackEvent::Stopped => self.on_stopped(),
+                PlaybackEvent::Looped => Ok(()),
+            };
+            if let Err(err) = result {
+                self.error_dialog = Some(format!("{:#}", err));
+            }
+        }
+    }
+
+    /// Plays the next song if the current one ended before the next could be
+    /// queued, eg. because it ended early in silence.
+    fn on_stopped(&mut self) -> Result<()> {
+        match self.playlist.next(false) {
+            Some(index) => self.play_index(index),
+            None => Ok(()),
+        }
+    }
+
+    fn queue_next(&mut self) -> Result<()> {
+        let index = match self.playlist.peek_next(false) {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+        let entry = &self.playlist.entries()[index];
+        let path = entry.path.clone();
+        let overrides = entry.overrides;
+        let player = self.new_player(&path, &overrides)?;
+
+        if let Some(output) = &self.output {
+            output.queue(player);
+            self.queued = Some(QueuedSong {
+                index,
+                generation: self.playlist.generation(),
+                path,
+            });
+        }
+        Ok(())
+    }
+
+    fn on_queued_started(&mut self) -> Result<()> {
+        let queued = match self.queued.take() {
+            Some(queued) => queued,
+            None => return Ok(()),
+        };
+        // The playlist may have been edited after the song started, moving
+        // its entry.
+        let entries = self.playlist.entries();
+        let index = Some(queued.index)
+            .filter(|&i| matches!(entries.get(i), Some(entry) if entry.path == queued.path))
+            .or_else(|| entries.iter().position(|entry| entry.path == queued.path));
+        self.playlist.set_current(index);
+
+        if let Some(output) = &self.output {
+            if let Some(player) = output.lock().current() {
+                self.spc_info = player.get_spc_info();
+            }
+        }
+        self.on_song_started(&queued.path)
+    }
+
+    fn on_next_pressed(&mut self) -> Result<()> {
+        if let Some(index) = self.playlist.next(true) {
+            self.play_index(index)?;
+        }
+        Ok(())
+    }
+
+    fn on_previous_pressed(&mut self) -> Result<()> {
+        if let Some(index) = self.playlist.previous() {
+            self.play_index(index)?;
+        }
+        Ok(())
+    }
+
+    fn on_add_files_pressed(&mut self) -> Result<()> {
+        let mut dialog =
+            rfd::FileDialog::new().add_filter("SPC files and archives", OPEN_EXTENSIONS);
+        if let Some(folder) = &self.settings.last_folder {
+            dialog = dialog.set_directory(folder);
+        }
+
+        if let Some(paths) = dialog.pick_files() {
+            if let Some(path) = paths.first() {
+                self.settings.last_folder = path.parent().map(Path::to_owned);
+            }
+            self.add_to_playlist(&paths)?;
+        }
+        Ok(())
+    }
+
+    fn on_add_folder_to_playlist_pressed(&mut self) -> Result<()> {
+        let mut dialog = rfd::FileDialog::new();
+        if let Some(folder) = &self.settings.last_folder {
+            dialog = dialog.set_directory(folder);
+        }
+
+        if let Some(folder) = dialog.pick_folder() {
+            let mut paths = vec![];
+            let mut errors = vec![];
+            library::find_spcs(&folder, &mut paths, &mut errors);
+            paths.sort();
+            self.add_to_playlist(&paths)?;
+            if let Some((path, err)) = errors.first() {
+                anyhow::bail!("Could not read {}: {}", path.display(), err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds files to the playlist, skipping any which can't be loaded. Archives
+    /// add every SPC inside them. Returns the first error.
+    fn add_to_playlist(&mut self, paths: &[PathBuf]) -> Result<()> {
+        let mut first_err = None;
+        let mut spcs = vec![];
+        for path in paths {
+            if archive::is_archive(path) {
+                match archive::list_spcs(path) {
+                    Ok(inner) => spcs.extend(inner),
+                    Err(err) => {
+                        first_err.get_or_insert(err);
+                    }
+                }
+            } else {
+                spcs.push(path.clone());
+            }
+        }
+
+        let entries: Vec<PlaylistEntry> = spcs
+            .iter()
+            .filter_map(|path| match PlaylistEntry::from_file(path) {
+                Ok(entry) => Some(entry),
+                Err(err) => {
+                    first_err.get_or_insert(err);
+                    None
+                }
+            })
+            .collect();
+        self.playlist.add(entries);
+        self.playlist_dirty = true;
+
+        match first_err {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    fn show_archive_browser(&mut self, ctx: &egui::CtxRef) {
+        let browser = match &self.archive_browser {
+            Some(browser) => browser,
+            None => return,
+        };
+
+        let mut open = true;
+        let mut add_all = false;
+        let mut to_play = None;
+
+        let title = match browser.archive.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => browser.archive.display().to_string(),
+        };
+        egui::Window::new(title)
+            .id(egui::Id::new("archive_browser"))
+            .open(&mut open)
+            .show(ctx, |ui| {
+                if browser.spcs.is_empty() {
+                    ui.label("This archive contains no SPC files.");
+                }
+                add_all = ui.button("Add all to playlist").clicked();
+                ui.separator();
+                egui::ScrollArea::auto_sized()
+                    .id_source("archive_browser")
+                    .show(ui, |ui| {
+                        for (i, path) in browser.spcs.iter().enumerate() {
+                            let name = match archive::split_path(path) {
+                                Some((_, inner)) => inner,
+                                None => path.display().to_string(),
+                            };
+                            if ui.selectable_label(false, name).double_clicked() {
+                                to_play = Some(i);
+                            }
+                        }
+                    });
+            });
 
-            self.spc_info = player.get_spc_info();
+        if add_all {
+            let spcs = browser.spcs.clone();
+            if let Err(err) = self.add_to_playlist(&spcs) {
+                self.error_dialog = Some(err.to_string());
+            }
+        }
+        if let Some(i) = to_play {
+            let path = self.archive_browser.as_ref().unwrap().spcs[i].clone();
+            let result = PlaylistEntry::from_file(&path).and_then(|entry| {
+                let index = self.playlist.add(vec![entry]);
+                self.playlist_dirty = true;
+                self.play_index(index)
+            });
+            if let Err(err) = result {
+                self.error_dialog = Some(format!("{:#}", err));
+            }
+        }
+        if !open {
+            self.archive_browser = None;
+        }
+    }
+
+    fn on_import_m3u_pressed(&mut self) -> Result<()> {
+        let mut dialog = rfd::FileDialog::new().add_filter("M3U playlists", &["m3u", "m3u8"]);
+        if let Some(folder) = &self.settings.last_folder {
+            dialog = dialog.set_directory(folder);
+        }
+
+        let path = match dialog.pick_file() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let data =
+            std::fs::read(&path).with_context(|| format!("Could not read {}", path.display()))?;
+        let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let m3u_entries = m3u::parse(&m3u::decode(&data), base_dir);
+
+        let mut errors = vec![];
+        let entries: Vec<PlaylistEntry> = m3u_entries
+            .iter()
+            .filter_map(|m3u_entry| match PlaylistEntry::from_m3u(m3u_entry) {
+                Ok(entry) => Some(entry),
+                Err(err) => {
+                    errors.push(err);
+                    None
+                }
+            })
+            .collect();
+        self.playlist.add(entries);
+        self.playlist_dirty = true;
+
+        if !errors.is_empty() {
+            let mut msg = format!("{} files could not be added:\n", errors.len());
+            for err in errors.iter().take(10) {
+                msg += &format!("{:#}\n", err);
+            }
+            anyhow::bail!(msg);
+        }
+        Ok(())
+    }
+
+    /// Saves the playlist as M3U. If `extended` is true, titles and timing
+    /// are written in `::SPC` fields, which other SPC players understand.
+    fn on_export_m3u_pressed(&mut self, extended: bool) -> Result<()> {
+        let mut dialog = rfd::FileDialog::new().add_filter("M3U playlists", &["m3u", "m3u8"]);
+        if let Some(folder) = &self.settings.last_folder {
+            dialog = dialog.set_directory(folder);
+        }
+
+        if let Some(path) = dialog.save_file() {
+            let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+            let m3u_entries: Vec<_> = self.playlist.entries().iter().map(|e| e.to_m3u()).collect();
+            let text = m3u::write(&m3u_entries, base_dir, extended);
+
+            // Players assume .m3u files use the system code page, so only
+            // use UTF-8 for .m3u8 files. Non-Latin-1 characters become ?.
+            let is_m3u8 = matches!(path.extension(), Some(ext) if ext.eq_ignore_ascii_case("m3u8"));
+            let data: Vec<u8> = if is_m3u8 {
+                text.into_bytes()
+            } else {
+                text.chars()
+                    .map(|c| if (c as u32) <= 0xff { c as u8 } else { b'?' })
+                    .collect()
+            };
+            std::fs::write(&path, data)
+                .with_context(|| format!("Could not save {}", path.display()))?;
+        }
+        Ok(())
+    }
 
-            let audio = AudioHandle::new(player)?;
-            self.audio = Some(audio);
+    /// The playlist's songs to export, and the settings to render them with.
+    fn export_jobs(&self) -> (Vec<ExportJob>, Settings) {
+        let jobs = self
+            .playlist
+            .entries()
+            .iter()
+            .map(|entry| ExportJob {
+                path: entry.path.clone(),
+                overrides: entry.overrides,
+            })
+            .collect();
+        // Files are rendered at full volume, whatever the player's is.
+        let mut settings = self.settings.clone();
+        settings.volume = 1.0;
+        (jobs, settings)
+    }
 
-            self.audio.as_ref().unwrap().play()?;
+    /// Renders every playlist entry into a folder, in the background, named by
+    /// the export template.
+    fn on_export_audio_pressed(&mut self) -> Result<()> {
+        export::check_template(&self.settings.export_template)?;
+        let repaint_signal = self.repaint_signal.clone().context("window is not ready")?;
+        let mut dialog = rfd::FileDialog::new();
+        if let Some(folder) = &self.settings.last_folder {
+            dialog = dialog.set_directory(folder);
         }
 
+        if let Some(folder) = dialog.pick_folder() {
+            let (jobs, settings) = self.export_jobs();
+            self.export = Some(Export::start(
+                jobs,
+                folder,
+                self.settings.export_template.clone(),
+                settings,
+                self.settings_path.clone(),
+                Arc::new(move || repaint_signal.request_repaint()),
+            ));
+        }
         Ok(())
     }
+
+    /// Renders the playlist into one file, in the background, with a cue
+    /// sheet next to it.
+    fn on_export_soundtrack_pressed(&mut self) -> Result<()> {
+        let repaint_signal = self.repaint_signal.clone().context("window is not ready")?;
+        let mut dialog = rfd::FileDialog::new()
+            .add_filter("FLAC", &["flac"])
+            .add_filter("WAV", &["wav"]);
+        if let Some(folder) = &self.settings.last_folder {
+            dialog = dialog.set_directory(folder);
+        }
+
+        if let Some(path) = dialog.save_file() {
+            let (jobs, settings) = self.export_jobs();
+            self.soundtrack_export = Some(SoundtrackExport::start(
+                jobs,
+                path,
+                settings,
+                self.settings_path.clone(),
+                Arc::new(move || repaint_signal.request_repaint()),
+            ));
+        }
+        Ok(())
+    }
+
+    fn poll_soundtrack_export(&mut self) {
+        let result = match self
+            .soundtrack_export
+            .as_ref()
+            .and_then(SoundtrackExport::try_finish)
+        {
+            Some(result) => result,
+            None => return,
+        };
+        self.soundtrack_export = None;
+        match result {
+            Ok(errors) if !errors.is_empty() => {
+                let mut msg = format!("{} files were left out:\n", errors.len());
+                for (path, err) in errors.iter().take(10) {
+                    msg += &format!("{}: {}\n", path.display(), err);
+                }
+                self.error_dialog = Some(msg);
+            }
+            Ok(_) => {}
+            Err(err) => self.error_dialog = Some(format!("Error exporting: {:#}", err)),
+        }
+    }
+
+    fn poll_export(&mut self) {
+        let errors = match self.export.as_ref().and_then(Export::try_finish) {
+            Some(errors) => errors,
+            None => return,
+        };
+        self.export = None;
+        if !errors.is_empty() {
+            let mut msg = format!("{} files could not be exported:\n", errors.len());
+            for (path, err) in errors.iter().take(10) {
+                msg += &format!("{}: {}\n", path.display(), err);
+            }
+            self.error_dialog = Some(msg);
+        }
+    }
+
+    /// Opens the Samples window with the samples of the song playing.
+    fn open_samples_panel(&mut self) {
+        let (path, spc, tags) = match &self.output {
+            Some(output) => match output.lock().current() {
+                Some(player) => (player.path().to_owned(), player.to_spc(), player.tags()),
+                None => return,
+            },
+            None => return,
+        };
+        // Emulating the song to set up the sampler takes a while, so don't
+        // hold up playback meanwhile.
+        let sampler = Sampler::new(&spc);
+        if let Some(output) = &self.output {
+            output.lock().set_sampler(Some(sampler));
+        }
+        let repaint_signal = self.repaint_signal.clone();
+        let notify = Arc::new(move || {
+            if let Some(repaint_signal) = &repaint_signal {
+                repaint_signal.request_repaint();
+            }
+        });
+        self.samples_panel = Some(SamplesPanel {
+            path,
+            tags,
+            samples: vec![],
+            scan: Some(SampleScan::start(spc, notify)),
+            replaced: HashSet::new(),
+            selected: 0,
+            octave: DEFAULT_OCTAVE,
+            sounding: HashSet::new(),
+        });
+    }
+
+    fn poll_samples_scan(&mut self) {
+        let panel = match &mut self.samples_panel {
+            Some(panel) => panel,
+            None => return,
+        };
+        let result = match panel.scan.as_ref().and_then(SampleScan::try_finish) {
+            Some(result) => result,
+            None => return,
+        };
+        panel.scan = None;
+        match result {
+            Ok(samples) => panel.samples = samples,
+            Err(err) => self.error_dialog = Some(format!("{:#}", err)),
+        }
+    }
+
+    fn show_samples_panel(&mut self, ctx: &egui::CtxRef) {
+        let panel = match &mut self.samples_panel {
+            Some(panel) => panel,
+            None => return,
+        };
+
+        let mut open = true;
+        let mut to_export = None;
+        let mut to_replace = None;
+        let mut export_all = false;
+        let mut export_sf2 = false;
+        let mut clicked_note = None;
+        egui::Window::new(format!("Samples of {}", panel.tags.title))
+            .id(egui::Id::new("samples_panel"))
+            .open(&mut open)
+            .show(ctx, |ui| {
+                if panel.scan.is_some() {
+                    ui.label("Scanning…");
+                } else if panel.samples.is_empty() {
+                    ui.label("No samples were found.");
+                }
+                ui.horizontal(|ui| {
+                    export_all = ui
+                        .add(egui::Button::new("Export all…").enabled(!panel.samples.is_empty()))
+                        .clicked();
+                    export_sf2 = ui
+                        .add(
+                            egui::Button::new("Export SoundFont…")
+                                .enabled(!panel.samples.is_empty()),
+                        )
+                        .clicked();
+                });
+                ui.separator();
+                egui::ScrollArea::auto_sized()
+                    .id_source("samples_panel")
+                    .show(ui, |ui| {
+                        egui::Grid::new("samples").striped(true).show(ui, |ui| {
+                            for (i, sample) in panel.samples.iter().enumerate() {
+                                let title = brr::sample_tags(sample, &panel.tags).title;
+                                if ui
+                                    .selectable_label(panel.selected == i, title)
+                                    .on_hover_text("Play this sample with the keyboard")
+                                    .clicked()
+                                {
+                                    panel.selected = i;
+                                }
+                                ui.label(format!("${:04X}", sample.address));
+                                ui.label(format!("{:.2} s", sample.duration_secs()));
+                                ui.label(format!("{} blocks", sample.brr.len() / brr::BLOCK_LEN));
+                                ui.label(if sample.loop_start.is_some() {
+                                    "Loops"
+                                } else {
+                                    ""
+                                });
+                                ui.label(if panel.replaced.contains(&i) {
+                                    "Replaced"
+                                } else {
+                                    ""
+                                });
+                                if ui.button("Export…").clicked() {
+                                    to_export = Some(i);
+                                }
+                                if ui.button("Replace…").clicked() {
+                                    to_replace = Some(i);
+                                }
+                                ui.end_row();
+                            }
+                        });
+                    });
+                if panel.samples.is_empty() {
+                    return;
+                }
+
+                ui.separator();
+                ui.horizontal(|ui| {
+                    ui.label("Octave");
+                    if ui
+                        .add(egui::Button::new("−").enabled(panel.octave > 0))
+                        .clicked()
+                    {
+                        panel.octave -= 1;
+                    }
+                    if ui
+                        .add(egui::Button::new("+").enabled(panel.octave < MAX_OCTAVE))
+                        .clicked()
+                    {
+                        panel.octave += 1;
+                    }
+                    ui.label(format!(
+                        "{} to {}",
+                        audition::note_name(panel.lowest_note()),
+                        audition::note_name(panel.lowest_note() + PIANO_OCTAVES * 12)
+                    ));
+                });
+                clicked_note = piano(ui, panel.lowest_note(), &panel.sounding);
+                ui.label("Or play with Z to M and Q to I, with the black keys above them.");
+            });
+
+        // Notes held with the mouse or the computer keyboard.
+        let mut notes: HashSet<u8> = clicked_note.into_iter().collect();
+        if !ctx.wants_keyboard_input() {
+            let input = ctx.input();
+            for &(key, offset) in NOTE_KEYS {
+                if input.key_down(key) {
+                    notes.insert(panel.lowest_note() + offset);
+                }
+            }
+        }
+        if !open || panel.samples.is_empty() {
+            notes.clear();
+        }
+        self.play_notes(notes);
+
+        let result = if export_sf2 {
+            self.on_export_sf2_pressed()
+        } else if export_all {
+            self.on_export_samples_pressed(None)
+        } else if let Some(i) = to_export {
+            self.on_export_samples_pressed(Some(i))
+        } else if let Some(i) = to_replace {
+            self.on_replace_sample_pressed(i)
+        } else {
+            Ok(())
+        };
+        if let Err(err) = result {
+            self.error_dialog = Some(format!("{:#}", err));
+        }
+        if !open {
+            self.samples_panel = None;
+            if let Some(output) = &self.output {
+                output.lock().set_sampler(None);
+            }
+        }
+    }
+
+    /// Plays `notes` with the sample selected in the Samples window, keying
+    /// notes on and off as they change.
+    fn play_notes(&mut self, notes: HashSet<u8>) {
+        let (panel, output) = match (&mut self.samples_panel, &self.output) {
+            (Some(panel), Some(output)) => (panel, output),
+            _ => return,
+        };
+        if notes == panel.sounding {
+            return;
+        }
+        let mut playback = output.lock();
+        let sampler = match playback.sampler_mut() {
+            Some(sampler) => sampler,
+            None => return,
+        };
+        for &note in panel.sounding.difference(&notes) {
+            sampler.note_off(note);
+        }
+        let sample = &panel.samples[panel.selected];
+        let source = sample.sources[0];
+        let envelope = sample
+            .envelope(source)
+            .unwrap_or(audition::DEFAULT_ENVELOPE);
+        for &note in notes.difference(&panel.sounding) {
+            sampler.note_on(source, note, sample.base_note(source), envelope);
+        }
+        panel.sounding = notes;
+    }
+
+    /// Saves one sample from the Samples window as WAV, or if `index` is None,
+    /// saves them all into a folder.
+    fn on_export_samples_pressed(&mut self, index: Option<usize>) -> Result<()> {
+        let panel = match &self.samples_panel {
+            Some(panel) => panel,
+            None => return Ok(()),
+        };
+        let mut dialog = rfd::FileDialog::new();
+        if let Some(folder) = &self.settings.last_folder {
+            dialog = dialog.set_directory(folder);
+        }
+
+        let file_name = |sample: &BrrSample| {
+            let title = if panel.tags.title.is_empty() {
+                "song"
+            } else {
+                &panel.tags.title
+            };
+            export::sanitize(&format!("{} - sample {}.wav", title, sample.sources[0]))
+        };
+        match index {
+            Some(i) => {
+                let sample = &panel.samples[i];
+                if let Some(path) = dialog
+                    .add_filter("WAV files", &["wav"])
+                    .set_file_name(&file_name(sample))
+                    .save_file()
+                {
+                    brr::write_wav(sample, &path, &brr::sample_tags(sample, &panel.tags))?;
+                }
+            }
+            None => {
+                if let Some(folder) = dialog.pick_folder() {
+                    for sample in &panel.samples {
+                        let path = folder.join(file_name(sample));
+                        brr::write_wav(sample, &path, &brr::sample_tags(sample, &panel.tags))?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces a sample of the song playing with one from a WAV file, tuned
+    /// to play the same notes, so the song can be heard with a different
+    /// instrument.
+    fn on_replace_sample_pressed(&mut self, index: usize) -> Result<()> {
+        let panel = match &mut self.samples_panel {
+            Some(panel) => panel,
+            None => return Ok(()),
+        };
+        let mut dialog = rfd::FileDialog::new().add_filter("WAV files", &["wav"]);
+        if let Some(folder) = &self.settings.last_folder {
+            dialog = dialog.set_directory(folder);
+        }
+        let path = match dialog.pick_file() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let sample = &panel.samples[index];
+        let encoded = brr::encode_wav(&wav::read(&path)?, sample.base_note(sample.sources[0]))?;
+
+        let output = match &self.output {
+            Some(output) => output,
+            None => return Ok(()),
+        };
+        let mut playback = output.lock();
+        match playback.current_mut() {
+            Some(player) if player.path() == panel.path => {
+                player.replace_sample(&sample.sources, &encoded)?;
+            }
+            _ => bail!("The song in the Samples window is no longer playing"),
+        }
+        if let Some(sampler) = playback.sampler_mut() {
+            sampler.replace_sample(&sample.sources, &encoded)?;
+        }
+        panel.replaced.insert(index);
+        Ok(())
+    }
+
+    /// Saves the samples in the Samples window as a SoundFont, tuned and
+    /// shaped as the song plays them.
+    fn on_export_sf2_pressed(&mut self) -> Result<()> {
+        let panel = match &self.samples_panel {
+            Some(panel) => panel,
+            None => return Ok(()),
+        };
+        let title = if panel.tags.title.is_empty() {
+            "song"
+        } else {
+            &panel.tags.title
+        };
+        let mut dialog = rfd::FileDialog::new()
+            .add_filter("SoundFonts", &["sf2"])
+            .set_file_name(&export::sanitize(&format!("{}.sf2", title)));
+        if let Some(folder) = &self.settings.last_folder {
+            dialog = dialog.set_directory(folder);
+        }
+        if let Some(path) = dialog.save_file() {
+            let data = sf2::to_sf2(
+                &panel.samples,
+                &panel.tags,
+                &brr::base_notes(&panel.samples),
+            );
+            std::fs::write(&path, data)
+                .with_context(|| format!("Could not save {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Describes which part of the current song's loop is playing.
+    fn loop_status(&self) -> Option<String> {
+        let playback = self.output.as_ref()?.lock();
+        let player = playback.current()?;
+        Some(match (player.current_loop()?, player.loop_count()) {
+            (0, _) => "Intro".to_owned(),
+            (n, Some(count)) if count > 0 && n > count => "Fading out".to_owned(),
+            (n, Some(count)) if count > 0 => format!("Loop {} of {}", n, count),
+            (n, _) => format!("Loop {}", n),
+        })
+    }
+
+    fn show_playlist(&mut self, ui: &mut egui::Ui) {
+        ui.heading("Playlist");
+
+        ui.horizontal(|ui| {
+            if ui.button("⏮").on_hover_text("Previous").clicked() {
+                if let Err(err) = self.on_previous_pressed() {
+                    self.error_dialog = Some(err.to_string());
+                }
+            }
+            if ui.button("⏹").on_hover_text("Stop").clicked() {
+                if let Some(output) = &self.output {
+                    output.stop();
+                }
+                self.queued = None;
+            }
+            if ui.button("⏭").on_hover_text("Next").clicked() {
+                if let Err(err) = self.on_next_pressed() {
+                    self.error_dialog = Some(err.to_string());
+                }
+            }
+            if let Some(status) = self.loop_status() {
+                ui.label(status);
+            }
+        });
+
+        ui.horizontal(|ui| {
+            let mut repeat = self.playlist.repeat();
+            egui::ComboBox::from_label("Repeat")
+                .selected_text(match repeat {
+                    Repeat::Off => "Off",
+                    Repeat::One => "One",
+                    Repeat::All => "All",
+                })
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut repeat, Repeat::Off, "Off");
+                    ui.selectable_value(&mut repeat, Repeat::One, "One");
+                    ui.selectable_value(&mut repeat, Repeat::All, "All");
+                });
+            self.playlist.set_repeat(repeat);
+        });
+        ui.horizontal(|ui| {
+            let mut shuffle = self.playlist.shuffle();
+            egui::ComboBox::from_label("Shuffle")
+                .selected_text(match shuffle {
+                    Shuffle::Off => "Off",
+                    Shuffle::Tracks => "Tracks",
+                    Shuffle::Games => "Games",
+                })
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut shuffle, Shuffle::Off, "Off");
+                    ui.selectable_value(&mut shuffle, Shuffle::Tracks, "Tracks");
+                    ui.selectable_value(&mut shuffle, Shuffle::Games, "Games");
+                });
+            self.playlist.set_shuffle(shuffle);
+        });
+
+        ui.horizontal(|ui| {
+            if ui.button("Add files…").clicked() {
+                if let Err(err) = self.on_add_files_pressed() {
+                    self.error_dialog = Some(err.to_string());
+                }
+            }
+            if ui.button("Add folder…").clicked() {
+                if let Err(err) = self.on_add_folder_to_playlist_pressed() {
+                    self.error_dialog = Some(err.to_string());
+                }
+            }
+            if ui.button("Clear").clicked() {
+                self.playlist.clear();
+                self.playlist_dirty = true;
+            }
+        });
+        ui.horizontal(|ui| {
+            let result = if ui.button("Import M3U…").clicked() {
+                self.on_import_m3u_pressed()
+            } else if ui.button("Export M3U…").clicked() {
+                self.on_export_m3u_pressed(false)
+            } else if ui
+                .button("Export extended M3U…")
+                .on_hover_text(
+                    "Saves titles, lengths and fades as file::SPC,title,length,loop,fade",
+                )
+                .clicked()
+            {
+                self.on_export_m3u_pressed(true)
+            } else if ui
+                .add(egui::Button::new("Export audio…").enabled(self.export.is_none()))
+                .on_hover_text("Render every song to a WAV or FLAC file")
+                .clicked()
+            {
+                self.on_export_audio_pressed()
+            } else if ui
+                .add(
+                    egui::Button::new("Export as one file…")
+                        .enabled(self.soundtrack_export.is_none()),
+                )
+                .on_hover_text("Render the playlist to one WAV or FLAC file, with a cue sheet")
+                .clicked()
+            {
+                self.on_export_soundtrack_pressed()
+            } else {
+                Ok(())
+            };
+            if let Err(err) = result {
+                self.error_dialog = Some(err.to_string());
+            }
+        });
+        if let Some(export) = &self.export {
+            let progress = export.progress();
+            ui.label(format!(
+                "Exporting… {}/{} files",
+                progress.files_done, progress.files_total
+            ));
+        }
+        if self.soundtrack_export.is_some() {
+            ui.label("Exporting soundtrack…");
+        }
+        ui.separator();
+
+        let mut to_play = None;
+        let mut to_remove = None;
+        let mut row_rects = vec![];
+
+        let playlist = &self.playlist;
+        let playlist_drag = &mut self.playlist_drag;
+        egui::ScrollArea::auto_sized()
+            .id_source("playlist")
+            .show(ui, |ui| {
+                for (i, entry) in playlist.entries().iter().enumerate() {
+                    ui.horizontal(|ui| {
+                        if ui.small_button("✖").on_hover_text("Remove").clicked() {
+                            to_remove = Some(i);
+                        }
+                        let is_current = playlist.current() == Some(i);
+                        let response = ui
+                            .selectable_label(is_current, entry.display_title())
+                            .interact(egui::Sense::drag())
+                            .on_hover_text(entry.path.display().to_string());
+                        if response.double_clicked() {
+                            to_play = Some(i);
+                        }
+                        if response.drag_started() {
+                            *playlist_drag = Some(i);
+                        }
+                        row_rects.push(response.rect);
+                        ui.label(format_length(entry.effective_length_ms()));
+                    });
+                }
+            });
+
+        // Drop the dragged entry onto the row under the pointer.
+        if let Some(from) = self.playlist_drag {
+            let input = ui.input();
+            if input.pointer.any_released() {
+                let to = input.pointer.hover_pos().and_then(|pos| {
+                    row_rects
+                        .iter()
+                        .position(|rect| rect.min.y <= pos.y && pos.y < rect.max.y)
+                });
+                if let Some(to) = to {
+                    self.playlist.move_entry(from, to);
+                    self.playlist_dirty = true;
+                }
+                self.playlist_drag = None;
+            }
+        }
+
+        if let Some(i) = to_remove {
+            self.playlist.remove(i);
+            self.playlist_dirty = true;
+        }
+        if let Some(i) = to_play {
+            if let Err(err) = self.play_index(i) {
+                self.error_dialog = Some(err.to_string());
+            }
+        }
+    }
+
+    fn on_add_folder_pressed(&mut self, frame: &epi::Frame<'_>) -> Result<()> {
+        let settings_db = self
+            .settings_db
+            .as_ref()
+            .context("settings database is unavailable")?;
+
+        let mut dialog = rfd::FileDialog::new();
+        if let Some(folder) = &self.settings.last_folder {
+            dialog = dialog.set_directory(folder);
+        }
+
+        if let Some(path) = dialog.pick_folder() {
+            library::add_folder(settings_db, &path)?;
+            if self.library.scan.is_none() {
+                self.start_scan(frame)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Rescans every library folder in the background.
+    fn start_scan(&mut self, frame: &epi::Frame<'_>) -> Result<()> {
+        let (settings_db, settings_path) = match (&self.settings_db, &self.settings_path) {
+            (Some(settings_db), Some(settings_path)) => (settings_db, settings_path),
+            _ => anyhow::bail!("settings database is unavailable"),
+        };
+
+        let folders = library::folders(settings_db)?;
+        self.library.scan = Some(Scan::start(
+            settings_path.clone(),
+            folders,
+            frame.repaint_signal(),
+        ));
+        Ok(())
+    }
+
+    /// Reloads the library when a scan finishes.
+    fn poll_scan(&mut self) {
+        let result = match self.library.scan.as_ref().and_then(Scan::try_finish) {
+            Some(result) => result,
+            None => return,
+        };
+        self.library.scan = None;
+
+        match result {
+            Ok(summary) if !summary.errors.is_empty() => {
+                let mut msg = format!("{} files could not be scanned:\n", summary.errors.len());
+                for (path, err) in summary.errors.iter().take(10) {
+                    msg += &format!("{}: {}\n", path.display(), err);
+                }
+                self.error_dialog = Some(msg);
+            }
+            Ok(_) => {}
+            Err(err) => self.error_dialog = Some(format!("Error scanning library: {:#}", err)),
+        }
+
+        if self.library.loop_analysis.is_none() {
+            if let Err(err) = self.start_analysis(true) {
+                self.error_dialog = Some(err.to_string());
+            }
+        }
+
+        if let Some(settings_db) = &self.settings_db {
+            if let Err(err) = self.library.load(settings_db) {
+                self.error_dialog = Some(err.to_string());
+            }
+        }
+    }
+
+    /// Detects the loops of library files which haven't been analyzed, in the
+    /// background. If `untagged_only` is true, files whose tags give a length
+    /// are skipped.
+    fn start_analysis(&mut self, untagged_only: bool) -> Result<()> {
+        let settings_db = self
+            .settings_db
+            .as_ref()
+            .context("settings database is unavailable")?;
+        let paths = loops::unanalyzed_files(settings_db, untagged_only)?;
+        if !paths.is_empty() {
+            self.library.loop_analysis = Some(self.analyze(paths, loops::analyze_file)?);
+        }
+        Ok(())
+    }
+
+    /// Measures the loudness of library files which haven't been measured, in
+    /// the background.
+    fn start_loudness_analysis(&mut self) -> Result<()> {
+        let settings_db = self
+            .settings_db
+            .as_ref()
+            .context("settings database is unavailable")?;
+        let paths = loudness::unmeasured_files(settings_db)?;
+        if !paths.is_empty() {
+            self.library.loudness_analysis = Some(self.analyze(paths, loudness::analyze_file)?);
+        }
+        Ok(())
+    }
+
+    fn analyze(&self, paths: Vec<PathBuf>, analyze_file: AnalyzeFn) -> Result<Analysis> {
+        let settings_path = self
+            .settings_path
+            .as_ref()
+            .context("settings database is unavailable")?;
+        let repaint_signal = self.repaint_signal.clone().context("window is not ready")?;
+        Ok(Analysis::start(
+            settings_path.clone(),
+            paths,
+            analyze_file,
+            repaint_signal,
+        ))
+    }
+
+    fn poll_analysis(&mut self) {
+        fn poll(analysis: &mut Option<Analysis>) -> Option<Vec<(PathBuf, String)>> {
+            let errors = analysis.as_ref().and_then(Analysis::try_finish)?;
+            *analysis = None;
+            Some(errors)
+        }
+
+        // Reload the library when loop detection finishes, since lengths may
+        // have changed.
+        if let Some(errors) = poll(&mut self.library.loop_analysis) {
+            self.show_analysis_errors(&errors);
+            if let Some(settings_db) = &self.settings_db {
+                if let Err(err) = self.library.load(settings_db) {
+                    self.error_dialog = Some(err.to_string());
+                }
+            }
+        }
+        if let Some(errors) = poll(&mut self.library.loudness_analysis) {
+            self.show_analysis_errors(&errors);
+        }
+    }
+
+    fn show_analysis_errors(&mut self, errors: &[(PathBuf, String)]) {
+        if !errors.is_empty() {
+            let mut msg = format!("{} files could not be analyzed:\n", errors.len());
+            for (path, err) in errors.iter().take(10) {
+                msg += &format!("{}: {}\n", path.display(), err);
+            }
+            self.error_dialog = Some(msg);
+        }
+    }
+
+    fn show_library(&mut self, ui: &mut egui::Ui) {
+        if let Some(scan) = &self.library.scan {
+            let progress = scan.progress();
+            ui.label(format!(
+                "Scanning library… {}/{} files",
+                progress.files_checked, progress.files_found
+            ));
+        } else if let Some(analysis) = &self.library.loop_analysis {
+            let progress = analysis.progress();
+            ui.label(format!(
+                "Detecting loops… {}/{} files",
+                progress.files_done, progress.files_total
+            ));
+        } else if let Some(analysis) = &self.library.loudness_analysis {
+            let progress = analysis.progress();
+            ui.label(format!(
+                "Measuring loudness… {}/{} files",
+                progress.files_done, progress.files_total
+            ));
+        } else if self.library.entries.is_empty() {
+            ui.label("Use File → Add folder to library… to add SPC files.");
+        }
+
+        let old_filter = self.library.filter.clone();
+        self.show_library_filter(ui);
+        if self.library.filter != old_filter {
+            if let Some(settings_db) = &self.settings_db {
+                if let Err(err) = self.library.apply_filter(settings_db) {
+                    self.error_dialog = Some(err.to_string());
+                }
+            }
+        }
+
+        let mut sort_by = None;
+        let mut to_open = None;
+        let mut to_queue = vec![];
+        let mut to_rate = None;
+
+        let library = &self.library;
+        egui::ScrollArea::auto_sized().show(ui, |ui| {
+            for (game, group) in &library.groups {
+                let header = if game.is_empty() {
+                    "(Unknown game)"
+                } else {
+                    game
+                };
+                egui::CollapsingHeader::new(header)
+                    .id_source(game)
+                    .show(ui, |ui| {
+                        if ui.small_button("Add all to playlist").clicked() {
+                            to_queue.extend(group);
+                        }
+                        egui::Grid::new(game).striped(true).show(ui, |ui| {
+                            ui.label("");
+                            let columns = [
+                                (LibraryColumn::Title, "Title"),
+                                (LibraryColumn::Artist, "Artist"),
+                                (LibraryColumn::Length, "Length"),
+                                (LibraryColumn::Rating, "Rating"),
+                            ];
+                            for &(column, name) in &columns {
+                                let arrow = match library.sort_column == column {
+                                    true if library.sort_ascending => " ⏶",
+                                    true => " ⏷",
+                                    false => "",
+                                };
+                                if ui.small_button(format!("{}{}", name, arrow)).clicked() {
+                                    sort_by = Some(column);
+                                }
+                            }
+                            ui.end_row();
+
+                            for &i in group {
+                                let entry = &library.entries[i];
+                                if ui
+                                    .small_button("+")
+                                    .on_hover_text("Add to playlist")
+                                    .clicked()
+                                {
+                                    to_queue.push(i);
+                                }
+                                if ui.selectable_label(false, &entry.title).double_clicked() {
+                                    to_open = Some(i);
+                                }
+                                ui.label(&entry.artist);
+                                ui.label(format_length(entry.length_ms));
+                                ui.horizontal(|ui| {
+                                    ui.spacing_mut().item_spacing.x = 0.0;
+                                    let rating = entry.rating.unwrap_or(0);
+                                    for star in 1..=MAX_RATING {
+                                        let text = if star <= rating { "★" } else { "☆" };
+                                        if ui.small_button(text).clicked() {
+                                            // Clicking the current rating clears it.
+                                            let new_rating =
+                                                if star == rating { None } else { Some(star) };
+                                            to_rate = Some((i, new_rating));
+                                        }
+                                    }
+                                });
+                                ui.end_row();
+                            }
+                        });
+                    });
+            }
+        });
+
+        if let Some(column) = sort_by {
+            self.library.sort_by(column);
+        }
+        if let Some((i, rating)) = to_rate {
+            if let Err(err) = self.set_rating(i, rating) {
+                self.error_dialog = Some(err.to_string());
+            }
+        }
+        if !to_queue.is_empty() {
+            let entries = &self.library.entries;
+            self.playlist
+                .add(to_queue.iter().map(|&i| PlaylistEntry::from(&entries[i])));
+            self.playlist_dirty = true;
+        }
+        // Double-clicking a file queues and plays it.
+        if let Some(i) = to_open {
+            let index = self
+                .playlist
+                .add(vec![PlaylistEntry::from(&self.library.entries[i])]);
+            self.playlist_dirty = true;
+            if let Err(err) = self.play_index(index) {
+                self.error_dialog = Some(err.to_string());
+            }
+        }
+    }
+
+    fn show_library_filter(&mut self, ui: &mut egui::Ui) {
+        let filter = &mut self.library.filter;
+
+        ui.horizontal(|ui| {
+            ui.label("Search");
+            ui.text_edit_singleline(&mut filter.query);
+            if ui.small_button("Clear").clicked() {
+                *filter = LibraryFilter::default();
+            }
+        });
+
+        egui::CollapsingHeader::new("Filters").show(ui, |ui| {
+            ui.horizontal(|ui| {
+                length_limit(ui, "Longer than", &mut filter.min_length_secs, 60);
+                length_limit(ui, "Shorter than", &mut filter.max_length_secs, 300);
+            });
+            ui.horizontal(|ui| {
+                tri_state_combo(ui, "Tags", &mut filter.tagged, "Tagged", "Untagged");
+                tri_state_combo(ui, "Played", &mut filter.played, "Played", "Unplayed");
+                tri_state_combo(ui, "Rating", &mut filter.rated, "Rated", "Unrated");
+
+                let emulator_name = |emulator| match emulator {
+                    None => "Any",
+                    Some(0) => "Unknown",
+                    Some(1) => "ZSNES",
+                    Some(2) => "Snes9x",
+                    Some(_) => "Other",
+                };
+                egui::ComboBox::from_label("Dumped with")
+                    .selected_text(emulator_name(filter.emulator))
+                    .show_ui(ui, |ui| {
+                        for &option in &[None, Some(0), Some(1), Some(2)] {
+                            ui.selectable_value(
+                                &mut filter.emulator,
+                                option,
+                                emulator_name(option),
+                            );
+                        }
+                    });
+            });
+        });
+
+        if filter != &LibraryFilter::default() {
+            ui.label(format!(
+                "{} of {} files",
+                self.library.match_count(),
+                self.library.entries.len()
+            ));
+        }
+    }
+
+    /// Sets the rating of `self.library.entries[i]`.
+    fn set_rating(&mut self, i: usize, rating: Option<u8>) -> Result<()> {
+        let settings_db = self
+            .settings_db
+            .as_ref()
+            .context("settings database is unavailable")?;
+
+        let entry = &mut self.library.entries[i];
+        library::set_rating(settings_db, entry.id, rating)?;
+        entry.rating = rating;
+
+        if self.library.filter.rated.is_some() {
+            self.library.apply_filter(settings_db)?;
+        } else if self.library.sort_column == LibraryColumn::Rating {
+            self.library.sort();
+        }
+        Ok(())
+    }
+
+    fn on_save_state_pressed(&mut self) -> Result<()> {
+        // Capture the state before the dialog opens, so the saved position is
+        // where the user clicked.
+        let spc = match &self.output {
+            Some(output) => match output.lock().current() {
+                Some(player) => player.to_spc(),
+                None => return Ok(()),
+            },
+            None => return Ok(()),
+        };
+
+        if let Some(path) = rfd::FileDialog::new()
+            .add_filter("SPC files", &["spc"])
+            .save_file()
+        {
+            spc.save(&path)
+                .with_context(|| format!("Could not save {}", path.display()))?;
+        }
+
+        Ok(())
+    }
+
+    fn open_preferences(&mut self) {
+        let device_names = match output_device_names() {
+            Ok(names) => names,
+            Err(err) => {
+                self.error_dialog = Some(err.to_string());
+                vec![]
+            }
+        };
+        self.preferences = Some(Preferences {
+            draft: self.settings.clone(),
+            device_names,
+        });
+    }
+
+    fn show_preferences(&mut self, ctx: &egui::CtxRef) {
+        let Preferences {
+            draft,
+            device_names,
+        } = match &mut self.preferences {
+            Some(prefs) => prefs,
+            None => return,
+        };
+
+        let mut open = true;
+        let mut ok_pressed = false;
+        let mut cancel_pressed = false;
+
+        egui::Window::new("Preferences")
+            .open(&mut open)
+            .show(ctx, |ui| {
+                egui::Grid::new("preferences")
+                    .num_columns(2)
+                    .show(ui, |ui| {
+                        ui.label("Output device");
+                        egui::ComboBox::from_label("")
+                            .selected_text(draft.output_device.as_deref().unwrap_or("Default"))
+                            .show_ui(ui, |ui| {
+                                ui.selectable_value(&mut draft.output_device, None, "Default");
+                                for name in device_names.iter() {
+                                    ui.selectable_value(
+                                        &mut draft.output_device,
+                                        Some(name.clone()),
+                                        name,
+                                    );
+                                }
+                            });
+                        ui.end_row();
+
+                        ui.label("Buffer size");
+                        ui.horizontal(|ui| {
+                            let mut fixed = draft.buffer_size.is_some();
+                            ui.checkbox(&mut fixed, "Fixed");
+                            if fixed {
+                                let buffer_size = draft.buffer_size.get_or_insert(1024);
+                                ui.add(
+                                    egui::DragValue::new(buffer_size)
+                                        .clamp_range(64..=16384)
+                                        .suffix(" frames"),
+                                );
+                            } else {
+                                draft.buffer_size = None;
+                            }
+                        });
+                        ui.end_row();
+
+                        ui.label("Volume");
+                        let mut percent = draft.volume * 100.0;
+                        ui.add(egui::Slider::new(&mut percent, 0.0..=100.0).suffix("%"));
+                        draft.volume = percent / 100.0;
+                        ui.end_row();
+
+                        ui.label("Interpolation");
+                        ui.horizontal(|ui| {
+                            ui.radio_value(
+                                &mut draft.interpolation,
+                                ResamplingMode::Gaussian,
+                                "Gaussian",
+                            );
+                            ui.radio_value(
+                                &mut draft.interpolation,
+                                ResamplingMode::Linear,
+                                "Linear",
+                            );
+                        });
+                        ui.end_row();
+
+                        ui.label("Default length");
+                        ui.add(
+                            egui::DragValue::new(&mut draft.default_length_secs)
+                                .clamp_range(0..=999)
+                                .suffix(" s"),
+                        );
+                        ui.end_row();
+
+                        ui.label("Default fade");
+                        ui.add(
+                            egui::DragValue::new(&mut draft.default_fade_ms)
+                                .clamp_range(0..=99999)
+                                .suffix(" ms"),
+                        );
+                        ui.end_row();
+
+                        ui.label("Loops").on_hover_text(
+                            "Times to play songs whose loop is known, or 0 to play forever",
+                        );
+                        ui.add(egui::DragValue::new(&mut draft.loop_count).clamp_range(0..=99));
+                        ui.end_row();
+
+                        ui.label("Crossfade");
+                        ui.add(
+                            egui::DragValue::new(&mut draft.crossfade_ms)
+                                .clamp_range(0..=20000)
+                                .suffix(" ms"),
+                        );
+                        ui.end_row();
+
+                        ui.label("End after silence").on_hover_text(
+                            "Seconds of silence before ending a song, or 0 to never end early",
+                        );
+                        ui.add(
+                            egui::DragValue::new(&mut draft.silence_secs)
+                                .clamp_range(0..=60)
+                                .suffix(" s"),
+                        );
+                        ui.end_row();
+
+                        ui.label("Silence threshold");
+                        ui.add(
+                            egui::DragValue::new(&mut draft.silence_threshold_db)
+                                .clamp_range(-96.0..=-20.0)
+                                .suffix(" dB"),
+                        );
+                        ui.end_row();
+
+                        ui.label("Leading silence");
+                        ui.checkbox(&mut draft.trim_leading_silence, "Skip silence at the start");
+                        ui.end_row();
+
+                        ui.label("ReplayGain").on_hover_text(
+                            "Adjust each song's volume to match, using its measured loudness. \
+                             Album gain keeps the differences between songs of the same game.",
+                        );
+                        ui.horizontal(|ui| {
+                            ui.radio_value(&mut draft.replay_gain, ReplayGain::Off, "Off");
+                            ui.radio_value(&mut draft.replay_gain, ReplayGain::Track, "Track");
+                            ui.radio_value(&mut draft.replay_gain, ReplayGain::Album, "Album");
+                        });
+                        ui.end_row();
+
+                        ui.label("Target loudness");
+                        ui.add(
+                            egui::DragValue::new(&mut draft.target_lufs)
+                                .clamp_range(-30.0..=-5.0)
+                                .suffix(" LUFS"),
+                        );
+                        ui.end_row();
+
+                        ui.label("Clipping");
+                        ui.checkbox(&mut draft.prevent_clipping, "Limit gain to avoid clipping");
+                        ui.end_row();
+
+                        ui.label("Surround");
+                        ui.checkbox(
+                            &mut draft.remove_surround,
+                            "Play inverted channels normally",
+                        );
+                        ui.end_row();
+
+                        ui.label("Soundtrack gap").on_hover_text(
+                            "Silence between songs when exporting one file, or 0 to crossfade \
+                             them as in playback",
+                        );
+                        ui.add(
+                            egui::DragValue::new(&mut draft.soundtrack_gap_ms)
+                                .clamp_range(0..=20000)
+                                .suffix(" ms"),
+                        );
+                        ui.end_row();
+
+                        ui.label("Export filenames").on_hover_text(
+                            "Where Export audio writes each song, relative to the chosen \
+                             folder. {game}, {title}, {artist}, {track}, {index} and {file} \
+                             are replaced, / makes folders, and the extension picks WAV or FLAC.",
+                        );
+                        ui.text_edit_singleline(&mut draft.export_template);
+                        ui.end_row();
+                    });
+
+                ui.label("Changing the device or buffer size stops playback.");
+                ui.horizontal(|ui| {
+                    ok_pressed = ui.button("OK").clicked();
+                    cancel_pressed = ui.button("Cancel").clicked();
+                });
+            });
+
+        if ok_pressed {
+            let reopen = draft.output_device != self.settings.output_device
+                || draft.buffer_size != self.settings.buffer_size;
+            self.settings = draft.clone();
+            if reopen {
+                // Reopened on the next play.
+                self.output = None;
+                self.queued = None;
+            } else if let Some(output) = &self.output {
+                output.lock().apply_settings(&self.settings);
+            }
+            if let Err(err) = self.save_settings() {
+                self.error_dialog = Some(err.to_string());
+            }
+        }
+        if !open || ok_pressed || cancel_pressed {
+            self.preferences = None;
+        }
+    }
 }
@@ -0,0 +1,286 @@
+use std::fs::{self, File};
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use spc::Spc;
+
+use crate::library::is_spc;
+
+/// Separates an archive's path from the path of a file inside it, as in
+/// `music/game.rsn!/01 Title.spc`.
+pub const SEPARATOR: &str = "!/";
+
+/// Largest file read from an archive. SPCs are about 66 KB, so anything much
+/// bigger isn't one, and is refused rather than read into memory.
+const MAX_FILE_LEN: u64 = 16 << 20;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum ArchiveKind {
+    Zip,
+    /// .rsn files are RAR archives of SPCs.
+    Rar,
+}
+
+fn archive_kind(path: &Path) -> Option<ArchiveKind> {
+    let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+    match ext.as_str() {
+        "zip" => Some(ArchiveKind::Zip),
+        "rsn" | "rar" => Some(ArchiveKind::Rar),
+        _ => None,
+    }
+}
+
+pub fn is_archive(path: &Path) -> bool {
+    archive_kind(path).is_some()
+}
+
+/// Splits a path like `game.rsn!/inner.spc` into the archive's path and the
+/// inner name. Returns None for ordinary paths.
+pub fn split_path(path: &Path) -> Option<(PathBuf, String)> {
+    let s = path.to_str()?;
+    let pos = s.find(SEPARATOR)?;
+    let archive = PathBuf::from(&s[..pos]);
+    if !is_archive(&archive) {
+        return None;
+    }
+    Some((archive, s[pos + SEPARATOR.len()..].to_owned()))
+}
+
+/// The path of a file inside an archive.
+pub fn join_path(archive: &Path, inner: &str) -> PathBuf {
+    let mut path = archive.as_os_str().to_owned();
+    path.push(SEPARATOR);
+    path.push(inner);
+    PathBuf::from(path)
+}
+
+/// The file on disk holding `path`: the archive if `path` is inside one,
+/// otherwise `path` itself.
+pub fn physical_path(path: &Path) -> PathBuf {
+    match split_path(path) {
+        Some((archive, _)) => archive,
+        None => path.to_owned(),
+    }
+}
+
+/// Lists the SPC files in an archive, as paths which can be passed to
+/// `read()`, sorted by name.
+pub fn list_spcs(archive: &Path) -> Result<Vec<PathBuf>> {
+    let names: Vec<String> = match archive_kind(archive) {
+        Some(ArchiveKind::Zip) => {
+            let zip = zip::ZipArchive::new(open(archive)?)
+                .with_context(|| format!("Could not read {}", archive.display()))?;
+            zip.file_names().map(str::to_owned).collect()
+        }
+        Some(ArchiveKind::Rar) => unrar::Archive::new(archive)
+            .open_for_listing()
+            .and_then(|entries| {
+                entries
+                    .filter(|entry| !matches!(entry, Ok(header) if header.is_directory()))
+                    .map(|entry| entry.map(|header| rar_name(&header)))
+                    .collect()
+            })
+            .with_context(|| format!("Could not read {}", archive.display()))?,
+        None => bail!("{} is not an archive", archive.display()),
+    };
+
+    let mut names: Vec<String> = names
+        .into_iter()
+        .filter(|name| is_spc(Path::new(name)))
+        .collect();
+    names.sort();
+    Ok(names.iter().map(|name| join_path(archive, name)).collect())
+}
+
+fn open(path: &Path) -> Result<File> {
+    File::open(path).with_context(|| format!("Could not open {}", path.display()))
+}
+
+/// Reads a file, which may be inside an archive, into memory.
+pub fn read(path: &Path) -> Result<Vec<u8>> {
+    let (archive, inner) = match split_path(path) {
+        Some(split) => split,
+        None => {
+            return fs::read(path).with_context(|| format!("Could not read {}", path.display()))
+        }
+    };
+
+    let mut data = vec![];
+    match archive_kind(&archive) {
+        Some(ArchiveKind::Zip) => {
+            let mut zip = zip::ZipArchive::new(open(&archive)?)
+                .with_context(|| format!("Could not read {}", archive.display()))?;
+            let file = zip
+                .by_name(&inner)
+                .with_context(|| format!("Could not find {}", path.display()))?;
+            if file.size() > MAX_FILE_LEN {
+                bail!("{} is too large to be an SPC file", path.display());
+            }
+            // The size comes from the archive, so don't trust it.
+            file.take(MAX_FILE_LEN)
+                .read_to_end(&mut data)
+                .with_context(|| format!("Could not extract {}", path.display()))?;
+        }
+        Some(ArchiveKind::Rar) => {
+            data = read_rar(&archive, &inner)
+                .with_context(|| format!("Could not extract {}", path.display()))?;
+        }
+        None => unreachable!(),
+    }
+    Ok(data)
+}
+
+/// Loads an SPC file, which may be inside an archive.
+pub fn load_spc(path: &Path) -> Result<Spc> {
+    let data = read(path)?;
+    Spc::from_reader(Cursor::new(data))
+        .with_context(|| format!("Could not load spc file {}", path.display()))
+}
+
+/// The name of a file in a RAR archive, with `/` separating folders as in ZIP
+/// archives.
+fn rar_name(header: &unrar::FileHeader) -> String {
+    header.filename.to_string_lossy().replace('\\', "/")
+}
+
+fn read_rar(archive: &Path, name: &str) -> Result<Vec<u8>> {
+    let mut rar = unrar::Archive::new(archive).open_for_processing()?;
+    while let Some(entry) = rar.read_header()? {
+        let header = entry.entry();
+        if rar_name(header) != name || header.is_directory() {
+            rar = entry.skip()?;
+            continue;
+        }
+        if header.unpacked_size > MAX_FILE_LEN {
+            bail!("{} is too large to be an SPC file", name);
+        }
+        let (data, _) = entry.read()?;
+        return Ok(data);
+    }
+    bail!("{} is not in the archive", name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::{FileOptions, ZipWriter};
+    use zip::CompressionMethod;
+
+    fn temp_dir(name: &str) -> Result<PathBuf> {
+        let dir = std::env::temp_dir().join(format!("spcplay-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir)?;
+        Ok(dir)
+    }
+
+    #[test]
+    fn test_split_and_join() {
+        let archive = Path::new("music/game.rsn");
+        let path = join_path(archive, "sub/01 Title.spc");
+        assert_eq!(path, Path::new("music/game.rsn!/sub/01 Title.spc"));
+        assert_eq!(
+            split_path(&path),
+            Some((archive.to_owned(), "sub/01 Title.spc".to_owned()))
+        );
+        assert_eq!(physical_path(&path), archive);
+
+        let zip = Path::new("Game.ZIP");
+        assert_eq!(
+            split_path(&join_path(zip, "a.spc")),
+            Some((zip.to_owned(), "a.spc".to_owned()))
+        );
+
+        // Not inside an archive.
+        assert_eq!(split_path(Path::new("music/a.spc")), None);
+        assert_eq!(split_path(Path::new("music/a.txt!/b.spc")), None);
+        assert_eq!(
+            physical_path(Path::new("music/a.spc")),
+            Path::new("music/a.spc")
+        );
+    }
+
+    #[test]
+    fn test_zip() -> Result<()> {
+        let spc = fs::read("3rdparty/snes-apu/test/ferris-nu.spc")?;
+        let dir = temp_dir("zip")?;
+        let archive = dir.join("game.zip");
+        let mut zip = ZipWriter::new(File::create(&archive)?);
+        let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+        let deflated = FileOptions::default().compression_method(CompressionMethod::Deflated);
+        zip.start_file("b.spc", deflated)?;
+        zip.write_all(&spc)?;
+        zip.start_file("a.spc", stored)?;
+        zip.write_all(&spc[..0x200])?;
+        zip.add_directory("sub/", stored)?;
+        zip.start_file("sub/c.SPC", deflated)?;
+        zip.write_all(b"c")?;
+        zip.start_file("info.txt", stored)?;
+        zip.write_all(b"info")?;
+        zip.start_file("large.spc", deflated)?;
+        zip.write_all(&vec![0; MAX_FILE_LEN as usize + 1])?;
+        zip.finish()?;
+
+        let paths = list_spcs(&archive)?;
+        assert_eq!(
+            paths,
+            vec![
+                join_path(&archive, "a.spc"),
+                join_path(&archive, "b.spc"),
+                join_path(&archive, "large.spc"),
+                join_path(&archive, "sub/c.SPC"),
+            ]
+        );
+        assert_eq!(read(&paths[0])?, &spc[..0x200]);
+        assert_eq!(read(&paths[1])?, spc);
+        assert!(read(&paths[2]).is_err());
+        assert_eq!(read(&paths[3])?, b"c");
+        assert!(load_spc(&paths[1]).is_ok());
+        assert!(load_spc(&paths[0]).is_err());
+        assert!(read(&join_path(&archive, "missing.spc")).is_err());
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_rar() -> Result<()> {
+        // Holds a.spc, the start of ferris-nu.spc, and sub\c.spc and
+        // info.txt stored, and b.spc compressed by WinRAR.
+        let fixture = Path::new("3rdparty/snes-apu/test/archive.rsn");
+        let spc = fs::read("3rdparty/snes-apu/test/ferris-nu.spc")?;
+
+        let paths = list_spcs(fixture)?;
+        assert_eq!(
+            paths,
+            vec![
+                join_path(fixture, "a.spc"),
+                join_path(fixture, "b.spc"),
+                join_path(fixture, "sub/c.spc"),
+            ]
+        );
+        assert_eq!(read(&paths[0])?, &spc[..0x100]);
+        // Compressed entries are extracted too.
+        assert_eq!(read(&paths[1])?, b"unrar-0.4.0");
+        assert_eq!(read(&paths[2])?, b"c");
+        assert!(load_spc(&paths[0]).is_err());
+        assert!(read(&join_path(fixture, "missing.spc")).is_err());
+
+        // A corrupt entry fails its CRC check.
+        let dir = temp_dir("rar")?;
+        let archive = dir.join("game.rsn");
+        let mut corrupt = fs::read(fixture)?;
+        let packed = corrupt
+            .windows(5)
+            .position(|name| name == b"b.spc")
+            .unwrap()
+            + 5;
+        corrupt[packed + 4] ^= 0xff;
+        fs::write(&archive, corrupt)?;
+        assert!(read(&join_path(&archive, "a.spc")).is_ok());
+        assert!(read(&join_path(&archive, "b.spc")).is_err());
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,235 @@
+//! Playing a song's samples at any note, to audition them. They play on a
+//! DSP of their own, with the song's RAM, so the song's driver can't change
+//! them.
+
+use anyhow::Result;
+use snes_apu::apu::Apu;
+use snes_apu::dsp::dsp::{BUFFER_LEN, NUM_VOICES, SAMPLE_RATE};
+use snes_apu::dsp::voice::ResamplingMode;
+use spc::Spc;
+
+use crate::brr::{self, EncodedSample, EnvelopeRegs};
+
+const MVOL_LEFT: u8 = 0x0c;
+const MVOL_RIGHT: u8 = 0x1c;
+const EVOL_LEFT: u8 = 0x2c;
+const EVOL_RIGHT: u8 = 0x3c;
+const KON: u8 = 0x4c;
+const KOF: u8 = 0x5c;
+const FLG: u8 = 0x6c;
+const DIR: u8 = 0x5d;
+/// Disables echo writes, so the echo buffer doesn't overwrite samples.
+const FLG_ECHO_OFF: u8 = 0x20;
+const CONTROL: u32 = 0xf1;
+const RAM_LEN: usize = 0x10000;
+const IO_PORTS: std::ops::Range<usize> = 0xf0..0x100;
+
+/// Of each voice, leaving headroom for chords.
+const VOICE_VOLUME: u8 = 0x40;
+const MASTER_VOLUME: u8 = 0x7f;
+const PITCH_ONE: f64 = 0x1000 as f64;
+const MAX_PITCH: f64 = 0x3fff as f64;
+/// For samples the song wasn't heard playing: full volume until released.
+pub const DEFAULT_ENVELOPE: EnvelopeRegs = EnvelopeRegs {
+    adsr0: 0x8f,
+    adsr1: 0xe0,
+    gain: 0,
+};
+
+static NOTE_NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+/// The name of MIDI note `note`, such as C4 for middle C.
+pub fn note_name(note: u8) -> String {
+    format!("{}{}", NOTE_NAMES[note as usize % 12], note as i32 / 12 - 1)
+}
+
+/// Plays notes on a DSP loaded with a song's samples, with up to one note
+/// per voice.
+pub struct Sampler {
+    apu: Box<Apu>,
+    /// The note each voice was last keyed on with, until it's released.
+    notes: [Option<u8>; NUM_VOICES],
+    /// The voice to try first for the next note, so released notes ring out.
+    next_voice: usize,
+    buffer: Vec<i16>,
+}
+
+impl Sampler {
+    /// Loads the samples in `spc`'s RAM, found through its source directory.
+    /// The song plays for a moment first, since drivers often set the
+    /// directory and load samples after starting.
+    pub fn new(spc: &Spc) -> Sampler {
+        let mut song = Apu::from_spc(spc);
+        song.render_interleaved(&mut vec![0; SAMPLE_RATE * 2]);
+        let dir = song.dsp.as_ref().unwrap().get_register_value(DIR);
+
+        let mut apu = Apu::new();
+        // Map RAM over the IPL ROM.
+        apu.write_u8(CONTROL, 0);
+        for address in 0..RAM_LEN {
+            if !IO_PORTS.contains(&address) {
+                apu.write_u8(address as u32, song.read_u8(address as u32));
+            }
+        }
+
+        let dsp = apu.dsp.as_mut().unwrap();
+        dsp.set_resampling_mode(ResamplingMode::Gaussian);
+        dsp.set_register(DIR, dir);
+        dsp.set_register(FLG, FLG_ECHO_OFF);
+        dsp.set_register(MVOL_LEFT, MASTER_VOLUME);
+        dsp.set_register(MVOL_RIGHT, MASTER_VOLUME);
+        // The echo buffer holds the song's echo, so it's left silent.
+        dsp.set_register(EVOL_LEFT, 0);
+        dsp.set_register(EVOL_RIGHT, 0);
+        Sampler {
+            apu,
+            notes: [None; NUM_VOICES],
+            next_voice: 0,
+            buffer: vec![],
+        }
+    }
+
+    /// Plays MIDI note `note` with sample `source`, which plays `base_note`
+    /// at pitch $1000. Replaces the same note if it's already playing, and
+    /// otherwise the oldest note.
+    pub fn note_on(&mut self, source: u8, note: u8, base_note: f64, envelope: EnvelopeRegs) {
+        let voice = match self.notes.iter().position(|&playing| playing == Some(note)) {
+            Some(voice) => voice,
+            None => {
+                let voice = (0..NUM_VOICES)
+                    .map(|i| (self.next_voice + i) % NUM_VOICES)
+                    .find(|&voice| self.notes[voice].is_none())
+                    .unwrap_or(self.next_voice);
+                self.next_voice = (voice + 1) % NUM_VOICES;
+                voice
+            }
+        };
+        self.notes[voice] = Some(note);
+
+        let pitch = (PITCH_ONE * 2f64.powf((note as f64 - base_note) / 12.0))
+            .round()
+            .clamp(1.0, MAX_PITCH) as u16;
+        let base = voice as u8 * 0x10;
+        let regs = [
+            VOICE_VOLUME,
+            VOICE_VOLUME,
+            pitch as u8,
+            (pitch >> 8) as u8,
+            source,
+            envelope.adsr0,
+            envelope.adsr1,
+            envelope.gain,
+        ];
+        let dsp = self.apu.dsp.as_mut().unwrap();
+        for (i, &value) in regs.iter().enumerate() {
+            dsp.set_register(base + i as u8, value);
+        }
+        dsp.set_register(KON, 1 << voice);
+    }
+
+    /// Releases MIDI note `note`, if it's playing.
+    pub fn note_off(&mut self, note: u8) {
+        for voice in 0..NUM_VOICES {
+            if self.notes[voice] == Some(note) {
+                self.notes[voice] = None;
+                self.apu.dsp.as_mut().unwrap().set_register(KOF, 1 << voice);
+            }
+        }
+    }
+
+    /// Replaces a sample, as for `brr::replace_sample()`.
+    pub fn replace_sample(&mut self, sources: &[u8], sample: &EncodedSample) -> Result<u16> {
+        brr::replace_sample(&mut self.apu, sources, sample)
+    }
+
+    /// Fills `out` with interleaved stereo audio.
+    pub fn render(&mut self, out: &mut [i16]) {
+        for chunk in out.chunks_mut(BUFFER_LEN) {
+            self.apu.render_dsp_interleaved(chunk);
+        }
+    }
+
+    /// Adds the notes playing to interleaved stereo audio.
+    pub fn mix_into(&mut self, out: &mut [i16]) {
+        let mut buffer = std::mem::take(&mut self.buffer);
+        buffer.resize(out.len(), 0);
+        self.render(&mut buffer);
+        for (sample, &note) in out.iter_mut().zip(&buffer) {
+            *sample = sample.saturating_add(note);
+        }
+        self.buffer = buffer;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The strongest frequency of a mono signal, in Hz, found by counting
+    /// rising zero crossings.
+    fn frequency(samples: &[i16]) -> f64 {
+        let crossings = samples
+            .windows(2)
+            .filter(|pair| pair[0] < 0 && pair[1] >= 0)
+            .count();
+        crossings as f64 * SAMPLE_RATE as f64 / samples.len() as f64
+    }
+
+    fn left(stereo: &[i16]) -> Vec<i16> {
+        stereo.iter().step_by(2).copied().collect()
+    }
+
+    #[test]
+    fn test_note_name() {
+        assert_eq!(note_name(60), "C4");
+        assert_eq!(note_name(0), "C-1");
+        assert_eq!(note_name(70), "A#4");
+    }
+
+    #[test]
+    fn test_sampler() {
+        // A looping square wave, 32 samples per cycle: 1 kHz at pitch $1000.
+        let square: Vec<i16> = (0..64)
+            .map(|i| if i % 32 < 16 { 8000 } else { -8000 })
+            .collect();
+        let encoded = brr::encode(&square, Some(0));
+        let spc = Spc::load("3rdparty/snes-apu/test/ferris-nu.spc").unwrap();
+        let mut sampler = Sampler::new(&spc);
+        sampler.replace_sample(&[0], &encoded).unwrap();
+
+        let mut out = vec![0; SAMPLE_RATE / 2];
+        sampler.render(&mut out);
+        assert!(out.iter().all(|&sample| sample == 0));
+
+        // At its base note, then an octave up.
+        sampler.note_on(0, 60, 60.0, DEFAULT_ENVELOPE);
+        sampler.render(&mut out);
+        let played = left(&out[SAMPLE_RATE / 4..]);
+        assert!(
+            (frequency(&played) - 1000.0).abs() < 20.0,
+            "{}",
+            frequency(&played)
+        );
+
+        sampler.note_off(60);
+        sampler.note_on(0, 72, 60.0, DEFAULT_ENVELOPE);
+        sampler.render(&mut out);
+        let played = left(&out[SAMPLE_RATE / 4..]);
+        assert!(
+            (frequency(&played) - 2000.0).abs() < 40.0,
+            "{}",
+            frequency(&played)
+        );
+
+        // Released notes fade out quickly.
+        sampler.note_off(72);
+        sampler.render(&mut out);
+        assert!(out[SAMPLE_RATE / 4..].iter().all(|&sample| sample == 0));
+
+        let mut mixed = vec![100; 64];
+        sampler.mix_into(&mut mixed);
+        assert!(mixed.iter().all(|&sample| sample == 100));
+    }
+}
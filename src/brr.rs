@@ -0,0 +1,882 @@
+//! Extracting the instrument samples a song plays from the BRR data in its
+//! RAM, for reuse in samplers.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+
+use anyhow::{anyhow, bail, Context, Result};
+use snes_apu::apu::Apu;
+use snes_apu::dsp::brr_block_decoder::BrrBlockDecoder;
+use snes_apu::dsp::dsp::{NUM_VOICES, SAMPLE_RATE};
+use spc::Spc;
+
+use crate::library::{self, SpcTags};
+use crate::loops::LoopPoints;
+use crate::midi;
+use crate::sink::AudioSink;
+use crate::wav::{SampleFormat, WavFile, WavWriter};
+
+/// BRR blocks are a header byte and 16 4-bit samples.
+pub const BLOCK_LEN: usize = 9;
+pub const SAMPLES_PER_BLOCK: usize = 16;
+/// Time to emulate the song for, to find which samples it plays.
+const SCAN_SECS: usize = 30;
+/// A sample this long has wrapped around RAM without ending, so the directory
+/// entry isn't really a sample.
+const MAX_BLOCKS: usize = 0x10000 / BLOCK_LEN;
+
+const KON: u8 = 0x4c;
+const DIR: u8 = 0x5d;
+/// Nibble values, which are signed.
+const NIBBLES: std::ops::RangeInclusive<i32> = -8..=7;
+/// Larger shifts are invalid, and decode to 0 or -2048.
+const MAX_SHIFT: u8 = 12;
+const END_FLAG: u8 = 1;
+const LOOP_FLAG: u8 = 2;
+/// RAM the CPU keeps its variables and stack in, and the IPL ROM may hide.
+const RESERVED_LOW: usize = 0x200;
+const RESERVED_HIGH: usize = 0xffc0;
+/// Most a replacement sample's loop is retuned by to fill whole blocks,
+/// before it's repeated instead.
+const MAX_LOOP_DETUNE_CENTS: f64 = 5.0;
+
+/// A voice's envelope registers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EnvelopeRegs {
+    pub adsr0: u8,
+    pub adsr1: u8,
+    pub gain: u8,
+}
+
+/// A note the song played, from the voice's registers when it was keyed on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeyOn {
+    pub source: u8,
+    pub pitch: u16,
+    pub envelope: EnvelopeRegs,
+}
+
+/// An instrument sample, decoded from BRR.
+#[derive(Clone, Debug)]
+pub struct BrrSample {
+    /// Source numbers (SRCN) whose directory entries point to the sample.
+    pub sources: Vec<u8>,
+    /// Address of its first block in RAM.
+    pub address: u16,
+    /// The encoded blocks.
+    pub brr: Vec<u8>,
+    /// Decoded 16-bit samples, which play at 32 kHz at pitch $1000.
+    pub samples: Vec<i16>,
+    /// Where it repeats from after its last block, in samples, if it loops.
+    pub loop_start: Option<usize>,
+    /// Identifies the sample data and loop, for skipping duplicates.
+    pub hash: String,
+    /// Notes the song played with the sample while it was scanned.
+    pub key_ons: Vec<KeyOn>,
+}
+
+impl BrrSample {
+    pub fn loop_points(&self) -> Option<LoopPoints> {
+        let start = self.loop_start?;
+        Some(LoopPoints {
+            start: start as u64,
+            length: (self.samples.len() - start) as u64,
+        })
+    }
+
+    /// Length in seconds, when played at its recorded rate.
+    pub fn duration_secs(&self) -> f32 {
+        self.samples.len() as f32 / SAMPLE_RATE as f32
+    }
+
+    /// Estimates the note `source` plays at pitch $1000, from the pitches
+    /// the song played it at.
+    pub fn base_note(&self, source: u8) -> f64 {
+        let pitches: Vec<u16> = self
+            .key_ons
+            .iter()
+            .filter(|key_on| key_on.source == source)
+            .map(|key_on| key_on.pitch)
+            .collect();
+        midi::estimate_base_note(&pitches)
+    }
+
+    /// The envelope registers `source` was most often keyed on with, or
+    /// None if it wasn't keyed on.
+    pub fn envelope(&self, source: u8) -> Option<EnvelopeRegs> {
+        let mut counts: Vec<(EnvelopeRegs, usize)> = vec![];
+        for key_on in self.key_ons.iter().filter(|key_on| key_on.source == source) {
+            match counts.iter_mut().find(|(regs, _)| *regs == key_on.envelope) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((key_on.envelope, 1)),
+            }
+        }
+        // The first used wins ties.
+        let most = counts.iter().map(|&(_, count)| count).max()?;
+        counts
+            .into_iter()
+            .find(|&(_, count)| count == most)
+            .map(|(regs, _)| regs)
+    }
+}
+
+/// Reads the sample at `address` in `ram` until its end flag, returning its
+/// blocks, decoded samples and whether it loops. Returns None if it doesn't
+/// end.
+fn decode(ram: &[u8], address: u16) -> Option<(Vec<u8>, Vec<i16>, bool)> {
+    let mut decoder = BrrBlockDecoder::new();
+    let mut brr = vec![];
+    let mut samples = vec![];
+    for block in 0..MAX_BLOCKS {
+        let block_address = address as usize + block * BLOCK_LEN;
+        let mut buf = [0; BLOCK_LEN];
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = ram[(block_address + i) & 0xffff];
+        }
+        decoder.read(&buf);
+        brr.extend_from_slice(&buf);
+        while !decoder.is_finished() {
+            samples.push(decoder.read_next_sample());
+        }
+        if decoder.is_end {
+            return Some((brr, samples, decoder.is_looping));
+        }
+    }
+    None
+}
+
+/// Samples encoded by `encode()`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EncodedSample {
+    pub brr: Vec<u8>,
+    /// Where it repeats from after its last block, in samples, if it loops.
+    /// Always at the start of a block.
+    pub loop_start: Option<usize>,
+}
+
+/// Emulates the song for a while, and returns the notes it plays, including
+/// those of voices already playing.
+fn key_ons(apu: &mut Apu) -> Vec<KeyOn> {
+    let dsp = apu.dsp.as_mut().unwrap();
+    let mut regs = dsp.get_state();
+    let mut key_ons = vec![];
+    let add_key_on = |regs: &[u8], key_on: u8, key_ons: &mut Vec<KeyOn>| {
+        for voice in 0..NUM_VOICES {
+            if key_on & (1 << voice) != 0 {
+                let voice = &regs[voice * 0x10..voice * 0x10 + 0x10];
+                key_ons.push(KeyOn {
+                    source: voice[4],
+                    pitch: u16::from_le_bytes([voice[2], voice[3]]) & 0x3fff,
+                    envelope: EnvelopeRegs {
+                        adsr0: voice[5],
+                        adsr1: voice[6],
+                        gain: voice[7],
+                    },
+                });
+            }
+        }
+    };
+    add_key_on(&regs, regs[KON as usize], &mut key_ons);
+
+    dsp.set_register_logging(true);
+    let mut buffer = vec![0; SAMPLE_RATE * 2];
+    for _ in 0..SCAN_SECS {
+        apu.render_interleaved(&mut buffer);
+        for write in apu.dsp.as_mut().unwrap().take_register_writes() {
+            regs[write.address as usize] = write.value;
+            if write.address == KON {
+                add_key_on(&regs, write.value, &mut key_ons);
+            }
+        }
+    }
+    apu.dsp.as_mut().unwrap().set_register_logging(false);
+    key_ons
+}
+
+/// Finds the samples a song plays by following its source directory, and
+/// decodes them. Sources which point to the same sample are merged.
+pub fn extract(spc: &Spc) -> Vec<BrrSample> {
+    let mut apu = Apu::from_spc(spc);
+    let key_ons = key_ons(&mut apu);
+    let mut sources: Vec<u8> = key_ons.iter().map(|key_on| key_on.source).collect();
+    sources.sort_unstable();
+    sources.dedup();
+
+    let mut samples: Vec<BrrSample> = vec![];
+    for source in sources {
+        let dir = apu.dsp.as_ref().unwrap().get_register_value(DIR);
+        let (address, loop_address) = dir_entry(apu.ram(), dir, source);
+        let (brr, decoded, loops) = match decode(apu.ram(), address) {
+            Some(sample) => sample,
+            None => continue,
+        };
+
+        // Loops must start on a block within the sample.
+        let loop_offset = loop_address.wrapping_sub(address) as usize;
+        let (loop_block, partial) = (loop_offset / BLOCK_LEN, loop_offset % BLOCK_LEN);
+        let loop_start = Some(loop_block * SAMPLES_PER_BLOCK)
+            .filter(|_| loops && loop_offset < brr.len() && partial == 0);
+
+        let mut hashed = brr.clone();
+        if let Some(loop_start) = loop_start {
+            hashed.extend_from_slice(&(loop_start as u32).to_le_bytes());
+        }
+        let hash = library::file_hash(&hashed);
+        let played = key_ons.iter().filter(|key_on| key_on.source == source);
+        match samples.iter_mut().find(|sample| sample.hash == hash) {
+            Some(sample) => {
+                sample.sources.push(source);
+                sample.key_ons.extend(played);
+            }
+            None => samples.push(BrrSample {
+                sources: vec![source],
+                address,
+                brr,
+                samples: decoded,
+                loop_start,
+                hash,
+                key_ons: played.copied().collect(),
+            }),
+        }
+    }
+    samples
+}
+
+/// Runs `extract()` on a background thread, since it emulates the song for
+/// `SCAN_SECS`.
+pub struct SampleScan {
+    result: Receiver<Vec<BrrSample>>,
+}
+
+impl SampleScan {
+    /// Starts extracting the samples of `spc`. `notify` is called when it
+    /// finishes.
+    pub fn start(spc: Spc, notify: Arc<dyn Fn() + Send + Sync>) -> SampleScan {
+        let (tx, result) = channel();
+        thread::spawn(move || {
+            let _ = tx.send(extract(&spc));
+            notify();
+        });
+        SampleScan { result }
+    }
+
+    /// Returns the samples once they've been extracted.
+    pub fn try_finish(&self) -> Option<Result<Vec<BrrSample>>> {
+        match self.result.try_recv() {
+            Ok(samples) => Some(Ok(samples)),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err(anyhow!("sample scan crashed"))),
+        }
+    }
+}
+
+/// Estimates the note each source of `samples` plays at pitch $1000, as for
+/// `midi::to_midi()`.
+pub fn base_notes(samples: &[BrrSample]) -> HashMap<u8, f64> {
+    samples
+        .iter()
+        .flat_map(|sample| {
+            sample
+                .sources
+                .iter()
+                .map(move |&source| (source, sample.base_note(source)))
+        })
+        .collect()
+}
+
+/// Writes a sample as a mono WAV file, with its loop in a sampler chunk.
+pub fn write_wav(sample: &BrrSample, path: &Path, tags: &SpcTags) -> Result<()> {
+    let mut wav = WavWriter::create_with_channels(path, SampleFormat::Int16, tags, 1)?;
+    if let Some(points) = sample.loop_points() {
+        wav.set_loop(points);
+    }
+    wav.write(&sample.samples)?;
+    wav.finish()
+}
+
+/// Tags for a sample's WAV file, naming it after its source numbers and the
+/// song's game.
+pub fn sample_tags(sample: &BrrSample, song: &SpcTags) -> SpcTags {
+    let sources: Vec<String> = sample.sources.iter().map(u8::to_string).collect();
+    SpcTags {
+        title: format!("Sample {}", sources.join(", ")),
+        game: song.game.clone(),
+        artist: song.artist.clone(),
+        amplification: 1.0,
+        ..SpcTags::default()
+    }
+}
+
+/// Decodes a nibble as the DSP does, given the last two decoded samples.
+fn decode_nibble(nibble: i32, shift: u8, filter: u8, p1: i16, p2: i16) -> i16 {
+    let mut sample = (nibble << shift) >> 1;
+    let p1 = p1 as i32;
+    let p2 = (p2 >> 1) as i32;
+    sample += match filter {
+        1 => (p1 >> 1) + ((-p1) >> 5),
+        2 => p1 - p2 + (p2 >> 4) + ((p1 * -3) >> 6),
+        3 => p1 - p2 + ((p1 * -13) >> 7) + ((p2 * 3) >> 4),
+        _ => 0,
+    };
+    // Clamped to 16 bits, then wrapped to 15.
+    (sample.clamp(i16::MIN as i32, i16::MAX as i32) << 1) as i16
+}
+
+/// Encodes 16 samples as the block which decodes closest to them, trying
+/// every shift and the given filters, and choosing each nibble in turn.
+/// Returns the block and its decoded samples.
+fn encode_block(
+    samples: &[i16],
+    history: (i16, i16),
+    filters: std::ops::RangeInclusive<u8>,
+    flags: u8,
+) -> ([u8; BLOCK_LEN], [i16; SAMPLES_PER_BLOCK]) {
+    let mut best = None;
+    let mut best_error = i64::MAX;
+    for filter in filters {
+        for shift in 0..=MAX_SHIFT {
+            let (mut p1, mut p2) = history;
+            let mut nibbles = [0; SAMPLES_PER_BLOCK];
+            let mut decoded = [0; SAMPLES_PER_BLOCK];
+            let mut error = 0;
+            for (i, &target) in samples.iter().enumerate() {
+                // The nibble closest to the target, or its neighbours, as
+                // rounding and clamping can make them closer.
+                let predicted = decode_nibble(0, shift, filter, p1, p2) as i32;
+                let estimate = ((target as i32 - predicted) as f64 / (1 << shift) as f64).round();
+                let estimate = (estimate as i32).clamp(*NIBBLES.start(), *NIBBLES.end());
+                let (nibble, value) = (estimate - 1..=estimate + 1)
+                    .filter(|nibble| NIBBLES.contains(nibble))
+                    .map(|nibble| (nibble, decode_nibble(nibble, shift, filter, p1, p2)))
+                    .min_by_key(|&(_, value)| (value as i32 - target as i32).abs())
+                    .unwrap();
+                let diff = (value as i64 - target as i64).abs();
+                error += diff * diff;
+                nibbles[i] = nibble;
+                decoded[i] = value;
+                p2 = p1;
+                p1 = value;
+            }
+            if error < best_error {
+                best_error = error;
+                best = Some((filter, shift, nibbles, decoded));
+            }
+        }
+    }
+
+    let (filter, shift, nibbles, decoded) = best.unwrap();
+    let mut block = [0; BLOCK_LEN];
+    block[0] = shift << 4 | filter << 2 | flags;
+    for (byte, pair) in block[1..].iter_mut().zip(nibbles.chunks(2)) {
+        *byte = ((pair[0] & 0xf) << 4 | (pair[1] & 0xf)) as u8;
+    }
+    (block, decoded)
+}
+
+/// Encodes 16-bit samples as BRR, choosing each block's filter and shift to
+/// decode as closely as possible. A loop is moved to the start of a block by
+/// adding silence before the sample, and repeated until its length is a
+/// whole number of blocks. The first block, and the block the loop starts
+/// on, don't use the previous samples, as those differ when the loop
+/// repeats.
+pub fn encode(samples: &[i16], loop_start: Option<usize>) -> EncodedSample {
+    let (mut padded, loop_start) = match loop_start.filter(|&start| start < samples.len()) {
+        Some(start) => {
+            let lead = (SAMPLES_PER_BLOCK - start % SAMPLES_PER_BLOCK) % SAMPLES_PER_BLOCK;
+            let mut padded = vec![0; lead];
+            padded.extend_from_slice(&samples[..start]);
+            let body = &samples[start..];
+            // Block length over the greatest common divisor of it and the
+            // loop's length.
+            let (mut a, mut b) = (SAMPLES_PER_BLOCK, body.len());
+            while b > 0 {
+                let rem = a % b;
+                a = b;
+                b = rem;
+            }
+            for _ in 0..SAMPLES_PER_BLOCK / a {
+                padded.extend_from_slice(body);
+            }
+            (padded, Some(start + lead))
+        }
+        None => (samples.to_vec(), None),
+    };
+    let partial = padded.len() % SAMPLES_PER_BLOCK;
+    if partial > 0 || padded.is_empty() {
+        padded.resize(padded.len() + SAMPLES_PER_BLOCK - partial, 0);
+    }
+    let blocks = padded.len() / SAMPLES_PER_BLOCK;
+
+    let loop_block = loop_start.map(|start| start / SAMPLES_PER_BLOCK);
+    let mut brr = vec![];
+    let mut history = (0, 0);
+    for (i, chunk) in padded.chunks(SAMPLES_PER_BLOCK).enumerate() {
+        let filters = if i == 0 || Some(i) == loop_block {
+            0..=0
+        } else {
+            0..=3
+        };
+        let mut flags = 0;
+        if i == blocks - 1 {
+            flags |= END_FLAG;
+            if loop_start.is_some() {
+                flags |= LOOP_FLAG;
+            }
+        }
+        let (block, decoded) = encode_block(chunk, history, filters, flags);
+        brr.extend_from_slice(&block);
+        history = (
+            decoded[SAMPLES_PER_BLOCK - 1],
+            decoded[SAMPLES_PER_BLOCK - 2],
+        );
+    }
+    EncodedSample { brr, loop_start }
+}
+
+/// The length closest to `len` that a resampled loop can be stretched to,
+/// so it fills whole blocks when repeated as few times as possible.
+fn block_loop_len(len: f64) -> f64 {
+    let mut repeats = 1;
+    loop {
+        let unit = (SAMPLES_PER_BLOCK / repeats) as f64;
+        let stretched = ((len / unit).round() * unit).max(unit);
+        let cents = 1200.0 * (stretched / len).log2();
+        if cents.abs() <= MAX_LOOP_DETUNE_CENTS || repeats == SAMPLES_PER_BLOCK {
+            return stretched;
+        }
+        repeats *= 2;
+    }
+}
+
+/// Converts a WAV file to a sample which plays `base_note` at pitch $1000,
+/// as the sample it replaces does, and encodes it. The file's loop is kept,
+/// and anything after it dropped. Files which don't give the note they play
+/// are played at their own speed.
+///
+/// The loop is retuned by up to `MAX_LOOP_DETUNE_CENTS` so that it's a whole
+/// number of blocks long, as otherwise `encode()` repeats it until it is.
+pub fn encode_wav(wav: &WavFile, base_note: f64) -> Result<EncodedSample> {
+    let mono = wav.mono();
+    if mono.is_empty() {
+        bail!("The file has no audio");
+    }
+    let unity_note = wav.unity_note.map_or(base_note, |note| note as f64);
+    // Input samples per output sample.
+    let mut step =
+        wav.sample_rate as f64 / SAMPLE_RATE as f64 * 2f64.powf((base_note - unity_note) / 12.0);
+    if let Some(points) = wav.loop_points.filter(|points| points.length > 0) {
+        let loop_len = points.length as f64;
+        step = loop_len / block_loop_len(loop_len / step);
+    }
+    let (len, loop_start) = match wav.loop_points {
+        Some(points) => (points.end() as usize, Some(points.start as f64 / step)),
+        None => (mono.len(), None),
+    };
+
+    // Linear interpolation is enough, as BRR is already lossy.
+    let output_len = ((len as f64 / step).round() as usize).max(1);
+    let resampled: Vec<i16> = (0..output_len)
+        .map(|i| {
+            let pos = i as f64 * step;
+            let index = pos as usize;
+            let a = mono[index.min(len - 1)] as f64;
+            let b = mono[(index + 1).min(len - 1)] as f64;
+            (a + (b - a) * pos.fract()).round() as i16
+        })
+        .collect();
+    Ok(encode(
+        &resampled,
+        loop_start.map(|start| start.round() as usize),
+    ))
+}
+
+/// Finds `len` bytes of RAM which look unused, at the end of the longest run
+/// of one repeated value, skipping the echo buffer, the source directory and
+/// the pages the CPU keeps its variables and stack in.
+fn free_space(apu: &Apu, len: usize) -> Option<u16> {
+    let dsp = apu.dsp.as_ref().unwrap();
+    let echo_start = dsp.get_echo_start_address() as usize;
+    let echo = echo_start..echo_start + dsp.calculate_echo_length().max(4) as usize;
+    let dir_start = dsp.get_register_value(DIR) as usize * 0x100;
+    let dir = dir_start..dir_start + 0x100 * 4;
+
+    let mut longest = 0..0;
+    let mut run = RESERVED_LOW..RESERVED_LOW;
+    let mut value = None;
+    for address in RESERVED_LOW..RESERVED_HIGH {
+        if echo.contains(&address) || dir.contains(&address) {
+            value = None;
+            continue;
+        }
+        let byte = apu.ram()[address];
+        if value != Some(byte) {
+            value = Some(byte);
+            run.start = address;
+        }
+        run.end = address + 1;
+        if run.len() > longest.len() {
+            longest = run.clone();
+        }
+    }
+    if longest.len() < len {
+        return None;
+    }
+    Some((longest.end - len) as u16)
+}
+
+/// Reads the directory entry of `source` from `ram`, given the DIR register:
+/// the addresses of its sample and of the sample's loop.
+fn dir_entry(ram: &[u8], dir: u8, source: u8) -> (u16, u16) {
+    let entry = dir as usize * 0x100 + source as usize * 4;
+    let word = |offset: usize| {
+        u16::from_le_bytes([
+            ram[(entry + offset) & 0xffff],
+            ram[(entry + offset + 1) & 0xffff],
+        ])
+    };
+    (word(0), word(2))
+}
+
+/// Replaces the sample which the source numbers `sources` play in `apu`'s
+/// RAM, going by the first one's directory entry. It's written over the old
+/// sample if it fits, and otherwise into RAM which looks unused. The
+/// directory entries of every source which played the old sample, not just
+/// `sources`, are then pointed at it. Returns the sample's address.
+pub fn replace_sample(apu: &mut Apu, sources: &[u8], sample: &EncodedSample) -> Result<u16> {
+    let &first = sources.first().context("No sources to replace")?;
+    let dir = apu.dsp.as_ref().unwrap().get_register_value(DIR);
+    let (old_address, _) = dir_entry(apu.ram(), dir, first);
+    let old_len = decode(apu.ram(), old_address).map_or(0, |(brr, _, _)| brr.len());
+    let address = if sample.brr.len() <= old_len {
+        old_address
+    } else {
+        free_space(apu, sample.brr.len()).with_context(|| {
+            format!(
+                "There isn't {} bytes of free memory for the sample",
+                sample.brr.len()
+            )
+        })?
+    };
+
+    let ram = apu.ram_mut();
+    for (i, &byte) in sample.brr.iter().enumerate() {
+        ram[(address as usize + i) & 0xffff] = byte;
+    }
+    let loop_offset = sample.loop_start.unwrap_or(0) / SAMPLES_PER_BLOCK * BLOCK_LEN;
+    let loop_address = address.wrapping_add(loop_offset as u16);
+    for source in 0..=255 {
+        if !sources.contains(&source) && dir_entry(ram, dir, source).0 != old_address {
+            continue;
+        }
+        let entry = dir as usize * 0x100 + source as usize * 4;
+        for (i, &byte) in address
+            .to_le_bytes()
+            .iter()
+            .chain(&loop_address.to_le_bytes())
+            .enumerate()
+        {
+            ram[(entry + i) & 0xffff] = byte;
+        }
+    }
+    Ok(address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::fs;
+
+    const SPC_PATH: &str = "3rdparty/snes-apu/test/ferris-nu.spc";
+
+    #[test]
+    fn test_extract() -> Result<()> {
+        let samples = extract(&Spc::load(SPC_PATH)?);
+        assert!(samples.len() > 2, "{} samples", samples.len());
+
+        let mut sources = HashSet::new();
+        let mut hashes = HashSet::new();
+        for sample in &samples {
+            assert!(sample.sources.iter().all(|&source| sources.insert(source)));
+            assert!(hashes.insert(sample.hash.clone()));
+            assert_eq!(sample.brr.len() % BLOCK_LEN, 0);
+            assert_eq!(
+                sample.samples.len(),
+                sample.brr.len() / BLOCK_LEN * SAMPLES_PER_BLOCK
+            );
+            // Only the last block has the end flag.
+            let headers: Vec<u8> = sample.brr.iter().step_by(BLOCK_LEN).copied().collect();
+            assert_eq!(headers.last().unwrap() & 1, 1);
+            assert!(headers[..headers.len() - 1]
+                .iter()
+                .all(|header| header & 1 == 0));
+            if let Some(loop_start) = sample.loop_start {
+                assert!(loop_start < sample.samples.len());
+            }
+        }
+        assert!(samples.iter().any(|sample| sample.loop_start.is_some()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_sample_scan() -> Result<()> {
+        let spc = Spc::load(SPC_PATH)?;
+        let hashes: Vec<String> = extract(&spc).into_iter().map(|s| s.hash).collect();
+
+        let (tx, rx) = channel();
+        let tx = std::sync::Mutex::new(tx);
+        let scan = SampleScan::start(spc, Arc::new(move || tx.lock().unwrap().send(()).unwrap()));
+        rx.recv()?;
+        let samples = scan.try_finish().unwrap()?;
+        let scanned: Vec<String> = samples.into_iter().map(|s| s.hash).collect();
+        assert_eq!(scanned, hashes);
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicates_are_merged() -> Result<()> {
+        let mut spc = Spc::load(SPC_PATH)?;
+        let samples = extract(&spc);
+        let (first, second) = (samples[0].sources[0], samples[1].sources[0]);
+
+        // Point the second source's directory entry at the first sample. The
+        // song sets the directory's address once it starts.
+        let mut apu = Apu::from_spc(&spc);
+        apu.render_interleaved(&mut [0; 2000]);
+        let dir = apu.dsp.as_ref().unwrap().get_register_value(DIR) as usize * 0x100;
+        let entry = |source: u8| dir + source as usize * 4;
+        let copied: Vec<u8> = spc.ram[entry(first)..entry(first) + 4].to_vec();
+        spc.ram[entry(second)..entry(second) + 4].copy_from_slice(&copied);
+
+        let merged = extract(&spc);
+        assert_eq!(merged.len(), samples.len() - 1);
+        assert_eq!(merged[0].sources, [first, second]);
+        assert_eq!(merged[0].hash, samples[0].hash);
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_wav() -> Result<()> {
+        let spc = Spc::load(SPC_PATH)?;
+        let samples = extract(&spc);
+        let sample = samples
+            .iter()
+            .find(|sample| sample.loop_start.is_some())
+            .unwrap();
+        let path = std::env::temp_dir().join(format!("spcplay-sample-{}.wav", std::process::id()));
+        write_wav(sample, &path, &SpcTags::default())?;
+        let wav = fs::read(&path)?;
+        fs::remove_file(&path)?;
+
+        // Mono, 16-bit.
+        assert_eq!(wav[22..24], 1u16.to_le_bytes());
+        let data = wav.windows(4).position(|id| id == b"data").unwrap();
+        let len = u32::from_le_bytes([wav[data + 4], wav[data + 5], wav[data + 6], wav[data + 7]]);
+        assert_eq!(len as usize, sample.samples.len() * 2);
+        let smpl = wav.windows(4).position(|id| id == b"smpl").unwrap();
+        let loop_start = &wav[smpl + 8 + 44..smpl + 8 + 48];
+        assert_eq!(
+            loop_start,
+            (sample.loop_start.unwrap() as u32).to_le_bytes()
+        );
+        Ok(())
+    }
+
+    /// Decodes blocks as the DSP would play them through once.
+    fn decode_blocks(brr: &[u8]) -> Vec<i16> {
+        let mut decoder = BrrBlockDecoder::new();
+        let mut samples = vec![];
+        for block in brr.chunks(BLOCK_LEN) {
+            decoder.read(block);
+            while !decoder.is_finished() {
+                samples.push(decoder.read_next_sample());
+            }
+        }
+        samples
+    }
+
+    #[test]
+    fn test_encode() {
+        // A decaying tone with a loop which starts and ends mid-block.
+        let samples: Vec<i16> = (0..1000)
+            .map(|i| {
+                let t = i as f64 / 50.0;
+                (20000.0 * (t * 2.0 * std::f64::consts::PI).sin() * (-t / 20.0).exp()) as i16
+            })
+            .collect();
+        let encoded = encode(&samples, Some(300));
+        let loop_start = encoded.loop_start.unwrap();
+        assert_eq!(loop_start % SAMPLES_PER_BLOCK, 0);
+        assert_eq!(loop_start, 304);
+        // The 700 sample loop repeats 4 times to fill whole blocks.
+        let decoded = decode_blocks(&encoded.brr);
+        assert_eq!(decoded.len(), 304 + 700 * 4);
+
+        let headers: Vec<u8> = encoded.brr.iter().step_by(BLOCK_LEN).copied().collect();
+        assert_eq!(headers.last().unwrap() & 3, END_FLAG | LOOP_FLAG);
+        assert!(headers[..headers.len() - 1]
+            .iter()
+            .all(|header| header & 1 == 0));
+        assert_eq!(headers[0] >> 2 & 3, 0);
+        assert_eq!(headers[loop_start / SAMPLES_PER_BLOCK] >> 2 & 3, 0);
+
+        // Close to the original, allowing for BRR's 4 bits per sample.
+        let expected = samples[..300].iter().chain(samples[300..].iter().cycle());
+        let error = decoded[4..]
+            .iter()
+            .zip(expected)
+            .map(|(&a, &b)| (a as f64 - b as f64).powi(2))
+            .sum::<f64>();
+        let rms = (error / decoded.len() as f64).sqrt();
+        assert!(rms < 400.0, "{}", rms);
+
+        assert_eq!(encode(&[], None).brr.len(), BLOCK_LEN);
+    }
+
+    #[test]
+    fn test_replace_sample() -> Result<()> {
+        let spc = Spc::load(SPC_PATH)?;
+        let samples = extract(&spc);
+        let old = samples
+            .iter()
+            .min_by_key(|sample| sample.brr.len())
+            .unwrap();
+        let mut apu = Apu::from_spc(&spc);
+        apu.render_interleaved(&mut [0; 2000]);
+        let dir = apu.dsp.as_ref().unwrap().get_register_value(DIR);
+        let current = |apu: &mut Apu, source: u8| {
+            let (start, loop_address) = dir_entry(apu.ram(), dir, source);
+            (start, loop_address, decode(apu.ram(), start).unwrap().0)
+        };
+        // Another source sharing the sample, but not passed in.
+        let used: Vec<u8> = samples.iter().flat_map(|s| s.sources.clone()).collect();
+        let shared = (0..=255).find(|source| !used.contains(source)).unwrap();
+        let entry = dir as usize * 0x100 + shared as usize * 4;
+        let old_entry = [old.address.to_le_bytes(), old.address.to_le_bytes()].concat();
+        apu.ram_mut()[entry..entry + 4].copy_from_slice(&old_entry);
+
+        // A short sample fits over the old one.
+        let short = encode(&[1000; 32], Some(16));
+        let address = replace_sample(&mut apu, &old.sources, &short)?;
+        assert_eq!(address, old.address);
+        for source in [old.sources[0], shared] {
+            assert_eq!(
+                current(&mut apu, source),
+                (address, address + BLOCK_LEN as u16, short.brr.clone())
+            );
+        }
+
+        // A long one moves.
+        let long_len = old.samples.len() + SAMPLES_PER_BLOCK * 4;
+        let long = encode(&vec![1000; long_len], None);
+        let address = replace_sample(&mut apu, &old.sources, &long)?;
+        assert_ne!(address, old.address);
+        assert_eq!(current(&mut apu, old.sources[0]).2, long.brr);
+        assert_eq!(current(&mut apu, shared), (address, address, long.brr));
+        for sample in samples.iter().filter(|sample| sample.hash != old.hash) {
+            let (start, _, brr) = current(&mut apu, sample.sources[0]);
+            assert_eq!((start, brr), (sample.address, sample.brr.clone()));
+        }
+
+        // Nothing this big fits.
+        assert!(replace_sample(&mut apu, &old.sources, &encode(&vec![0; 0x10000], None)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_reads_ram() -> Result<()> {
+        // A sample running over the I/O registers, which read as something
+        // else through the CPU's view of memory.
+        let brr = encode(&[1000; SAMPLES_PER_BLOCK * 3], None).brr;
+        let mut apu = Apu::from_spc(&Spc::load(SPC_PATH)?);
+        apu.ram_mut()[0xe0..0xe0 + brr.len()].copy_from_slice(&brr);
+        assert_eq!(decode(apu.ram(), 0xe0).unwrap().0, brr);
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_wav() -> Result<()> {
+        let spc = Spc::load(SPC_PATH)?;
+        let samples = extract(&spc);
+        let sample = samples
+            .iter()
+            .find(|sample| sample.loop_start.is_some())
+            .unwrap();
+        let path = std::env::temp_dir().join(format!("spcplay-replace-{}.wav", std::process::id()));
+        write_wav(sample, &path, &SpcTags::default())?;
+        let wav = crate::wav::read(&path)?;
+        fs::remove_file(&path)?;
+        assert_eq!((wav.sample_rate, wav.channels), (SAMPLE_RATE as u32, 1));
+        assert_eq!(wav.samples, sample.samples);
+        assert_eq!(wav.unity_note, Some(60));
+
+        // Re-encoding at the same rate keeps the loop and the audio.
+        let encoded = encode_wav(&wav, 60.0)?;
+        assert_eq!(encoded.loop_start, sample.loop_start);
+        let decoded = decode_blocks(&encoded.brr);
+        assert_eq!(decoded.len(), sample.samples.len());
+        let error = decoded
+            .iter()
+            .zip(&sample.samples)
+            .map(|(&a, &b)| (a as f64 - b as f64).powi(2))
+            .sum::<f64>();
+        let rms = (error / decoded.len() as f64).sqrt();
+        assert!(rms < 400.0, "{}", rms);
+
+        // An octave up takes half as many samples, plus the silence which
+        // moves the loop to the start of a block.
+        let encoded = encode_wav(&wav, 72.0)?;
+        let half = sample.loop_start.unwrap() / 2;
+        let loop_start = encoded.loop_start.unwrap();
+        assert!(
+            loop_start >= half && loop_start < half + SAMPLES_PER_BLOCK,
+            "{}",
+            loop_start
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_wav_odd_loop() -> Result<()> {
+        let wav = |loop_length: u64| WavFile {
+            sample_rate: SAMPLE_RATE as u32,
+            channels: 1,
+            samples: (0..3000)
+                .map(|i| (10000.0 * (i as f64 / 10.0).sin()) as i16)
+                .collect(),
+            loop_points: Some(LoopPoints {
+                start: 100,
+                length: loop_length,
+            }),
+            unity_note: Some(60),
+        };
+
+        // A 2001 sample loop is retuned to 2000 samples, 125 blocks, rather
+        // than being repeated 16 times.
+        let encoded = encode_wav(&wav(2001), 60.0)?;
+        assert_eq!(encoded.loop_start, Some(112));
+        assert_eq!(
+            encoded.brr.len(),
+            (112 + 2000) / SAMPLES_PER_BLOCK * BLOCK_LEN
+        );
+
+        // 1001 samples is a little further from 1008, so it's retuned to
+        // 1000 and repeated twice.
+        let encoded = encode_wav(&wav(1001), 60.0)?;
+        assert_eq!(
+            encoded.brr.len(),
+            (112 + 2000) / SAMPLES_PER_BLOCK * BLOCK_LEN
+        );
+
+        // A loop too short to retune without being heard is repeated.
+        let encoded = encode_wav(&wav(101), 60.0)?;
+        assert_eq!(
+            encoded.brr.len(),
+            (112 + 101 * 16) / SAMPLES_PER_BLOCK * BLOCK_LEN
+        );
+        Ok(())
+    }
+}
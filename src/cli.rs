@@ -0,0 +1,929 @@
+//! Command-line interface, for using the player without opening a window.
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyModifiers};
+use crossterm::terminal;
+use rusqlite::{Connection, OpenFlags};
+use snes_apu::dsp::dsp::SAMPLE_RATE;
+use spc::Spc;
+
+use crate::app;
+use crate::archive;
+use crate::brr;
+use crate::export::{self, Export, ExportJob};
+use crate::flac::{is_flac, FlacWriter};
+use crate::library::{self, SpcTags};
+use crate::loops::{self, LoopInfo};
+use crate::m3u;
+use crate::midi;
+use crate::settings::Settings;
+use crate::sf2;
+use crate::sink::{AudioSink, CpalSink, RawSink};
+use crate::soundtrack;
+use crate::spcplay::{AudioOutput, PlaybackEvent, SpcPlayer, TrackOverrides, STEM_CHANNELS};
+use crate::stems::{self, SplitSink};
+use crate::wav::{SampleFormat, WavWriter};
+
+pub const SUBCOMMANDS: &[&str] = &["info", "play", "render", "samples", "help", "--help", "-h"];
+
+const USAGE: &str = "\
+Usage: spcplay-rs [COMMAND] [OPTIONS] FILE...
+
+Without a command, opens the player window.
+
+Commands:
+  info FILE...          Print tags and registers
+  play FILE...          Play files in the terminal
+  render FILE [-o OUT]  Render a file to OUT.wav or OUT.flac, its notes to
+                        OUT.mid, its instruments to OUT.sf2, raw PCM to stdout
+                        if OUT is -, or the audio device if there's no OUT
+  samples FILE... [-o DIR]
+                        Extract the instrument samples songs play to WAV files
+                        in DIR, or the current folder, skipping duplicates
+
+Options:
+  --json                Print info as JSON, one object per line
+  -o, --output PATH     Where to render to
+  --float               Render 32-bit float WAV rather than 16-bit
+  --seamless            Render the intro and one loop, with the loop marked so
+                        it can be repeated seamlessly
+  --stems               Render each voice, each voice with its echo, and the
+                        echo to separate files named after OUT
+  --multichannel        With --stems, render them as channels of one WAV file
+  --batch               Render every file, folder and M3U playlist given into
+                        the folder OUT, or the current folder
+  --template T          With --batch, names of the files to write, from {game},
+                        {title}, {artist}, {track}, {index} and {file}
+                        (default: {game}/{track} - {title}.wav)
+  --soundtrack          Render every file, folder and M3U playlist given into
+                        one file OUT, with a cue sheet and chapters
+  --crossfade MS        With --soundtrack, overlap songs by this long
+  --gap MS              With --soundtrack, put this much silence between songs
+  --tuning SRCN=NOTE    For MIDI and SF2, the note sample SRCN plays at pitch
+                        $1000, eg. 12=69.5 (default: estimated from the
+                        pitches the song plays, within a semitone of 60)
+  --length SECS         Time to play before fading out
+  --fade MS             Length of the fade out
+  --loops N             Times to play songs whose loop is known, or 0 for forever
+
+Keys while playing:
+  space  pause    n  next    p  previous    +/-  volume    q  quit
+
+Raw PCM is 16-bit little-endian stereo at 32000 Hz.";
+
+/// Time to wait for a key before updating the status line.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const VOLUME_STEP: f32 = 0.1;
+
+struct Options {
+    files: Vec<PathBuf>,
+    json: bool,
+    output: Option<PathBuf>,
+    format: SampleFormat,
+    seamless: bool,
+    stems: bool,
+    multichannel: bool,
+    batch: bool,
+    template: Option<String>,
+    soundtrack: bool,
+    crossfade_ms: Option<u32>,
+    gap_ms: Option<u32>,
+    /// Notes played by samples at pitch $1000, for MIDI and SF2.
+    base_notes: HashMap<u8, f64>,
+    overrides: TrackOverrides,
+    loop_count: Option<u32>,
+}
+
+impl Options {
+    fn parse(args: impl Iterator<Item = OsString>) -> Result<Options> {
+        fn number<T: std::str::FromStr>(flag: &str, value: Option<OsString>) -> Result<T> {
+            value
+                .as_ref()
+                .and_then(|value| value.to_str())
+                .and_then(|value| value.parse().ok())
+                .with_context(|| format!("{} needs a number", flag))
+        }
+
+        let mut options = Options {
+            files: vec![],
+            json: false,
+            output: None,
+            format: SampleFormat::Int16,
+            seamless: false,
+            stems: false,
+            multichannel: false,
+            batch: false,
+            template: None,
+            soundtrack: false,
+            crossfade_ms: None,
+            gap_ms: None,
+            base_notes: HashMap::new(),
+            overrides: TrackOverrides::default(),
+            loop_count: None,
+        };
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.to_str() {
+                Some("--json") => options.json = true,
+                Some("--float") => options.format = SampleFormat::Float32,
+                Some("--seamless") => options.seamless = true,
+                Some("--stems") => options.stems = true,
+                Some("--multichannel") => options.multichannel = true,
+                Some("--batch") => options.batch = true,
+                Some("--soundtrack") => options.soundtrack = true,
+                Some("--crossfade") => {
+                    options.crossfade_ms = Some(number("--crossfade", args.next())?)
+                }
+                Some("--tuning") => {
+                    let tuning = args.next().context("--tuning needs SRCN=NOTE")?;
+                    let (source, note) = midi::parse_tuning(&tuning.to_string_lossy())?;
+                    options.base_notes.insert(source, note);
+                }
+                Some("--gap") => options.gap_ms = Some(number("--gap", args.next())?),
+                Some("--template") => {
+                    let template = args.next().context("--template needs a template")?;
+                    match template.into_string() {
+                        Ok(template) => options.template = Some(template),
+                        Err(_) => bail!("--template is not valid Unicode"),
+                    }
+                }
+                Some("-o") | Some("--output") => {
+                    options.output = Some(args.next().context("-o needs a path")?.into());
+                }
+                Some("--length") => {
+                    let secs: u32 = number("--length", args.next())?;
+                    options.overrides.length_ms = Some(secs.saturating_mul(1000));
+                }
+                Some("--fade") => options.overrides.fade_ms = Some(number("--fade", args.next())?),
+                Some("--loops") => options.loop_count = Some(number("--loops", args.next())?),
+                Some(flag) if flag.starts_with('-') && flag != "-" => {
+                    bail!("unknown option {}\n\n{}", flag, USAGE)
+                }
+                _ => options.files.push(arg.into()),
+            }
+        }
+        Ok(options)
+    }
+
+    fn settings(&self) -> Settings {
+        let mut settings = Settings::default();
+        if let Some(loop_count) = self.loop_count {
+            settings.loop_count = loop_count;
+        }
+        if let Some(crossfade_ms) = self.crossfade_ms {
+            settings.crossfade_ms = crossfade_ms;
+        }
+        if let Some(gap_ms) = self.gap_ms {
+            settings.soundtrack_gap_ms = gap_ms;
+        }
+        settings
+    }
+}
+
+/// Runs a command, given the arguments after the program name.
+pub fn run(args: Vec<OsString>) -> Result<()> {
+    let mut args = args.into_iter();
+    let command = args.next().unwrap_or_default();
+    let options = Options::parse(args)?;
+
+    match command.to_str() {
+        Some("info") | Some("play") | Some("render") | Some("samples")
+            if options.files.is_empty() =>
+        {
+            bail!("no files given\n\n{}", USAGE)
+        }
+        Some("info") => info(&options),
+        Some("play") => play(&options),
+        Some("render") => render(&options),
+        Some("samples") => samples(&options),
+        _ => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+    }
+}
+
+/// Opens the library database to look up detected loops, if it exists.
+fn open_library() -> Option<Connection> {
+    let path = app::settings_path().ok()?;
+    Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).ok()
+}
+
+fn detected_loop(library: Option<&Connection>, path: &Path) -> Option<LoopInfo> {
+    let hash = library::file_hash(&archive::read(path).ok()?);
+    loops::lookup(library?, &hash).ok().flatten().flatten()
+}
+
+fn load(
+    library: Option<&Connection>,
+    path: &Path,
+    settings: &Settings,
+    options: &Options,
+) -> Result<SpcPlayer> {
+    let loop_info = detected_loop(library, path);
+    SpcPlayer::new(path, settings, &options.overrides, loop_info)
+}
+
+fn format_ms(ms: u32) -> String {
+    format!("{}:{:02}", ms / 60000, ms / 1000 % 60)
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            '\n' => out += "\\n",
+            '\r' => out += "\\r",
+            '\t' => out += "\\t",
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_option(value: Option<u32>) -> String {
+    value.map_or("null".to_owned(), |value| value.to_string())
+}
+
+fn info(options: &Options) -> Result<()> {
+    let library = open_library();
+    for path in &options.files {
+        let spc = archive::load_spc(path)?;
+        let tags = SpcTags::from_spc(&spc);
+        let loop_info = tags
+            .loop_info
+            .or_else(|| detected_loop(library.as_ref(), path));
+        if options.json {
+            println!("{}", info_json(path, &spc, &tags, loop_info));
+        } else {
+            print!("{}", info_text(path, &spc, &tags, loop_info));
+        }
+    }
+    Ok(())
+}
+
+/// Writes each sample the songs play to a WAV file named after the song and
+/// the sample's source number.
+fn samples(options: &Options) -> Result<()> {
+    let output_dir = options.output.clone().unwrap_or_else(|| PathBuf::from("."));
+    std::fs::create_dir_all(&output_dir)
+        .with_context(|| format!("Could not create {}", output_dir.display()))?;
+
+    let mut seen = HashSet::new();
+    let mut duplicates = 0;
+    for job in batch_jobs(options)? {
+        let spc = archive::load_spc(&job.path)?;
+        let tags = SpcTags::from_spc(&spc);
+        let stem = job.path.file_stem().unwrap_or_default().to_string_lossy();
+        for sample in brr::extract(&spc) {
+            if !seen.insert(sample.hash.clone()) {
+                duplicates += 1;
+                continue;
+            }
+            let path = output_dir.join(format!("{} - sample {}.wav", stem, sample.sources[0]));
+            brr::write_wav(&sample, &path, &brr::sample_tags(&sample, &tags))?;
+            println!("{}", path.display());
+        }
+    }
+    if duplicates > 0 {
+        eprintln!("Skipped {} duplicate samples", duplicates);
+    }
+    Ok(())
+}
+
+fn info_text(path: &Path, spc: &Spc, tags: &SpcTags, loop_info: Option<LoopInfo>) -> String {
+    let mut buf = String::new();
+    let ms_or_unknown = |ms: Option<u32>| ms.map_or("unknown".to_owned(), format_ms);
+
+    writeln!(buf, "{}", path.display()).unwrap();
+    writeln!(buf, "  Game:     {}", tags.game).unwrap();
+    writeln!(buf, "  Title:    {}", tags.title).unwrap();
+    writeln!(buf, "  Artist:   {}", tags.artist).unwrap();
+    writeln!(buf, "  Dumper:   {}", tags.dumper).unwrap();
+    writeln!(buf, "  Comments: {}", tags.comments).unwrap();
+    writeln!(buf, "  Length:   {}", ms_or_unknown(tags.length_ms)).unwrap();
+    writeln!(buf, "  Fade:     {}", ms_or_unknown(tags.fade_ms)).unwrap();
+    if let Some(info) = loop_info {
+        writeln!(
+            buf,
+            "  Loop:     {} intro, {} loop",
+            format_ms(info.intro_ms),
+            format_ms(info.loop_ms)
+        )
+        .unwrap();
+    }
+    writeln!(
+        buf,
+        "  CPU:      PC={:04x} A={:02x} X={:02x} Y={:02x} PSW={:02x} SP={:02x}",
+        spc.pc, spc.a, spc.x, spc.y, spc.psw, spc.sp
+    )
+    .unwrap();
+    writeln!(buf, "  DSP:").unwrap();
+    for (row, regs) in spc.regs.chunks(16).enumerate() {
+        write!(buf, "    {:02x}:", row * 16).unwrap();
+        for reg in regs {
+            write!(buf, " {:02x}", reg).unwrap();
+        }
+        writeln!(buf).unwrap();
+    }
+    buf
+}
+
+fn info_json(path: &Path, spc: &Spc, tags: &SpcTags, loop_info: Option<LoopInfo>) -> String {
+    let dsp: Vec<String> = spc.regs.iter().map(u8::to_string).collect();
+    format!(
+        "{{\"path\":{},\"game\":{},\"title\":{},\"artist\":{},\"dumper\":{},\"comments\":{},\
+         \"emulator\":{},\"length_ms\":{},\"fade_ms\":{},\"intro_ms\":{},\"loop_ms\":{},\
+         \"cpu\":{{\"pc\":{},\"a\":{},\"x\":{},\"y\":{},\"psw\":{},\"sp\":{}}},\"dsp\":[{}]}}",
+        json_string(&path.to_string_lossy()),
+        json_string(&tags.game),
+        json_string(&tags.title),
+        json_string(&tags.artist),
+        json_string(&tags.dumper),
+        json_string(&tags.comments),
+        tags.emulator,
+        json_option(tags.length_ms),
+        json_option(tags.fade_ms),
+        json_option(loop_info.map(|info| info.intro_ms)),
+        json_option(loop_info.map(|info| info.loop_ms)),
+        spc.pc,
+        spc.a,
+        spc.x,
+        spc.y,
+        spc.psw,
+        spc.sp,
+        dsp.join(",")
+    )
+}
+
+fn render(options: &Options) -> Result<()> {
+    if options.batch {
+        return render_batch(options);
+    }
+    if options.soundtrack {
+        return render_soundtrack(options);
+    }
+    let path = match options.files.as_slice() {
+        [path] => path,
+        _ => bail!("render takes one file"),
+    };
+
+    if let Some(output) = options.output.as_ref().filter(|output| sf2::is_sf2(output)) {
+        let spc = archive::load_spc(path)?;
+        let samples = brr::extract(&spc);
+        let data = sf2::to_sf2(
+            &samples,
+            &SpcTags::from_spc(&spc),
+            &base_notes(&samples, options),
+        );
+        return std::fs::write(output, data)
+            .with_context(|| format!("Could not save {}", output.display()));
+    }
+
+    let library = open_library();
+    let settings = options.settings();
+    let mut player = load(library.as_ref(), path, &settings, options)?;
+    if options.seamless {
+        return render_seamless(&mut player, options);
+    }
+    if options.stems {
+        return render_stems(&mut player, options);
+    }
+    if let Some(output) = options
+        .output
+        .as_ref()
+        .filter(|output| midi::is_midi(output))
+    {
+        let log = player.render_register_writes()?;
+        let title = player.tags().title;
+        let samples = brr::extract(&archive::load_spc(path)?);
+        let data = midi::to_midi(&log, &title, &base_notes(&samples, options));
+        return std::fs::write(output, data)
+            .with_context(|| format!("Could not save {}", output.display()));
+    }
+    if player.remaining_frames().is_none() {
+        bail!(
+            "{} plays forever. Use --length or --loops to end it.",
+            path.display()
+        );
+    }
+
+    let mut sink: Box<dyn AudioSink> = match &options.output {
+        Some(output) if output == Path::new("-") => Box::new(RawSink::new(io::stdout())),
+        Some(output) if is_flac(output) => {
+            if options.format != SampleFormat::Int16 {
+                bail!("FLAC files can only be 16-bit");
+            }
+            Box::new(FlacWriter::create(output, &player.tags(), &[])?)
+        }
+        Some(output) => Box::new(WavWriter::create(output, options.format, &player.tags())?),
+        None => Box::new(CpalSink::new(&settings)?),
+    };
+    player.render_to(sink.as_mut())
+}
+
+/// The note each sample plays at pitch $1000, from `--tuning` or estimated,
+/// so that MIDI and SF2 exports of a song play in tune together.
+fn base_notes(samples: &[brr::BrrSample], options: &Options) -> HashMap<u8, f64> {
+    let mut base_notes = brr::base_notes(samples);
+    base_notes.extend(&options.base_notes);
+    base_notes
+}
+
+/// Lists the songs to render for `--batch` or `--soundtrack`. Folders are searched for SPC
+/// files, and playlists add their songs with their timing.
+fn batch_jobs(options: &Options) -> Result<Vec<ExportJob>> {
+    let job = |path: PathBuf, overrides: &TrackOverrides| ExportJob {
+        path,
+        overrides: TrackOverrides {
+            length_ms: overrides.length_ms.or(options.overrides.length_ms),
+            fade_ms: overrides.fade_ms.or(options.overrides.fade_ms),
+            loop_ms: overrides.loop_ms.or(options.overrides.loop_ms),
+        },
+    };
+    let none = TrackOverrides::default();
+
+    let mut jobs = vec![];
+    for path in &options.files {
+        let is_m3u = matches!(path.extension().and_then(|ext| ext.to_str()),
+            Some(ext) if ext.eq_ignore_ascii_case("m3u") || ext.eq_ignore_ascii_case("m3u8"));
+        if path.is_dir() {
+            let mut paths = vec![];
+            let mut errors = vec![];
+            library::find_spcs(path, &mut paths, &mut errors);
+            if let Some((path, err)) = errors.first() {
+                bail!("Could not read {}: {}", path.display(), err);
+            }
+            paths.sort();
+            jobs.extend(paths.into_iter().map(|path| job(path, &none)));
+        } else if is_m3u {
+            let data = std::fs::read(path)
+                .with_context(|| format!("Could not read {}", path.display()))?;
+            let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+            for entry in m3u::parse(&m3u::decode(&data), base_dir) {
+                jobs.push(job(entry.path, &entry.overrides));
+            }
+        } else if archive::is_archive(path) {
+            let paths = archive::list_spcs(path)?;
+            jobs.extend(paths.into_iter().map(|path| job(path, &none)));
+        } else {
+            jobs.push(job(path.clone(), &none));
+        }
+    }
+    Ok(jobs)
+}
+
+/// Renders many songs into a folder, printing progress as they finish.
+fn render_batch(options: &Options) -> Result<()> {
+    let template = options
+        .template
+        .clone()
+        .unwrap_or_else(|| export::DEFAULT_TEMPLATE.to_owned());
+    export::check_template(&template)?;
+    let output_dir = options.output.clone().unwrap_or_else(|| PathBuf::from("."));
+    let jobs = batch_jobs(options)?;
+
+    let export = Export::start(
+        jobs,
+        output_dir,
+        template,
+        options.settings(),
+        app::settings_path().ok(),
+        Arc::new(|| {}),
+    );
+    let errors = loop {
+        let result = export.try_finish();
+        let progress = export.progress();
+        let mut status = format!(
+            "\rRendered {}/{} files",
+            progress.files_done, progress.files_total
+        );
+        if progress.files_skipped > 0 {
+            write!(status, " ({} already rendered)", progress.files_skipped)?;
+        }
+        eprint!("{}", status);
+        if let Some(errors) = result {
+            eprintln!();
+            break errors;
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    for (path, err) in &errors {
+        eprintln!("{}: {}", path.display(), err);
+    }
+    if !errors.is_empty() {
+        bail!("{} files could not be rendered", errors.len());
+    }
+    Ok(())
+}
+
+/// Renders many songs into one file, with a cue sheet next to it.
+fn render_soundtrack(options: &Options) -> Result<()> {
+    let output = match &options.output {
+        Some(output) if output != Path::new("-") => output,
+        _ => bail!("--soundtrack needs a WAV or FLAC file to render to"),
+    };
+    let jobs = batch_jobs(options)?;
+    let library = open_library();
+    let errors = soundtrack::render_to_file(
+        &jobs,
+        &options.settings(),
+        library.as_ref(),
+        output,
+        options.format,
+    )?;
+    for (path, err) in &errors {
+        eprintln!("Skipped {}: {}", path.display(), err);
+    }
+    Ok(())
+}
+
+/// Renders each voice and the echo to separate files, or one multichannel WAV
+/// file.
+fn render_stems(player: &mut SpcPlayer, options: &Options) -> Result<()> {
+    let output = match &options.output {
+        Some(output) if output != Path::new("-") => output,
+        _ => bail!("--stems needs a WAV or FLAC file to render to"),
+    };
+    if is_flac(output) && (options.multichannel || options.format != SampleFormat::Int16) {
+        bail!("FLAC files can only be 16-bit stereo");
+    }
+    let tags = player.tags();
+
+    let mut sink: Box<dyn AudioSink> = if options.multichannel {
+        let channels = STEM_CHANNELS as u16;
+        Box::new(WavWriter::create_with_channels(
+            output,
+            options.format,
+            &tags,
+            channels,
+        )?)
+    } else {
+        let mut sinks: Vec<Box<dyn AudioSink>> = vec![];
+        for (path, name) in stems::stem_paths(output).iter().zip(stems::stem_names()) {
+            let mut tags = tags.clone();
+            tags.title = format!("{} ({})", tags.title, name);
+            if is_flac(path) {
+                sinks.push(Box::new(FlacWriter::create(path, &tags, &[])?));
+            } else {
+                sinks.push(Box::new(WavWriter::create(path, options.format, &tags)?));
+            }
+        }
+        Box::new(SplitSink::new(sinks))
+    };
+    player.render_stems_to(sink.as_mut())
+}
+
+/// Renders the intro and one loop to a file, marking the loop with a WAV
+/// sampler chunk or FLAC `LOOPSTART` and `LOOPLENGTH` tags.
+fn render_seamless(player: &mut SpcPlayer, options: &Options) -> Result<()> {
+    let output = match &options.output {
+        Some(output) if output != Path::new("-") => output,
+        _ => bail!("--seamless needs a WAV or FLAC file to render to"),
+    };
+    let (samples, points) = player.render_loop()?;
+
+    let mut sink: Box<dyn AudioSink> = if is_flac(output) {
+        if options.format != SampleFormat::Int16 {
+            bail!("FLAC files can only be 16-bit");
+        }
+        let comments = [
+            ("LOOPSTART", points.start.to_string()),
+            ("LOOPLENGTH", points.length.to_string()),
+        ];
+        Box::new(FlacWriter::create(output, &player.tags(), &comments)?)
+    } else {
+        let mut wav = WavWriter::create(output, options.format, &player.tags())?;
+        wav.set_loop(points);
+        Box::new(wav)
+    };
+    sink.write(&samples)?;
+    sink.finish()
+}
+
+/// Puts the terminal in raw mode, so keys are read as they're pressed, until
+/// dropped.
+struct RawMode;
+
+impl RawMode {
+    fn enable() -> Result<RawMode> {
+        terminal::enable_raw_mode().context("Could not read keys from the terminal")?;
+        Ok(RawMode)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = terminal::disable_raw_mode();
+        println!();
+    }
+}
+
+/// Plays files one after another, reading keys from the terminal.
+struct TerminalPlayer<'a> {
+    options: &'a Options,
+    library: Option<Connection>,
+    settings: Settings,
+    output: AudioOutput,
+    /// Index of the playing file.
+    current: usize,
+    /// Index of the file queued to play next.
+    queued: Option<usize>,
+    paused: bool,
+}
+
+impl TerminalPlayer<'_> {
+    /// Loads the first file which loads, starting at `index`.
+    fn load_from(&self, mut index: usize) -> Option<(usize, SpcPlayer)> {
+        while let Some(path) = self.options.files.get(index) {
+            match load(self.library.as_ref(), path, &self.settings, self.options) {
+                Ok(player) => return Some((index, player)),
+                // Raw mode doesn't move to the start of the line.
+                Err(err) => eprint!("\r{:#}\x1b[K\r\n", err),
+            }
+            index += 1;
+        }
+        None
+    }
+
+    /// Plays the file at `index`, or the next one which loads. Returns false
+    /// if there are none left.
+    fn play_from(&mut self, index: usize) -> bool {
+        match self.load_from(index) {
+            Some((index, player)) => {
+                self.current = index;
+                self.queued = None;
+                self.output.play(player);
+                self.paused = false;
+                self.output.set_paused(false);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn print_status(&self) -> Result<()> {
+        let path = &self.options.files[self.current];
+        let playback = self.output.lock();
+        let player = match playback.current() {
+            Some(player) => player,
+            None => return Ok(()),
+        };
+        let position_ms = player.position_ms();
+        let length = match player.remaining_frames() {
+            Some(frames) => {
+                format_ms(position_ms + (frames as u64 * 1000 / SAMPLE_RATE as u64) as u32)
+            }
+            None => "∞".to_owned(),
+        };
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        print!(
+            "\r[{}/{}] {}  {} / {}  vol {:.0}%{}\x1b[K",
+            self.current + 1,
+            self.options.files.len(),
+            name,
+            format_ms(position_ms),
+            length,
+            self.settings.volume * 100.0,
+            if self.paused { "  (paused)" } else { "" }
+        );
+        io::stdout().flush()?;
+        Ok(())
+    }
+
+    /// Handles a key. Returns false to quit.
+    fn on_key(&mut self, key: KeyEvent) -> bool {
+        match key.code {
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return false,
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Char(' ') => {
+                self.paused = !self.paused;
+                self.output.set_paused(self.paused);
+            }
+            KeyCode::Char('n') | KeyCode::Right => return self.play_from(self.current + 1),
+            KeyCode::Char('p') | KeyCode::Left => {
+                self.play_from(self.current.saturating_sub(1));
+            }
+            KeyCode::Char('+') | KeyCode::Char('=') | KeyCode::Up => {
+                self.settings.volume = (self.settings.volume + VOLUME_STEP).min(1.0);
+                self.output.lock().apply_settings(&self.settings);
+            }
+            KeyCode::Char('-') | KeyCode::Down => {
+                self.settings.volume = (self.settings.volume - VOLUME_STEP).max(0.0);
+                self.output.lock().apply_settings(&self.settings);
+            }
+            _ => {}
+        }
+        true
+    }
+
+    /// Handles events from the audio thread. Returns false once the last file
+    /// has finished.
+    fn on_playback_events(&mut self) -> bool {
+        for event in self.output.take_events() {
+            match event {
+                PlaybackEvent::NeedNext => {
+                    if let Some((index, player)) = self.load_from(self.current + 1) {
+                        self.queued = Some(index);
+                        self.output.queue(player);
+                    }
+                }
+                PlaybackEvent::Started => {
+                    if let Some(index) = self.queued.take() {
+                        self.current = index;
+                    }
+                }
+                PlaybackEvent::Stopped => {
+                    if !self.play_from(self.current + 1) {
+                        return false;
+                    }
+                }
+                PlaybackEvent::Looped => {}
+            }
+        }
+        true
+    }
+}
+
+fn play(options: &Options) -> Result<()> {
+    let settings = options.settings();
+    let output = AudioOutput::new(&settings, Box::new(|| {}))?;
+    let mut player = TerminalPlayer {
+        options,
+        library: open_library(),
+        settings,
+        output,
+        current: 0,
+        queued: None,
+        paused: false,
+    };
+
+    let _raw_mode = RawMode::enable()?;
+    if !player.play_from(0) {
+        return Ok(());
+    }
+    loop {
+        if !player.on_playback_events() {
+            return Ok(());
+        }
+        player.print_status()?;
+        if event::poll(POLL_INTERVAL)? {
+            if let Event::Key(key) = event::read()? {
+                if !player.on_key(key) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options> {
+        Options::parse(args.iter().map(OsString::from))
+    }
+
+    fn parse_error(args: &[&str]) -> String {
+        match parse(args) {
+            Ok(_) => panic!("{:?} parsed", args),
+            Err(err) => format!("{:#}", err),
+        }
+    }
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let options = parse(&[
+            "a.spc",
+            "--json",
+            "-o",
+            "out.wav",
+            "--float",
+            "--length",
+            "90",
+            "--fade",
+            "500",
+            "--loops",
+            "0",
+            "--crossfade",
+            "250",
+            "--gap",
+            "1000",
+            "--tuning",
+            "12=69.5",
+            "-",
+            "b.spc",
+        ])?;
+        let files = [PathBuf::from("a.spc"), "-".into(), "b.spc".into()];
+        assert_eq!(options.files, files);
+        assert!(options.json);
+        assert_eq!(options.output, Some(PathBuf::from("out.wav")));
+        assert_eq!(options.format, SampleFormat::Float32);
+        assert_eq!(
+            options.overrides,
+            TrackOverrides {
+                length_ms: Some(90_000),
+                loop_ms: None,
+                fade_ms: Some(500),
+            }
+        );
+        assert_eq!(options.base_notes.get(&12), Some(&69.5));
+        assert!(!options.seamless && !options.stems && !options.batch);
+
+        let settings = options.settings();
+        assert_eq!(settings.loop_count, 0);
+        assert_eq!(settings.crossfade_ms, 250);
+        assert_eq!(settings.soundtrack_gap_ms, 1000);
+
+        let options = parse(&[])?;
+        assert!(options.files.is_empty());
+        assert_eq!(options.settings(), Settings::default());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse_error(&["a.spc", "--bogus"]).starts_with("unknown option --bogus\n\nUsage:"));
+        assert!(parse_error(&["-x"]).starts_with("unknown option -x\n"));
+        assert_eq!(parse_error(&["a.spc", "-o"]), "-o needs a path");
+        assert_eq!(parse_error(&["--output"]), "-o needs a path");
+        assert_eq!(parse_error(&["--length"]), "--length needs a number");
+        assert_eq!(
+            parse_error(&["--length", "1:30"]),
+            "--length needs a number"
+        );
+        assert_eq!(parse_error(&["--fade", "-1"]), "--fade needs a number");
+        assert_eq!(parse_error(&["--loops", "a.spc"]), "--loops needs a number");
+        assert_eq!(parse_error(&["--crossfade"]), "--crossfade needs a number");
+        assert_eq!(parse_error(&["--gap", ""]), "--gap needs a number");
+        assert_eq!(parse_error(&["--tuning"]), "--tuning needs SRCN=NOTE");
+        assert_eq!(parse_error(&["--template"]), "--template needs a template");
+    }
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string(""), r#""""#);
+        assert_eq!(json_string("say \"hi\""), r#""say \"hi\"""#);
+        assert_eq!(json_string(r"C:\spc"), r#""C:\\spc""#);
+        assert_eq!(json_string("a\nb\rc\td"), r#""a\nb\rc\td""#);
+        assert_eq!(
+            json_string("\u{0}\u{1}\u{1b}\u{1f} \u{7f}"),
+            "\"\\u0000\\u0001\\u001b\\u001f \u{7f}\""
+        );
+        assert_eq!(json_string("ゼルダ é"), "\"ゼルダ é\"");
+    }
+
+    #[test]
+    fn test_info_json() -> Result<()> {
+        let path = Path::new("3rdparty/snes-apu/test/ferris-nu.spc");
+        let spc = Spc::load(path)?;
+        let tags = SpcTags::from_spc(&spc);
+        let loop_info = LoopInfo {
+            intro_ms: 1500,
+            loop_ms: 30_000,
+        };
+        let json = info_json(path, &spc, &tags, Some(loop_info));
+        let dsp = vec!["0"; 128].join(",");
+        assert_eq!(
+            json,
+            format!(
+                "{{\"path\":\"3rdparty/snes-apu/test/ferris-nu.spc\",\"game\":\"elix - nu\",\
+                 \"title\":\"nu\",\"artist\":\"ferris\",\"dumper\":\"\",\
+                 \"comments\":\"soundtrack for \\\"nu\\\" by elix\",\"emulator\":0,\
+                 \"length_ms\":121000,\"fade_ms\":0,\"intro_ms\":1500,\"loop_ms\":30000,\
+                 \"cpu\":{{\"pc\":768,\"a\":0,\"x\":0,\"y\":0,\"psw\":2,\"sp\":239}},\
+                 \"dsp\":[{}]}}",
+                dsp
+            )
+        );
+
+        // Unknown values are null, and it's all on one line.
+        let tags = SpcTags {
+            title: "line\nbreak".to_owned(),
+            ..SpcTags::default()
+        };
+        let json = info_json(path, &spc, &tags, None);
+        assert!(json.contains(
+            "\"title\":\"line\\nbreak\",\"artist\":\"\",\"dumper\":\"\",\"comments\":\"\",\
+             \"emulator\":0,\"length_ms\":null,\"fade_ms\":null,\"intro_ms\":null,\
+             \"loop_ms\":null,"
+        ));
+        assert!(!json.contains('\n'));
+        Ok(())
+    }
+}
@@ -0,0 +1,235 @@
+use std::fmt;
+use std::fs::rename;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use rusqlite::{Connection, ErrorCode};
+
+/// `MIGRATIONS[i]` upgrades the schema from `user_version` i to i + 1.
+/// Once a migration has shipped, don't edit it; append a new one instead.
+static MIGRATIONS: &[&str] = &[
+    // 1: key-value store for settings.
+    "create table settings (
+         key text primary key,
+         value text not null
+     );",
+    // 2: music library.
+    "create table library_folders (
+         id integer primary key,
+         path text not null unique
+     );
+     create table library_files (
+         id integer primary key,
+         path text not null unique,
+         hash text not null,
+         mtime integer not null,
+         size integer not null,
+         game text not null,
+         title text not null,
+         artist text not null,
+         dumper text not null,
+         comments text not null,
+         emulator integer not null,
+         length_ms integer,
+         fade_ms integer
+     );
+     create index library_files_game on library_files (game);
+     create index library_files_hash on library_files (hash);",
+    // 3: library search, play counts and ratings.
+    "alter table library_files add column play_count integer not null default 0;
+     alter table library_files add column rating integer;
+     create virtual table library_search using fts5 (
+         title, game, artist, dumper, comments,
+         content = 'library_files', content_rowid = 'id',
+         tokenize = 'unicode61 remove_diacritics 2'
+     );
+     insert into library_search (library_search) values ('rebuild');
+     create trigger library_files_ai after insert on library_files begin
+         insert into library_search (rowid, title, game, artist, dumper, comments)
+         values (new.id, new.title, new.game, new.artist, new.dumper, new.comments);
+     end;
+     create trigger library_files_ad after delete on library_files begin
+         insert into library_search (library_search, rowid, title, game, artist, dumper, comments)
+         values ('delete', old.id, old.title, old.game, old.artist, old.dumper, old.comments);
+     end;
+     create trigger library_files_au after update of title, game, artist, dumper, comments
+     on library_files begin
+         insert into library_search (library_search, rowid, title, game, artist, dumper, comments)
+         values ('delete', old.id, old.title, old.game, old.artist, old.dumper, old.comments);
+         insert into library_search (rowid, title, game, artist, dumper, comments)
+         values (new.id, new.title, new.game, new.artist, new.dumper, new.comments);
+     end;",
+    // 4: the play queue.
+    "create table playlist (
+         position integer primary key,
+         path text not null,
+         game text not null,
+         title text not null,
+         length_ms integer
+     );",
+    // 5: per-entry timing from M3U playlists.
+    "alter table playlist add column override_length_ms integer;
+     alter table playlist add column override_loop_ms integer;
+     alter table playlist add column override_fade_ms integer;",
+    // 6: detected loop points, by file hash. Null lengths mean no loop was
+    // found.
+    "create table song_loops (
+         hash text primary key,
+         intro_ms integer,
+         loop_ms integer
+     );",
+    // 7: loudness, by file hash. Null loudness means the file is silent.
+    "create table song_loudness (
+         hash text primary key,
+         lufs real,
+         true_peak real not null,
+         duration_ms integer not null
+     );",
+];
+
+/// The `user_version` of a fully migrated database.
+pub fn schema_version() -> u32 {
+    MIGRATIONS.len() as u32
+}
+
+pub fn user_version(conn: &Connection) -> rusqlite::Result<u32> {
+    conn.query_row("pragma user_version", [], |row| row.get(0))
+}
+
+/// Reasons an existing database can't be migrated in place.
+#[derive(Debug, PartialEq)]
+pub enum Unusable {
+    Corrupt(String),
+    /// Written by a newer version of the program.
+    TooNew(u32),
+}
+
+impl fmt::Display for Unusable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Unusable::Corrupt(msg) => write!(f, "settings database is corrupt ({})", msg),
+            Unusable::TooNew(version) => write!(
+                f,
+                "settings database has version {}, newer than supported version {}",
+                version,
+                schema_version()
+            ),
+        }
+    }
+}
+
+fn is_corrupt(err: &rusqlite::Error) -> bool {
+    match err {
+        rusqlite::Error::SqliteFailure(err, _) => matches!(
+            err.code,
+            ErrorCode::DatabaseCorrupt | ErrorCode::NotADatabase
+        ),
+        _ => false,
+    }
+}
+
+/// Checks whether `conn` needs to be replaced before migrating. Errors other
+/// than corruption (eg. the file being locked) are returned as-is.
+pub fn check(conn: &Connection) -> Result<Option<Unusable>> {
+    match conn.query_row("pragma quick_check", [], |row| row.get::<_, String>(0)) {
+        Ok(result) if result == "ok" => {}
+        Ok(result) => return Ok(Some(Unusable::Corrupt(result))),
+        Err(err) if is_corrupt(&err) => return Ok(Some(Unusable::Corrupt(err.to_string()))),
+        Err(err) => return Err(err.into()),
+    }
+
+    let version = user_version(conn)?;
+    if version > schema_version() {
+        return Ok(Some(Unusable::TooNew(version)));
+    }
+    Ok(None)
+}
+
+/// Runs every migration newer than the database's `user_version`, each in its
+/// own transaction.
+pub fn migrate(conn: &mut Connection) -> Result<()> {
+    let version = user_version(conn)?;
+    if version > schema_version() {
+        bail!("{}", Unusable::TooNew(version));
+    }
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let new_version = i as u32 + 1;
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)
+            .with_context(|| format!("migrating settings database to version {}", new_version))?;
+        tx.pragma_update(None, "user_version", &new_version)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+/// Moves a database (and any leftover journal, which would otherwise be
+/// replayed into its replacement) out of the way. Returns the new path.
+pub fn back_up(path: &Path) -> Result<PathBuf> {
+    let backup_path = (1..)
+        .map(|i| {
+            let mut name = path.as_os_str().to_owned();
+            name.push(format!(".{}.bak", i));
+            PathBuf::from(name)
+        })
+        .find(|p| !p.exists())
+        .unwrap();
+
+    rename(path, &backup_path)
+        .with_context(|| format!("backing up {} to {}", path.display(), backup_path.display()))?;
+
+    for suffix in &["-journal", "-wal"] {
+        let mut from = path.as_os_str().to_owned();
+        from.push(suffix);
+        let mut to = backup_path.as_os_str().to_owned();
+        to.push(suffix);
+        if Path::new(&from).exists() {
+            rename(&from, &to)?;
+        }
+    }
+
+    Ok(backup_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrate_empty() -> Result<()> {
+        let mut conn = Connection::open_in_memory()?;
+        assert_eq!(check(&conn)?, None);
+
+        migrate(&mut conn)?;
+        assert_eq!(user_version(&conn)?, schema_version());
+
+        conn.execute("insert into settings values ('a', 'b')", [])?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_twice() -> Result<()> {
+        let mut conn = Connection::open_in_memory()?;
+        migrate(&mut conn)?;
+        conn.execute("insert into settings values ('a', 'b')", [])?;
+
+        migrate(&mut conn)?;
+        assert_eq!(user_version(&conn)?, schema_version());
+        let value: String = conn.query_row("select value from settings", [], |row| row.get(0))?;
+        assert_eq!(value, "b");
+        Ok(())
+    }
+
+    #[test]
+    fn test_too_new() -> Result<()> {
+        let mut conn = Connection::open_in_memory()?;
+        let version = schema_version() + 1;
+        conn.pragma_update(None, "user_version", &version)?;
+
+        assert_eq!(check(&conn)?, Some(Unusable::TooNew(version)));
+        assert!(migrate(&mut conn).is_err());
+        assert_eq!(user_version(&conn)?, version);
+        Ok(())
+    }
+}
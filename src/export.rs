@@ -0,0 +1,421 @@
+//! Rendering many songs to files at once, eg. a whole game's soundtrack.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use anyhow::{bail, Context, Result};
+use rusqlite::{Connection, OpenFlags};
+
+use crate::archive;
+use crate::flac::{is_flac, FlacWriter};
+use crate::library::{self, SpcTags};
+use crate::loops;
+use crate::settings::Settings;
+use crate::sink::AudioSink;
+use crate::spcplay::{SpcPlayer, TrackOverrides};
+use crate::wav::{SampleFormat, WavWriter};
+
+pub const DEFAULT_TEMPLATE: &str = "{game}/{track} - {title}.wav";
+
+/// Checks that a filename template names a WAV or FLAC file inside the
+/// output folder.
+pub fn check_template(template: &str) -> Result<()> {
+    let path = Path::new(template);
+    let is_wav = matches!(path.extension().and_then(|ext| ext.to_str()), Some(ext) if ext.eq_ignore_ascii_case("wav"));
+    if !is_wav && !is_flac(path) {
+        bail!("The filename template must end in .wav or .flac");
+    }
+    if template.starts_with('/') || template.split('/').any(|part| part == "..") {
+        bail!("The filename template must stay inside the output folder");
+    }
+    Ok(())
+}
+
+/// Makes a tag usable as a file name, on any system.
+pub fn sanitize(value: &str) -> String {
+    let name: String = value
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    // Windows drops trailing dots and spaces.
+    let name = name.trim().trim_end_matches('.');
+    if name.is_empty() {
+        "_".to_owned()
+    } else {
+        name.to_owned()
+    }
+}
+
+/// Fills in a filename template, relative to the output folder. `{game}`,
+/// `{title}`, `{artist}`, `{track}` (the OST track, or else the song's
+/// position in the export), `{index}` and `{file}` are replaced, and `/`
+/// separates folders.
+pub fn expand_template(template: &str, tags: &SpcTags, path: &Path, index: usize) -> PathBuf {
+    let file = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let or = |value: &str, default: &str| {
+        if value.is_empty() {
+            default.to_owned()
+        } else {
+            value.to_owned()
+        }
+    };
+    let position = format!("{:02}", index + 1);
+    let value = |key: &str| match key {
+        "game" => Some(or(&tags.game, "Unknown game")),
+        "title" => Some(or(&tags.title, &file)),
+        "artist" => Some(or(&tags.artist, "Unknown artist")),
+        "track" => Some(or(&tags.track, &position)),
+        "index" => Some(position.clone()),
+        "file" => Some(file.clone()),
+        _ => None,
+    };
+
+    let mut out = PathBuf::new();
+    for part in template.split('/').filter(|part| !part.is_empty()) {
+        let mut name = String::new();
+        let mut rest = part;
+        while let Some(start) = rest.find('{') {
+            name += &rest[..start];
+            rest = &rest[start..];
+            let end = match rest.find('}') {
+                Some(end) => end,
+                None => break,
+            };
+            match value(&rest[1..end]) {
+                Some(value) => name += &sanitize(&value),
+                None => name += &rest[..=end],
+            }
+            rest = &rest[end + 1..];
+        }
+        name += rest;
+        out.push(name);
+    }
+    out
+}
+
+/// Adds a number to `path` until it isn't in `used`.
+fn unique_path(path: PathBuf, used: &mut HashSet<PathBuf>) -> PathBuf {
+    let mut unique = path.clone();
+    let mut n = 2;
+    while used.contains(&unique) {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let extension = path.extension().unwrap_or_default().to_string_lossy();
+        unique.set_file_name(format!("{} ({}).{}", stem, n, extension));
+        n += 1;
+    }
+    used.insert(unique.clone());
+    unique
+}
+
+/// A song to render, with timing from a playlist.
+#[derive(Clone, Debug)]
+pub struct ExportJob {
+    pub path: PathBuf,
+    pub overrides: TrackOverrides,
+}
+
+/// What the export threads are doing, for display while they run.
+#[derive(Clone, Default, Debug)]
+pub struct ExportProgress {
+    pub files_total: usize,
+    pub files_done: usize,
+    /// Files which were already rendered by an earlier export.
+    pub files_skipped: usize,
+}
+
+/// Loads a song to render, with its loop if it was detected in `library`.
+/// Fails if the song plays forever.
+pub fn load_player(
+    job: &ExportJob,
+    settings: &Settings,
+    library: Option<&Connection>,
+) -> Result<SpcPlayer> {
+    let detected_loop = match library {
+        Some(conn) => {
+            loops::lookup(conn, &library::file_hash(&archive::read(&job.path)?))?.flatten()
+        }
+        None => None,
+    };
+    let player = SpcPlayer::new(&job.path, settings, &job.overrides, detected_loop)?;
+    if player.remaining_frames().is_none() {
+        bail!("plays forever");
+    }
+    Ok(player)
+}
+
+/// Renders a song to `output`, via a temporary file so that a file with the
+/// final name is always complete.
+fn export_file(
+    job: &ExportJob,
+    output: &Path,
+    settings: &Settings,
+    library: Option<&Connection>,
+) -> Result<()> {
+    let mut player = load_player(job, settings, library)?;
+
+    if let Some(dir) = output.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Could not create {}", dir.display()))?;
+    }
+    let mut partial = output.as_os_str().to_owned();
+    partial.push(".part");
+    let partial = PathBuf::from(partial);
+
+    let result = (|| {
+        let mut sink: Box<dyn AudioSink> = if is_flac(output) {
+            Box::new(FlacWriter::create(&partial, &player.tags(), &[])?)
+        } else {
+            Box::new(WavWriter::create(
+                &partial,
+                SampleFormat::Int16,
+                &player.tags(),
+            )?)
+        };
+        player.render_to(sink.as_mut())
+    })();
+    match result {
+        Ok(()) => fs::rename(&partial, output)
+            .with_context(|| format!("Could not rename {}", partial.display())),
+        Err(err) => {
+            let _ = fs::remove_file(&partial);
+            Err(err)
+        }
+    }
+}
+
+/// Renders a list of songs to files, on one background thread per CPU.
+pub struct Export {
+    progress: Arc<Mutex<ExportProgress>>,
+    result: Receiver<Vec<(PathBuf, String)>>,
+}
+
+impl Export {
+    /// Starts rendering `jobs` into `output_dir`, named by `template`. Files
+    /// which already exist are skipped, so an interrupted export can be
+    /// resumed by starting it again. Loops detected in the library at
+    /// `db_path`, if given, are used. `notify` is called as each file
+    /// finishes.
+    pub fn start(
+        jobs: Vec<ExportJob>,
+        output_dir: PathBuf,
+        template: String,
+        settings: Settings,
+        db_path: Option<PathBuf>,
+        notify: Arc<dyn Fn() + Send + Sync>,
+    ) -> Export {
+        let progress = Arc::new(Mutex::new(ExportProgress {
+            files_total: jobs.len(),
+            ..ExportProgress::default()
+        }));
+        let (tx, result) = channel();
+
+        {
+            let progress = progress.clone();
+            thread::spawn(move || {
+                let errors = Mutex::new(vec![]);
+                let finished = |path: &Path, result: Result<()>| {
+                    if let Err(err) = result {
+                        errors
+                            .lock()
+                            .unwrap()
+                            .push((path.to_owned(), format!("{:#}", err)));
+                    }
+                    progress.lock().unwrap().files_done += 1;
+                    notify();
+                };
+
+                // Name every file first, so songs with the same name don't
+                // overwrite each other.
+                let mut used = HashSet::new();
+                let mut queue = vec![];
+                for (index, job) in jobs.into_iter().enumerate() {
+                    let tags = match archive::load_spc(&job.path) {
+                        Ok(spc) => SpcTags::from_spc(&spc),
+                        Err(err) => {
+                            finished(&job.path, Err(err));
+                            continue;
+                        }
+                    };
+                    let name = expand_template(&template, &tags, &job.path, index);
+                    let output = unique_path(output_dir.join(name), &mut used);
+                    if output.exists() {
+                        progress.lock().unwrap().files_skipped += 1;
+                        finished(&job.path, Ok(()));
+                    } else {
+                        queue.push((job, output));
+                    }
+                }
+
+                let queue = Mutex::new(queue.into_iter());
+                let threads = thread::available_parallelism().map_or(1, |n| n.get());
+                thread::scope(|s| {
+                    for _ in 0..threads {
+                        s.spawn(|| {
+                            let library = db_path.as_ref().and_then(|db_path| {
+                                Connection::open_with_flags(
+                                    db_path,
+                                    OpenFlags::SQLITE_OPEN_READ_ONLY,
+                                )
+                                .ok()
+                            });
+                            loop {
+                                let (job, output) = match queue.lock().unwrap().next() {
+                                    Some(next) => next,
+                                    None => break,
+                                };
+                                let result =
+                                    export_file(&job, &output, &settings, library.as_ref());
+                                finished(&job.path, result);
+                            }
+                        });
+                    }
+                });
+
+                let _ = tx.send(errors.into_inner().unwrap());
+                notify();
+            });
+        }
+
+        Export { progress, result }
+    }
+
+    pub fn progress(&self) -> ExportProgress {
+        self.progress.lock().unwrap().clone()
+    }
+
+    /// Returns the files which couldn't be rendered, and why, once every file
+    /// has been processed.
+    pub fn try_finish(&self) -> Option<Vec<(PathBuf, String)>> {
+        match self.result.try_recv() {
+            Ok(errors) => Some(errors),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                Some(vec![(PathBuf::new(), "export crashed".to_owned())])
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spcplay::test_overrides;
+    use std::time::Duration;
+
+    #[test]
+    fn test_expand_template() {
+        let tags = SpcTags {
+            title: "What? Now: A/B".to_owned(),
+            game: "Game.".to_owned(),
+            track: "3a".to_owned(),
+            ..SpcTags::default()
+        };
+        let path = Path::new("spcs/song.spc");
+        assert_eq!(
+            expand_template(DEFAULT_TEMPLATE, &tags, path, 0),
+            Path::new("Game/3a - What_ Now_ A_B.wav")
+        );
+
+        let untagged = SpcTags::default();
+        assert_eq!(
+            expand_template(
+                "{game}/{track} {title} {artist} {x}.flac",
+                &untagged,
+                path,
+                4
+            ),
+            Path::new("Unknown game/05 song Unknown artist {x}.flac")
+        );
+    }
+
+    #[test]
+    fn test_check_template() {
+        assert!(check_template(DEFAULT_TEMPLATE).is_ok());
+        assert!(check_template("{title}.FLAC").is_ok());
+        assert!(check_template("{title}.mp3").is_err());
+        assert!(check_template("../{title}.wav").is_err());
+        assert!(check_template("/tmp/{title}.wav").is_err());
+    }
+
+    fn list_files(dir: &Path) -> Result<Vec<PathBuf>> {
+        let mut files = vec![];
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                files.extend(list_files(&path)?);
+            } else {
+                files.push(path);
+            }
+        }
+        Ok(files)
+    }
+
+    fn run(jobs: Vec<ExportJob>, output_dir: &Path) -> (ExportProgress, Vec<(PathBuf, String)>) {
+        let export = Export::start(
+            jobs,
+            output_dir.to_owned(),
+            "{game}/{file}.wav".to_owned(),
+            Settings::default(),
+            None,
+            Arc::new(|| {}),
+        );
+        loop {
+            if let Some(errors) = export.try_finish() {
+                return (export.progress(), errors);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_export_and_resume() -> Result<()> {
+        let output_dir = std::env::temp_dir().join("spcplay-rs-test_export_and_resume");
+        let _ = fs::remove_dir_all(&output_dir);
+        let job = |path: &str| ExportJob {
+            path: PathBuf::from(path),
+            overrides: test_overrides(500, 0),
+        };
+        let jobs = vec![
+            job("3rdparty/snes-apu/test/ferris-nu.spc"),
+            job("3rdparty/snes-apu/test/smashit.spc"),
+            job("3rdparty/snes-apu/test/missing.spc"),
+        ];
+
+        let (progress, errors) = run(jobs.clone(), &output_dir);
+        assert_eq!(progress.files_done, 3);
+        assert_eq!(progress.files_skipped, 0);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, jobs[2].path);
+        let mut outputs = list_files(&output_dir)?;
+        outputs.sort();
+        assert_eq!(outputs.len(), 2);
+        for output in &outputs {
+            assert_eq!(output.extension().unwrap(), "wav");
+        }
+
+        // Resuming renders what's missing, including a file left partly
+        // rendered.
+        fs::remove_file(&outputs[1])?;
+        let mut partial = outputs[1].as_os_str().to_owned();
+        partial.push(".part");
+        fs::write(&partial, b"RIFF")?;
+        let (progress, errors) = run(jobs, &output_dir);
+        assert_eq!(progress.files_skipped, 1);
+        assert_eq!(errors.len(), 1);
+        assert!(outputs[1].exists());
+        assert!(!Path::new(&partial).exists());
+
+        fs::remove_dir_all(&output_dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,846 @@
+//! A FLAC encoder for 16-bit stereo audio at the SNES's sample rate.
+//!
+//! Each block is coded with whichever stereo decorrelation and predictor
+//! (constant, verbatim, fixed polynomial or LPC) comes out smallest.
+
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use md5::{Digest, Md5};
+use snes_apu::dsp::dsp::SAMPLE_RATE;
+
+use crate::library::SpcTags;
+use crate::sink::AudioSink;
+
+const BLOCK_SIZE: usize = 4096;
+const BITS_PER_SAMPLE: u32 = 16;
+const MAX_FIXED_ORDER: usize = 4;
+const MAX_LPC_ORDER: usize = 8;
+/// Bits per quantized LPC coefficient.
+const LPC_PRECISION: u32 = 14;
+const MAX_PARTITION_ORDER: u32 = 8;
+/// Largest Rice parameter for residual coding method 0. Method 1 allows up to
+/// 30.
+const MAX_RICE_PARAM_4BIT: u32 = 14;
+
+const STREAMINFO: u8 = 0;
+const VORBIS_COMMENT: u8 = 4;
+
+/// Writes bits most significant first.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    /// Bits in `acc` not yet written to `bytes`.
+    bits: u32,
+}
+
+impl BitWriter {
+    /// Writes the low `bits` bits of `value`, where `bits` is at most 32.
+    fn write(&mut self, value: u64, bits: u32) {
+        if bits == 0 {
+            return;
+        }
+        self.acc = (self.acc << bits) | (value & ((1 << bits) - 1));
+        self.bits += bits;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.bytes.push((self.acc >> self.bits) as u8);
+        }
+    }
+
+    fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write(value as u64, bits);
+    }
+
+    /// Writes `value` zeros followed by a one.
+    fn write_unary(&mut self, mut value: u64) {
+        while value >= 32 {
+            self.write(0, 32);
+            value -= 32;
+        }
+        self.write(1, value as u32 + 1);
+    }
+
+    fn write_rice(&mut self, value: i32, param: u32) {
+        // Interleave positive and negative values: 0, -1, 1, -2...
+        let folded = ((value << 1) ^ (value >> 31)) as u32 as u64;
+        self.write_unary(folded >> param);
+        self.write(folded, param);
+    }
+
+    /// Pads with zeros to a byte boundary.
+    fn align(&mut self) {
+        if self.bits > 0 {
+            self.write(0, 8 - self.bits);
+        }
+    }
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// How a block's residual is split into partitions, each with its own Rice
+/// parameter.
+struct RiceCoding {
+    partition_order: u32,
+    params: Vec<u32>,
+    bits: u64,
+}
+
+impl RiceCoding {
+    /// Finds the smallest coding of `residual`, which follows `order` warm-up
+    /// samples in a block of `block_size`.
+    fn choose(residual: &[i32], order: usize, block_size: usize) -> RiceCoding {
+        let folded: Vec<u64> = residual
+            .iter()
+            .map(|&r| ((r << 1) ^ (r >> 31)) as u32 as u64)
+            .collect();
+
+        let mut best: Option<RiceCoding> = None;
+        for partition_order in 0..=MAX_PARTITION_ORDER {
+            let partitions = 1 << partition_order;
+            if block_size & (partitions - 1) != 0 || block_size / partitions <= order {
+                break;
+            }
+            let partition_len = block_size / partitions;
+
+            let mut params = vec![];
+            let mut bits = 0;
+            let mut start = 0;
+            for partition in 0..partitions {
+                let len = if partition == 0 {
+                    partition_len - order
+                } else {
+                    partition_len
+                };
+                let (param, param_bits) = best_param(&folded[start..start + len]);
+                params.push(param);
+                bits += param_bits;
+                start += len;
+            }
+
+            let max_param = params.iter().copied().max().unwrap_or(0);
+            let param_bits = if max_param > MAX_RICE_PARAM_4BIT {
+                5
+            } else {
+                4
+            };
+            bits += 2 + 4 + partitions as u64 * param_bits;
+
+            let better = match &best {
+                Some(best) => bits < best.bits,
+                None => true,
+            };
+            if better {
+                best = Some(RiceCoding {
+                    partition_order,
+                    params,
+                    bits,
+                });
+            }
+        }
+        best.unwrap()
+    }
+
+    fn write(&self, out: &mut BitWriter, residual: &[i32], order: usize, block_size: usize) {
+        let wide = self.params.iter().any(|&param| param > MAX_RICE_PARAM_4BIT);
+        out.write(wide as u64, 2);
+        out.write(self.partition_order as u64, 4);
+
+        let partition_len = block_size >> self.partition_order;
+        let mut start = 0;
+        for (partition, &param) in self.params.iter().enumerate() {
+            let len = if partition == 0 {
+                partition_len - order
+            } else {
+                partition_len
+            };
+            out.write(param as u64, if wide { 5 } else { 4 });
+            for &r in &residual[start..start + len] {
+                out.write_rice(r, param);
+            }
+            start += len;
+        }
+    }
+}
+
+/// Returns the Rice parameter which codes `folded` in the fewest bits, and how
+/// many bits that is.
+fn best_param(folded: &[u64]) -> (u32, u64) {
+    let bits_with = |param: u32| -> u64 {
+        folded.len() as u64 * (param as u64 + 1) + folded.iter().map(|&u| u >> param).sum::<u64>()
+    };
+
+    let sum: u64 = folded.iter().sum();
+    let mean = sum / folded.len().max(1) as u64;
+    let estimate = (64 - mean.leading_zeros()).min(30);
+
+    let mut best = (estimate, bits_with(estimate));
+    for param in [estimate.saturating_sub(1), (estimate + 1).min(30)] {
+        let bits = bits_with(param);
+        if bits < best.1 {
+            best = (param, bits);
+        }
+    }
+    best
+}
+
+enum Predictor {
+    Constant,
+    Verbatim,
+    Fixed,
+    Lpc { shift: u32, coefs: Vec<i32> },
+}
+
+/// One channel of a block, coded with a particular predictor.
+struct Subframe {
+    predictor: Predictor,
+    order: usize,
+    residual: Vec<i32>,
+    coding: Option<RiceCoding>,
+    bits: u64,
+}
+
+impl Subframe {
+    fn write(&self, out: &mut BitWriter, samples: &[i32], bps: u32) {
+        out.write(0, 1);
+        match &self.predictor {
+            Predictor::Constant => {
+                out.write(0b000000, 6);
+                out.write(0, 1);
+                out.write_signed(samples[0] as i64, bps);
+                return;
+            }
+            Predictor::Verbatim => {
+                out.write(0b000001, 6);
+                out.write(0, 1);
+                for &sample in samples {
+                    out.write_signed(sample as i64, bps);
+                }
+                return;
+            }
+            Predictor::Fixed => {
+                out.write(0b001000 | self.order as u64, 6);
+                out.write(0, 1);
+                for &sample in &samples[..self.order] {
+                    out.write_signed(sample as i64, bps);
+                }
+            }
+            Predictor::Lpc { shift, coefs } => {
+                out.write(0b100000 | (self.order as u64 - 1), 6);
+                out.write(0, 1);
+                for &sample in &samples[..self.order] {
+                    out.write_signed(sample as i64, bps);
+                }
+                out.write(LPC_PRECISION as u64 - 1, 4);
+                out.write(*shift as u64, 5);
+                for &coef in coefs {
+                    out.write_signed(coef as i64, LPC_PRECISION);
+                }
+            }
+        }
+        let coding = self.coding.as_ref().unwrap();
+        coding.write(out, &self.residual, self.order, samples.len());
+    }
+}
+
+/// Subframe header and warm-up samples, before the residual.
+fn header_bits(order: usize, bps: u32) -> u64 {
+    8 + order as u64 * bps as u64
+}
+
+fn residual_subframe(
+    predictor: Predictor,
+    order: usize,
+    residual: Vec<i32>,
+    extra_bits: u64,
+    bps: u32,
+    block_size: usize,
+) -> Subframe {
+    let coding = RiceCoding::choose(&residual, order, block_size);
+    let bits = header_bits(order, bps) + extra_bits + coding.bits;
+    Subframe {
+        predictor,
+        order,
+        residual,
+        coding: Some(coding),
+        bits,
+    }
+}
+
+/// Residual of the fixed polynomial predictor of `order`, or None if it
+/// doesn't fit in 32 bits.
+fn fixed_residual(samples: &[i32], order: usize) -> Option<Vec<i32>> {
+    const COEFS: [&[i64]; MAX_FIXED_ORDER + 1] =
+        [&[], &[1], &[2, -1], &[3, -3, 1], &[4, -6, 4, -1]];
+    let coefs = COEFS[order];
+    (order..samples.len())
+        .map(|i| {
+            let prediction: i64 = coefs
+                .iter()
+                .enumerate()
+                .map(|(j, &c)| c * samples[i - 1 - j] as i64)
+                .sum();
+            residual_i32(samples[i] as i64 - prediction)
+        })
+        .collect()
+}
+
+fn residual_i32(residual: i64) -> Option<i32> {
+    // Leave room for folding the sign into the low bit.
+    if residual.abs() < 1 << 30 {
+        Some(residual as i32)
+    } else {
+        None
+    }
+}
+
+/// Autocorrelation of `samples` after a Welch window, up to `MAX_LPC_ORDER`.
+fn autocorrelation(samples: &[i32]) -> [f64; MAX_LPC_ORDER + 1] {
+    let n = samples.len();
+    let half = (n as f64 - 1.0) / 2.0;
+    let windowed: Vec<f64> = samples
+        .iter()
+        .enumerate()
+        .map(|(i, &x)| {
+            let t = (i as f64 - half) / (half + 1.0);
+            x as f64 * (1.0 - t * t)
+        })
+        .collect();
+
+    let mut autoc = [0.0; MAX_LPC_ORDER + 1];
+    for (lag, autoc) in autoc.iter_mut().enumerate() {
+        *autoc = (lag..n).map(|i| windowed[i] * windowed[i - lag]).sum();
+    }
+    autoc
+}
+
+/// Levinson-Durbin recursion, returning the predictor coefficients of every
+/// order from 1 to `MAX_LPC_ORDER`.
+fn lpc_coefficients(autoc: &[f64; MAX_LPC_ORDER + 1]) -> Vec<Vec<f64>> {
+    let mut orders = vec![];
+    let mut lpc = [0.0; MAX_LPC_ORDER];
+    let mut error = autoc[0];
+    for i in 0..MAX_LPC_ORDER {
+        if error <= 0.0 {
+            break;
+        }
+        let mut r = -autoc[i + 1];
+        for j in 0..i {
+            r -= lpc[j] * autoc[i - j];
+        }
+        r /= error;
+
+        lpc[i] = r;
+        for j in 0..i / 2 {
+            let tmp = lpc[j];
+            lpc[j] += r * lpc[i - 1 - j];
+            lpc[i - 1 - j] += r * tmp;
+        }
+        if i % 2 == 1 {
+            lpc[i / 2] += lpc[i / 2] * r;
+        }
+        error *= 1.0 - r * r;
+
+        // The recursion predicts -x[n]; flip the signs to predict x[n].
+        orders.push(lpc[..=i].iter().map(|&c| -c).collect());
+    }
+    orders
+}
+
+/// Quantizes coefficients to `LPC_PRECISION` bits, returning them and the
+/// shift to apply after summing.
+fn quantize(coefs: &[f64]) -> Option<(Vec<i32>, u32)> {
+    let max = coefs.iter().fold(0.0f64, |max, &c| max.max(c.abs()));
+    if max <= 0.0 || !max.is_finite() {
+        return None;
+    }
+    let limit = (1 << (LPC_PRECISION - 1)) - 1;
+    // The largest coefficient has LPC_PRECISION - 1 bits of magnitude.
+    let log2 = max.log2().floor() as i32 + 1;
+    let shift = (LPC_PRECISION as i32 - 1 - log2).clamp(0, 15) as u32;
+
+    // Carry each coefficient's rounding error into the next.
+    let mut error = 0.0;
+    let quantized = coefs
+        .iter()
+        .map(|&c| {
+            error += c * (1 << shift) as f64;
+            let q = (error.round() as i32).clamp(-limit - 1, limit);
+            error -= q as f64;
+            q
+        })
+        .collect();
+    Some((quantized, shift))
+}
+
+fn lpc_residual(samples: &[i32], coefs: &[i32], shift: u32) -> Option<Vec<i32>> {
+    let order = coefs.len();
+    (order..samples.len())
+        .map(|i| {
+            let prediction: i64 = coefs
+                .iter()
+                .enumerate()
+                .map(|(j, &c)| c as i64 * samples[i - 1 - j] as i64)
+                .sum();
+            residual_i32(samples[i] as i64 - (prediction >> shift))
+        })
+        .collect()
+}
+
+/// Finds the smallest coding of one channel of a block.
+fn encode_subframe(samples: &[i32], bps: u32) -> Subframe {
+    let block_size = samples.len();
+    if samples.iter().all(|&x| x == samples[0]) {
+        return Subframe {
+            predictor: Predictor::Constant,
+            order: 0,
+            residual: vec![],
+            coding: None,
+            bits: 8 + bps as u64,
+        };
+    }
+
+    let mut best = Subframe {
+        predictor: Predictor::Verbatim,
+        order: 0,
+        residual: vec![],
+        coding: None,
+        bits: 8 + bps as u64 * block_size as u64,
+    };
+    let mut consider = |subframe: Subframe| {
+        if subframe.bits < best.bits {
+            best = subframe;
+        }
+    };
+
+    for order in 0..=MAX_FIXED_ORDER.min(block_size - 1) {
+        if let Some(residual) = fixed_residual(samples, order) {
+            consider(residual_subframe(
+                Predictor::Fixed,
+                order,
+                residual,
+                0,
+                bps,
+                block_size,
+            ));
+        }
+    }
+
+    if block_size > MAX_LPC_ORDER {
+        for coefs in lpc_coefficients(&autocorrelation(samples)) {
+            let order = coefs.len();
+            let (coefs, shift) = match quantize(&coefs) {
+                Some(quantized) => quantized,
+                None => continue,
+            };
+            if let Some(residual) = lpc_residual(samples, &coefs, shift) {
+                let extra_bits = 4 + 5 + order as u64 * LPC_PRECISION as u64;
+                consider(residual_subframe(
+                    Predictor::Lpc { shift, coefs },
+                    order,
+                    residual,
+                    extra_bits,
+                    bps,
+                    block_size,
+                ));
+            }
+        }
+    }
+
+    best
+}
+
+/// Writes `value` in the UTF-8-like variable length code used for frame
+/// numbers.
+fn write_utf8(out: &mut BitWriter, value: u32) {
+    if value < 0x80 {
+        out.write(value as u64, 8);
+        return;
+    }
+    let mut continuation = 1;
+    while value >= 1 << (5 * continuation + 6) {
+        continuation += 1;
+    }
+    let marker = (0xff << (7 - continuation)) & 0xff;
+    out.write((marker | (value >> (6 * continuation))) as u64, 8);
+    for i in (0..continuation).rev() {
+        out.write((0x80 | ((value >> (6 * i)) & 0x3f)) as u64, 8);
+    }
+}
+
+/// Encodes one block of stereo audio as a frame.
+fn encode_frame(left: &[i32], right: &[i32], frame_number: u32) -> Vec<u8> {
+    let block_size = left.len();
+    let mid: Vec<i32> = left.iter().zip(right).map(|(l, r)| (l + r) >> 1).collect();
+    let side: Vec<i32> = left.iter().zip(right).map(|(l, r)| l - r).collect();
+
+    let l = encode_subframe(left, BITS_PER_SAMPLE);
+    let r = encode_subframe(right, BITS_PER_SAMPLE);
+    let m = encode_subframe(&mid, BITS_PER_SAMPLE);
+    let s = encode_subframe(&side, BITS_PER_SAMPLE + 1);
+
+    // Channel assignment, and each channel's subframe, samples and sample size.
+    let independent = l.bits + r.bits;
+    let left_side = l.bits + s.bits;
+    let right_side = s.bits + r.bits;
+    let mid_side = m.bits + s.bits;
+    let smallest = independent.min(left_side).min(right_side).min(mid_side);
+    let side_bps = BITS_PER_SAMPLE + 1;
+    let (assignment, channels) = if smallest == independent {
+        (
+            0b0001,
+            [(&l, left, BITS_PER_SAMPLE), (&r, right, BITS_PER_SAMPLE)],
+        )
+    } else if smallest == left_side {
+        (
+            0b1000,
+            [(&l, left, BITS_PER_SAMPLE), (&s, &side[..], side_bps)],
+        )
+    } else if smallest == right_side {
+        (
+            0b1001,
+            [(&s, &side[..], side_bps), (&r, right, BITS_PER_SAMPLE)],
+        )
+    } else {
+        (
+            0b1010,
+            [(&m, &mid[..], BITS_PER_SAMPLE), (&s, &side[..], side_bps)],
+        )
+    };
+
+    let mut out = BitWriter::default();
+    out.write(0b11111111111110, 14);
+    out.write(0, 1); // Reserved
+    out.write(0, 1); // Fixed block size
+    let block_size_code = if block_size == BLOCK_SIZE {
+        0b1100
+    } else {
+        0b0111
+    };
+    out.write(block_size_code, 4);
+    out.write(0b1000, 4); // 32 kHz
+    out.write(assignment, 4);
+    out.write(0b100, 3); // 16 bits per sample
+    out.write(0, 1); // Reserved
+    write_utf8(&mut out, frame_number);
+    if block_size_code == 0b0111 {
+        out.write(block_size as u64 - 1, 16);
+    }
+    let crc = crc8(&out.bytes);
+    out.write(crc as u64, 8);
+
+    for (subframe, samples, bps) in channels.iter() {
+        subframe.write(&mut out, samples, *bps);
+    }
+    out.align();
+    let crc = crc16(&out.bytes);
+    out.write(crc as u64, 16);
+    out.bytes
+}
+
+fn metadata_block_header(out: &mut Vec<u8>, block_type: u8, last: bool, len: usize) {
+    out.push(((last as u8) << 7) | block_type);
+    out.extend_from_slice(&(len as u32).to_be_bytes()[1..]);
+}
+
+/// Whether a path names a FLAC file, going by its extension.
+pub fn is_flac(path: &Path) -> bool {
+    matches!(path.extension().and_then(|ext| ext.to_str()), Some(ext) if ext.eq_ignore_ascii_case("flac"))
+}
+
+/// Vorbis comments from the song's tags.
+fn vorbis_comments(tags: &SpcTags, extra: &[(&str, String)]) -> Vec<u8> {
+    let mut comments: Vec<(&str, &str)> = vec![
+        ("TITLE", &tags.title),
+        ("ALBUM", &tags.game),
+        ("ARTIST", &tags.artist),
+        ("DUMPER", &tags.dumper),
+        ("COMMENT", &tags.comments),
+        ("TRACKNUMBER", &tags.track),
+    ];
+    comments.extend(extra.iter().map(|(key, value)| (*key, value.as_str())));
+    comments.retain(|(_, value)| !value.is_empty());
+
+    let mut out = vec![];
+    let vendor = b"spcplay-rs";
+    out.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    out.extend_from_slice(vendor);
+    out.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+    for (key, value) in comments {
+        let comment = format!("{}={}", key, value);
+        out.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        out.extend_from_slice(comment.as_bytes());
+    }
+    out
+}
+
+/// Writes 16-bit stereo audio at the SNES's sample rate to a FLAC file, tagged
+/// with the song's tags as Vorbis comments.
+pub struct FlacWriter<W: Write + Seek> {
+    w: W,
+    /// Offset of STREAMINFO, which is filled in by `finish()`.
+    streaminfo_offset: u64,
+    /// Samples of the block being collected, per channel.
+    left: Vec<i32>,
+    right: Vec<i32>,
+    frames_written: u32,
+    total_samples: u64,
+    min_frame_len: usize,
+    max_frame_len: usize,
+    md5: Md5,
+}
+
+impl FlacWriter<BufWriter<File>> {
+    pub fn create(path: &Path, tags: &SpcTags, extra_comments: &[(&str, String)]) -> Result<Self> {
+        let file =
+            File::create(path).with_context(|| format!("Could not create {}", path.display()))?;
+        FlacWriter::new(BufWriter::new(file), tags, extra_comments)
+    }
+}
+
+impl<W: Write + Seek> FlacWriter<W> {
+    /// Writes the header and tags. `extra_comments` are added to the tags.
+    pub fn new(mut w: W, tags: &SpcTags, extra_comments: &[(&str, String)]) -> Result<Self> {
+        w.write_all(b"fLaC")?;
+
+        let mut header = vec![];
+        metadata_block_header(&mut header, STREAMINFO, false, 34);
+        w.write_all(&header)?;
+        let streaminfo_offset = w.stream_position()?;
+        w.write_all(&[0; 34])?;
+
+        let comments = vorbis_comments(tags, extra_comments);
+        let mut header = vec![];
+        metadata_block_header(&mut header, VORBIS_COMMENT, true, comments.len());
+        w.write_all(&header)?;
+        w.write_all(&comments)?;
+
+        Ok(FlacWriter {
+            w,
+            streaminfo_offset,
+            left: Vec::with_capacity(BLOCK_SIZE),
+            right: Vec::with_capacity(BLOCK_SIZE),
+            frames_written: 0,
+            total_samples: 0,
+            min_frame_len: usize::MAX,
+            max_frame_len: 0,
+            md5: Md5::new(),
+        })
+    }
+
+    fn write_frame(&mut self) -> Result<()> {
+        let frame = encode_frame(&self.left, &self.right, self.frames_written);
+        self.w.write_all(&frame)?;
+        self.min_frame_len = self.min_frame_len.min(frame.len());
+        self.max_frame_len = self.max_frame_len.max(frame.len());
+        self.frames_written += 1;
+        self.left.clear();
+        self.right.clear();
+        Ok(())
+    }
+
+    fn streaminfo(&self) -> Vec<u8> {
+        let mut out = BitWriter::default();
+        out.write(BLOCK_SIZE as u64, 16);
+        out.write(BLOCK_SIZE as u64, 16);
+        out.write(self.min_frame_len.min(self.max_frame_len) as u64, 24);
+        out.write(self.max_frame_len as u64, 24);
+        out.write(SAMPLE_RATE as u64, 20);
+        out.write(2 - 1, 3);
+        out.write(BITS_PER_SAMPLE as u64 - 1, 5);
+        out.write(self.total_samples >> 32, 4);
+        out.write(self.total_samples, 32);
+        out.bytes.extend_from_slice(&self.md5.clone().finalize());
+        out.bytes
+    }
+}
+
+impl<W: Write + Seek> AudioSink for FlacWriter<W> {
+    fn write(&mut self, samples: &[i16]) -> Result<()> {
+        for frame in samples.chunks_exact(2) {
+            self.md5.update(frame[0].to_le_bytes());
+            self.md5.update(frame[1].to_le_bytes());
+            self.left.push(frame[0] as i32);
+            self.right.push(frame[1] as i32);
+            if self.left.len() == BLOCK_SIZE {
+                self.write_frame()?;
+            }
+        }
+        self.total_samples += (samples.len() / 2) as u64;
+        Ok(())
+    }
+
+    /// Encodes the last partial block and fills in STREAMINFO.
+    fn finish(&mut self) -> Result<()> {
+        if !self.left.is_empty() {
+            self.write_frame()?;
+        }
+        let end = self.w.stream_position()?;
+        let streaminfo = self.streaminfo();
+        self.w.seek(SeekFrom::Start(self.streaminfo_offset))?;
+        self.w.write_all(&streaminfo)?;
+        self.w.seek(SeekFrom::Start(end))?;
+        self.w.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::MemorySink;
+    use crate::spcplay::test_player;
+    use std::io::Cursor;
+
+    fn encode(samples: &[i16], tags: &SpcTags) -> Result<Vec<u8>> {
+        let mut cursor = Cursor::new(vec![]);
+        let mut flac = FlacWriter::new(&mut cursor, tags, &[])?;
+        // Write in uneven pieces, so blocks span several writes.
+        for chunk in samples.chunks(1000 * 2 + 2) {
+            flac.write(chunk)?;
+        }
+        flac.finish()?;
+        drop(flac);
+        Ok(cursor.into_inner())
+    }
+
+    /// Decodes a file with an independent decoder, checking the MD5 signature
+    /// as `flac -t` would.
+    fn decode(bytes: &[u8]) -> Result<Vec<i16>> {
+        let mut reader = claxon::FlacReader::new(Cursor::new(bytes))?;
+        let samples = reader
+            .samples()
+            .map(|sample| Ok(sample? as i16))
+            .collect::<Result<Vec<i16>>>()?;
+
+        let info = reader.streaminfo();
+        assert_eq!(info.sample_rate, SAMPLE_RATE as u32);
+        assert_eq!(info.channels, 2);
+        // A total of zero means "unknown".
+        assert_eq!(info.samples.unwrap_or(0), samples.len() as u64 / 2);
+        let mut md5 = Md5::new();
+        for sample in &samples {
+            md5.update(sample.to_le_bytes());
+        }
+        assert_eq!(info.md5sum, md5.finalize()[..]);
+        Ok(samples)
+    }
+
+    /// Deterministic noise.
+    fn noise(seed: &mut u32) -> i16 {
+        *seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+        (*seed >> 16) as i16
+    }
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        let mut seed = 1;
+        let len = BLOCK_SIZE * 3 + 123;
+        let signals: Vec<(&str, Vec<i16>)> = vec![
+            ("silence", vec![0; len * 2]),
+            ("empty", vec![]),
+            (
+                "sine",
+                (0..len)
+                    .flat_map(|i| {
+                        let x = (i as f64 * 0.05).sin() * 20000.0;
+                        [x as i16, (x * 0.5) as i16]
+                    })
+                    .collect(),
+            ),
+            ("noise", (0..len * 2).map(|_| noise(&mut seed)).collect()),
+            (
+                "extremes",
+                (0..len)
+                    .flat_map(|i| {
+                        if i % 3 == 0 {
+                            [i16::MIN, i16::MAX]
+                        } else {
+                            [i16::MAX, i16::MIN]
+                        }
+                    })
+                    .collect(),
+            ),
+        ];
+
+        for (name, samples) in signals {
+            let decoded = decode(&encode(&samples, &SpcTags::default())?)?;
+            assert!(decoded == samples, "{} changed when encoded", name);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_vorbis_comments() -> Result<()> {
+        let tags = SpcTags {
+            title: "Título".to_owned(),
+            game: "Game".to_owned(),
+            artist: "Artist".to_owned(),
+            dumper: "Dumper".to_owned(),
+            comments: "Comments".to_owned(),
+            track: "3a".to_owned(),
+            ..SpcTags::default()
+        };
+        let bytes = encode(&[1, 2, 3, 4], &tags)?;
+        let reader = claxon::FlacReader::new(Cursor::new(bytes))?;
+        let tag = |name| reader.get_tag(name).next();
+        assert_eq!(tag("TITLE"), Some("Título"));
+        assert_eq!(tag("ALBUM"), Some("Game"));
+        assert_eq!(tag("ARTIST"), Some("Artist"));
+        assert_eq!(tag("DUMPER"), Some("Dumper"));
+        assert_eq!(tag("COMMENT"), Some("Comments"));
+        assert_eq!(tag("TRACKNUMBER"), Some("3a"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_song() -> Result<()> {
+        let load = || test_player("3rdparty/snes-apu/test/ferris-nu.spc", 3000, 1000);
+
+        let mut memory = MemorySink::default();
+        load()?.render_to(&mut memory)?;
+
+        let mut cursor = Cursor::new(vec![]);
+        let mut player = load()?;
+        let mut flac = FlacWriter::new(&mut cursor, &player.tags(), &[])?;
+        player.render_to(&mut flac)?;
+        drop(flac);
+        let bytes = cursor.into_inner();
+
+        assert!(decode(&bytes)? == memory.samples);
+        // Music should compress well below the size of PCM.
+        assert!(bytes.len() < memory.samples.len() * 2 * 3 / 4);
+        Ok(())
+    }
+}
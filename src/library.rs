@@ -0,0 +1,754 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use eframe::epi;
+use rusqlite::{params, params_from_iter, Connection, ToSql};
+use spc::{Emulator, Spc, TICKS_PER_SECOND};
+
+use crate::archive;
+use crate::loops::{LoopInfo, DEFAULT_LOOP_COUNT};
+
+/// Number of files to write per transaction while scanning.
+const SCAN_BATCH_SIZE: usize = 100;
+
+/// Tags read from an SPC file, preferring xid6 fields over ID666 ones.
+#[derive(Clone, Default, Debug)]
+pub struct SpcTags {
+    pub game: String,
+    pub title: String,
+    pub artist: String,
+    pub dumper: String,
+    pub comments: String,
+    /// Track number on the soundtrack from xid6, eg. "3" or "3a", or empty.
+    pub track: String,
+    /// 0 if unknown, otherwise the xid6 emulator ID (1 is ZSNES, 2 is Snes9x).
+    pub emulator: u8,
+    /// Time to play before fading out, if the tags specify it.
+    pub length_ms: Option<u32>,
+    pub fade_ms: Option<u32>,
+    /// From xid6, if it gives a loop length.
+    pub loop_info: Option<LoopInfo>,
+    /// Linear gain from xid6, or 1.
+    pub amplification: f32,
+}
+
+fn ticks_to_ms(ticks: u32) -> u32 {
+    (ticks as u64 * 1000 / TICKS_PER_SECOND as u64) as u32
+}
+
+impl SpcTags {
+    pub fn from_spc(spc: &Spc) -> SpcTags {
+        let mut tags = SpcTags {
+            amplification: 1.0,
+            ..SpcTags::default()
+        };
+
+        if let Some(id666) = &spc.id666_tag {
+            tags.game = id666.game_title.clone();
+            tags.title = id666.song_title.clone();
+            tags.artist = id666.artist_name.clone();
+            tags.dumper = id666.dumper_name.clone();
+            tags.comments = id666.comments.clone();
+            tags.emulator = match id666.dumping_emulator {
+                Emulator::Unknown => 0,
+                Emulator::ZSnes => 1,
+                Emulator::Snes9x => 2,
+            };
+            if id666.seconds_to_play_before_fading_out > 0 {
+                tags.length_ms = Some(id666.seconds_to_play_before_fading_out as u32 * 1000);
+                tags.fade_ms = Some(id666.fade_out_length.max(0) as u32);
+            }
+        }
+
+        if let Some(xid6) = &spc.xid6_tag {
+            let strings = [
+                (&mut tags.game, &xid6.game_title),
+                (&mut tags.title, &xid6.song_title),
+                (&mut tags.artist, &xid6.artist_name),
+                (&mut tags.dumper, &xid6.dumper_name),
+                (&mut tags.comments, &xid6.comments),
+            ];
+            for (dest, value) in strings {
+                if let Some(value) = value {
+                    *dest = value.clone();
+                }
+            }
+            if let Some(emulator) = xid6.dumping_emulator {
+                tags.emulator = emulator;
+            }
+            if let Some((track, letter)) = xid6.ost_track {
+                tags.track = track.to_string();
+                tags.track.extend(letter);
+            }
+            if let (Some(intro), Some(loop_length)) = (xid6.intro_length, xid6.loop_length) {
+                if loop_length > 0 {
+                    tags.loop_info = Some(LoopInfo {
+                        intro_ms: ticks_to_ms(intro),
+                        loop_ms: ticks_to_ms(loop_length),
+                    });
+                }
+            }
+            if let Some(intro) = xid6.intro_length {
+                let loops =
+                    xid6.loop_length.unwrap_or(0) as u64 * xid6.loop_count.unwrap_or(1) as u64;
+                let ticks = intro as u64 + loops + xid6.end_length.unwrap_or(0) as u64;
+                tags.length_ms = Some(ticks_to_ms(ticks.min(u32::MAX as u64) as u32));
+            }
+            if let Some(amplification) = xid6.amplification {
+                tags.amplification = amplification as f32 / 0x10000 as f32;
+            }
+            if let Some(fade) = xid6.fade_length {
+                tags.fade_ms = Some(ticks_to_ms(fade));
+            }
+        }
+
+        tags
+    }
+}
+
+/// Highest rating a file can be given.
+pub const MAX_RATING: u8 = 5;
+
+/// A file in the library, as shown in the library panel.
+#[derive(Clone, Debug)]
+pub struct LibraryEntry {
+    pub id: i64,
+    pub path: PathBuf,
+    pub game: String,
+    pub title: String,
+    pub artist: String,
+    pub length_ms: Option<u32>,
+    /// From 1 to `MAX_RATING`, or None if unrated.
+    pub rating: Option<u8>,
+}
+
+/// Which files to show in the library panel. Each `Option<bool>` filter
+/// matches every file if None.
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct LibraryFilter {
+    /// Words to search for in the title, game, artist, dumper and comments.
+    /// The last word may be incomplete, so results update while typing.
+    pub query: String,
+    pub min_length_secs: Option<u32>,
+    pub max_length_secs: Option<u32>,
+    /// Whether the file has a title, game or artist.
+    pub tagged: Option<bool>,
+    /// xid6 emulator ID, as in `SpcTags::emulator`.
+    pub emulator: Option<u8>,
+    pub played: Option<bool>,
+    pub rated: Option<bool>,
+}
+
+/// SQL for the length of a file in `library_files f`. If the tags don't give
+/// one, it's the detected loop in `song_loops l` played `DEFAULT_LOOP_COUNT`
+/// times.
+fn length_ms_sql() -> String {
+    format!(
+        "coalesce(f.length_ms, l.intro_ms + l.loop_ms * {})",
+        DEFAULT_LOOP_COUNT
+    )
+}
+
+/// Converts user input into an FTS5 query matching rows containing every word
+/// as a prefix. Returns None if there are no words.
+fn match_expression(query: &str) -> Option<String> {
+    let words: Vec<String> = query
+        .split_whitespace()
+        // Quoting each word stops FTS5 from parsing operators and punctuation.
+        .map(|word| format!("\"{}\"*", word.replace('"', "\"\"")))
+        .collect();
+    if words.is_empty() {
+        None
+    } else {
+        Some(words.join(" "))
+    }
+}
+
+/// Returns every file in the library, ordered by game and title.
+pub fn load_entries(conn: &Connection) -> Result<Vec<LibraryEntry>> {
+    let sql = format!(
+        "select f.id, f.path, f.game, f.title, f.artist, {}, f.rating from library_files f
+         left join song_loops l on l.hash = f.hash
+         order by f.game, f.title",
+        length_ms_sql()
+    );
+    let mut stmt = conn.prepare(&sql)?;
+    let rows = stmt.query_map([], |row| {
+        Ok(LibraryEntry {
+            id: row.get(0)?,
+            path: PathBuf::from(row.get::<_, String>(1)?),
+            game: row.get(2)?,
+            title: row.get(3)?,
+            artist: row.get(4)?,
+            length_ms: row.get(5)?,
+            rating: row.get(6)?,
+        })
+    })?;
+    Ok(rows.collect::<rusqlite::Result<_>>()?)
+}
+
+/// Returns the IDs of files matching `filter`.
+///
+/// This only reads IDs, which is several times faster than reading whole rows
+/// with `load_entries()`. Callers should load entries once and filter them by
+/// ID on every keystroke.
+pub fn search(conn: &Connection, filter: &LibraryFilter) -> Result<HashSet<i64>> {
+    let mut sql = "select f.id from library_files f".to_owned();
+    if filter.min_length_secs.is_some() || filter.max_length_secs.is_some() {
+        sql += " left join song_loops l on l.hash = f.hash";
+    }
+    let mut conditions: Vec<&str> = vec![];
+    let mut values: Vec<Box<dyn ToSql>> = vec![];
+
+    if let Some(expr) = match_expression(&filter.query) {
+        sql += " join library_search s on s.rowid = f.id";
+        conditions.push("library_search match ?");
+        values.push(Box::new(expr));
+    }
+    let length_min = format!("{} >= ?", length_ms_sql());
+    let length_max = format!("{} <= ?", length_ms_sql());
+    if let Some(min) = filter.min_length_secs {
+        conditions.push(&length_min);
+        values.push(Box::new(min as i64 * 1000));
+    }
+    if let Some(max) = filter.max_length_secs {
+        conditions.push(&length_max);
+        values.push(Box::new(max as i64 * 1000));
+    }
+    match filter.tagged {
+        Some(true) => conditions.push("(f.title != '' or f.game != '' or f.artist != '')"),
+        Some(false) => conditions.push("(f.title = '' and f.game = '' and f.artist = '')"),
+        None => {}
+    }
+    if let Some(emulator) = filter.emulator {
+        conditions.push("f.emulator = ?");
+        values.push(Box::new(emulator));
+    }
+    match filter.played {
+        Some(true) => conditions.push("f.play_count > 0"),
+        Some(false) => conditions.push("f.play_count = 0"),
+        None => {}
+    }
+    match filter.rated {
+        Some(true) => conditions.push("f.rating is not null"),
+        Some(false) => conditions.push("f.rating is null"),
+        None => {}
+    }
+
+    if !conditions.is_empty() {
+        sql += " where ";
+        sql += &conditions.join(" and ");
+    }
+
+    let mut stmt = conn.prepare_cached(&sql)?;
+    let rows = stmt.query_map(params_from_iter(values.iter()), |row| row.get(0))?;
+    Ok(rows.collect::<rusqlite::Result<_>>()?)
+}
+
+/// Counts a play of `path`, if it's in the library.
+pub fn record_play(conn: &Connection, path: &Path) -> Result<()> {
+    conn.execute(
+        "update library_files set play_count = play_count + 1 where path = ?",
+        params![path_to_str(path)?],
+    )?;
+    Ok(())
+}
+
+pub fn set_rating(conn: &Connection, id: i64, rating: Option<u8>) -> Result<()> {
+    conn.execute(
+        "update library_files set rating = ? where id = ?",
+        params![rating, id],
+    )?;
+    Ok(())
+}
+
+pub fn folders(conn: &Connection) -> Result<Vec<PathBuf>> {
+    let mut stmt = conn.prepare("select path from library_folders order by path")?;
+    let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+    Ok(rows
+        .map(|path| path.map(PathBuf::from))
+        .collect::<rusqlite::Result<_>>()?)
+}
+
+pub fn add_folder(conn: &Connection, path: &Path) -> Result<()> {
+    let path = path_to_str(path)?;
+    conn.execute(
+        "insert or ignore into library_folders (path) values (?)",
+        params![path],
+    )?;
+    Ok(())
+}
+
+/// Identifies a file by its contents, so results computed from it stay valid
+/// if it's moved.
+pub fn file_hash(data: &[u8]) -> String {
+    sha1::Sha1::from(data).digest().to_string()
+}
+
+fn path_to_str(path: &Path) -> Result<&str> {
+    path.to_str()
+        .with_context(|| format!("{} is not valid Unicode", path.display()))
+}
+
+pub fn is_spc(path: &Path) -> bool {
+    matches!(path.extension(), Some(ext) if ext.eq_ignore_ascii_case("spc"))
+}
+
+/// Recursively lists SPC files under `dir`, including those inside archives,
+/// without following symlinks to directories (which could loop).
+pub fn find_spcs(dir: &Path, out: &mut Vec<PathBuf>, errors: &mut Vec<(PathBuf, String)>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            errors.push((dir.to_owned(), err.to_string()));
+            return;
+        }
+    };
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                errors.push((dir.to_owned(), err.to_string()));
+                continue;
+            }
+        };
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(ty) if ty.is_dir() => find_spcs(&path, out, errors),
+            Ok(_) if is_spc(&path) => out.push(path),
+            // Archives are listed as if they were folders.
+            Ok(_) if archive::is_archive(&path) => match archive::list_spcs(&path) {
+                Ok(spcs) => out.extend(spcs),
+                Err(err) => errors.push((path, format!("{:#}", err))),
+            },
+            Ok(_) => {}
+            Err(err) => errors.push((path, err.to_string())),
+        }
+    }
+}
+
+/// What the scan thread is doing, for display while it runs.
+#[derive(Clone, Default, Debug)]
+pub struct ScanProgress {
+    pub files_found: usize,
+    pub files_checked: usize,
+}
+
+#[derive(Default, Debug)]
+pub struct ScanSummary {
+    pub files_added: usize,
+    pub files_updated: usize,
+    pub files_removed: usize,
+    /// Files or folders which couldn't be read, and why.
+    pub errors: Vec<(PathBuf, String)>,
+}
+
+/// A scan of library folders, running on a background thread.
+pub struct Scan {
+    progress: Arc<Mutex<ScanProgress>>,
+    result: Receiver<Result<ScanSummary>>,
+}
+
+impl Scan {
+    /// Starts scanning `folders` into the database at `db_path`. Files whose
+    /// size and modification time haven't changed since the last scan are
+    /// skipped, and files which no longer exist are removed.
+    pub fn start(
+        db_path: PathBuf,
+        folders: Vec<PathBuf>,
+        repaint_signal: Arc<dyn epi::RepaintSignal>,
+    ) -> Scan {
+        let progress = Arc::new(Mutex::new(ScanProgress::default()));
+        let (tx, result) = channel();
+
+        {
+            let progress = progress.clone();
+            thread::spawn(move || {
+                let result = scan(&db_path, &folders, &progress, &*repaint_signal);
+                // If the receiver was dropped, nobody cares about the result.
+                let _ = tx.send(result);
+                repaint_signal.request_repaint();
+            });
+        }
+
+        Scan { progress, result }
+    }
+
+    pub fn progress(&self) -> ScanProgress {
+        self.progress.lock().unwrap().clone()
+    }
+
+    /// Returns the result once the scan has finished.
+    pub fn try_finish(&self) -> Option<Result<ScanSummary>> {
+        match self.result.try_recv() {
+            Ok(result) => Some(result),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err(anyhow::anyhow!("library scan crashed"))),
+        }
+    }
+}
+
+struct FileStat {
+    mtime: i64,
+    size: i64,
+}
+
+fn stat(path: &Path) -> Result<FileStat> {
+    let metadata = fs::metadata(path)?;
+    let mtime = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    Ok(FileStat {
+        mtime,
+        size: metadata.len() as i64,
+    })
+}
+
+fn scan(
+    db_path: &Path,
+    folders: &[PathBuf],
+    progress: &Mutex<ScanProgress>,
+    repaint_signal: &dyn epi::RepaintSignal,
+) -> Result<ScanSummary> {
+    let mut conn = Connection::open(db_path)?;
+    // The UI thread may be writing settings at the same time.
+    conn.busy_timeout(Duration::from_secs(10))?;
+
+    let mut summary = ScanSummary::default();
+
+    let mut paths = vec![];
+    for folder in folders {
+        find_spcs(folder, &mut paths, &mut summary.errors);
+    }
+    progress.lock().unwrap().files_found = paths.len();
+    repaint_signal.request_repaint();
+
+    // Existing rows under the scanned folders. Any left after the scan refer
+    // to files which have been deleted.
+    let mut existing: HashMap<PathBuf, (i64, FileStat)> = HashMap::new();
+    {
+        let mut stmt = conn.prepare("select id, path, mtime, size from library_files")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                PathBuf::from(row.get::<_, String>(1)?),
+                FileStat {
+                    mtime: row.get(2)?,
+                    size: row.get(3)?,
+                },
+            ))
+        })?;
+        for row in rows {
+            let (id, path, stat) = row?;
+            if folders.iter().any(|folder| path.starts_with(folder)) {
+                existing.insert(path, (id, stat));
+            }
+        }
+    }
+
+    for batch in paths.chunks(SCAN_BATCH_SIZE) {
+        let tx = conn.transaction()?;
+        for path in batch {
+            let old = existing.remove(path);
+            match scan_file(&tx, path, old.as_ref().map(|(_, stat)| stat)) {
+                Ok(ScanResult::Unchanged) => {}
+                Ok(ScanResult::Written) => {
+                    if old.is_some() {
+                        summary.files_updated += 1;
+                    } else {
+                        summary.files_added += 1;
+                    }
+                }
+                Err(err) => summary.errors.push((path.clone(), format!("{:#}", err))),
+            }
+        }
+        tx.commit()?;
+
+        progress.lock().unwrap().files_checked += batch.len();
+        repaint_signal.request_repaint();
+    }
+
+    let tx = conn.transaction()?;
+    for (id, _) in existing.values() {
+        tx.execute("delete from library_files where id = ?", params![id])?;
+    }
+    tx.commit()?;
+    summary.files_removed = existing.len();
+
+    Ok(summary)
+}
+
+enum ScanResult {
+    Unchanged,
+    Written,
+}
+
+fn scan_file(conn: &Connection, path: &Path, old: Option<&FileStat>) -> Result<ScanResult> {
+    let path_str = path_to_str(path)?;
+    // Files in archives are rescanned when the archive changes.
+    let stat = stat(&archive::physical_path(path))?;
+    if let Some(old) = old {
+        if old.mtime == stat.mtime && old.size == stat.size {
+            return Ok(ScanResult::Unchanged);
+        }
+    }
+
+    let data = archive::read(path)?;
+    let spc = Spc::from_reader(std::io::Cursor::new(&data)).context("Could not load spc file")?;
+    let tags = SpcTags::from_spc(&spc);
+    let hash = file_hash(&data);
+
+    conn.execute(
+        "insert into library_files
+             (path, hash, mtime, size, game, title, artist, dumper, comments, emulator,
+              length_ms, fade_ms)
+         values (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+         on conflict (path) do update set
+             hash = excluded.hash, mtime = excluded.mtime, size = excluded.size,
+             game = excluded.game, title = excluded.title, artist = excluded.artist,
+             dumper = excluded.dumper, comments = excluded.comments,
+             emulator = excluded.emulator, length_ms = excluded.length_ms,
+             fade_ms = excluded.fade_ms",
+        params![
+            path_str,
+            hash,
+            stat.mtime,
+            stat.size,
+            tags.game,
+            tags.title,
+            tags.artist,
+            tags.dumper,
+            tags.comments,
+            tags.emulator,
+            tags.length_ms,
+            tags.fade_ms,
+        ],
+    )?;
+    Ok(ScanResult::Written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use crate::loops;
+    use spc::{Id666Tag, Xid6Tag};
+
+    struct NoRepaint;
+
+    impl epi::RepaintSignal for NoRepaint {
+        fn request_repaint(&self) {}
+    }
+
+    #[test]
+    fn test_tags_prefer_xid6() -> Result<()> {
+        let mut spc = Spc::load("3rdparty/snes-apu/test/ferris-nu.spc")?;
+        spc.id666_tag = Some(Id666Tag {
+            song_title: "ID666 title".to_owned(),
+            game_title: "Game".to_owned(),
+            dumper_name: String::new(),
+            comments: String::new(),
+            date_dumped: String::new(),
+            seconds_to_play_before_fading_out: 90,
+            fade_out_length: 5000,
+            artist_name: String::new(),
+            default_channel_disables: 0,
+            dumping_emulator: Emulator::ZSnes,
+        });
+        let tags = SpcTags::from_spc(&spc);
+        assert_eq!(tags.title, "ID666 title");
+        assert_eq!(tags.emulator, 1);
+        assert_eq!(tags.length_ms, Some(90_000));
+        assert_eq!(tags.fade_ms, Some(5000));
+        assert!(tags.loop_info.is_none());
+
+        spc.xid6_tag = Some(Xid6Tag {
+            song_title: Some("xid6 title".to_owned()),
+            ost_track: Some((3, Some('a'))),
+            intro_length: Some(TICKS_PER_SECOND * 2),
+            loop_length: Some(TICKS_PER_SECOND * 10),
+            loop_count: Some(3),
+            amplification: Some(0x20000),
+            ..Xid6Tag::default()
+        });
+        let tags = SpcTags::from_spc(&spc);
+        assert_eq!(tags.title, "xid6 title");
+        assert_eq!(tags.game, "Game");
+        assert_eq!(tags.track, "3a");
+        assert_eq!(tags.length_ms, Some(32_000));
+        assert_eq!(tags.fade_ms, Some(5000));
+        assert_eq!(
+            tags.loop_info,
+            Some(LoopInfo {
+                intro_ms: 2000,
+                loop_ms: 10_000
+            })
+        );
+        assert_eq!(tags.amplification, 2.0);
+        Ok(())
+    }
+
+    fn run_scan(db_path: &Path, folder: &Path) -> Result<ScanSummary> {
+        let progress = Mutex::new(ScanProgress::default());
+        let summary = scan(db_path, &[folder.to_owned()], &progress, &NoRepaint)?;
+        let progress = progress.into_inner().unwrap();
+        assert_eq!(progress.files_checked, progress.files_found);
+        Ok(summary)
+    }
+
+    #[test]
+    fn test_scan() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("spcplay-scan-{}", std::process::id()));
+        let folder = dir.join("music");
+        fs::create_dir_all(&folder)?;
+        for name in &["ferris-nu.spc", "smashit.spc"] {
+            fs::copy(
+                Path::new("3rdparty/snes-apu/test").join(name),
+                folder.join(name),
+            )?;
+        }
+        fs::write(folder.join("notes.txt"), "not an spc")?;
+        let db_path = dir.join("settings.sqlite3");
+        db::migrate(&mut Connection::open(&db_path)?)?;
+
+        let summary = run_scan(&db_path, &folder)?;
+        assert_eq!(summary.files_added, 2);
+        assert!(summary.errors.is_empty());
+        let entries = load_entries(&Connection::open(&db_path)?)?;
+        assert_eq!(entries.len(), 2);
+
+        // Unchanged files are skipped.
+        let summary = run_scan(&db_path, &folder)?;
+        assert_eq!(
+            (
+                summary.files_added,
+                summary.files_updated,
+                summary.files_removed
+            ),
+            (0, 0, 0)
+        );
+
+        // Changed files are read again, and deleted ones removed.
+        let mut data = fs::read(folder.join("smashit.spc"))?;
+        data.extend_from_slice(
+            &Xid6Tag {
+                song_title: Some("Retitled".to_owned()),
+                ..Xid6Tag::default()
+            }
+            .to_bytes(),
+        );
+        fs::write(folder.join("smashit.spc"), data)?;
+        fs::remove_file(folder.join("ferris-nu.spc"))?;
+        fs::write(folder.join("broken.spc"), "not an spc")?;
+        let summary = run_scan(&db_path, &folder)?;
+        assert_eq!(
+            (
+                summary.files_added,
+                summary.files_updated,
+                summary.files_removed
+            ),
+            (0, 1, 1)
+        );
+        assert_eq!(summary.errors.len(), 1);
+        assert_eq!(summary.errors[0].0, folder.join("broken.spc"));
+        let entries = load_entries(&Connection::open(&db_path)?)?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].title, "Retitled");
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    /// Adds a file to the library, returning its ID.
+    fn add_file(
+        conn: &Connection,
+        hash: &str,
+        title: &str,
+        game: &str,
+        length_ms: Option<u32>,
+    ) -> Result<i64> {
+        conn.execute(
+            "insert into library_files
+                 (path, hash, mtime, size, game, title, artist, dumper, comments, emulator,
+                  length_ms)
+             values (?, ?, 0, 0, ?, ?, '', '', '', 0, ?)",
+            params![format!("{}.spc", hash), hash, game, title, length_ms],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    fn search_ids(conn: &Connection, filter: LibraryFilter) -> Result<Vec<i64>> {
+        let mut ids: Vec<i64> = search(conn, &filter)?.into_iter().collect();
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    #[test]
+    fn test_search() -> Result<()> {
+        let mut conn = Connection::open_in_memory()?;
+        db::migrate(&mut conn)?;
+        let opening = add_file(&conn, "a", "Opening Theme", "Super Game", Some(60_000))?;
+        let battle = add_file(&conn, "b", "Battle", "Super Game 2", Some(150_000))?;
+        let jingle = add_file(&conn, "c", "Fanfare", "Other Game", None)?;
+        let untimed = add_file(&conn, "d", "Ending", "Other Game", None)?;
+        // Only the jingle's length is known, from its detected loop.
+        loops::store(
+            &conn,
+            "c",
+            Some(LoopInfo {
+                intro_ms: 2000,
+                loop_ms: 4000,
+            }),
+        )?;
+        loops::store(&conn, "d", None)?;
+
+        let query = |query: &str| LibraryFilter {
+            query: query.to_owned(),
+            ..LibraryFilter::default()
+        };
+        assert_eq!(search_ids(&conn, LibraryFilter::default())?.len(), 4);
+        assert_eq!(search_ids(&conn, query("super"))?, [opening, battle]);
+        // The last word matches as a prefix, while typing.
+        assert_eq!(search_ids(&conn, query("game 2"))?, [battle]);
+        assert_eq!(search_ids(&conn, query("other fan"))?, [jingle]);
+        assert_eq!(search_ids(&conn, query("\"theme"))?, [opening]);
+        assert!(search_ids(&conn, query("missing"))?.is_empty());
+
+        let lengths = |min, max| LibraryFilter {
+            min_length_secs: min,
+            max_length_secs: max,
+            ..LibraryFilter::default()
+        };
+        let jingle_secs = 2 + 4 * DEFAULT_LOOP_COUNT;
+        assert_eq!(search_ids(&conn, lengths(Some(61), None))?, [battle]);
+        assert_eq!(
+            search_ids(&conn, lengths(None, Some(60)))?,
+            [opening, jingle]
+        );
+        assert_eq!(
+            search_ids(&conn, lengths(Some(jingle_secs), Some(jingle_secs)))?,
+            [jingle]
+        );
+        assert!(search_ids(&conn, lengths(Some(jingle_secs + 1), Some(59)))?.is_empty());
+
+        let filter = LibraryFilter {
+            min_length_secs: Some(1),
+            ..query("game")
+        };
+        assert_eq!(search_ids(&conn, filter)?, [opening, battle, jingle]);
+
+        let entries = load_entries(&conn)?;
+        let entry = entries.iter().find(|entry| entry.id == jingle).unwrap();
+        assert_eq!(entry.length_ms, Some(jingle_secs * 1000));
+        let entry = entries.iter().find(|entry| entry.id == untimed).unwrap();
+        assert_eq!(entry.length_ms, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,472 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use snes_apu::apu::Apu;
+use snes_apu::dsp::dsp::SAMPLE_RATE;
+use spc::Spc;
+
+use crate::archive;
+use crate::library;
+
+/// Default for `Settings::loop_count`, also used for the lengths of library
+/// files with a detected loop.
+pub const DEFAULT_LOOP_COUNT: u32 = 2;
+
+/// Longest a song is emulated for while looking for a loop.
+const MAX_ANALYSIS_SECS: usize = 600;
+/// How often to look for a loop while emulating.
+const CHECK_INTERVAL_SECS: usize = 30;
+/// Shorter repeats are usually an ostinato rather than the whole song looping.
+const MIN_LOOP_SECS: usize = 1;
+/// Timer-driven notes can land a sample either side of where they'd be if the
+/// song looped exactly.
+const TOLERANCE_SAMPLES: u64 = 64;
+
+const KON: u8 = 0x4c;
+/// The end of a seamless loop is crossfaded into the audio before its start
+/// over this many frames.
+const CROSSFADE_FRAMES: usize = SAMPLE_RATE / 20;
+
+/// Where a song's loop starts and how long it is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LoopInfo {
+    pub intro_ms: u32,
+    pub loop_ms: u32,
+}
+
+impl LoopInfo {
+    /// Time to play the intro and then the loop `loop_count` times.
+    pub fn length_ms(&self, loop_count: u32) -> u32 {
+        self.intro_ms
+            .saturating_add(self.loop_ms.saturating_mul(loop_count))
+    }
+}
+
+/// A note started by writing to KON.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct KeyOn {
+    sample: u64,
+    voice: u8,
+    source: u8,
+    pitch: u16,
+}
+
+impl KeyOn {
+    fn same_note(&self, other: &KeyOn) -> bool {
+        (self.voice, self.source, self.pitch) == (other.voice, other.source, other.pitch)
+    }
+}
+
+fn samples_to_ms(samples: u64) -> u32 {
+    (samples * 1000 / SAMPLE_RATE as u64).min(u32::MAX as u64) as u32
+}
+
+/// Emulates a song without playing it, and looks for the point where the notes
+/// it plays start repeating. Returns None if the song doesn't loop within
+/// `MAX_ANALYSIS_SECS`, eg. because it ends.
+pub fn detect(spc: &Spc) -> Option<LoopInfo> {
+    let mut apu = Apu::from_spc(spc);
+    apu.clear_echo_buffer();
+    apu.dsp.as_mut().unwrap().set_register_logging(true);
+
+    let mut regs = spc.regs;
+    let mut key_ons = vec![];
+    let mut buffer = vec![0; SAMPLE_RATE * 2];
+
+    for secs in 1..=MAX_ANALYSIS_SECS {
+        apu.render_interleaved(&mut buffer);
+
+        let dsp = apu.dsp.as_mut().unwrap();
+        for write in dsp.take_register_writes() {
+            regs[write.address as usize] = write.value;
+            if write.address != KON {
+                continue;
+            }
+            for voice in 0..8 {
+                if write.value & (1 << voice) == 0 {
+                    continue;
+                }
+                let base = voice as usize * 0x10;
+                key_ons.push(KeyOn {
+                    sample: write.sample,
+                    voice,
+                    source: regs[base + 4],
+                    pitch: u16::from_le_bytes([regs[base + 2], regs[base + 3]]) & 0x3fff,
+                });
+            }
+        }
+
+        // Three repeats is convincing. Two will do once there's no time left
+        // to hear a third.
+        let min_repeats = if secs == MAX_ANALYSIS_SECS { 2 } else { 3 };
+        if secs % CHECK_INTERVAL_SECS == 0 || secs == MAX_ANALYSIS_SECS {
+            let end = dsp.samples_rendered();
+            if let Some(info) = find_loop(&key_ons, end, min_repeats) {
+                return Some(info);
+            }
+        }
+    }
+    None
+}
+
+/// Finds the shortest period at which every note from some point up to `end`
+/// repeats, starting as early as possible.
+fn find_loop(key_ons: &[KeyOn], end: u64, min_repeats: usize) -> Option<LoopInfo> {
+    let n = key_ons.len();
+    let min_loop_samples = (MIN_LOOP_SECS * SAMPLE_RATE) as u64;
+
+    for period in 1..=n / min_repeats {
+        let last = &key_ons[n - 1];
+        let loop_samples = last.sample - key_ons[n - 1 - period].sample;
+        if loop_samples < min_loop_samples {
+            continue;
+        }
+        // A song which stopped playing notes has ended rather than looped.
+        if end - last.sample > loop_samples {
+            continue;
+        }
+
+        // Walk backwards while each note matches the one a period later.
+        let mut start = n - period;
+        while start > 0 {
+            let a = &key_ons[start - 1];
+            let b = &key_ons[start - 1 + period];
+            let distance = b.sample - a.sample;
+            if !a.same_note(b) || distance.abs_diff(loop_samples) > TOLERANCE_SAMPLES {
+                break;
+            }
+            start -= 1;
+        }
+
+        if n - start >= period * min_repeats {
+            return Some(LoopInfo {
+                intro_ms: samples_to_ms(key_ons[start].sample),
+                loop_ms: samples_to_ms(loop_samples),
+            });
+        }
+    }
+    None
+}
+
+/// Where a loop starts and how long it is, in frames from the start of some
+/// rendered audio.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LoopPoints {
+    pub start: u64,
+    pub length: u64,
+}
+
+impl LoopPoints {
+    pub fn end(&self) -> u64 {
+        self.start + self.length
+    }
+}
+
+/// Frames of audio `make_seamless` needs for a loop.
+pub fn seamless_frames_needed(start: usize, length: usize) -> usize {
+    start + length + TOLERANCE_SAMPLES as usize + CROSSFADE_FRAMES
+}
+
+/// Trims interleaved audio to an intro of `start` frames and one loop, which
+/// can then be repeated without a click.
+///
+/// The loop's length is first adjusted by up to `TOLERANCE_SAMPLES`, to where
+/// the audio repeats most closely. Then the end of the loop is crossfaded into
+/// the audio just before its start, so that jumping back to the start carries
+/// on exactly as the emulator would. Without an intro, the start of the loop
+/// is crossfaded from the audio which followed its end instead.
+pub fn make_seamless(mut samples: Vec<i16>, start: usize, length: usize) -> (Vec<i16>, LoopPoints) {
+    let frames = samples.len() / 2;
+    let fade = match start {
+        0 => CROSSFADE_FRAMES,
+        _ => start.min(CROSSFADE_FRAMES),
+    };
+    // Compare the audio being crossfaded at each possible length.
+    let base = start.saturating_sub(fade);
+    let tolerance = TOLERANCE_SAMPLES as usize;
+    let length = (length.saturating_sub(tolerance).max(1)..=length + tolerance)
+        .filter(|&length| base + length + fade <= frames)
+        .min_by_key(|&length| {
+            (base * 2..(base + fade) * 2)
+                .map(|i| (samples[i] as i64 - samples[i + length * 2] as i64).abs())
+                .sum::<i64>()
+        })
+        .unwrap_or(length)
+        .min(frames - start);
+    let fade = fade.min(length).min(frames - start - length);
+
+    let end = start + length;
+    let mix = |a: i16, b: i16, t: f32| (a as f32 * (1.0 - t) + b as f32 * t).round() as i16;
+    for i in 0..fade * 2 {
+        let frame = i / 2;
+        if start > 0 {
+            // Ends on the frame before the loop's start.
+            let t = (frame + 1) as f32 / fade as f32;
+            let out = (end - fade) * 2 + i;
+            samples[out] = mix(samples[out], samples[(start - fade) * 2 + i], t);
+        } else {
+            // Starts on the frame after the loop's end.
+            let t = frame as f32 / fade as f32;
+            samples[i] = mix(samples[end * 2 + i], samples[i], t);
+        }
+    }
+
+    samples.truncate(end * 2);
+    let points = LoopPoints {
+        start: start as u64,
+        length: length as u64,
+    };
+    (samples, points)
+}
+
+/// Looks up the result of analyzing a file with the given hash. Returns None
+/// if it hasn't been analyzed, and `Some(None)` if it was but no loop was
+/// found.
+pub fn lookup(conn: &Connection, hash: &str) -> Result<Option<Option<LoopInfo>>> {
+    let row = conn
+        .query_row(
+            "select intro_ms, loop_ms from song_loops where hash = ?",
+            params![hash],
+            |row| Ok((row.get::<_, Option<u32>>(0)?, row.get::<_, Option<u32>>(1)?)),
+        )
+        .optional()?;
+    Ok(row.map(|lengths| match lengths {
+        (Some(intro_ms), Some(loop_ms)) => Some(LoopInfo { intro_ms, loop_ms }),
+        _ => None,
+    }))
+}
+
+pub fn store(conn: &Connection, hash: &str, info: Option<LoopInfo>) -> Result<()> {
+    conn.execute(
+        "insert or replace into song_loops (hash, intro_ms, loop_ms) values (?, ?, ?)",
+        params![
+            hash,
+            info.map(|info| info.intro_ms),
+            info.map(|info| info.loop_ms)
+        ],
+    )?;
+    Ok(())
+}
+
+/// Library files which haven't been analyzed. If `untagged_only` is true,
+/// files whose tags give a length are skipped.
+pub fn unanalyzed_files(conn: &Connection, untagged_only: bool) -> Result<Vec<PathBuf>> {
+    let mut stmt = conn.prepare(
+        "select path from library_files
+         where hash not in (select hash from song_loops)
+             and (length_ms is null or not ?)
+         order by length_ms is not null, game, title",
+    )?;
+    let rows = stmt.query_map(params![untagged_only], |row| row.get::<_, String>(0))?;
+    Ok(rows
+        .map(|path| path.map(PathBuf::from))
+        .collect::<rusqlite::Result<_>>()?)
+}
+
+/// Detects the loop of a file and stores it. For use with `Analysis`.
+pub fn analyze_file(conn: &Connection, path: &Path) -> Result<()> {
+    let data = archive::read(path)?;
+    let spc = Spc::from_reader(std::io::Cursor::new(&data)).context("Could not load spc file")?;
+    store(conn, &library::file_hash(&data), detect(&spc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flac::FlacWriter;
+    use crate::settings::Settings;
+    use crate::sink::{AudioSink, MemorySink};
+    use crate::spcplay::{test_overrides, SpcPlayer, TrackOverrides};
+    use crate::wav::{SampleFormat, WavWriter};
+    use std::convert::TryInto;
+    use std::io::Cursor;
+
+    const SPC_PATH: &str = "3rdparty/snes-apu/test/smashit.spc";
+
+    /// Renders the song with the given loop, seamlessly and then straight
+    /// through for comparison.
+    fn render(info: LoopInfo) -> Result<(Vec<i16>, LoopPoints, Vec<i16>)> {
+        let path = Path::new(SPC_PATH);
+        let settings = Settings::default();
+        let (samples, points) =
+            SpcPlayer::new(path, &settings, &TrackOverrides::default(), Some(info))?
+                .render_loop()?;
+
+        let overrides = test_overrides(info.length_ms(2), 0);
+        let mut straight = MemorySink::default();
+        SpcPlayer::new(path, &settings, &overrides, Some(info))?.render_to(&mut straight)?;
+        Ok((samples, points, straight.samples))
+    }
+
+    const SECOND: u64 = SAMPLE_RATE as u64;
+
+    fn key_on(sample: u64, source: u8) -> KeyOn {
+        KeyOn {
+            sample,
+            voice: source % 8,
+            source,
+            pitch: 0x1000 + source as u16,
+        }
+    }
+
+    /// Two intro notes, then `repeats` of a three note, two second loop
+    /// starting at half a second. `jitter` moves each note by its result.
+    fn song(repeats: u64, jitter: impl Fn(usize) -> i64) -> Vec<KeyOn> {
+        let mut key_ons = vec![key_on(0, 9), key_on(SECOND / 4, 10)];
+        for repeat in 0..repeats {
+            let start = SECOND / 2 + repeat * 2 * SECOND;
+            for (i, &offset) in [0, SECOND / 2, SECOND * 3 / 2].iter().enumerate() {
+                key_ons.push(key_on(start + offset, i as u8));
+            }
+        }
+        for (i, key_on) in key_ons.iter_mut().enumerate() {
+            key_on.sample = (key_on.sample as i64 + jitter(i)) as u64;
+        }
+        key_ons
+    }
+
+    #[test]
+    fn test_find_loop() {
+        let key_ons = song(4, |_| 0);
+        let end = key_ons.last().unwrap().sample + SECOND;
+        assert_eq!(
+            find_loop(&key_ons, end, 3),
+            Some(LoopInfo {
+                intro_ms: 500,
+                loop_ms: 2000,
+            })
+        );
+
+        // Without an intro.
+        assert_eq!(
+            find_loop(&key_ons[2..], end, 3),
+            Some(LoopInfo {
+                intro_ms: 500,
+                loop_ms: 2000,
+            })
+        );
+    }
+
+    #[test]
+    fn test_find_loop_song_ends() {
+        // Notes stopped more than a loop before the end, so the song ended.
+        let key_ons = song(4, |_| 0);
+        let end = key_ons.last().unwrap().sample + 3 * SECOND;
+        assert_eq!(find_loop(&key_ons, end, 3), None);
+    }
+
+    #[test]
+    fn test_find_loop_jitter() {
+        let key_ons = song(4, |i| [0, 15, -15, 5][i % 4]);
+        let end = key_ons.last().unwrap().sample + SECOND;
+        let info = find_loop(&key_ons, end, 3).unwrap();
+        assert!(info.intro_ms.abs_diff(500) <= 1);
+        assert!(info.loop_ms.abs_diff(2000) <= 1);
+
+        // Notes further out than the tolerance don't repeat.
+        let key_ons = song(4, |i| [0, 100, -100, 50][i % 4]);
+        assert_eq!(find_loop(&key_ons, end, 3), None);
+    }
+
+    #[test]
+    fn test_find_loop_min_repeats() {
+        let key_ons = song(2, |_| 0);
+        let end = key_ons.last().unwrap().sample + SECOND;
+        assert_eq!(find_loop(&key_ons, end, 3), None);
+        assert_eq!(
+            find_loop(&key_ons, end, 2),
+            Some(LoopInfo {
+                intro_ms: 500,
+                loop_ms: 2000,
+            })
+        );
+    }
+
+    #[test]
+    fn test_find_loop_too_short() {
+        // A note every quarter second is an ostinato, not a loop.
+        let key_ons: Vec<KeyOn> = (0..40).map(|i| key_on(i * SECOND / 4, 0)).collect();
+        let end = key_ons.last().unwrap().sample + SECOND / 4;
+        let info = find_loop(&key_ons, end, 3).unwrap();
+        assert_eq!(info.loop_ms, 1000);
+    }
+
+    #[test]
+    fn test_seamless_loop_with_intro() -> Result<()> {
+        let info = LoopInfo {
+            intro_ms: 1000,
+            loop_ms: 7490,
+        };
+        let (samples, points, straight) = render(info)?;
+        let (start, end) = (points.start as usize, points.end() as usize);
+        assert_eq!(samples.len(), end * 2);
+        assert!(points.length.abs_diff(7490 * 32) <= TOLERANCE_SAMPLES);
+
+        // Everything before the crossfade is as rendered.
+        let fade_start = (end - CROSSFADE_FRAMES) * 2;
+        assert!(samples[..fade_start] == straight[..fade_start]);
+        // The loop ends on the frame before its start, so jumping back
+        // continues exactly as the song would.
+        assert_eq!(samples[end * 2 - 2..], straight[start * 2 - 2..start * 2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_seamless_loop_without_intro() -> Result<()> {
+        let info = LoopInfo {
+            intro_ms: 0,
+            loop_ms: 7490,
+        };
+        let (samples, points, straight) = render(info)?;
+        let end = points.end() as usize;
+        assert_eq!(points.start, 0);
+        assert_eq!(samples.len(), end * 2);
+
+        // The loop starts on the frame after its end.
+        assert_eq!(samples[..2], straight[end * 2..end * 2 + 2]);
+        let fade_end = CROSSFADE_FRAMES * 2;
+        assert!(samples[fade_end..] == straight[fade_end..end * 2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_loop_markers() -> Result<()> {
+        let points = LoopPoints {
+            start: 1000,
+            length: 3000,
+        };
+        let samples = vec![0; 4000 * 2];
+        let tags = library::SpcTags::default();
+
+        let mut cursor = Cursor::new(vec![]);
+        let mut wav = WavWriter::new(&mut cursor, SampleFormat::Int16, &tags)?;
+        wav.set_loop(points);
+        wav.write(&samples)?;
+        wav.finish()?;
+        let bytes = cursor.into_inner();
+        let smpl = bytes.windows(4).position(|id| id == b"smpl").unwrap() + 8;
+        let field = |offset: usize| -> Result<u32> {
+            Ok(u32::from_le_bytes(
+                bytes[smpl + offset..smpl + offset + 4].try_into()?,
+            ))
+        };
+        assert_eq!(field(28)?, 1);
+        assert_eq!(field(44)?, 1000);
+        assert_eq!(field(48)?, 3999);
+
+        let comments = [
+            ("LOOPSTART", points.start.to_string()),
+            ("LOOPLENGTH", points.length.to_string()),
+        ];
+        let mut cursor = Cursor::new(vec![]);
+        let mut flac = FlacWriter::new(&mut cursor, &tags, &comments)?;
+        flac.write(&samples)?;
+        flac.finish()?;
+        drop(flac);
+        let reader = claxon::FlacReader::new(Cursor::new(cursor.into_inner()))?;
+        assert_eq!(reader.get_tag("LOOPSTART").next(), Some("1000"));
+        assert_eq!(reader.get_tag("LOOPLENGTH").next(), Some("3000"));
+        Ok(())
+    }
+}
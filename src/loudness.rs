@@ -0,0 +1,518 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use rusqlite::{params, Connection, OptionalExtension};
+use snes_apu::dsp::dsp::SAMPLE_RATE;
+
+use crate::archive;
+use crate::library;
+use crate::loops::{self, LoopInfo};
+use crate::settings::Settings;
+use crate::spcplay::{SpcPlayer, TrackOverrides};
+
+/// Longest a song is rendered for while measuring it.
+const MAX_MEASURE_SECS: usize = 600;
+
+/// Loudness is measured over 400 ms blocks, overlapping by 75%.
+const SUB_BLOCK_FRAMES: usize = SAMPLE_RATE / 10;
+const SUB_BLOCKS_PER_BLOCK: usize = 4;
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+const RELATIVE_GATE_LU: f64 = -10.0;
+
+/// True peak is measured by upsampling by this factor.
+const OVERSAMPLE: usize = 4;
+/// Taps of the upsampling filter per output phase.
+const PHASE_TAPS: usize = 12;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ReplayGain {
+    Off,
+    /// Make every song equally loud.
+    Track,
+    /// Make every game equally loud, keeping the differences between its
+    /// songs.
+    Album,
+}
+
+/// Loudness of a song, as stored in the database.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Loudness {
+    /// Integrated loudness in LUFS, or None if the song is silent.
+    pub lufs: Option<f64>,
+    /// Highest sample value between samples, where 1.0 is full scale.
+    pub true_peak: f64,
+    pub duration_ms: u32,
+}
+
+/// A second-order IIR filter.
+#[derive(Clone, Copy, Default)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[0] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+/// The K-weighting filters from ITU-R BS.1770, a high shelf modelling the
+/// head followed by a high-pass. The coefficients are derived for the SNES's
+/// sample rate, rather than the 48 kHz ones given in the standard.
+fn k_weighting() -> [Biquad; 2] {
+    let rate = SAMPLE_RATE as f64;
+
+    let (f0, gain_db, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain_db / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2],
+    };
+
+    let (f0, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2],
+    };
+
+    [shelf, high_pass]
+}
+
+/// A windowed-sinc low-pass filter for upsampling by `OVERSAMPLE`, split into
+/// one set of taps per output phase.
+fn upsampling_filter() -> Vec<[f64; PHASE_TAPS]> {
+    let len = OVERSAMPLE * PHASE_TAPS;
+    let center = (len - 1) as f64 / 2.0;
+    let mut phases = vec![[0.0; PHASE_TAPS]; OVERSAMPLE];
+    for i in 0..len {
+        let t = (i as f64 - center) / OVERSAMPLE as f64;
+        let sinc = if t == 0.0 {
+            1.0
+        } else {
+            (PI * t).sin() / (PI * t)
+        };
+        // Blackman window.
+        let w = 2.0 * PI * i as f64 / (len - 1) as f64;
+        let window = 0.42 - 0.5 * w.cos() + 0.08 * (2.0 * w).cos();
+        phases[i % OVERSAMPLE][i / OVERSAMPLE] = sinc * window;
+    }
+    phases
+}
+
+/// Measures integrated loudness and true peak as in EBU R128, for stereo audio
+/// at the SNES's sample rate.
+pub struct LoudnessMeter {
+    filters: [[Biquad; 2]; 2],
+    /// Sum of squared filtered samples in the current sub-block.
+    energy: f64,
+    frames_in_sub_block: usize,
+    /// Energy of the last few complete sub-blocks.
+    recent: VecDeque<f64>,
+    /// Mean square of each complete block.
+    blocks: Vec<f64>,
+
+    upsampling: Vec<[f64; PHASE_TAPS]>,
+    /// Last `PHASE_TAPS` input samples of each channel, newest first.
+    history: [VecDeque<f64>; 2],
+    true_peak: f64,
+    frames: u64,
+}
+
+impl LoudnessMeter {
+    pub fn new() -> LoudnessMeter {
+        LoudnessMeter {
+            filters: [k_weighting(), k_weighting()],
+            energy: 0.0,
+            frames_in_sub_block: 0,
+            recent: VecDeque::new(),
+            blocks: vec![],
+            upsampling: upsampling_filter(),
+            history: [vec![0.0; PHASE_TAPS].into(), vec![0.0; PHASE_TAPS].into()],
+            true_peak: 0.0,
+            frames: 0,
+        }
+    }
+
+    /// Adds interleaved stereo samples, multiplied by `gain`, which can take
+    /// them past full scale.
+    pub fn add(&mut self, samples: &[i16], gain: f64) {
+        for frame in samples.chunks_exact(2) {
+            for (channel, &sample) in frame.iter().enumerate() {
+                let x = sample as f64 / 32768.0 * gain;
+
+                let history = &mut self.history[channel];
+                history.pop_back();
+                history.push_front(x);
+                for taps in &self.upsampling {
+                    let y: f64 = taps.iter().zip(history.iter()).map(|(t, h)| t * h).sum();
+                    self.true_peak = self.true_peak.max(y.abs());
+                }
+
+                let [shelf, high_pass] = &mut self.filters[channel];
+                let y = high_pass.process(shelf.process(x));
+                self.energy += y * y;
+            }
+
+            self.frames += 1;
+            self.frames_in_sub_block += 1;
+            if self.frames_in_sub_block == SUB_BLOCK_FRAMES {
+                self.end_sub_block();
+            }
+        }
+    }
+
+    fn end_sub_block(&mut self) {
+        self.recent.push_back(self.energy);
+        self.energy = 0.0;
+        self.frames_in_sub_block = 0;
+
+        if self.recent.len() > SUB_BLOCKS_PER_BLOCK {
+            self.recent.pop_front();
+        }
+        if self.recent.len() == SUB_BLOCKS_PER_BLOCK {
+            let energy: f64 = self.recent.iter().sum();
+            self.blocks
+                .push(energy / (SUB_BLOCK_FRAMES * SUB_BLOCKS_PER_BLOCK) as f64);
+        }
+    }
+
+    /// Gated loudness of everything added so far, in LUFS, or None if it's
+    /// all quieter than the absolute gate.
+    pub fn integrated_loudness(&self) -> Option<f64> {
+        let loudness = |mean_square: f64| -0.691 + 10.0 * mean_square.log10();
+        let mean = |blocks: &[f64]| blocks.iter().sum::<f64>() / blocks.len() as f64;
+
+        let gated: Vec<f64> = self
+            .blocks
+            .iter()
+            .copied()
+            .filter(|&block| loudness(block) > ABSOLUTE_GATE_LUFS)
+            .collect();
+        if gated.is_empty() {
+            return None;
+        }
+
+        let threshold = loudness(mean(&gated)) + RELATIVE_GATE_LU;
+        let gated: Vec<f64> = gated
+            .into_iter()
+            .filter(|&block| loudness(block) > threshold)
+            .collect();
+        Some(loudness(mean(&gated)))
+    }
+
+    pub fn loudness(&self) -> Loudness {
+        Loudness {
+            lufs: self.integrated_loudness(),
+            true_peak: self.true_peak,
+            duration_ms: (self.frames * 1000 / SAMPLE_RATE as u64) as u32,
+        }
+    }
+}
+
+/// Renders a song for its length (from tags or its detected loop) and measures
+/// it. The fade is left out, and only the xid6 amplification is applied, in
+/// full precision so peaks past full scale are measured rather than clipped.
+pub fn measure(path: &Path, detected_loop: Option<LoopInfo>) -> Result<Loudness> {
+    let settings = Settings::default();
+    let mut player = SpcPlayer::new(path, &settings, &TrackOverrides::default(), detected_loop)?;
+    let amplification = player.amplification() as f64;
+
+    let mut meter = LoudnessMeter::new();
+    let mut buffer = vec![0; SAMPLE_RATE * 2];
+    for _ in 0..MAX_MEASURE_SECS {
+        let frames = player.render_unfaded(&mut buffer);
+        meter.add(&buffer[..frames * 2], amplification);
+        if frames < SAMPLE_RATE {
+            break;
+        }
+    }
+    Ok(meter.loudness())
+}
+
+pub fn lookup(conn: &Connection, hash: &str) -> Result<Option<Loudness>> {
+    Ok(conn
+        .query_row(
+            "select lufs, true_peak, duration_ms from song_loudness where hash = ?",
+            params![hash],
+            |row| {
+                Ok(Loudness {
+                    lufs: row.get(0)?,
+                    true_peak: row.get(1)?,
+                    duration_ms: row.get(2)?,
+                })
+            },
+        )
+        .optional()?)
+}
+
+pub fn store(conn: &Connection, hash: &str, loudness: &Loudness) -> Result<()> {
+    conn.execute(
+        "insert or replace into song_loudness (hash, lufs, true_peak, duration_ms)
+         values (?, ?, ?, ?)",
+        params![
+            hash,
+            loudness.lufs,
+            loudness.true_peak,
+            loudness.duration_ms
+        ],
+    )?;
+    Ok(())
+}
+
+/// Loudness of every measured library file from the same game as the file
+/// with this hash, combined. None if the file isn't in the library or has no
+/// game.
+///
+/// This averages the songs' power weighted by duration, which is close to
+/// measuring them as one long recording.
+pub fn album_loudness(conn: &Connection, hash: &str) -> Result<Option<Loudness>> {
+    let mut stmt = conn.prepare_cached(
+        "select l.lufs, l.true_peak, l.duration_ms from song_loudness l
+         join library_files f on f.hash = l.hash
+         where f.game = (select game from library_files where hash = ? and game != '' limit 1)",
+    )?;
+    let rows = stmt.query_map(params![hash], |row| {
+        Ok((
+            row.get::<_, Option<f64>>(0)?,
+            row.get::<_, f64>(1)?,
+            row.get::<_, u32>(2)?,
+        ))
+    })?;
+
+    let mut album: Option<Loudness> = None;
+    let mut power = 0.0;
+    let mut power_ms = 0.0;
+    for row in rows {
+        let (lufs, true_peak, duration_ms) = row?;
+        let album = album.get_or_insert(Loudness {
+            lufs: None,
+            true_peak: 0.0,
+            duration_ms: 0,
+        });
+        album.true_peak = album.true_peak.max(true_peak);
+        album.duration_ms = album.duration_ms.saturating_add(duration_ms);
+        if let Some(lufs) = lufs {
+            power += 10f64.powf(lufs / 10.0) * duration_ms as f64;
+            power_ms += duration_ms as f64;
+        }
+    }
+    if let Some(album) = &mut album {
+        if power_ms > 0.0 {
+            album.lufs = Some(10.0 * (power / power_ms).log10());
+        }
+    }
+    Ok(album)
+}
+
+/// Gain in dB which brings `loudness` to `settings.target_lufs`, reduced if
+/// needed so the true peak doesn't clip.
+pub fn gain_db(settings: &Settings, loudness: &Loudness) -> f64 {
+    let mut gain = match loudness.lufs {
+        Some(lufs) => settings.target_lufs as f64 - lufs,
+        None => 0.0,
+    };
+    if settings.prevent_clipping && loudness.true_peak > 0.0 {
+        let headroom = -20.0 * loudness.true_peak.log10();
+        gain = gain.min(headroom);
+    }
+    gain
+}
+
+/// Measures a library file and stores the result. For use with `Analysis`.
+pub fn analyze_file(conn: &Connection, path: &Path) -> Result<()> {
+    let hash = library::file_hash(&archive::read(path)?);
+    let detected_loop = loops::lookup(conn, &hash)?.flatten();
+    store(conn, &hash, &measure(path, detected_loop)?)
+}
+
+/// Library files which haven't been measured.
+pub fn unmeasured_files(conn: &Connection) -> Result<Vec<PathBuf>> {
+    let mut stmt = conn.prepare(
+        "select path from library_files
+         where hash not in (select hash from song_loudness)
+         order by game, title",
+    )?;
+    let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+    Ok(rows
+        .map(|path| path.map(PathBuf::from))
+        .collect::<rusqlite::Result<_>>()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use spc::{Emulator, Id666Tag, Spc, Xid6Tag};
+
+    /// A 997 Hz sine in both channels, with peaks at `dbfs`.
+    fn sine(dbfs: f64, secs: usize) -> Vec<i16> {
+        let amplitude = 32768.0 * 10f64.powf(dbfs / 20.0);
+        (0..SAMPLE_RATE * secs)
+            .flat_map(|i| {
+                let x = amplitude * (2.0 * PI * 997.0 * i as f64 / SAMPLE_RATE as f64).sin();
+                let x = x.round() as i16;
+                vec![x, x]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_sine() {
+        let mut meter = LoudnessMeter::new();
+        meter.add(&sine(-20.0, 10), 1.0);
+        let loudness = meter.loudness();
+        let lufs = loudness.lufs.unwrap();
+        assert!((lufs + 20.0).abs() < 0.1, "{}", lufs);
+        assert!((loudness.true_peak - 0.1).abs() < 0.002);
+        assert_eq!(loudness.duration_ms, 10_000);
+    }
+
+    #[test]
+    fn test_silence() {
+        let mut meter = LoudnessMeter::new();
+        assert_eq!(meter.integrated_loudness(), None);
+        meter.add(&vec![0; SAMPLE_RATE * 2 * 5], 1.0);
+        assert_eq!(meter.integrated_loudness(), None);
+        // Below the absolute gate.
+        meter.add(&sine(-80.0, 5), 1.0);
+        assert_eq!(meter.integrated_loudness(), None);
+        assert_eq!(meter.loudness().duration_ms, 10_000);
+    }
+
+    #[test]
+    fn test_gating() {
+        // A quiet tail below the relative gate is left out, rather than
+        // lowering the average.
+        let mut meter = LoudnessMeter::new();
+        meter.add(&sine(-20.0, 10), 1.0);
+        meter.add(&sine(-45.0, 20), 1.0);
+        let lufs = meter.integrated_loudness().unwrap();
+        assert!((lufs + 20.0).abs() < 0.1, "{}", lufs);
+
+        // One above it counts.
+        let mut meter = LoudnessMeter::new();
+        meter.add(&sine(-20.0, 10), 1.0);
+        meter.add(&sine(-26.0, 10), 1.0);
+        let lufs = meter.integrated_loudness().unwrap();
+        assert!(lufs < -21.0, "{}", lufs);
+    }
+
+    fn add_file(conn: &Connection, hash: &str, game: &str) -> Result<()> {
+        conn.execute(
+            "insert into library_files
+                 (path, hash, mtime, size, game, title, artist, dumper, comments, emulator)
+             values (?, ?, 0, 0, ?, '', '', '', '', 0)",
+            params![format!("{}.spc", hash), hash, game],
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_store() -> Result<()> {
+        let mut conn = Connection::open_in_memory()?;
+        db::migrate(&mut conn)?;
+
+        let loud = Loudness {
+            lufs: Some(-10.0),
+            true_peak: 0.9,
+            duration_ms: 1000,
+        };
+        let quiet = Loudness {
+            lufs: Some(-20.0),
+            true_peak: 0.5,
+            duration_ms: 1000,
+        };
+        let silent = Loudness {
+            lufs: None,
+            true_peak: 0.0,
+            duration_ms: 2000,
+        };
+        assert_eq!(lookup(&conn, "a")?, None);
+        for (hash, game, loudness) in &[
+            ("a", "Game", &loud),
+            ("b", "Game", &quiet),
+            ("c", "Game", &silent),
+            ("d", "Other Game", &loud),
+            ("e", "", &quiet),
+        ] {
+            add_file(&conn, hash, game)?;
+            store(&conn, hash, loudness)?;
+        }
+        assert_eq!(lookup(&conn, "a")?, Some(loud));
+        assert_eq!(lookup(&conn, "c")?, Some(silent));
+
+        // Equal lengths at -10 and -20 LUFS average their power, and the
+        // silent song only adds to the duration.
+        let album = album_loudness(&conn, "b")?.unwrap();
+        let lufs = 10.0 * ((0.1 + 0.01) / 2f64).log10();
+        assert!((album.lufs.unwrap() - lufs).abs() < 1e-9);
+        assert_eq!(album.true_peak, 0.9);
+        assert_eq!(album.duration_ms, 4000);
+
+        assert_eq!(album_loudness(&conn, "d")?, Some(loud));
+        // Files without a game, or not in the library.
+        assert_eq!(album_loudness(&conn, "e")?, None);
+        assert_eq!(album_loudness(&conn, "f")?, None);
+
+        // Storing again replaces the measurement.
+        store(&conn, "a", &quiet)?;
+        assert_eq!(lookup(&conn, "a")?, Some(quiet));
+        Ok(())
+    }
+
+    #[test]
+    fn test_measure() -> Result<()> {
+        let mut spc = Spc::load("3rdparty/snes-apu/test/ferris-nu.spc")?;
+        spc.id666_tag = Some(Id666Tag {
+            song_title: String::new(),
+            game_title: String::new(),
+            dumper_name: String::new(),
+            comments: String::new(),
+            date_dumped: String::new(),
+            seconds_to_play_before_fading_out: 3,
+            fade_out_length: 2000,
+            artist_name: String::new(),
+            default_channel_disables: 0,
+            dumping_emulator: Emulator::Unknown,
+        });
+        let path = std::env::temp_dir().join(format!("spcplay-measure-{}.spc", std::process::id()));
+        spc.save(&path)?;
+        let plain = measure(&path, None)?;
+
+        // Amplified past full scale.
+        spc.xid6_tag = Some(Xid6Tag {
+            amplification: Some(0x80000),
+            ..Xid6Tag::default()
+        });
+        spc.save(&path)?;
+        let amplified = measure(&path, None)?;
+        std::fs::remove_file(&path)?;
+
+        // The fade isn't measured.
+        assert_eq!(plain.duration_ms, 3000);
+        assert_eq!(amplified.duration_ms, 3000);
+        assert!(amplified.true_peak > 1.0, "{}", amplified.true_peak);
+        assert!((amplified.true_peak / plain.true_peak - 8.0).abs() < 1e-9);
+        let gain = amplified.lufs.unwrap() - plain.lufs.unwrap();
+        assert!((gain - 20.0 * 8f64.log10()).abs() < 1e-6, "{}", gain);
+        Ok(())
+    }
+}
@@ -0,0 +1,385 @@
+use std::fmt::Write;
+use std::path::{Component, Path, PathBuf};
+
+use encoding_rs::SHIFT_JIS;
+
+use crate::spcplay::TrackOverrides;
+
+/// A line of an M3U file which names a file.
+#[derive(Clone, Default, Debug, PartialEq)]
+pub struct M3uEntry {
+    pub path: PathBuf,
+    /// From `#EXTINF` or the `::SPC` title field, if present and not empty.
+    pub title: Option<String>,
+    pub overrides: TrackOverrides,
+}
+
+/// Decodes an M3U file. `.m3u8` files are UTF-8, but older `.m3u` files are
+/// usually Shift-JIS for Japanese sets and Latin-1 otherwise, so fall back to
+/// those if the file isn't valid UTF-8.
+pub fn decode(data: &[u8]) -> String {
+    let data = data.strip_prefix(b"\xef\xbb\xbf").unwrap_or(data);
+    if let Ok(text) = std::str::from_utf8(data) {
+        return text.to_owned();
+    }
+    match SHIFT_JIS.decode_without_bom_handling_and_without_replacement(data) {
+        Some(text) => text.into_owned(),
+        None => data.iter().map(|&b| b as char).collect(),
+    }
+}
+
+/// Parses an M3U playlist. Relative paths are resolved against `base_dir`,
+/// the folder containing the playlist.
+pub fn parse(text: &str, base_dir: &Path) -> Vec<M3uEntry> {
+    let mut entries = vec![];
+    let mut extinf_title = None;
+
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            // #EXTINF:seconds,title
+            extinf_title = info
+                .split_once(',')
+                .map(|(_, title)| title.trim())
+                .filter(|title| !title.is_empty())
+                .map(str::to_owned);
+            continue;
+        }
+        if line.starts_with('#') {
+            continue;
+        }
+
+        let mut entry = parse_entry(line);
+        if entry.title.is_none() {
+            entry.title = extinf_title.take();
+        }
+        extinf_title = None;
+        entry.path = resolve(base_dir, &entry.path);
+        entries.push(entry);
+    }
+
+    entries
+}
+
+/// Parses a file line. SPC collections often use the extended form
+/// `file::SPC,title,length,loop,fade` to give each track its own title and
+/// timing.
+fn parse_entry(line: &str) -> M3uEntry {
+    let (path, fields) = match line.find("::") {
+        Some(pos) if line[pos + 2..].to_ascii_uppercase().starts_with("SPC") => {
+            let fields = &line[pos + 2 + 3..];
+            (&line[..pos], fields.strip_prefix(',').unwrap_or(fields))
+        }
+        _ => (line, ""),
+    };
+
+    let fields = split_fields(fields);
+    let field = |i: usize| fields.get(i).map(|f| f.trim()).filter(|f| !f.is_empty());
+
+    let length_ms = field(1).and_then(parse_time);
+    // The loop field is the loop's length, or with a trailing - the intro's,
+    // leaving the rest of the length for the loop.
+    let loop_ms = field(2).and_then(|f| match f.strip_suffix('-') {
+        Some(intro) => length_ms?.checked_sub(parse_time(intro)?),
+        None => parse_time(f),
+    });
+    M3uEntry {
+        path: PathBuf::from(path),
+        title: field(0).map(str::to_owned),
+        overrides: TrackOverrides {
+            length_ms,
+            loop_ms,
+            fade_ms: field(3).and_then(parse_time),
+        },
+    }
+}
+
+/// Splits `::SPC` fields on commas, except for escaped `\,`.
+fn split_fields(s: &str) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(next) => field.push(next),
+                None => field.push(c),
+            },
+            ',' => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+fn escape_field(s: &str) -> String {
+    s.replace('\\', "\\\\").replace(',', "\\,")
+}
+
+/// Parses `[[h:]m:]s[.fraction]` into milliseconds.
+pub fn parse_time(s: &str) -> Option<u32> {
+    let (whole, fraction) = match s.find('.') {
+        Some(pos) => (&s[..pos], &s[pos + 1..]),
+        None => (s, ""),
+    };
+
+    let mut secs: u32 = 0;
+    for (i, part) in whole.split(':').enumerate() {
+        if i >= 3 {
+            return None;
+        }
+        secs = secs
+            .checked_mul(60)?
+            .checked_add(part.trim().parse().ok()?)?;
+    }
+
+    let mut ms: u32 = 0;
+    if !fraction.is_empty() {
+        // Only milliseconds are significant.
+        let digits = &fraction[..fraction.len().min(3)];
+        ms = digits.parse().ok()?;
+        ms *= 10u32.pow(3 - digits.len() as u32);
+    }
+
+    secs.checked_mul(1000)?.checked_add(ms)
+}
+
+/// Formats milliseconds as `m:ss`, with a fraction if needed.
+pub fn format_time(ms: u32) -> String {
+    let secs = ms / 1000;
+    let mut out = format!("{}:{:02}", secs / 60, secs % 60);
+    match ms % 1000 {
+        0 => {}
+        fraction => {
+            let _ = write!(out, ".{:03}", fraction);
+        }
+    }
+    out
+}
+
+fn resolve(base_dir: &Path, path: &Path) -> PathBuf {
+    if path.is_absolute() {
+        path.to_owned()
+    } else {
+        base_dir.join(path)
+    }
+}
+
+/// Returns `path` relative to `base_dir` if it's inside it, otherwise the
+/// absolute path.
+fn relative_path(base_dir: &Path, path: &Path) -> PathBuf {
+    match path.strip_prefix(base_dir) {
+        Ok(relative)
+            if relative
+                .components()
+                .all(|c| matches!(c, Component::Normal(_))) =>
+        {
+            relative.to_owned()
+        }
+        _ => path.to_owned(),
+    }
+}
+
+/// Writes a playlist for a file in `base_dir`. If `extended` is true, titles
+/// and timing are written as `::SPC` fields, otherwise as `#EXTINF` lines.
+pub fn write(entries: &[M3uEntry], base_dir: &Path, extended: bool) -> String {
+    let mut out = String::new();
+    if !extended {
+        out += "#EXTM3U\n";
+    }
+
+    for entry in entries {
+        let path = relative_path(base_dir, &entry.path);
+        let path = path.to_string_lossy();
+        let overrides = &entry.overrides;
+
+        if extended {
+            let time = |ms: Option<u32>| ms.map(format_time).unwrap_or_default();
+            let _ = writeln!(
+                out,
+                "{}::SPC,{},{},{},{}",
+                path,
+                escape_field(entry.title.as_deref().unwrap_or("")),
+                time(overrides.length_ms),
+                time(overrides.loop_ms),
+                time(overrides.fade_ms),
+            );
+        } else {
+            let secs = match overrides.length_ms {
+                Some(ms) => ((ms + overrides.fade_ms.unwrap_or(0)) / 1000) as i64,
+                None => -1,
+            };
+            let _ = writeln!(
+                out,
+                "#EXTINF:{},{}",
+                secs,
+                entry.title.as_deref().unwrap_or("")
+            );
+            let _ = writeln!(out, "{}", path);
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode("\u{feff}ゼルダ.spc".as_bytes()), "ゼルダ.spc");
+        // "ゼルダ.spc" in Shift-JIS.
+        assert_eq!(decode(b"\x83\x5b\x83\x8b\x83\x5f.spc"), "ゼルダ.spc");
+        // Invalid as UTF-8 and Shift-JIS.
+        assert_eq!(decode(b"caf\xe9\n"), "café\n");
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time("5"), Some(5000));
+        assert_eq!(parse_time("1:05"), Some(65_000));
+        assert_eq!(parse_time("1:00:00"), Some(3_600_000));
+        assert_eq!(parse_time("0:01.5"), Some(1500));
+        assert_eq!(parse_time("2.25"), Some(2250));
+        assert_eq!(parse_time("1.23456"), Some(1234));
+        assert_eq!(parse_time(""), None);
+        assert_eq!(parse_time("1:2:3:4"), None);
+        assert_eq!(parse_time("abc"), None);
+        assert_eq!(parse_time("99999999"), None);
+    }
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time(0), "0:00");
+        assert_eq!(format_time(65_000), "1:05");
+        assert_eq!(format_time(1500), "0:01.500");
+        assert_eq!(format_time(3_600_000), "60:00");
+        for ms in [0, 999, 61_001, 3_600_000] {
+            assert_eq!(parse_time(&format_time(ms)), Some(ms));
+        }
+    }
+
+    #[test]
+    fn test_escaping() {
+        assert_eq!(split_fields(r"a\,b,c\\,d\"), ["a,b", r"c\", r"d\"]);
+        assert_eq!(split_fields(""), [""]);
+        let title = r"Boss, Phase 2 \ Final";
+        assert_eq!(split_fields(&escape_field(title)), [title]);
+    }
+
+    #[test]
+    fn test_parse() {
+        let base_dir = Path::new("/music/set");
+        let text = "#EXTM3U\n\
+                    #EXTINF:125,Title From EXTINF\n\
+                    01.spc\n\
+                    \n\
+                    # A comment\n\
+                    sub/02.spc::SPC,Battle\\, Part 1,2:00,1:30-,10\n\
+                    /abs/03.spc::spc,,1:05.5\n\
+                    05.spc::SPC,Loop,1:00,0:45,5\n\
+                    06.spc::SPC,Intro Only,,0:15-\n\
+                    #EXTINF:-1,Ignored\n\
+                    04.spc::SPC,Own Title\n";
+        let entries = parse(text, base_dir);
+        assert_eq!(
+            entries,
+            [
+                M3uEntry {
+                    path: PathBuf::from("/music/set/01.spc"),
+                    title: Some("Title From EXTINF".to_owned()),
+                    overrides: TrackOverrides::default(),
+                },
+                M3uEntry {
+                    path: PathBuf::from("/music/set/sub/02.spc"),
+                    title: Some("Battle, Part 1".to_owned()),
+                    overrides: TrackOverrides {
+                        length_ms: Some(120_000),
+                        // A 90 second intro, leaving 30 seconds of loop.
+                        loop_ms: Some(30_000),
+                        fade_ms: Some(10_000),
+                    },
+                },
+                M3uEntry {
+                    path: PathBuf::from("/abs/03.spc"),
+                    title: None,
+                    overrides: TrackOverrides {
+                        length_ms: Some(65_500),
+                        ..TrackOverrides::default()
+                    },
+                },
+                M3uEntry {
+                    path: PathBuf::from("/music/set/05.spc"),
+                    title: Some("Loop".to_owned()),
+                    overrides: TrackOverrides {
+                        length_ms: Some(60_000),
+                        loop_ms: Some(45_000),
+                        fade_ms: Some(5000),
+                    },
+                },
+                // Without a length, the loop's can't be worked out.
+                M3uEntry {
+                    path: PathBuf::from("/music/set/06.spc"),
+                    title: Some("Intro Only".to_owned()),
+                    overrides: TrackOverrides::default(),
+                },
+                M3uEntry {
+                    path: PathBuf::from("/music/set/04.spc"),
+                    title: Some("Own Title".to_owned()),
+                    overrides: TrackOverrides::default(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_write_and_parse() {
+        let base_dir = Path::new("/music/set");
+        let entries = vec![
+            M3uEntry {
+                path: PathBuf::from("/music/set/sub/01.spc"),
+                title: Some(r"Title, with \ punctuation".to_owned()),
+                overrides: TrackOverrides {
+                    length_ms: Some(61_500),
+                    loop_ms: Some(30_000),
+                    fade_ms: Some(8000),
+                },
+            },
+            M3uEntry {
+                path: PathBuf::from("/elsewhere/02.spc"),
+                title: None,
+                overrides: TrackOverrides::default(),
+            },
+        ];
+
+        let extended = write(&entries, base_dir, true);
+        // Loops are written as lengths, without the - marking an intro.
+        assert!(extended
+            .starts_with("sub/01.spc::SPC,Title\\, with \\\\ punctuation,1:01.500,0:30,0:08\n"));
+        assert!(extended.contains("\n/elsewhere/02.spc::SPC,"));
+        assert_eq!(parse(&extended, base_dir), entries);
+
+        // An intro is written back as the loop's length.
+        let intro = parse("03.spc::SPC,,1:00,0:20-,0:05\n", base_dir);
+        assert_eq!(intro[0].overrides.loop_ms, Some(40_000));
+        assert_eq!(
+            write(&intro, base_dir, true),
+            "03.spc::SPC,,1:00,0:40,0:05\n"
+        );
+
+        // Plain M3U keeps titles but not timing.
+        let plain = write(&entries, base_dir, false);
+        assert!(plain.starts_with("#EXTM3U\n#EXTINF:69,"));
+        let parsed = parse(&plain, base_dir);
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[0].path, entries[0].path);
+        assert_eq!(parsed[0].title, entries[0].title);
+        assert_eq!(parsed[1].path, entries[1].path);
+        assert_eq!(parsed[1].title, None);
+    }
+}
@@ -1,9 +1,36 @@
+mod analysis;
 mod app;
+mod archive;
+mod audition;
+mod brr;
+mod cli;
+mod db;
+mod export;
+mod flac;
+mod library;
+mod loops;
+mod loudness;
+mod m3u;
+mod midi;
+mod playlist;
+mod settings;
+mod sf2;
+mod sink;
+mod soundtrack;
 mod spcplay;
+mod stems;
+mod wav;
 
 use anyhow::Result;
 
 fn main() -> Result<()> {
+    let args: Vec<_> = std::env::args_os().skip(1).collect();
+    if let Some(command) = args.first().and_then(|arg| arg.to_str()) {
+        if cli::SUBCOMMANDS.contains(&command) {
+            return cli::run(args);
+        }
+    }
+
     let app = app::SpcPlayApp::new();
     let native_options = eframe::NativeOptions::default();
     eframe::run_native(Box::new(app), native_options);
@@ -0,0 +1,503 @@
+//! Converting the notes a song plays, as logged from its DSP register writes,
+//! to a Standard MIDI File for transcribing.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use snes_apu::dsp::dsp::{RegisterWrite, NUM_VOICES, SAMPLE_RATE};
+
+/// Note played by a sample at pitch $1000, its recorded rate, unless its
+/// tuning is given.
+pub const DEFAULT_BASE_NOTE: f64 = 60.0;
+/// Semitones the pitch wheel bends either way, set with RPN 0. Slides further
+/// than this retrigger the note.
+const BEND_RANGE: f64 = 12.0;
+const PITCH_ONE: f64 = 0x1000 as f64;
+
+const TICKS_PER_QUARTER: u64 = 480;
+/// At the default 120 BPM.
+const TICKS_PER_SECOND: u64 = TICKS_PER_QUARTER * 2;
+const VELOCITY: u8 = 100;
+
+const KON: usize = 0x4c;
+const KOF: usize = 0x5c;
+const REG_LEN: usize = 0x80;
+
+/// DSP register writes over a render, as returned by
+/// `SpcPlayer::render_register_writes()`.
+#[derive(Clone, Debug)]
+pub struct RegisterLog {
+    /// Registers when the render started, with KON holding the voices which
+    /// were playing.
+    pub initial: [u8; REG_LEN],
+    /// Writes, with `sample` counted from the start of the render.
+    pub writes: Vec<RegisterWrite>,
+    /// Samples rendered.
+    pub length: u64,
+}
+
+/// Whether a path names a MIDI file, going by its extension.
+pub fn is_midi(path: &Path) -> bool {
+    matches!(path.extension().and_then(|ext| ext.to_str()), Some(ext) if ext.eq_ignore_ascii_case("mid") || ext.eq_ignore_ascii_case("midi"))
+}
+
+/// Parses a sample's tuning, `SRCN=NOTE`, eg. `12=69.5` for a sample which
+/// plays a quarter tone above A4 at pitch $1000.
+pub fn parse_tuning(s: &str) -> Result<(u8, f64)> {
+    let (source, note) = s
+        .split_once('=')
+        .context("tunings look like SRCN=NOTE, eg. 12=69.5")?;
+    let source = source
+        .trim()
+        .parse()
+        .with_context(|| format!("{} is not a sample number from 0 to 255", source))?;
+    let note: f64 = note
+        .trim()
+        .parse()
+        .with_context(|| format!("{} is not a MIDI note number", note))?;
+    if !(0.0..128.0).contains(&note) {
+        bail!("{} is not a MIDI note number", note);
+    }
+    Ok((source, note))
+}
+
+/// Appends a variable-length quantity.
+fn write_vlq(out: &mut Vec<u8>, value: u64) {
+    let mut bytes = vec![(value & 0x7f) as u8];
+    let mut value = value >> 7;
+    while value > 0 {
+        bytes.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    out.extend(bytes.iter().rev());
+}
+
+/// Events of one MIDI track, in order.
+#[derive(Default)]
+struct Track {
+    bytes: Vec<u8>,
+    last_tick: u64,
+}
+
+impl Track {
+    fn event(&mut self, tick: u64, data: &[u8]) {
+        write_vlq(&mut self.bytes, tick - self.last_tick);
+        self.bytes.extend_from_slice(data);
+        self.last_tick = tick;
+    }
+
+    fn meta(&mut self, tick: u64, kind: u8, data: &[u8]) {
+        self.event(tick, &[0xff, kind]);
+        write_vlq(&mut self.bytes, data.len() as u64);
+        self.bytes.extend_from_slice(data);
+    }
+
+    fn chunk(mut self, end_tick: u64) -> Vec<u8> {
+        self.meta(end_tick, 0x2f, &[]);
+        let mut chunk = b"MTrk".to_vec();
+        chunk.extend_from_slice(&(self.bytes.len() as u32).to_be_bytes());
+        chunk.extend_from_slice(&self.bytes);
+        chunk
+    }
+}
+
+/// What a voice's MIDI channel was last set to.
+#[derive(Default)]
+struct VoiceState {
+    note: Option<u8>,
+    bend: Option<u16>,
+    source: Option<u8>,
+    volume: Option<u8>,
+    pan: Option<u8>,
+}
+
+/// Converts a voice's volume registers to MIDI volume and pan. Negative
+/// volumes invert the phase, which MIDI can't, so only their size counts.
+fn volume_and_pan(left: u8, right: u8) -> (u8, u8) {
+    let left = (left as i8 as i32).abs();
+    let right = (right as i8 as i32).abs();
+    let volume = left.max(right).min(127) as u8;
+    let pan = match left + right {
+        0 => 64,
+        total => (64 + (right - left) * 63 / total) as u8,
+    };
+    (volume, pan)
+}
+
+/// Converts a pitch register to a fractional MIDI note, or None if it's
+/// silent or out of MIDI's range.
+fn pitch_to_note(pitch: u16, base_note: f64) -> Option<f64> {
+    if pitch == 0 {
+        return None;
+    }
+    let note = base_note + 12.0 * (pitch as f64 / PITCH_ONE).log2();
+    Some(note).filter(|note| (0.0..127.5).contains(note))
+}
+
+/// Estimates the note a sample plays at pitch $1000 from the pitches a song
+/// played it at. Songs play samples in tune with each other, so this is
+/// `DEFAULT_BASE_NOTE`, moved by less than a semitone so that the pitches
+/// land as close to whole notes as they can. Returns `DEFAULT_BASE_NOTE` if
+/// there are none.
+pub fn estimate_base_note(pitches: &[u16]) -> f64 {
+    // The average offset from the nearest note, taken round a circle so that
+    // offsets just either side of a note average to it.
+    let (mut x, mut y) = (0.0, 0.0);
+    for &pitch in pitches.iter().filter(|&&pitch| pitch != 0) {
+        let semitones = 12.0 * (pitch as f64 / PITCH_ONE).log2();
+        let angle = semitones * 2.0 * std::f64::consts::PI;
+        x += angle.cos();
+        y += angle.sin();
+    }
+    if x == 0.0 && y == 0.0 {
+        return DEFAULT_BASE_NOTE;
+    }
+    let offset = y.atan2(x) / (2.0 * std::f64::consts::PI);
+    DEFAULT_BASE_NOTE - offset
+}
+
+/// The pitch wheel position which bends a note by `semitones`.
+fn bend(semitones: f64) -> u16 {
+    (8192.0 + semitones / BEND_RANGE * 8192.0)
+        .round()
+        .clamp(0.0, 16383.0) as u16
+}
+
+struct Converter<'a> {
+    regs: [u8; REG_LEN],
+    base_notes: &'a HashMap<u8, f64>,
+    tracks: Vec<Track>,
+    voices: Vec<VoiceState>,
+}
+
+impl Converter<'_> {
+    fn base_note(&self, source: u8) -> f64 {
+        self.base_notes
+            .get(&source)
+            .copied()
+            .unwrap_or(DEFAULT_BASE_NOTE)
+    }
+
+    fn note_off(&mut self, voice: usize, tick: u64) {
+        if let Some(note) = self.voices[voice].note.take() {
+            self.tracks[voice].event(tick, &[0x80 | voice as u8, note, 0]);
+        }
+    }
+
+    /// The voice's current pitch, as a fractional note.
+    fn note(&self, voice: usize) -> Option<f64> {
+        let base = voice * 0x10;
+        let pitch = u16::from_le_bytes([self.regs[base + 2], self.regs[base + 3]]) & 0x3fff;
+        pitch_to_note(pitch, self.base_note(self.regs[base + 4]))
+    }
+
+    fn set_bend(&mut self, voice: usize, tick: u64, bend: u16) {
+        if self.voices[voice].bend != Some(bend) {
+            let data = [0xe0 | voice as u8, (bend & 0x7f) as u8, (bend >> 7) as u8];
+            self.tracks[voice].event(tick, &data);
+            self.voices[voice].bend = Some(bend);
+        }
+    }
+
+    /// Sends changes to the voice's sample, volume and pan, and its pitch
+    /// while a note is playing.
+    fn update(&mut self, voice: usize, tick: u64) {
+        let base = voice * 0x10;
+        let channel = voice as u8;
+        let source = self.regs[base + 4];
+        if self.voices[voice].source != Some(source) {
+            // Bank select for samples past 127.
+            self.tracks[voice].event(tick, &[0xb0 | channel, 0, source >> 7]);
+            self.tracks[voice].event(tick, &[0xc0 | channel, source & 0x7f]);
+            self.voices[voice].source = Some(source);
+        }
+
+        let (volume, pan) = volume_and_pan(self.regs[base], self.regs[base + 1]);
+        if self.voices[voice].volume != Some(volume) {
+            self.tracks[voice].event(tick, &[0xb0 | channel, 7, volume]);
+            self.voices[voice].volume = Some(volume);
+        }
+        if self.voices[voice].pan != Some(pan) {
+            self.tracks[voice].event(tick, &[0xb0 | channel, 10, pan]);
+            self.voices[voice].pan = Some(pan);
+        }
+
+        let playing = match self.voices[voice].note {
+            Some(playing) => playing,
+            None => return,
+        };
+        match self.note(voice) {
+            Some(note) if (note - playing as f64).abs() <= BEND_RANGE => {
+                self.set_bend(voice, tick, bend(note - playing as f64));
+            }
+            // Too far to bend, so play the new note instead.
+            Some(_) => self.note_on(voice, tick),
+            None => self.note_off(voice, tick),
+        }
+    }
+
+    fn note_on(&mut self, voice: usize, tick: u64) {
+        self.note_off(voice, tick);
+        if let Some(note) = self.note(voice) {
+            let playing = note.round();
+            self.set_bend(voice, tick, bend(note - playing));
+            let data = [0x90 | voice as u8, playing as u8, VELOCITY];
+            self.tracks[voice].event(tick, &data);
+            self.voices[voice].note = Some(playing as u8);
+        }
+    }
+
+    /// Applies writes made at the same time, then sends the changes.
+    fn apply(&mut self, tick: u64, writes: &[RegisterWrite]) {
+        let mut key_on = 0;
+        let mut key_off = 0;
+        for write in writes {
+            self.regs[write.address as usize] = write.value;
+            match write.address as usize {
+                KON => key_on |= write.value,
+                KOF => key_off |= write.value,
+                _ => {}
+            }
+        }
+        for voice in 0..NUM_VOICES {
+            // Notes keyed on again are replaced rather than bent.
+            if (key_on | key_off) & (1 << voice) != 0 {
+                self.note_off(voice, tick);
+            }
+            self.update(voice, tick);
+            if key_on & (1 << voice) != 0 {
+                self.note_on(voice, tick);
+            }
+        }
+    }
+}
+
+/// Converts the notes in `log` to a format 1 Standard MIDI File: a track
+/// naming the song, then a track for each voice on the channel of the same
+/// number. Key on and off become notes, the pitch register becomes notes and
+/// pitch bends, tuned by `base_notes` (the note each sample plays at pitch
+/// $1000), and the sample number becomes the program, with bank select for
+/// samples past 127. Voice volume becomes channel volume and pan.
+pub fn to_midi(log: &RegisterLog, title: &str, base_notes: &HashMap<u8, f64>) -> Vec<u8> {
+    let to_tick = |sample: u64| sample * TICKS_PER_SECOND / SAMPLE_RATE as u64;
+    let end_tick = to_tick(log.length);
+
+    let mut converter = Converter {
+        regs: log.initial,
+        base_notes,
+        tracks: (0..NUM_VOICES).map(|_| Track::default()).collect(),
+        voices: (0..NUM_VOICES).map(|_| VoiceState::default()).collect(),
+    };
+    for (voice, track) in converter.tracks.iter_mut().enumerate() {
+        let channel = voice as u8;
+        track.meta(0, 0x03, format!("Voice {}", voice + 1).as_bytes());
+        // Set the pitch bend range with RPN 0.
+        for &(controller, value) in &[(101, 0), (100, 0), (6, BEND_RANGE as u8), (38, 0)] {
+            track.event(0, &[0xb0 | channel, controller, value]);
+        }
+    }
+
+    // Voices playing when the render started count as keyed on.
+    let initial_key_on = RegisterWrite {
+        sample: 0,
+        address: KON as u8,
+        value: log.initial[KON],
+    };
+    converter.apply(0, &[initial_key_on]);
+    let mut start = 0;
+    while start < log.writes.len() && log.writes[start].sample < log.length {
+        let sample = log.writes[start].sample;
+        let len = log.writes[start..]
+            .iter()
+            .take_while(|write| write.sample == sample)
+            .count();
+        converter.apply(to_tick(sample), &log.writes[start..start + len]);
+        start += len;
+    }
+    for voice in 0..NUM_VOICES {
+        converter.note_off(voice, end_tick);
+    }
+
+    let mut conductor = Track::default();
+    conductor.meta(0, 0x03, title.as_bytes());
+    // 120 BPM in 4/4.
+    conductor.meta(0, 0x51, &500_000u32.to_be_bytes()[1..]);
+    conductor.meta(0, 0x58, &[4, 2, 24, 8]);
+
+    let mut out = b"MThd".to_vec();
+    out.extend_from_slice(&6u32.to_be_bytes());
+    out.extend_from_slice(&1u16.to_be_bytes());
+    out.extend_from_slice(&(NUM_VOICES as u16 + 1).to_be_bytes());
+    out.extend_from_slice(&(TICKS_PER_QUARTER as u16).to_be_bytes());
+    out.extend(conductor.chunk(end_tick));
+    for track in converter.tracks {
+        out.extend(track.chunk(end_tick));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spcplay::test_player;
+    use std::convert::TryInto;
+
+    /// Reads a MIDI file's tracks as (tick, event) lists.
+    fn parse(data: &[u8]) -> Vec<Vec<(u64, Vec<u8>)>> {
+        fn read_vlq(data: &[u8], pos: &mut usize) -> u64 {
+            let mut value = 0;
+            loop {
+                let byte = data[*pos];
+                *pos += 1;
+                value = value << 7 | (byte & 0x7f) as u64;
+                if byte & 0x80 == 0 {
+                    return value;
+                }
+            }
+        }
+
+        assert_eq!(&data[..4], b"MThd");
+        let track_count = u16::from_be_bytes([data[10], data[11]]);
+        let mut pos = 14;
+        let mut tracks = vec![];
+        for _ in 0..track_count {
+            assert_eq!(&data[pos..pos + 4], b"MTrk");
+            let len = u32::from_be_bytes(data[pos + 4..pos + 8].try_into().unwrap()) as usize;
+            pos += 8;
+            let end = pos + len;
+            let mut tick = 0;
+            let mut events = vec![];
+            while pos < end {
+                tick += read_vlq(data, &mut pos);
+                let start = pos;
+                match data[pos] {
+                    0xff => {
+                        pos += 2;
+                        let len = read_vlq(data, &mut pos) as usize;
+                        pos += len;
+                    }
+                    status if status & 0xf0 == 0xc0 => pos += 2,
+                    _ => pos += 3,
+                }
+                events.push((tick, data[start..pos].to_vec()));
+            }
+            assert_eq!(pos, end);
+            tracks.push(events);
+        }
+        tracks
+    }
+
+    fn write(sample: u64, address: u8, value: u8) -> RegisterWrite {
+        RegisterWrite {
+            sample,
+            address,
+            value,
+        }
+    }
+
+    #[test]
+    fn test_pitch_to_note() {
+        assert_eq!(pitch_to_note(0x1000, DEFAULT_BASE_NOTE), Some(60.0));
+        assert_eq!(pitch_to_note(0x2000, DEFAULT_BASE_NOTE), Some(72.0));
+        assert_eq!(pitch_to_note(0x0800, 69.0), Some(57.0));
+        assert_eq!(pitch_to_note(0, DEFAULT_BASE_NOTE), None);
+        assert_eq!(bend(0.0), 8192);
+        assert_eq!(bend(BEND_RANGE), 16383);
+        assert_eq!(bend(-BEND_RANGE), 0);
+        assert_eq!(parse_tuning("12=69.5").unwrap(), (12, 69.5));
+        assert!(parse_tuning("256=60").is_err());
+        assert!(parse_tuning("1=128").is_err());
+    }
+
+    #[test]
+    fn test_estimate_base_note() {
+        assert_eq!(estimate_base_note(&[]), DEFAULT_BASE_NOTE);
+        assert!((estimate_base_note(&[0x1000, 0x2000]) - DEFAULT_BASE_NOTE).abs() < 1e-9);
+
+        // Notes a quarter tone flat of A, C and E.
+        let quarter_flat = |note: f64| {
+            let pitch = PITCH_ONE * 2f64.powf((note - 0.25 - DEFAULT_BASE_NOTE) / 12.0);
+            pitch.round() as u16
+        };
+        let pitches = [quarter_flat(69.0), quarter_flat(60.0), quarter_flat(76.0)];
+        let base_note = estimate_base_note(&pitches);
+        assert!((base_note - 60.25).abs() < 0.01, "{}", base_note);
+        for &pitch in &pitches {
+            let note = pitch_to_note(pitch, base_note).unwrap();
+            assert!((note - note.round()).abs() < 0.01, "{}", note);
+        }
+    }
+
+    #[test]
+    fn test_to_midi() {
+        let mut initial = [0; REG_LEN];
+        // Voice 2 plays sample 3 at pitch $2000, panned left.
+        initial[0x20] = 0x40;
+        initial[0x21] = 0x20;
+        initial[0x23] = 0x20;
+        initial[0x24] = 3;
+        let log = RegisterLog {
+            initial,
+            writes: vec![
+                write(100, 0x4c, 0x04),
+                // A semitone up, as a pair of writes.
+                write(16000, 0x22, 0xf0),
+                write(16000, 0x23, 0x21),
+                write(32000, 0x5c, 0x04),
+            ],
+            length: SAMPLE_RATE as u64 * 2,
+        };
+        let mut base_notes = HashMap::new();
+        base_notes.insert(3, 69.0);
+        let tracks = parse(&to_midi(&log, "Song", &base_notes));
+        assert_eq!(tracks.len(), NUM_VOICES + 1);
+        assert_eq!(tracks[0][0], (0, b"\xff\x03\x04Song".to_vec()));
+
+        let voice: Vec<(u64, Vec<u8>)> = tracks[3]
+            .iter()
+            .filter(|(_, event)| event[0] != 0xff && event[0] & 0xf0 != 0xb0)
+            .cloned()
+            .collect();
+        let bend_up = bend(12.0 * (0x21f0 as f64 / 0x2000 as f64).log2());
+        assert_eq!(
+            voice,
+            [
+                (0, vec![0xc2, 3]),
+                (3, vec![0xe2, 0x00, 0x40]),
+                (3, vec![0x92, 81, VELOCITY]),
+                (
+                    480,
+                    vec![0xe2, (bend_up & 0x7f) as u8, (bend_up >> 7) as u8]
+                ),
+                (960, vec![0x82, 81, 0]),
+            ]
+        );
+        let controllers: Vec<&[u8]> = tracks[3]
+            .iter()
+            .map(|(_, event)| &event[..])
+            .filter(|event| event[0] == 0xb2)
+            .collect();
+        assert!(controllers.contains(&&[0xb2, 7, 0x40][..]));
+        assert!(controllers.contains(&&[0xb2, 10, 64 - 21][..]));
+        // Other voices play nothing.
+        assert!(tracks[1].iter().all(|(_, event)| event[0] & 0xf0 != 0x90));
+    }
+
+    #[test]
+    fn test_render_song() -> Result<()> {
+        let mut player = test_player("3rdparty/snes-apu/test/smashit.spc", 3000, 0)?;
+        let log = player.render_register_writes()?;
+        assert_eq!(log.length, SAMPLE_RATE as u64 * 3);
+        assert!(log.writes.iter().all(|write| write.sample <= log.length));
+
+        let tracks = parse(&to_midi(&log, "", &HashMap::new()));
+        let notes = tracks
+            .iter()
+            .flatten()
+            .filter(|(_, event)| event[0] & 0xf0 == 0x90)
+            .count();
+        assert!(notes > 10, "{} notes", notes);
+        Ok(())
+    }
+}
@@ -0,0 +1,722 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use rusqlite::{params, Connection};
+
+use crate::archive;
+use crate::library::{LibraryEntry, SpcTags};
+use crate::m3u::M3uEntry;
+use crate::spcplay::TrackOverrides;
+
+/// A file queued in the playlist.
+#[derive(Clone, Debug)]
+pub struct PlaylistEntry {
+    pub path: PathBuf,
+    pub game: String,
+    pub title: String,
+    /// Length from the file's tags.
+    pub length_ms: Option<u32>,
+    pub overrides: TrackOverrides,
+}
+
+impl PlaylistEntry {
+    /// Reads the entry's tags from the file.
+    pub fn from_file(path: &Path) -> Result<PlaylistEntry> {
+        let spc = archive::load_spc(path)?;
+        let tags = SpcTags::from_spc(&spc);
+        Ok(PlaylistEntry {
+            path: path.to_owned(),
+            game: tags.game,
+            title: tags.title,
+            length_ms: tags.length_ms,
+            overrides: TrackOverrides::default(),
+        })
+    }
+
+    /// Reads the file named by an M3U entry, and applies the entry's title and
+    /// timing.
+    pub fn from_m3u(m3u_entry: &M3uEntry) -> Result<PlaylistEntry> {
+        let mut entry = PlaylistEntry::from_file(&m3u_entry.path)?;
+        if let Some(title) = &m3u_entry.title {
+            entry.title = title.clone();
+        }
+        entry.overrides = m3u_entry.overrides;
+        Ok(entry)
+    }
+
+    pub fn to_m3u(&self) -> M3uEntry {
+        let mut overrides = self.overrides;
+        overrides.length_ms = overrides.length_ms.or(self.length_ms);
+        M3uEntry {
+            path: self.path.clone(),
+            title: Some(self.title.clone()).filter(|title| !title.is_empty()),
+            overrides,
+        }
+    }
+
+    /// The length to play, preferring the override.
+    pub fn effective_length_ms(&self) -> Option<u32> {
+        self.overrides.length_ms.or(self.length_ms)
+    }
+
+    /// The title, or the file name if the file has no title.
+    pub fn display_title(&self) -> String {
+        if !self.title.is_empty() {
+            return self.title.clone();
+        }
+        match self.path.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => self.path.display().to_string(),
+        }
+    }
+}
+
+impl From<&LibraryEntry> for PlaylistEntry {
+    fn from(entry: &LibraryEntry) -> Self {
+        PlaylistEntry {
+            path: entry.path.clone(),
+            game: entry.game.clone(),
+            title: entry.title.clone(),
+            length_ms: entry.length_ms,
+            overrides: TrackOverrides::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Repeat {
+    Off,
+    /// Play the current song again when it ends.
+    One,
+    /// Go back to the start after the last song.
+    All,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Shuffle {
+    Off,
+    /// Play songs in random order.
+    Tracks,
+    /// Play games in random order, with each game's songs in playlist order.
+    Games,
+}
+
+/// The play queue. Songs are played in `order`, which is a permutation of
+/// `entries` indices, shuffled according to `shuffle`.
+pub struct Playlist {
+    entries: Vec<PlaylistEntry>,
+    order: Vec<usize>,
+    /// Index into `entries` of the song playing (or last played).
+    current: Option<usize>,
+    /// Position in `order` to continue from, if the current song was removed.
+    resume_pos: Option<usize>,
+    repeat: Repeat,
+    shuffle: Shuffle,
+    generation: u64,
+}
+
+impl Playlist {
+    pub fn new(entries: Vec<PlaylistEntry>, repeat: Repeat, shuffle: Shuffle) -> Playlist {
+        let mut playlist = Playlist {
+            order: (0..entries.len()).collect(),
+            entries,
+            current: None,
+            resume_pos: None,
+            repeat,
+            shuffle,
+            generation: 0,
+        };
+        playlist.reshuffle();
+        playlist
+    }
+
+    pub fn entries(&self) -> &[PlaylistEntry] {
+        &self.entries
+    }
+
+    pub fn current(&self) -> Option<usize> {
+        self.current
+    }
+
+    /// Selects a song without changing the order (eg. when it's double-clicked).
+    pub fn set_current(&mut self, current: Option<usize>) {
+        self.current = current.filter(|&i| i < self.entries.len());
+        self.resume_pos = None;
+    }
+
+    pub fn repeat(&self) -> Repeat {
+        self.repeat
+    }
+
+    pub fn set_repeat(&mut self, repeat: Repeat) {
+        if self.repeat != repeat {
+            self.repeat = repeat;
+            self.generation += 1;
+        }
+    }
+
+    pub fn shuffle(&self) -> Shuffle {
+        self.shuffle
+    }
+
+    pub fn set_shuffle(&mut self, shuffle: Shuffle) {
+        if self.shuffle != shuffle {
+            self.shuffle = shuffle;
+            self.generation += 1;
+            self.resume_pos = None;
+            self.reshuffle();
+        }
+    }
+
+    /// Appends entries, returning the index of the first one added. With
+    /// shuffle on, they're shuffled into the songs still to play, leaving the
+    /// order of the rest alone.
+    pub fn add(&mut self, entries: impl IntoIterator<Item = PlaylistEntry>) -> usize {
+        let first = self.entries.len();
+        self.entries.extend(entries);
+        self.generation += 1;
+
+        let mut rng = rand::thread_rng();
+        let start = self.next_position();
+        match self.shuffle {
+            Shuffle::Off => self.order.extend(first..self.entries.len()),
+            Shuffle::Tracks => {
+                for i in first..self.entries.len() {
+                    let pos = rng.gen_range(start..=self.order.len());
+                    self.order.insert(pos, i);
+                }
+            }
+            Shuffle::Games => {
+                for i in first..self.entries.len() {
+                    let game_at = |pos: usize| &self.entries[self.order[pos]].game;
+                    let game = &self.entries[i].game;
+                    // After the rest of its game if that's still to play (or
+                    // playing), or else between two other games.
+                    let pos = match (start.saturating_sub(1)..self.order.len())
+                        .rev()
+                        .find(|&pos| game_at(pos) == game)
+                    {
+                        Some(pos) => pos + 1,
+                        None => {
+                            let boundaries: Vec<usize> = (start..=self.order.len())
+                                .filter(|&pos| {
+                                    pos == 0
+                                        || pos == self.order.len()
+                                        || game_at(pos - 1) != game_at(pos)
+                                })
+                                .collect();
+                            *boundaries.choose(&mut rng).unwrap()
+                        }
+                    };
+                    self.order.insert(pos, i);
+                }
+            }
+        }
+        first
+    }
+
+    /// Removes an entry. If it's the current song, the next song is the one
+    /// after it.
+    pub fn remove(&mut self, index: usize) {
+        let pos = self.position(index);
+        let next_pos = self.next_position();
+        self.entries.remove(index);
+        self.generation += 1;
+        self.order.remove(pos);
+        for i in &mut self.order {
+            if *i > index {
+                *i -= 1;
+            }
+        }
+        self.current = match self.current {
+            Some(current) if current == index => None,
+            Some(current) if current > index => Some(current - 1),
+            current => current,
+        };
+        if self.current.is_none() {
+            self.resume_pos = Some(if pos < next_pos {
+                next_pos - 1
+            } else {
+                next_pos
+            });
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.generation += 1;
+        self.order.clear();
+        self.current = None;
+        self.resume_pos = None;
+    }
+
+    /// Moves the entry at `from` so it ends up at index `to`.
+    pub fn move_entry(&mut self, from: usize, to: usize) {
+        if from == to {
+            return;
+        }
+        let entry = self.entries.remove(from);
+        self.entries.insert(to, entry);
+        self.generation += 1;
+
+        // Where each old index ended up.
+        let remap = |i: usize| {
+            if i == from {
+                to
+            } else if from < i && i <= to {
+                i - 1
+            } else if to <= i && i < from {
+                i + 1
+            } else {
+                i
+            }
+        };
+        for i in &mut self.order {
+            *i = remap(*i);
+        }
+        self.current = self.current.map(remap);
+
+        if self.shuffle == Shuffle::Off {
+            // Positions in the new order are entry indices, so resume from
+            // the same song's new index.
+            if let Some(&next) = self.resume_pos.and_then(|pos| self.order.get(pos)) {
+                self.resume_pos = Some(next);
+            }
+            self.order = (0..self.entries.len()).collect();
+        }
+    }
+
+    /// Returns the index of the song after the current one, or None at the
+    /// end of the playlist. `manual` is true if the user skipped, rather than
+    /// the song ending; it ignores repeat-one.
+    pub fn peek_next(&self, manual: bool) -> Option<usize> {
+        if self.entries.is_empty() {
+            return None;
+        }
+        if self.repeat == Repeat::One && !manual && self.current.is_some() {
+            return self.current;
+        }
+
+        match self.order.get(self.next_position()) {
+            Some(&i) => Some(i),
+            None if self.repeat == Repeat::All => Some(self.order[0]),
+            None => None,
+        }
+    }
+
+    /// Advances to the next song and returns its index, or None (leaving the
+    /// current song selected) at the end of the playlist.
+    pub fn next(&mut self, manual: bool) -> Option<usize> {
+        let next = self.peek_next(manual)?;
+        self.current = Some(next);
+        self.resume_pos = None;
+        Some(next)
+    }
+
+    /// Incremented whenever the order of songs may have changed, so a song
+    /// queued for gapless playback can be discarded if it's no longer next.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Goes back to the previous song and returns its index, or None at the
+    /// start of the playlist.
+    pub fn previous(&mut self) -> Option<usize> {
+        let pos = match self.current {
+            Some(current) => self.position(current),
+            None => self.resume_pos?,
+        };
+        let pos = match pos.checked_sub(1) {
+            Some(pos) => pos,
+            None if self.repeat == Repeat::All => self.order.len().checked_sub(1)?,
+            None => return None,
+        };
+        self.current = Some(self.order[pos]);
+        self.resume_pos = None;
+        self.current
+    }
+
+    fn position(&self, index: usize) -> usize {
+        self.order.iter().position(|&i| i == index).unwrap()
+    }
+
+    /// Position in `order` of the song after the current one.
+    fn next_position(&self) -> usize {
+        match self.current {
+            Some(current) => self.position(current) + 1,
+            None => self.resume_pos.unwrap_or(0),
+        }
+    }
+
+    /// Regenerates `order` for the current shuffle mode, keeping the current
+    /// song (and for `Shuffle::Games`, the rest of its game) first.
+    fn reshuffle(&mut self) {
+        let mut rng = rand::thread_rng();
+        let len = self.entries.len();
+
+        match self.shuffle {
+            Shuffle::Off => self.order = (0..len).collect(),
+            Shuffle::Tracks => {
+                let mut order: Vec<usize> = (0..len).filter(|&i| Some(i) != self.current).collect();
+                order.shuffle(&mut rng);
+                if let Some(current) = self.current {
+                    order.insert(0, current);
+                }
+                self.order = order;
+            }
+            Shuffle::Games => {
+                let mut games: Vec<(&str, Vec<usize>)> = vec![];
+                for (i, entry) in self.entries.iter().enumerate() {
+                    match games.iter_mut().find(|(game, _)| *game == entry.game) {
+                        Some((_, group)) => group.push(i),
+                        None => games.push((&entry.game, vec![i])),
+                    }
+                }
+                games.shuffle(&mut rng);
+
+                if let Some(current) = self.current {
+                    let game = &self.entries[current].game;
+                    let pos = games.iter().position(|(g, _)| g == game).unwrap();
+                    let (_, group) = &mut games[pos];
+                    // Start partway through the game, at the current song.
+                    let start = group.iter().position(|&i| i == current).unwrap();
+                    group.rotate_left(start);
+                    let current_game = games.remove(pos);
+                    games.insert(0, current_game);
+                }
+
+                self.order = games.into_iter().flat_map(|(_, group)| group).collect();
+            }
+        }
+    }
+}
+
+/// Loads the saved playlist, in order.
+pub fn load(conn: &Connection) -> Result<Vec<PlaylistEntry>> {
+    let mut stmt = conn.prepare(
+        "select path, game, title, length_ms,
+             override_length_ms, override_loop_ms, override_fade_ms
+         from playlist order by position",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok(PlaylistEntry {
+            path: PathBuf::from(row.get::<_, String>(0)?),
+            game: row.get(1)?,
+            title: row.get(2)?,
+            length_ms: row.get(3)?,
+            overrides: TrackOverrides {
+                length_ms: row.get(4)?,
+                loop_ms: row.get(5)?,
+                fade_ms: row.get(6)?,
+            },
+        })
+    })?;
+    Ok(rows.collect::<rusqlite::Result<_>>()?)
+}
+
+/// Replaces the saved playlist. Entries whose paths aren't valid Unicode are
+/// skipped.
+pub fn save(conn: &mut Connection, entries: &[PlaylistEntry]) -> Result<()> {
+    let tx = conn.transaction()?;
+    tx.execute("delete from playlist", [])?;
+    {
+        let mut stmt = tx.prepare(
+            "insert into playlist (position, path, game, title, length_ms,
+                 override_length_ms, override_loop_ms, override_fade_ms)
+             values (?, ?, ?, ?, ?, ?, ?, ?)",
+        )?;
+        for (position, entry) in entries.iter().enumerate() {
+            if let Some(path) = entry.path.to_str() {
+                stmt.execute(params![
+                    position as i64,
+                    path,
+                    entry.game,
+                    entry.title,
+                    entry.length_ms,
+                    entry.overrides.length_ms,
+                    entry.overrides.loop_ms,
+                    entry.overrides.fade_ms,
+                ])?;
+            }
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(game: &str, title: &str) -> PlaylistEntry {
+        PlaylistEntry {
+            path: PathBuf::from(format!("{}/{}.spc", game, title)),
+            game: game.to_owned(),
+            title: title.to_owned(),
+            length_ms: None,
+            overrides: TrackOverrides::default(),
+        }
+    }
+
+    fn playlist(len: usize, repeat: Repeat, shuffle: Shuffle) -> Playlist {
+        let entries = (0..len).map(|i| entry("Game", &i.to_string())).collect();
+        Playlist::new(entries, repeat, shuffle)
+    }
+
+    fn title(playlist: &Playlist, index: Option<usize>) -> Option<&str> {
+        index.map(|i| playlist.entries()[i].title.as_str())
+    }
+
+    /// Plays songs until the end of the playlist, or `limit` songs, checking
+    /// that `peek_next()` agrees with `next()`.
+    fn play(playlist: &mut Playlist, manual: bool, limit: usize) -> Vec<usize> {
+        let mut played = vec![];
+        while played.len() < limit {
+            let peeked = playlist.peek_next(manual);
+            let next = playlist.next(manual);
+            assert_eq!(peeked, next);
+            match next {
+                Some(next) => played.push(next),
+                None => break,
+            }
+        }
+        played
+    }
+
+    #[test]
+    fn test_next_and_previous() {
+        let mut list = playlist(3, Repeat::Off, Shuffle::Off);
+        assert_eq!(list.previous(), None);
+        assert_eq!(play(&mut list, false, 10), [0, 1, 2]);
+        // The last song stays selected at the end.
+        assert_eq!(list.current(), Some(2));
+        assert_eq!(list.previous(), Some(1));
+        assert_eq!(list.previous(), Some(0));
+        assert_eq!(list.previous(), None);
+        assert_eq!(list.current(), Some(0));
+
+        list.set_repeat(Repeat::All);
+        assert_eq!(list.previous(), Some(2));
+        assert_eq!(play(&mut list, false, 4), [0, 1, 2, 0]);
+
+        // Repeat-one only applies when songs end.
+        list.set_repeat(Repeat::One);
+        assert_eq!(play(&mut list, false, 3), [0, 0, 0]);
+        assert_eq!(play(&mut list, true, 10), [1, 2]);
+
+        let mut empty = playlist(0, Repeat::All, Shuffle::Tracks);
+        assert_eq!(play(&mut empty, false, 10), []);
+        assert_eq!(empty.previous(), None);
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut list = playlist(5, Repeat::Off, Shuffle::Off);
+        list.set_current(Some(2));
+
+        // Removing songs before the current one keeps it selected.
+        list.remove(0);
+        assert_eq!(title(&list, list.current()), Some("2"));
+        list.remove(3);
+        assert_eq!(title(&list, list.current()), Some("2"));
+        assert_eq!(title(&list, list.peek_next(true)), Some("3"));
+
+        // Removing the current song deselects it, but the songs after it
+        // still play next.
+        list.remove(1);
+        assert_eq!(list.current(), None);
+        let titles: Vec<_> = play(&mut list, true, 10)
+            .into_iter()
+            .map(|i| title(&list, Some(i)).unwrap().to_owned())
+            .collect();
+        assert_eq!(titles, ["3"]);
+
+        list.remove(1);
+        let previous = list.previous();
+        assert_eq!(title(&list, previous), Some("1"));
+        list.remove(0);
+        assert_eq!(list.previous(), None);
+        assert_eq!(list.next(true), None);
+    }
+
+    #[test]
+    fn test_remove_current_shuffled() {
+        let mut list = playlist(8, Repeat::Off, Shuffle::Tracks);
+        let played = play(&mut list, true, 3);
+        let rest = list.order[3..].to_vec();
+
+        list.remove(played[2]);
+        let reindex = |i: usize| if i > played[2] { i - 1 } else { i };
+        assert_eq!(list.peek_next(true), Some(reindex(rest[0])));
+        // Moving songs around doesn't lose the place either.
+        list.move_entry(0, 6);
+        list.move_entry(6, 0);
+        assert_eq!(
+            play(&mut list, true, 10),
+            rest.iter().map(|&i| reindex(i)).collect::<Vec<_>>()
+        );
+        assert_eq!(list.previous(), Some(reindex(rest[3])));
+    }
+
+    #[test]
+    fn test_add_shuffled() {
+        let mut list = playlist(10, Repeat::Off, Shuffle::Tracks);
+        let played = play(&mut list, true, 4);
+        let order = list.order.clone();
+        let first = list.add((10..20).map(|i| entry("Game", &i.to_string())));
+        assert_eq!(first, 10);
+
+        // Songs already played keep their place, and the new ones are mixed
+        // into the rest without reordering it.
+        assert_eq!(list.order[..4], order[..4]);
+        assert_eq!(list.current(), Some(played[3]));
+        let rest = play(&mut list, true, 100);
+        let old: Vec<usize> = rest.iter().copied().filter(|&i| i < 10).collect();
+        assert_eq!(old, order[4..]);
+        let mut new: Vec<usize> = rest.iter().copied().filter(|&i| i >= 10).collect();
+        new.sort_unstable();
+        assert_eq!(new, (10..20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_add_shuffled_games() {
+        let entries = (0..12)
+            .map(|i| entry(&format!("Game {}", i % 3), &i.to_string()))
+            .collect();
+        let mut list = Playlist::new(entries, Repeat::Off, Shuffle::Games);
+        let mut played = play(&mut list, true, 2);
+        let game = list.entries()[played[1]].game.clone();
+        list.add(vec![
+            entry(&game, "12"),
+            entry("New", "13"),
+            entry("New", "14"),
+        ]);
+        played.extend(play(&mut list, true, 100));
+        assert_eq!(played.len(), 15);
+
+        // A song added to the current game plays after the rest of it, and
+        // each game still plays all together.
+        assert_eq!(played[4], 12);
+        let mut games: Vec<&str> = played
+            .iter()
+            .map(|&i| list.entries()[i].game.as_str())
+            .collect();
+        games.dedup();
+        assert_eq!(games.len(), 4);
+    }
+
+    #[test]
+    fn test_remove_shuffled() {
+        let mut list = playlist(8, Repeat::Off, Shuffle::Tracks);
+        let order: Vec<String> = play(&mut list, true, 10)
+            .into_iter()
+            .map(|i| list.entries()[i].title.clone())
+            .collect();
+        list.set_current(None);
+
+        // The songs still to play keep their order, without the one removed.
+        let removed = order[3].clone();
+        let index = list
+            .entries()
+            .iter()
+            .position(|e| e.title == removed)
+            .unwrap();
+        list.remove(index);
+        let titles: Vec<String> = play(&mut list, true, 10)
+            .into_iter()
+            .map(|i| list.entries()[i].title.clone())
+            .collect();
+        let expected: Vec<String> = order.into_iter().filter(|t| *t != removed).collect();
+        assert_eq!(titles, expected);
+    }
+
+    #[test]
+    fn test_move_entry() {
+        let mut list = playlist(5, Repeat::Off, Shuffle::Off);
+        list.set_current(Some(1));
+
+        // Moving other songs past the current one.
+        list.move_entry(0, 3);
+        assert_eq!(title(&list, list.current()), Some("1"));
+        assert_eq!(list.current(), Some(0));
+        list.move_entry(4, 0);
+        assert_eq!(title(&list, list.current()), Some("1"));
+        assert_eq!(list.current(), Some(1));
+
+        // Moving the current song.
+        list.move_entry(1, 4);
+        assert_eq!(list.current(), Some(4));
+        list.move_entry(4, 2);
+        assert_eq!(list.current(), Some(2));
+        let titles: Vec<_> = list.entries().iter().map(|e| e.title.as_str()).collect();
+        assert_eq!(titles, ["4", "2", "1", "3", "0"]);
+        // Without shuffle, songs play in the new order.
+        assert_eq!(play(&mut list, false, 10), [3, 4]);
+    }
+
+    #[test]
+    fn test_move_entry_shuffled() {
+        let mut list = playlist(8, Repeat::Off, Shuffle::Tracks);
+        let order: Vec<String> = play(&mut list, true, 10)
+            .into_iter()
+            .map(|i| list.entries()[i].title.clone())
+            .collect();
+        list.set_current(None);
+
+        // With shuffle, moving songs doesn't change what plays next.
+        list.move_entry(0, 7);
+        list.move_entry(5, 2);
+        let titles: Vec<String> = play(&mut list, true, 10)
+            .into_iter()
+            .map(|i| list.entries()[i].title.clone())
+            .collect();
+        assert_eq!(titles, order);
+    }
+
+    #[test]
+    fn test_shuffle_tracks() {
+        let mut list = playlist(20, Repeat::All, Shuffle::Off);
+        list.set_current(Some(7));
+        list.set_shuffle(Shuffle::Tracks);
+        // The current song stays first, so every other song plays before it
+        // repeats.
+        let mut played = play(&mut list, false, 20);
+        assert_eq!(played.last(), Some(&7));
+        played.sort_unstable();
+        assert_eq!(played, (0..20).collect::<Vec<_>>());
+
+        list.set_shuffle(Shuffle::Off);
+        list.set_current(Some(18));
+        assert_eq!(play(&mut list, false, 3), [19, 0, 1]);
+    }
+
+    #[test]
+    fn test_shuffle_games() {
+        let entries: Vec<PlaylistEntry> = (0..30)
+            .map(|i| entry(&format!("Game {}", i % 5), &i.to_string()))
+            .collect();
+        let mut list = Playlist::new(entries, Repeat::Off, Shuffle::Off);
+        list.set_current(Some(11));
+        list.set_shuffle(Shuffle::Games);
+
+        let mut played = vec![11];
+        played.extend(play(&mut list, false, 100));
+        assert_eq!(played.len(), 30);
+        let game = |i: usize| list.entries()[i].game.clone();
+
+        // Each game's songs play together, in playlist order, starting with
+        // the rest of the current song's game.
+        let games: Vec<Vec<usize>> = played.chunks(6).map(|chunk| chunk.to_vec()).collect();
+        assert_eq!(games[0], [11, 16, 21, 26, 1, 6]);
+        for songs in &games[1..] {
+            assert!(songs.iter().all(|&i| game(i) == game(songs[0])));
+            assert!(songs.windows(2).all(|pair| pair[0] < pair[1]));
+        }
+        let mut seen: Vec<String> = games.iter().map(|songs| game(songs[0])).collect();
+        seen.sort();
+        seen.dedup();
+        assert_eq!(seen.len(), 5);
+    }
+}
@@ -0,0 +1,393 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use rusqlite::{params, Connection};
+use snes_apu::dsp::voice::ResamplingMode;
+
+use crate::export::DEFAULT_TEMPLATE;
+use crate::loops::DEFAULT_LOOP_COUNT;
+use crate::loudness::ReplayGain;
+use crate::playlist::{Repeat, Shuffle};
+
+/// User preferences, stored as key-value pairs in the `settings` table.
+///
+/// Unknown keys and unparseable values are ignored when loading, so
+/// databases written by other versions of the program still load.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Settings {
+    /// Output device name, or None for the system default.
+    pub output_device: Option<String>,
+    /// Audio buffer size in frames, or None for the device default.
+    pub buffer_size: Option<u32>,
+    /// Linear gain applied to all output, from 0 to 1.
+    pub volume: f32,
+    pub interpolation: ResamplingMode,
+
+    /// Length to play songs whose tags don't specify one, or 0 to play forever.
+    pub default_length_secs: u32,
+    pub default_fade_ms: u32,
+    /// Times to play the loop of songs whose loop is known, before fading
+    /// out, or 0 to play forever. Takes precedence over tag lengths.
+    pub loop_count: u32,
+    /// Time to overlap the end of each song with the start of the next, or
+    /// 0 to play them back to back.
+    pub crossfade_ms: u32,
+    /// End songs after this many seconds of silence, or 0 to play silence
+    /// until the song's length.
+    pub silence_secs: u32,
+    /// Level below which output counts as silence, in dBFS.
+    pub silence_threshold_db: f32,
+    /// Skip silence at the start of songs.
+    pub trim_leading_silence: bool,
+
+    pub replay_gain: ReplayGain,
+    /// Loudness to adjust songs to, in LUFS.
+    pub target_lufs: f32,
+    /// Limit gain so songs' peaks don't clip.
+    pub prevent_clipping: bool,
+
+    pub remove_surround: bool,
+
+    /// Names of files written by batch export, as for
+    /// `export::expand_template()`.
+    pub export_template: String,
+    /// Silence between songs when rendering a soundtrack to one file, or 0
+    /// to overlap them by `crossfade_ms` as in playback.
+    pub soundtrack_gap_ms: u32,
+
+    pub repeat: Repeat,
+    pub shuffle: Shuffle,
+    /// Index of the selected playlist entry.
+    pub playlist_current: Option<usize>,
+
+    pub last_folder: Option<PathBuf>,
+    pub window_size: Option<(f32, f32)>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            output_device: None,
+            buffer_size: None,
+            volume: 1.0,
+            interpolation: ResamplingMode::Gaussian,
+            default_length_secs: 180,
+            default_fade_ms: 10000,
+            loop_count: DEFAULT_LOOP_COUNT,
+            crossfade_ms: 0,
+            silence_secs: 0,
+            silence_threshold_db: -60.0,
+            trim_leading_silence: false,
+            replay_gain: ReplayGain::Off,
+            target_lufs: -18.0,
+            prevent_clipping: true,
+            remove_surround: false,
+            export_template: DEFAULT_TEMPLATE.to_owned(),
+            soundtrack_gap_ms: 0,
+            repeat: Repeat::Off,
+            shuffle: Shuffle::Off,
+            playlist_current: None,
+            last_folder: None,
+            window_size: None,
+        }
+    }
+}
+
+fn interpolation_to_str(mode: ResamplingMode) -> &'static str {
+    match mode {
+        ResamplingMode::Gaussian => "gaussian",
+        ResamplingMode::Linear => "linear",
+    }
+}
+
+fn interpolation_from_str(s: &str) -> Option<ResamplingMode> {
+    match s {
+        "gaussian" => Some(ResamplingMode::Gaussian),
+        "linear" => Some(ResamplingMode::Linear),
+        _ => None,
+    }
+}
+
+fn repeat_to_str(repeat: Repeat) -> &'static str {
+    match repeat {
+        Repeat::Off => "off",
+        Repeat::One => "one",
+        Repeat::All => "all",
+    }
+}
+
+fn repeat_from_str(s: &str) -> Option<Repeat> {
+    match s {
+        "off" => Some(Repeat::Off),
+        "one" => Some(Repeat::One),
+        "all" => Some(Repeat::All),
+        _ => None,
+    }
+}
+
+fn shuffle_to_str(shuffle: Shuffle) -> &'static str {
+    match shuffle {
+        Shuffle::Off => "off",
+        Shuffle::Tracks => "tracks",
+        Shuffle::Games => "games",
+    }
+}
+
+fn shuffle_from_str(s: &str) -> Option<Shuffle> {
+    match s {
+        "off" => Some(Shuffle::Off),
+        "tracks" => Some(Shuffle::Tracks),
+        "games" => Some(Shuffle::Games),
+        _ => None,
+    }
+}
+
+fn replay_gain_to_str(replay_gain: ReplayGain) -> &'static str {
+    match replay_gain {
+        ReplayGain::Off => "off",
+        ReplayGain::Track => "track",
+        ReplayGain::Album => "album",
+    }
+}
+
+fn replay_gain_from_str(s: &str) -> Option<ReplayGain> {
+    match s {
+        "off" => Some(ReplayGain::Off),
+        "track" => Some(ReplayGain::Track),
+        "album" => Some(ReplayGain::Album),
+        _ => None,
+    }
+}
+
+impl Settings {
+    pub fn load(conn: &Connection) -> Result<Settings> {
+        let mut settings = Settings::default();
+
+        let mut stmt = conn.prepare("select key, value from settings")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        for row in rows {
+            let (key, value): (String, String) = row?;
+            settings.set(&key, &value);
+        }
+
+        Ok(settings)
+    }
+
+    pub fn save(&self, conn: &mut Connection) -> Result<()> {
+        let tx = conn.transaction()?;
+        for (key, value) in self.entries() {
+            match value {
+                Some(value) => tx.execute(
+                    "insert or replace into settings (key, value) values (?, ?)",
+                    params![key, value],
+                )?,
+                None => tx.execute("delete from settings where key = ?", params![key])?,
+            };
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Parses and stores a single value, leaving the default if it's invalid.
+    fn set(&mut self, key: &str, value: &str) {
+        fn parse<T: std::str::FromStr>(dest: &mut T, value: &str) {
+            if let Ok(value) = value.parse() {
+                *dest = value;
+            }
+        }
+
+        match key {
+            "output_device" => self.output_device = Some(value.to_owned()),
+            "buffer_size" => self.buffer_size = value.parse().ok(),
+            "volume" => parse(&mut self.volume, value),
+            "interpolation" => {
+                if let Some(mode) = interpolation_from_str(value) {
+                    self.interpolation = mode;
+                }
+            }
+            "default_length_secs" => parse(&mut self.default_length_secs, value),
+            "default_fade_ms" => parse(&mut self.default_fade_ms, value),
+            "loop_count" => parse(&mut self.loop_count, value),
+            "crossfade_ms" => parse(&mut self.crossfade_ms, value),
+            "silence_secs" => parse(&mut self.silence_secs, value),
+            "silence_threshold_db" => parse(&mut self.silence_threshold_db, value),
+            "trim_leading_silence" => parse(&mut self.trim_leading_silence, value),
+            "replay_gain" => {
+                if let Some(replay_gain) = replay_gain_from_str(value) {
+                    self.replay_gain = replay_gain;
+                }
+            }
+            "target_lufs" => parse(&mut self.target_lufs, value),
+            "prevent_clipping" => parse(&mut self.prevent_clipping, value),
+            "remove_surround" => parse(&mut self.remove_surround, value),
+            "export_template" => self.export_template = value.to_owned(),
+            "soundtrack_gap_ms" => parse(&mut self.soundtrack_gap_ms, value),
+            "repeat" => {
+                if let Some(repeat) = repeat_from_str(value) {
+                    self.repeat = repeat;
+                }
+            }
+            "shuffle" => {
+                if let Some(shuffle) = shuffle_from_str(value) {
+                    self.shuffle = shuffle;
+                }
+            }
+            "playlist_current" => self.playlist_current = value.parse().ok(),
+            "last_folder" => self.last_folder = Some(PathBuf::from(value)),
+            "window_size" => {
+                let mut it = value.split('x').map(str::parse);
+                if let (Some(Ok(w)), Some(Ok(h)), None) = (it.next(), it.next(), it.next()) {
+                    self.window_size = Some((w, h));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Every key and its value, or None if it should be removed.
+    fn entries(&self) -> Vec<(&'static str, Option<String>)> {
+        vec![
+            ("output_device", self.output_device.clone()),
+            ("buffer_size", self.buffer_size.map(|x| x.to_string())),
+            ("volume", Some(self.volume.to_string())),
+            (
+                "interpolation",
+                Some(interpolation_to_str(self.interpolation).to_owned()),
+            ),
+            (
+                "default_length_secs",
+                Some(self.default_length_secs.to_string()),
+            ),
+            ("default_fade_ms", Some(self.default_fade_ms.to_string())),
+            ("loop_count", Some(self.loop_count.to_string())),
+            ("crossfade_ms", Some(self.crossfade_ms.to_string())),
+            ("silence_secs", Some(self.silence_secs.to_string())),
+            (
+                "silence_threshold_db",
+                Some(self.silence_threshold_db.to_string()),
+            ),
+            (
+                "trim_leading_silence",
+                Some(self.trim_leading_silence.to_string()),
+            ),
+            (
+                "replay_gain",
+                Some(replay_gain_to_str(self.replay_gain).to_owned()),
+            ),
+            ("target_lufs", Some(self.target_lufs.to_string())),
+            ("prevent_clipping", Some(self.prevent_clipping.to_string())),
+            ("remove_surround", Some(self.remove_surround.to_string())),
+            ("export_template", Some(self.export_template.clone())),
+            (
+                "soundtrack_gap_ms",
+                Some(self.soundtrack_gap_ms.to_string()),
+            ),
+            ("repeat", Some(repeat_to_str(self.repeat).to_owned())),
+            ("shuffle", Some(shuffle_to_str(self.shuffle).to_owned())),
+            (
+                "playlist_current",
+                self.playlist_current.map(|x| x.to_string()),
+            ),
+            (
+                "last_folder",
+                self.last_folder
+                    .as_ref()
+                    .and_then(|p| p.to_str())
+                    .map(str::to_owned),
+            ),
+            (
+                "window_size",
+                self.window_size.map(|(w, h)| format!("{}x{}", w, h)),
+            ),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    fn open() -> Result<Connection> {
+        let mut conn = Connection::open_in_memory()?;
+        db::migrate(&mut conn)?;
+        Ok(conn)
+    }
+
+    #[test]
+    fn test_save_and_load() -> Result<()> {
+        let mut conn = open()?;
+        assert_eq!(Settings::load(&conn)?, Settings::default());
+
+        let settings = Settings {
+            output_device: Some("Speakers".to_owned()),
+            buffer_size: Some(512),
+            volume: 0.25,
+            interpolation: ResamplingMode::Linear,
+            default_length_secs: 0,
+            default_fade_ms: 2500,
+            loop_count: 3,
+            crossfade_ms: 1500,
+            silence_secs: 7,
+            silence_threshold_db: -72.5,
+            trim_leading_silence: true,
+            replay_gain: ReplayGain::Album,
+            target_lufs: -23.0,
+            prevent_clipping: false,
+            remove_surround: true,
+            export_template: "{game}/{track} x {title}".to_owned(),
+            soundtrack_gap_ms: 2000,
+            repeat: Repeat::All,
+            shuffle: Shuffle::Games,
+            playlist_current: Some(12),
+            last_folder: Some(PathBuf::from("/music/spc")),
+            window_size: Some((1280.0, 720.5)),
+        };
+        settings.save(&mut conn)?;
+        assert_eq!(Settings::load(&conn)?, settings);
+
+        // Values set back to None are removed.
+        Settings::default().save(&mut conn)?;
+        assert_eq!(Settings::load(&conn)?, Settings::default());
+        let count: u32 = conn.query_row(
+            "select count(*) from settings where key in ('output_device', 'window_size')",
+            [],
+            |row| row.get(0),
+        )?;
+        assert_eq!(count, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_values() -> Result<()> {
+        let conn = open()?;
+        for (key, value) in [
+            ("volume", "loud"),
+            ("interpolation", "cubic"),
+            ("default_length_secs", "-1"),
+            ("loop_count", "2.5"),
+            ("trim_leading_silence", "yes"),
+            ("replay_gain", "on"),
+            ("repeat", "twice"),
+            ("shuffle", ""),
+            ("buffer_size", "default"),
+            ("window_size", "1280x720x2"),
+            ("unknown_key", "1"),
+            ("crossfade_ms", "250"),
+        ] {
+            conn.execute(
+                "insert into settings (key, value) values (?, ?)",
+                params![key, value],
+            )?;
+        }
+
+        // Invalid values are left at their defaults, without stopping the
+        // valid ones loading.
+        let expected = Settings {
+            crossfade_ms: 250,
+            ..Settings::default()
+        };
+        assert_eq!(Settings::load(&conn)?, expected);
+        Ok(())
+    }
+}
@@ -0,0 +1,486 @@
+//! Writing a song's instrument samples as a SoundFont 2 bank, to play its
+//! MIDI export with.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use snes_apu::dsp::dsp::{COUNTER_RATES, SAMPLE_RATE};
+
+use crate::brr::{BrrSample, EnvelopeRegs};
+use crate::library::SpcTags;
+use crate::midi::DEFAULT_BASE_NOTE;
+use crate::wav::write_chunk;
+
+/// Silent samples the format requires after each sample.
+const SAMPLE_PADDING: usize = 46;
+/// Samples from the loop's start repeated after its end, for synths which
+/// interpolate past the end.
+const LOOP_GUARD: usize = 8;
+const NAME_LEN: usize = 20;
+
+// Generators.
+const ATTACK_VOL_ENV: u16 = 34;
+const DECAY_VOL_ENV: u16 = 36;
+const SUSTAIN_VOL_ENV: u16 = 37;
+const RELEASE_VOL_ENV: u16 = 38;
+const INSTRUMENT: u16 = 41;
+const FINE_TUNE: u16 = 52;
+const SAMPLE_ID: u16 = 53;
+const SAMPLE_MODES: u16 = 54;
+const OVERRIDING_ROOT_KEY: u16 = 58;
+
+/// Attenuation which synths treat as silence, as a decay of this much is
+/// the full decay time.
+const SILENCE_DB: f64 = 96.0;
+/// Attenuation of the sustain level, in centibels, at most.
+const MAX_SUSTAIN_CB: f64 = 1440.0;
+const MIN_TIMECENTS: f64 = -12000.0;
+const MAX_TIMECENTS: f64 = 8000.0;
+/// Envelope steps to go from silence to full in the attack, or to silence
+/// by linear decrease.
+const LINEAR_STEPS: f64 = (0x800 / 0x20) as f64;
+/// Each exponential step of the envelope is 255/256 of the last.
+const EXP_STEP_DB: f64 = 0.034;
+/// Key off lowers the envelope by 8 each sample, from at most $7ff.
+const RELEASE_SECS: f64 = 0x100 as f64 / SAMPLE_RATE as f64;
+
+/// A volume envelope, in SoundFont's terms.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VolumeEnvelope {
+    pub attack_secs: f64,
+    /// Time a decay from full to silence would take.
+    pub decay_secs: f64,
+    /// Attenuation while the note is held, after the decay.
+    pub sustain_db: f64,
+    pub release_secs: f64,
+}
+
+/// Whether a path names a SoundFont, going by its extension.
+pub fn is_sf2(path: &Path) -> bool {
+    matches!(path.extension().and_then(|ext| ext.to_str()), Some(ext) if ext.eq_ignore_ascii_case("sf2"))
+}
+
+/// Seconds between envelope steps at `rate`, or None if it never steps.
+fn step_secs(rate: u8) -> Option<f64> {
+    match rate & 0x1f {
+        0 => None,
+        rate => Some(COUNTER_RATES[rate as usize] as f64 / SAMPLE_RATE as f64),
+    }
+}
+
+/// Seconds taken to fall by `db` at an exponential `rate`, or None if it
+/// never falls.
+fn exp_decay_secs(rate: u8, db: f64) -> Option<f64> {
+    step_secs(rate).map(|secs| db / EXP_STEP_DB * secs)
+}
+
+/// Converts a voice's envelope registers to a SoundFont volume envelope.
+/// SoundFont envelopes hold at the sustain level, so ADSR envelopes which
+/// keep fading while held decay to silence instead, over the time the
+/// DSP takes to reach it. GAIN modes which change the level become an
+/// attack or a decay.
+pub fn volume_envelope(regs: EnvelopeRegs) -> VolumeEnvelope {
+    let mut envelope = VolumeEnvelope {
+        attack_secs: 0.0,
+        decay_secs: 0.0,
+        sustain_db: 0.0,
+        release_secs: RELEASE_SECS,
+    };
+
+    if regs.adsr0 & 0x80 != 0 {
+        let attack_rate = (regs.adsr0 & 0x0f) * 2 + 1;
+        let decay_rate = ((regs.adsr0 >> 3) & 0x0e) + 0x10;
+        let sustain_rate = regs.adsr1 & 0x1f;
+        let sustain_level = ((regs.adsr1 >> 5) + 1) as f64 / 8.0;
+        let sustain_db = -20.0 * sustain_level.log10();
+
+        // The fastest attack jumps to full in two steps.
+        let attack_steps = if attack_rate == 31 { 2.0 } else { LINEAR_STEPS };
+        envelope.attack_secs = attack_steps * step_secs(attack_rate).unwrap();
+        let decay_secs = exp_decay_secs(decay_rate, SILENCE_DB).unwrap();
+        match exp_decay_secs(sustain_rate, SILENCE_DB - sustain_db) {
+            None => {
+                envelope.decay_secs = decay_secs;
+                envelope.sustain_db = sustain_db;
+            }
+            Some(sustain_secs) => {
+                envelope.decay_secs = decay_secs * sustain_db / SILENCE_DB + sustain_secs;
+                envelope.sustain_db = SILENCE_DB;
+            }
+        }
+        return envelope;
+    }
+
+    let rate = regs.gain & 0x1f;
+    match regs.gain >> 5 {
+        // Direct: a fixed level.
+        mode if mode < 4 => {
+            let level = (regs.gain & 0x7f) as f64 * 0x10 as f64 / 0x7ff as f64;
+            envelope.sustain_db = if level > 0.0 {
+                -20.0 * level.log10()
+            } else {
+                SILENCE_DB
+            };
+        }
+        // Linear and exponential decrease.
+        mode @ 4..=5 => {
+            let decay_secs = if mode == 4 {
+                step_secs(rate).map(|secs| LINEAR_STEPS * secs)
+            } else {
+                exp_decay_secs(rate, SILENCE_DB)
+            };
+            if let Some(decay_secs) = decay_secs {
+                envelope.decay_secs = decay_secs;
+                envelope.sustain_db = SILENCE_DB;
+            }
+        }
+        // Linear increase, and bent line, which slows for the last quarter.
+        mode => {
+            let steps = if mode == 6 {
+                LINEAR_STEPS
+            } else {
+                (0x600 / 0x20 + 0x200 / 0x08) as f64
+            };
+            envelope.attack_secs = match step_secs(rate) {
+                Some(secs) => steps * secs,
+                None => f64::INFINITY,
+            };
+        }
+    }
+    envelope
+}
+
+/// Converts seconds to the timecents generators take.
+fn timecents(secs: f64) -> i16 {
+    if secs <= 0.0 {
+        return MIN_TIMECENTS as i16;
+    }
+    (1200.0 * secs.log2())
+        .round()
+        .clamp(MIN_TIMECENTS, MAX_TIMECENTS) as i16
+}
+
+/// A name padded or cut to the format's fixed length.
+fn name(text: &str) -> [u8; NAME_LEN] {
+    let mut name = [0; NAME_LEN];
+    for (dest, byte) in name[..NAME_LEN - 1].iter_mut().zip(text.bytes()) {
+        *dest = byte;
+    }
+    name
+}
+
+fn generator(gens: &mut Vec<u8>, oper: u16, amount: i16) {
+    gens.extend_from_slice(&oper.to_le_bytes());
+    gens.extend_from_slice(&amount.to_le_bytes());
+}
+
+fn bag(bags: &mut Vec<u8>, gen_index: usize) {
+    bags.extend_from_slice(&(gen_index as u16).to_le_bytes());
+    bags.extend_from_slice(&0u16.to_le_bytes());
+}
+
+/// The INFO list, naming the bank after the song.
+fn info_list(tags: &SpcTags) -> Vec<u8> {
+    let mut list = b"INFO".to_vec();
+    let mut version = 2u16.to_le_bytes().to_vec();
+    version.extend_from_slice(&1u16.to_le_bytes());
+    write_chunk(&mut list, b"ifil", &version).unwrap();
+    write_chunk(&mut list, b"isng", b"EMU8000\0").unwrap();
+    let title = if tags.title.is_empty() {
+        "Instruments"
+    } else {
+        &tags.title
+    };
+    let fields = [
+        (b"INAM", title),
+        (b"IPRD", tags.game.as_str()),
+        (b"IENG", tags.artist.as_str()),
+    ];
+    for (id, text) in fields.iter() {
+        if !text.is_empty() {
+            // Strings are padded to an even length with at least one zero.
+            let mut body = text.as_bytes().to_vec();
+            body.push(0);
+            if body.len() % 2 == 1 {
+                body.push(0);
+            }
+            write_chunk(&mut list, id, &body).unwrap();
+        }
+    }
+    write_chunk(&mut list, b"ISFT", b"spcplay-rs\0\0").unwrap();
+    list
+}
+
+/// Converts `samples` to a SoundFont 2 bank. Each source number becomes a
+/// preset, in bank `source >> 7` with program `source & 0x7f`, as
+/// `midi::to_midi()` selects them. The preset's instrument is tuned by
+/// `base_notes` (the note each sample plays at pitch $1000) and shaped by
+/// the envelope the song most often played the sample with.
+pub fn to_sf2(samples: &[BrrSample], tags: &SpcTags, base_notes: &HashMap<u8, f64>) -> Vec<u8> {
+    let mut sample_data = vec![];
+    let mut shdr = vec![];
+    let mut presets = vec![];
+    for (index, sample) in samples.iter().enumerate() {
+        let start = sample_data.len() / 2;
+        for value in &sample.samples {
+            sample_data.extend_from_slice(&value.to_le_bytes());
+        }
+        let end = start + sample.samples.len();
+        let (loop_start, loop_end) = match sample.loop_start {
+            Some(loop_start) => {
+                let guard = sample.samples[loop_start..].iter().cycle().take(LOOP_GUARD);
+                for value in guard {
+                    sample_data.extend_from_slice(&value.to_le_bytes());
+                }
+                (start + loop_start, end)
+            }
+            None => (start, start),
+        };
+        sample_data.resize(sample_data.len() + SAMPLE_PADDING * 2, 0);
+
+        shdr.extend_from_slice(&name(&format!("Sample {}", sample.sources[0])));
+        for &value in &[start, end, loop_start, loop_end] {
+            shdr.extend_from_slice(&(value as u32).to_le_bytes());
+        }
+        shdr.extend_from_slice(&(SAMPLE_RATE as u32).to_le_bytes());
+        shdr.push(DEFAULT_BASE_NOTE as u8);
+        shdr.push(0);
+        shdr.extend_from_slice(&0u16.to_le_bytes());
+        // Mono.
+        shdr.extend_from_slice(&1u16.to_le_bytes());
+
+        for &source in &sample.sources {
+            presets.push((source, index));
+        }
+    }
+    shdr.extend_from_slice(&name("EOS"));
+    shdr.resize(shdr.len() + 26, 0);
+    presets.sort_unstable();
+
+    // One preset per source, each with one zone using its instrument, which
+    // has one zone using the sample.
+    let (mut phdr, mut pbag, mut pgen) = (vec![], vec![], vec![]);
+    let (mut inst, mut ibag, mut igen) = (vec![], vec![], vec![]);
+    for (preset, &(source, index)) in presets.iter().enumerate() {
+        let sample = &samples[index];
+        let title = format!("Sample {}", source);
+
+        phdr.extend_from_slice(&name(&title));
+        phdr.extend_from_slice(&(source as u16 & 0x7f).to_le_bytes());
+        phdr.extend_from_slice(&(source as u16 >> 7).to_le_bytes());
+        phdr.extend_from_slice(&(preset as u16).to_le_bytes());
+        phdr.resize(phdr.len() + 12, 0);
+        bag(&mut pbag, pgen.len() / 4);
+        generator(&mut pgen, INSTRUMENT, preset as i16);
+
+        inst.extend_from_slice(&name(&title));
+        inst.extend_from_slice(&(preset as u16).to_le_bytes());
+        bag(&mut ibag, igen.len() / 4);
+        // A root key and fine tune which together make the base note.
+        let base_note = base_notes
+            .get(&source)
+            .copied()
+            .unwrap_or(DEFAULT_BASE_NOTE)
+            .clamp(0.0, 127.0);
+        let root_key = base_note.round();
+        let fine_tune = ((root_key - base_note) * 100.0).round();
+        generator(&mut igen, OVERRIDING_ROOT_KEY, root_key as i16);
+        generator(&mut igen, FINE_TUNE, fine_tune as i16);
+        if let Some(regs) = sample.envelope(source) {
+            let envelope = volume_envelope(regs);
+            let sustain_cb = (envelope.sustain_db * 10.0)
+                .round()
+                .clamp(0.0, MAX_SUSTAIN_CB);
+            generator(&mut igen, ATTACK_VOL_ENV, timecents(envelope.attack_secs));
+            generator(&mut igen, DECAY_VOL_ENV, timecents(envelope.decay_secs));
+            generator(&mut igen, SUSTAIN_VOL_ENV, sustain_cb as i16);
+            generator(&mut igen, RELEASE_VOL_ENV, timecents(envelope.release_secs));
+        }
+        let sample_mode = if sample.loop_start.is_some() { 1 } else { 0 };
+        generator(&mut igen, SAMPLE_MODES, sample_mode);
+        generator(&mut igen, SAMPLE_ID, index as i16);
+    }
+    // Terminal records, which also end the last zone.
+    phdr.extend_from_slice(&name("EOP"));
+    phdr.resize(phdr.len() + 4, 0);
+    phdr.extend_from_slice(&(presets.len() as u16).to_le_bytes());
+    phdr.resize(phdr.len() + 12, 0);
+    bag(&mut pbag, pgen.len() / 4);
+    pgen.resize(pgen.len() + 4, 0);
+    inst.extend_from_slice(&name("EOI"));
+    inst.extend_from_slice(&(presets.len() as u16).to_le_bytes());
+    bag(&mut ibag, igen.len() / 4);
+    igen.resize(igen.len() + 4, 0);
+    let no_modulators = [0; 10];
+
+    let mut sdta = b"sdta".to_vec();
+    write_chunk(&mut sdta, b"smpl", &sample_data).unwrap();
+    let mut pdta = b"pdta".to_vec();
+    let chunks: [(&[u8; 4], &[u8]); 9] = [
+        (b"phdr", &phdr),
+        (b"pbag", &pbag),
+        (b"pmod", &no_modulators),
+        (b"pgen", &pgen),
+        (b"inst", &inst),
+        (b"ibag", &ibag),
+        (b"imod", &no_modulators),
+        (b"igen", &igen),
+        (b"shdr", &shdr),
+    ];
+    for (id, body) in chunks.iter() {
+        write_chunk(&mut pdta, id, body).unwrap();
+    }
+
+    let mut body = b"sfbk".to_vec();
+    write_chunk(&mut body, b"LIST", &info_list(tags)).unwrap();
+    write_chunk(&mut body, b"LIST", &sdta).unwrap();
+    write_chunk(&mut body, b"LIST", &pdta).unwrap();
+    let mut out = vec![];
+    write_chunk(&mut out, b"RIFF", &body).unwrap();
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brr;
+    use anyhow::Result;
+    use spc::Spc;
+    use std::convert::TryInto;
+
+    /// Splits a list of chunks into their IDs and bodies.
+    fn chunks(data: &[u8]) -> Vec<(&[u8], &[u8])> {
+        let mut chunks = vec![];
+        let mut pos = 0;
+        while pos < data.len() {
+            let len = u32::from_le_bytes(data[pos + 4..pos + 8].try_into().unwrap()) as usize;
+            chunks.push((&data[pos..pos + 4], &data[pos + 8..pos + 8 + len]));
+            pos += 8 + len + len % 2;
+        }
+        chunks
+    }
+
+    fn u16_at(data: &[u8], pos: usize) -> u16 {
+        u16::from_le_bytes([data[pos], data[pos + 1]])
+    }
+
+    fn u32_at(data: &[u8], pos: usize) -> u32 {
+        u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_volume_envelope() {
+        // ADSR with the fastest attack, which holds at half level.
+        let held = volume_envelope(EnvelopeRegs {
+            adsr0: 0x8f,
+            adsr1: 0x60,
+            gain: 0,
+        });
+        assert!(held.attack_secs < 0.001);
+        assert!((held.sustain_db - 6.02).abs() < 0.01);
+        assert!(held.decay_secs > 0.0);
+        assert_eq!(held.release_secs, RELEASE_SECS);
+
+        // The same, but fading while held.
+        let fading = volume_envelope(EnvelopeRegs {
+            adsr0: 0x8f,
+            adsr1: 0x6a,
+            gain: 0,
+        });
+        assert_eq!(fading.sustain_db, SILENCE_DB);
+        assert!(fading.decay_secs > held.decay_secs * held.sustain_db / SILENCE_DB);
+
+        // The slowest ADSR attack takes 4.1 seconds.
+        let slow = volume_envelope(EnvelopeRegs {
+            adsr0: 0x80,
+            adsr1: 0xe0,
+            gain: 0,
+        });
+        assert!(
+            (slow.attack_secs - 4.1).abs() < 0.05,
+            "{}",
+            slow.attack_secs
+        );
+
+        // Direct GAIN at half level.
+        let direct = volume_envelope(EnvelopeRegs {
+            adsr0: 0,
+            adsr1: 0,
+            gain: 0x40,
+        });
+        assert_eq!(direct.attack_secs, 0.0);
+        assert!((direct.sustain_db - 6.02).abs() < 0.01);
+
+        assert_eq!(timecents(0.0), -12000);
+        assert_eq!(timecents(1.0), 0);
+        assert_eq!(timecents(f64::INFINITY), 8000);
+    }
+
+    #[test]
+    fn test_to_sf2() -> Result<()> {
+        let spc = Spc::load("3rdparty/snes-apu/test/ferris-nu.spc")?;
+        let samples = brr::extract(&spc);
+        let tags = SpcTags {
+            title: "Song".to_owned(),
+            ..SpcTags::default()
+        };
+        let mut base_notes = brr::base_notes(&samples);
+        let tuned = samples[0].sources[0];
+        base_notes.insert(tuned, 69.7);
+        let data = to_sf2(&samples, &tags, &base_notes);
+
+        let riff = chunks(&data);
+        assert_eq!(riff.len(), 1);
+        assert_eq!(riff[0].0, b"RIFF");
+        assert_eq!(&riff[0].1[..4], b"sfbk");
+        let lists = chunks(&riff[0].1[4..]);
+        let ids: Vec<&[u8]> = lists.iter().map(|(_, body)| &body[..4]).collect();
+        assert_eq!(ids, [&b"INFO"[..], b"sdta", b"pdta"]);
+        let info = chunks(&lists[0].1[4..]);
+        assert_eq!(info[0], (&b"ifil"[..], &[2, 0, 1, 0][..]));
+        assert!(info.contains(&(&b"INAM"[..], &b"Song\0\0"[..])));
+
+        let pdta: HashMap<&[u8], &[u8]> = chunks(&lists[2].1[4..]).into_iter().collect();
+        let sources: usize = samples.iter().map(|sample| sample.sources.len()).sum();
+        assert_eq!(pdta[&b"phdr"[..]].len(), (sources + 1) * 38);
+        assert_eq!(pdta[&b"inst"[..]].len(), (sources + 1) * 22);
+        assert_eq!(pdta[&b"shdr"[..]].len(), (samples.len() + 1) * 46);
+
+        // Sample headers point into the sample data, with the loop.
+        let smpl = chunks(&lists[1].1[4..]);
+        let sample_count = smpl[0].1.len() as u32 / 2;
+        let shdr = pdta[&b"shdr"[..]];
+        for (i, sample) in samples.iter().enumerate() {
+            let header = &shdr[i * 46..];
+            let (start, end) = (u32_at(header, 20), u32_at(header, 24));
+            assert_eq!((end - start) as usize, sample.samples.len());
+            assert!(end + SAMPLE_PADDING as u32 <= sample_count);
+            let first = u16_at(smpl[0].1, start as usize * 2) as i16;
+            assert_eq!(first, sample.samples[0]);
+            if let Some(loop_start) = sample.loop_start {
+                assert_eq!(u32_at(header, 28), start + loop_start as u32);
+                assert_eq!(u32_at(header, 32), end);
+            }
+        }
+
+        // Presets select the sample by source number, as MIDI programs do.
+        let phdr = pdta[&b"phdr"[..]];
+        let igen = pdta[&b"igen"[..]];
+        let ibag = pdta[&b"ibag"[..]];
+        let preset = (0..sources)
+            .find(|&i| u16_at(phdr, i * 38 + 20) == tuned as u16 & 0x7f)
+            .unwrap();
+        assert_eq!(u16_at(phdr, preset * 38 + 22), tuned as u16 >> 7);
+        let gens =
+            &igen[u16_at(ibag, preset * 4) as usize * 4..u16_at(ibag, preset * 4 + 4) as usize * 4];
+        let gens: Vec<(u16, i16)> = gens
+            .chunks(4)
+            .map(|gen| (u16_at(gen, 0), u16_at(gen, 2) as i16))
+            .collect();
+        assert!(gens.contains(&(OVERRIDING_ROOT_KEY, 70)));
+        assert!(gens.contains(&(FINE_TUNE, 30)));
+        assert!(gens.iter().any(|&(oper, _)| oper == SUSTAIN_VOL_ENV));
+        assert_eq!(gens.last(), Some(&(SAMPLE_ID, 0)));
+        Ok(())
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::{Arc, Condvar, Mutex};
+
+use anyhow::{Context, Result};
+use cpal::traits::{DeviceTrait, StreamTrait};
+use snes_apu::dsp::dsp::SAMPLE_RATE;
+
+use crate::settings::Settings;
+use crate::spcplay::open_device;
+
+/// Somewhere to send rendered audio: interleaved 16-bit stereo at the SNES's
+/// sample rate.
+pub trait AudioSink {
+    fn write(&mut self, samples: &[i16]) -> Result<()>;
+
+    /// Called once after the last write, to flush anything buffered.
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes samples as raw little-endian PCM, eg. to stdout.
+pub struct RawSink<W: Write> {
+    w: W,
+    bytes: Vec<u8>,
+}
+
+impl<W: Write> RawSink<W> {
+    pub fn new(w: W) -> Self {
+        RawSink { w, bytes: vec![] }
+    }
+}
+
+impl<W: Write> AudioSink for RawSink<W> {
+    fn write(&mut self, samples: &[i16]) -> Result<()> {
+        self.bytes.clear();
+        for sample in samples {
+            self.bytes.extend_from_slice(&sample.to_le_bytes());
+        }
+        self.w.write_all(&self.bytes)?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.w.flush()?;
+        Ok(())
+    }
+}
+
+/// Discards samples, counting them.
+#[cfg(test)]
+#[derive(Default)]
+pub struct NullSink {
+    pub frames: u64,
+}
+
+#[cfg(test)]
+impl AudioSink for NullSink {
+    fn write(&mut self, samples: &[i16]) -> Result<()> {
+        self.frames += (samples.len() / 2) as u64;
+        Ok(())
+    }
+}
+
+/// Keeps samples in memory, eg. to process a whole song at once.
+#[derive(Default)]
+pub struct MemorySink {
+    pub samples: Vec<i16>,
+}
+
+impl AudioSink for MemorySink {
+    fn write(&mut self, samples: &[i16]) -> Result<()> {
+        self.samples.extend_from_slice(samples);
+        Ok(())
+    }
+}
+
+/// Audio written but not yet played, shared with the audio thread.
+struct Queue {
+    samples: Mutex<VecDeque<i16>>,
+    /// Notified when the audio thread takes samples.
+    played: Condvar,
+}
+
+/// Plays samples on the output device. Writes block while more than
+/// `CPAL_QUEUE_FRAMES` are waiting to be played. Unlike `AudioOutput`, the
+/// caller renders the audio, so it can't switch songs without a gap.
+pub struct CpalSink {
+    queue: Arc<Queue>,
+    /// Dropping the stream closes the device.
+    _stream: cpal::Stream,
+}
+
+const CPAL_QUEUE_FRAMES: usize = SAMPLE_RATE / 4;
+
+impl CpalSink {
+    /// Opens the output device chosen in `settings`.
+    pub fn new(settings: &Settings) -> Result<CpalSink> {
+        let (device, config) = open_device(settings)?;
+        let queue = Arc::new(Queue {
+            samples: Mutex::new(VecDeque::new()),
+            played: Condvar::new(),
+        });
+
+        let err_fn = |err| eprintln!("an error occurred on the input audio stream: {}", err);
+
+        let stream = {
+            let queue = queue.clone();
+            device
+                .build_output_stream(
+                    &config,
+                    move |data: &mut [i16], _info| {
+                        let mut samples = queue.samples.lock().unwrap();
+                        for out in data {
+                            // Play silence if the caller falls behind.
+                            *out = samples.pop_front().unwrap_or(0);
+                        }
+                        queue.played.notify_all();
+                    },
+                    err_fn,
+                )
+                .context("Error building output stream")?
+        };
+        stream.play().context("Error playing audio device")?;
+
+        Ok(CpalSink {
+            queue,
+            _stream: stream,
+        })
+    }
+
+    /// Blocks until at most `frames` are waiting to be played.
+    fn wait_until_queued(&self, frames: usize) {
+        let mut samples = self.queue.samples.lock().unwrap();
+        while samples.len() > frames * 2 {
+            samples = self.queue.played.wait(samples).unwrap();
+        }
+    }
+}
+
+impl AudioSink for CpalSink {
+    fn write(&mut self, samples: &[i16]) -> Result<()> {
+        self.wait_until_queued(CPAL_QUEUE_FRAMES);
+        self.queue.samples.lock().unwrap().extend(samples);
+        Ok(())
+    }
+
+    /// Waits for everything written to finish playing.
+    fn finish(&mut self) -> Result<()> {
+        self.wait_until_queued(0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spcplay::test_player;
+    use crate::wav::{SampleFormat, WavWriter};
+    use std::convert::TryInto;
+    use std::fs;
+
+    const SPC_PATH: &str = "3rdparty/snes-apu/test/ferris-nu.spc";
+
+    #[test]
+    fn test_render_length_and_fade() -> Result<()> {
+        let mut sink = NullSink::default();
+        test_player(SPC_PATH, 2000, 500)?.render_to(&mut sink)?;
+        assert_eq!(sink.frames, 2500 * SAMPLE_RATE as u64 / 1000);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wav_matches_render() -> Result<()> {
+        let mut memory = MemorySink::default();
+        test_player(SPC_PATH, 2000, 500)?.render_to(&mut memory)?;
+
+        let path = std::env::temp_dir().join("spcplay-rs-test_wav_matches_render.wav");
+        let mut player = test_player(SPC_PATH, 2000, 500)?;
+        let mut wav = WavWriter::create(&path, SampleFormat::Int16, &player.tags())?;
+        player.render_to(&mut wav)?;
+        drop(wav);
+        let bytes = fs::read(&path)?;
+        fs::remove_file(&path)?;
+
+        assert_eq!(&bytes[..4], b"RIFF");
+        let riff_len = u32::from_le_bytes(bytes[4..8].try_into()?) as usize;
+        assert_eq!(riff_len, bytes.len() - 8);
+
+        // Walk the chunks to find the data.
+        let mut pos = 12;
+        let data = loop {
+            let len = u32::from_le_bytes(bytes[pos + 4..pos + 8].try_into()?) as usize;
+            let body = &bytes[pos + 8..pos + 8 + len];
+            if &bytes[pos..pos + 4] == b"data" {
+                break body;
+            }
+            pos += 8 + len + len % 2;
+        };
+        let samples: Vec<i16> = data
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        assert_eq!(samples, memory.samples);
+        Ok(())
+    }
+}
@@ -0,0 +1,527 @@
+//! Rendering a playlist or game as one continuous file, with each song's title
+//! and start time in a cue sheet and chapter markers, for players without SPC
+//! support.
+
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+
+use anyhow::{anyhow, bail, Context, Result};
+use rusqlite::{Connection, OpenFlags};
+use snes_apu::dsp::dsp::SAMPLE_RATE;
+
+use crate::export::{self, ExportJob};
+use crate::flac::{is_flac, FlacWriter};
+use crate::library::SpcTags;
+use crate::settings::Settings;
+use crate::sink::{AudioSink, RawSink};
+use crate::wav::{SampleFormat, WavWriter};
+
+/// Cue sheets count time in CD frames.
+const CD_FRAMES_PER_SECOND: u64 = 75;
+
+/// A song in the soundtrack.
+#[derive(Clone, Debug)]
+pub struct Chapter {
+    /// Frame where the song starts, including any crossfade into it.
+    pub start: u64,
+    pub tags: SpcTags,
+    pub path: PathBuf,
+}
+
+impl Chapter {
+    /// The song's title, or its file name if it has none.
+    pub fn title(&self) -> String {
+        if self.tags.title.is_empty() {
+            let name = self.path.file_stem().unwrap_or_default();
+            name.to_string_lossy().into_owned()
+        } else {
+            self.tags.title.clone()
+        }
+    }
+}
+
+/// The songs rendered by `render_soundtrack()`, and the files which couldn't
+/// be, and why.
+#[derive(Default, Debug)]
+pub struct Soundtrack {
+    pub chapters: Vec<Chapter>,
+    pub errors: Vec<(PathBuf, String)>,
+}
+
+/// Renders songs back to back into `sink`, without finishing it. Songs are
+/// separated by `settings.soundtrack_gap_ms` of silence, or if that's 0,
+/// overlapped by `settings.crossfade_ms` as in playback. Songs which can't
+/// be loaded, or play forever, are skipped.
+pub fn render_soundtrack(
+    jobs: &[ExportJob],
+    settings: &Settings,
+    library: Option<&Connection>,
+    sink: &mut dyn AudioSink,
+) -> Result<Soundtrack> {
+    let to_frames = |ms: u32| (ms as u64 * SAMPLE_RATE as u64 / 1000) as usize;
+    let gap_frames = to_frames(settings.soundtrack_gap_ms);
+    let crossfade_frames = if gap_frames > 0 {
+        0
+    } else {
+        to_frames(settings.crossfade_ms)
+    };
+
+    let mut soundtrack = Soundtrack::default();
+    let mut buffer = vec![0; SAMPLE_RATE * 2];
+    // Audio which hasn't been written yet, starting at frame `written`. Between
+    // songs, it holds the end of the last song, to crossfade with the next.
+    let mut pending: Vec<i16> = vec![];
+    let mut written = 0;
+
+    for job in jobs {
+        let mut player = match export::load_player(job, settings, library) {
+            Ok(player) => player,
+            Err(err) => {
+                soundtrack
+                    .errors
+                    .push((job.path.clone(), format!("{:#}", err)));
+                continue;
+            }
+        };
+        if gap_frames > 0 && !soundtrack.chapters.is_empty() {
+            let silence = vec![0; gap_frames * 2];
+            sink.write(&silence)?;
+            written += gap_frames as u64;
+        }
+        soundtrack.chapters.push(Chapter {
+            start: written,
+            tags: player.tags(),
+            path: job.path.clone(),
+        });
+
+        // The outgoing song has already faded out by itself, so only the
+        // incoming song fades in.
+        let fade_len = pending.len() / 2;
+        let mut pos = 0;
+        while !player.is_finished() {
+            let frames = player.render(&mut buffer);
+            for frame in buffer[..frames * 2].chunks_exact(2) {
+                if pos < fade_len {
+                    let gain = pos as f32 / fade_len as f32;
+                    for (channel, &sample) in frame.iter().enumerate() {
+                        let outgoing = &mut pending[pos * 2 + channel];
+                        *outgoing = (*outgoing as f32 + sample as f32 * gain)
+                            .clamp(i16::MIN as f32, i16::MAX as f32)
+                            as i16;
+                    }
+                } else {
+                    pending.extend_from_slice(frame);
+                }
+                pos += 1;
+            }
+
+            // Keep back what the next song may crossfade with.
+            let ready = pos.min((pending.len() / 2).saturating_sub(crossfade_frames));
+            sink.write(&pending[..ready * 2])?;
+            pending.drain(..ready * 2);
+            written += ready as u64;
+        }
+    }
+    sink.write(&pending)?;
+    Ok(soundtrack)
+}
+
+/// Tags for the whole soundtrack: the game and artist if every song shares
+/// them, or else `name`.
+pub fn album_tags(chapters: &[Chapter], name: &str) -> SpcTags {
+    let shared = |field: fn(&SpcTags) -> &String| {
+        let first = chapters.first().map(|chapter| field(&chapter.tags))?;
+        let all_same = chapters.iter().all(|chapter| field(&chapter.tags) == first);
+        Some(first.clone()).filter(|value| all_same && !value.is_empty())
+    };
+    let game = shared(|tags| &tags.game).unwrap_or_else(|| name.to_owned());
+    SpcTags {
+        title: game.clone(),
+        game,
+        artist: shared(|tags| &tags.artist).unwrap_or_default(),
+        amplification: 1.0,
+        ..SpcTags::default()
+    }
+}
+
+/// Formats a frame as a cue sheet time, `MM:SS:FF`.
+fn cue_time(frame: u64) -> String {
+    let cd_frames = frame * CD_FRAMES_PER_SECOND / SAMPLE_RATE as u64;
+    format!(
+        "{:02}:{:02}:{:02}",
+        cd_frames / CD_FRAMES_PER_SECOND / 60,
+        cd_frames / CD_FRAMES_PER_SECOND % 60,
+        cd_frames % CD_FRAMES_PER_SECOND
+    )
+}
+
+/// Quotes a cue sheet field. Cue sheets have no escapes, so double quotes
+/// become single quotes.
+fn cue_string(value: &str) -> String {
+    format!("\"{}\"", value.replace('"', "'").replace(['\r', '\n'], " "))
+}
+
+/// A cue sheet listing each song in `file_name`, which is in the same folder.
+pub fn cue_sheet(chapters: &[Chapter], album: &SpcTags, file_name: &str) -> String {
+    let mut cue = String::new();
+    if !album.artist.is_empty() {
+        cue += &format!("PERFORMER {}\n", cue_string(&album.artist));
+    }
+    cue += &format!("TITLE {}\n", cue_string(&album.title));
+    cue += &format!("FILE {} WAVE\n", cue_string(file_name));
+    for (i, chapter) in chapters.iter().enumerate() {
+        cue += &format!("  TRACK {:02} AUDIO\n", i + 1);
+        cue += &format!("    TITLE {}\n", cue_string(&chapter.title()));
+        if !chapter.tags.artist.is_empty() {
+            cue += &format!("    PERFORMER {}\n", cue_string(&chapter.tags.artist));
+        }
+        cue += &format!("    INDEX 01 {}\n", cue_time(chapter.start));
+    }
+    cue
+}
+
+/// Chapters as Vorbis comments, `CHAPTER001=00:00:00.000` and
+/// `CHAPTER001NAME=title`.
+fn chapter_comments(chapters: &[Chapter]) -> Vec<(String, String)> {
+    let mut comments = vec![];
+    for (i, chapter) in chapters.iter().enumerate() {
+        let ms = chapter.start * 1000 / SAMPLE_RATE as u64;
+        let time = format!(
+            "{:02}:{:02}:{:02}.{:03}",
+            ms / 3_600_000,
+            ms / 60_000 % 60,
+            ms / 1000 % 60,
+            ms % 1000
+        );
+        comments.push((format!("CHAPTER{:03}", i + 1), time));
+        comments.push((format!("CHAPTER{:03}NAME", i + 1), chapter.title()));
+    }
+    comments
+}
+
+/// Renders songs into one WAV or FLAC file at `output`, with chapter markers,
+/// and writes a cue sheet next to it. WAV files get a marker at the start of
+/// each song, and FLAC files get `CHAPTER` comments. Returns the files which
+/// were skipped, and why.
+pub fn render_to_file(
+    jobs: &[ExportJob],
+    settings: &Settings,
+    library: Option<&Connection>,
+    output: &Path,
+    format: SampleFormat,
+) -> Result<Vec<(PathBuf, String)>> {
+    let name = output.file_stem().unwrap_or_default().to_string_lossy();
+    let soundtrack = if is_flac(output) {
+        if format != SampleFormat::Int16 {
+            bail!("FLAC files can only be 16-bit");
+        }
+        // Chapters aren't known until the songs have been rendered, and
+        // FLAC tags come before the audio, so render to a temporary file.
+        let mut raw = output.as_os_str().to_owned();
+        raw.push(".part");
+        let raw = PathBuf::from(raw);
+        let result = render_flac(jobs, settings, library, output, &raw, &name);
+        let _ = fs::remove_file(&raw);
+        result?
+    } else {
+        let mut wav = WavWriter::create(output, format, &SpcTags::default())?;
+        let soundtrack = render_soundtrack(jobs, settings, library, &mut wav)?;
+        wav.set_markers(
+            soundtrack
+                .chapters
+                .iter()
+                .map(|chapter| (chapter.start, chapter.title()))
+                .collect(),
+        );
+        wav.finish()?;
+        soundtrack
+    };
+    if soundtrack.chapters.is_empty() {
+        let _ = fs::remove_file(output);
+        bail!("None of the songs could be rendered");
+    }
+
+    let album = album_tags(&soundtrack.chapters, &name);
+    let file_name = output.file_name().unwrap_or_default().to_string_lossy();
+    let cue_path = output.with_extension("cue");
+    fs::write(
+        &cue_path,
+        cue_sheet(&soundtrack.chapters, &album, &file_name),
+    )
+    .with_context(|| format!("Could not save {}", cue_path.display()))?;
+    Ok(soundtrack.errors)
+}
+
+fn render_flac(
+    jobs: &[ExportJob],
+    settings: &Settings,
+    library: Option<&Connection>,
+    output: &Path,
+    raw: &Path,
+    name: &str,
+) -> Result<Soundtrack> {
+    let soundtrack = {
+        let file =
+            File::create(raw).with_context(|| format!("Could not create {}", raw.display()))?;
+        let mut sink = RawSink::new(BufWriter::new(file));
+        let soundtrack = render_soundtrack(jobs, settings, library, &mut sink)?;
+        sink.finish()?;
+        soundtrack
+    };
+
+    let album = album_tags(&soundtrack.chapters, name);
+    let comments = chapter_comments(&soundtrack.chapters);
+    let comments: Vec<(&str, String)> = comments
+        .iter()
+        .map(|(key, value)| (key.as_str(), value.clone()))
+        .collect();
+    let mut flac = FlacWriter::create(output, &album, &comments)?;
+
+    let file = File::open(raw).with_context(|| format!("Could not read {}", raw.display()))?;
+    let mut reader = BufReader::new(file);
+    let mut bytes = vec![0; SAMPLE_RATE * 4];
+    let mut samples = vec![];
+    loop {
+        // Fill the buffer, so that stereo frames aren't split between reads.
+        let mut len = 0;
+        while len < bytes.len() {
+            match reader.read(&mut bytes[len..])? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        samples.clear();
+        samples.extend(
+            bytes[..len]
+                .chunks_exact(2)
+                .map(|pair| i16::from_le_bytes([pair[0], pair[1]])),
+        );
+        flac.write(&samples)?;
+        if len < bytes.len() {
+            break;
+        }
+    }
+    flac.finish()?;
+    Ok(soundtrack)
+}
+
+/// Renders a soundtrack to one file on a background thread.
+pub struct SoundtrackExport {
+    result: Receiver<Result<Vec<(PathBuf, String)>>>,
+}
+
+impl SoundtrackExport {
+    /// Starts rendering `jobs` to `output` as `render_to_file()` does, using
+    /// loops detected in the library at `db_path`, if given. `notify` is
+    /// called when it finishes.
+    pub fn start(
+        jobs: Vec<ExportJob>,
+        output: PathBuf,
+        settings: Settings,
+        db_path: Option<PathBuf>,
+        notify: Arc<dyn Fn() + Send + Sync>,
+    ) -> SoundtrackExport {
+        let (tx, result) = channel();
+        thread::spawn(move || {
+            let library = db_path.and_then(|db_path| {
+                Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY).ok()
+            });
+            let _ = tx.send(render_to_file(
+                &jobs,
+                &settings,
+                library.as_ref(),
+                &output,
+                SampleFormat::Int16,
+            ));
+            notify();
+        });
+        SoundtrackExport { result }
+    }
+
+    /// Returns the files which were skipped, and why, once the file has been
+    /// written.
+    pub fn try_finish(&self) -> Option<Result<Vec<(PathBuf, String)>>> {
+        match self.result.try_recv() {
+            Ok(result) => Some(result),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err(anyhow!("export crashed"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::MemorySink;
+    use crate::spcplay::{test_overrides, TrackOverrides};
+
+    const SPC_PATHS: [&str; 2] = [
+        "3rdparty/snes-apu/test/ferris-nu.spc",
+        "3rdparty/snes-apu/test/smashit.spc",
+    ];
+
+    fn jobs() -> Vec<ExportJob> {
+        let mut jobs: Vec<ExportJob> = SPC_PATHS
+            .iter()
+            .map(|path| ExportJob {
+                path: PathBuf::from(path),
+                overrides: test_overrides(2000, 500),
+            })
+            .collect();
+        jobs.insert(
+            1,
+            ExportJob {
+                path: PathBuf::from("missing.spc"),
+                overrides: TrackOverrides::default(),
+            },
+        );
+        jobs
+    }
+
+    fn render_song(job: &ExportJob, settings: &Settings) -> Result<Vec<i16>> {
+        let mut sink = MemorySink::default();
+        export::load_player(job, settings, None)?.render_to(&mut sink)?;
+        Ok(sink.samples)
+    }
+
+    #[test]
+    fn test_crossfade() -> Result<()> {
+        let settings = Settings {
+            crossfade_ms: 1000,
+            ..Settings::default()
+        };
+        let jobs = jobs();
+        let first = render_song(&jobs[0], &settings)?;
+        let second = render_song(&jobs[2], &settings)?;
+
+        let mut sink = MemorySink::default();
+        let soundtrack = render_soundtrack(&jobs, &settings, None, &mut sink)?;
+        assert_eq!(soundtrack.errors.len(), 1);
+        assert_eq!(soundtrack.errors[0].0, Path::new("missing.spc"));
+
+        let crossfade = SAMPLE_RATE * 2;
+        let second_start = first.len() - crossfade;
+        assert_eq!(soundtrack.chapters.len(), 2);
+        assert_eq!(soundtrack.chapters[0].start, 0);
+        assert_eq!(soundtrack.chapters[1].start, (second_start / 2) as u64);
+        assert_eq!(sink.samples.len(), second_start + second.len());
+        assert_eq!(sink.samples[..second_start], first[..second_start]);
+        assert_eq!(
+            sink.samples[first.len()..],
+            second[crossfade..],
+            "the second song continues after the crossfade"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_gap() -> Result<()> {
+        let settings = Settings {
+            crossfade_ms: 1000,
+            soundtrack_gap_ms: 500,
+            ..Settings::default()
+        };
+        let jobs = jobs();
+        let first = render_song(&jobs[0], &settings)?;
+        let second = render_song(&jobs[2], &settings)?;
+
+        let mut sink = MemorySink::default();
+        let soundtrack = render_soundtrack(&jobs, &settings, None, &mut sink)?;
+        let gap = SAMPLE_RATE;
+        assert_eq!(
+            soundtrack.chapters[1].start,
+            ((first.len() + gap) / 2) as u64
+        );
+        assert_eq!(sink.samples[..first.len()], first[..]);
+        assert!(sink.samples[first.len()..first.len() + gap]
+            .iter()
+            .all(|&sample| sample == 0));
+        assert_eq!(sink.samples[first.len() + gap..], second[..]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cue_sheet() {
+        let chapter = |start, title: &str, path: &str| Chapter {
+            start,
+            tags: SpcTags {
+                title: title.to_owned(),
+                game: "Game".to_owned(),
+                artist: "Composer".to_owned(),
+                ..SpcTags::default()
+            },
+            path: PathBuf::from(path),
+        };
+        let chapters = [
+            chapter(0, "Title \"Theme\"", "01.spc"),
+            chapter(
+                SAMPLE_RATE as u64 * 61 + SAMPLE_RATE as u64 / 2,
+                "",
+                "02 Boss.spc",
+            ),
+        ];
+        let album = album_tags(&chapters, "soundtrack");
+        assert_eq!(album.title, "Game");
+        assert_eq!(album.artist, "Composer");
+        assert_eq!(
+            cue_sheet(&chapters, &album, "Game.flac"),
+            "PERFORMER \"Composer\"\n\
+             TITLE \"Game\"\n\
+             FILE \"Game.flac\" WAVE\n\
+             \x20 TRACK 01 AUDIO\n\
+             \x20   TITLE \"Title 'Theme'\"\n\
+             \x20   PERFORMER \"Composer\"\n\
+             \x20   INDEX 01 00:00:00\n\
+             \x20 TRACK 02 AUDIO\n\
+             \x20   TITLE \"02 Boss\"\n\
+             \x20   PERFORMER \"Composer\"\n\
+             \x20   INDEX 01 01:01:37\n"
+        );
+        assert_eq!(
+            chapter_comments(&chapters[1..]),
+            [
+                ("CHAPTER001".to_owned(), "00:01:01.500".to_owned()),
+                ("CHAPTER001NAME".to_owned(), "02 Boss".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_render_to_file() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("spcplay-soundtrack-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let output = dir.join("soundtrack.flac");
+        let settings = Settings::default();
+        let errors = render_to_file(&jobs(), &settings, None, &output, SampleFormat::Int16)?;
+        assert_eq!(errors.len(), 1);
+
+        let mut reader = claxon::FlacReader::open(&output)?;
+        let comments: Vec<(String, String)> = reader
+            .tags()
+            .map(|(key, value)| (key.to_owned(), value.to_owned()))
+            .collect();
+        assert!(comments.contains(&("CHAPTER002".to_owned(), "00:00:02.500".to_owned())));
+        let samples = reader.samples().count();
+        assert_eq!(samples, SAMPLE_RATE * 2 * 5);
+
+        let cue = fs::read_to_string(dir.join("soundtrack.cue"))?;
+        assert!(cue.contains("FILE \"soundtrack.flac\" WAVE\n"));
+        assert!(cue.contains("  TRACK 02 AUDIO\n"));
+        assert!(!dir.join("soundtrack.flac.part").exists());
+
+        let output = dir.join("soundtrack.wav");
+        render_to_file(&jobs(), &settings, None, &output, SampleFormat::Int16)?;
+        let wav = fs::read(&output)?;
+        let cue_chunk = wav.windows(4).position(|id| id == b"cue ").unwrap();
+        // Two cue points, the second at frame 80000.
+        assert_eq!(wav[cue_chunk + 8..cue_chunk + 12], 2u32.to_le_bytes());
+        assert_eq!(wav[cue_chunk + 40..cue_chunk + 44], 80000u32.to_le_bytes());
+        assert!(wav.windows(4).any(|id| id == b"adtl"));
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -2,11 +2,21 @@ use anyhow::{bail, Context, Result};
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 
 use snes_apu::apu::Apu;
-use snes_apu::dsp::dsp::SAMPLE_RATE;
+use snes_apu::dsp::dsp::{NUM_VOICES, SAMPLE_RATE};
 
 use spc::{Emulator, Spc};
 
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::archive;
+use crate::audition::Sampler;
+use crate::brr::{self, EncodedSample};
+use crate::library::SpcTags;
+use crate::loops::{self, LoopInfo, LoopPoints};
+use crate::midi::RegisterLog;
+use crate::settings::Settings;
+use crate::sink::{AudioSink, MemorySink};
 
 struct SpcEndState {
     sample_pos: i32,
@@ -14,6 +24,41 @@ struct SpcEndState {
     end_sample: i32,
 }
 
+impl SpcEndState {
+    fn new(length_ms: u32, fade_ms: u32) -> SpcEndState {
+        let ms_to_samples = |ms: u32| (ms as u64 * SAMPLE_RATE as u64 / 1000) as i32;
+        let fade_out_sample = ms_to_samples(length_ms);
+        let end_sample = fade_out_sample + ms_to_samples(fade_ms);
+        SpcEndState {
+            sample_pos: 0,
+            fade_out_sample,
+            end_sample,
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        self.sample_pos >= self.end_sample
+    }
+
+    fn remaining_samples(&self) -> usize {
+        (self.end_sample - self.sample_pos).max(0) as usize
+    }
+
+    /// Returns the fade volume of the current sample, and advances to the next.
+    fn next_gain(&mut self) -> f32 {
+        let gain = if self.sample_pos < self.fade_out_sample {
+            1.0
+        } else if self.sample_pos < self.end_sample {
+            (self.end_sample - self.sample_pos) as f32
+                / (self.end_sample - self.fade_out_sample) as f32
+        } else {
+            0.0
+        };
+        self.sample_pos += 1;
+        gain
+    }
+}
+
 fn get_spc_info(path: &Path, spc: &Spc) -> String {
     let mut buf = String::new();
     use std::fmt::Write;
@@ -67,18 +112,76 @@ fn get_spc_info(path: &Path, spc: &Spc) -> String {
     buf
 }
 
+/// Per-track timing which takes precedence over the file's tags, eg. from an
+/// M3U playlist.
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct TrackOverrides {
+    /// Time to play before fading out.
+    pub length_ms: Option<u32>,
+    /// Length of the song's loop, if known.
+    pub loop_ms: Option<u32>,
+    pub fade_ms: Option<u32>,
+}
+
+/// Channels rendered by `SpcPlayer::render_stems_to()`.
+pub const STEM_CHANNELS: usize = (NUM_VOICES * 2 + 1) * 2;
+
+/// Silence at the start of a song is skipped in blocks this long.
+const TRIM_BLOCK_FRAMES: usize = 64;
+/// Songs which are silent for longer than this are played from the start
+/// anyway.
+const MAX_TRIM_FRAMES: u64 = 10 * SAMPLE_RATE as u64;
+/// Once every voice is keyed off and has finished releasing, silence this
+/// long ends the song even if `Settings::silence_secs` is longer.
+const KEYED_OFF_SILENCE_FRAMES: usize = 2 * SAMPLE_RATE;
+const EDL: u8 = 0x7d;
+
+fn db_to_amplitude(db: f32) -> i32 {
+    (32768.0 * 10f32.powf(db / 20.0)) as i32
+}
+
 pub struct SpcPlayer {
     path: PathBuf,
     spc: Spc,
     apu: Box<Apu>,
     end_state: Option<SpcEndState>,
+    volume: f32,
+    /// Preamp from the xid6 tags.
+    amplification: f32,
+    replay_gain: f32,
+    /// Frames rendered so far.
+    position: u64,
+    loop_info: Option<LoopInfo>,
+    /// Whether the song ends after `Settings::loop_count` loops, rather than
+    /// at a fixed length.
+    ends_after_loops: bool,
+    loop_count: u32,
+    fade_ms: u32,
+    /// Audio rendered while skipping leading silence, to play before
+    /// rendering more.
+    pending: Vec<i16>,
+    /// Samples no louder than this count as silence.
+    silence_threshold: i32,
+    /// Silence needed to end the song early, or 0 to never end early.
+    silence_frames: usize,
+    /// Frames of silence just played.
+    silent_frames: usize,
 }
 
 pub type FramesWritten = usize;
 
 impl SpcPlayer {
-    pub fn new(path: &Path) -> Result<SpcPlayer> {
-        let spc = Spc::load(&path).context("Could not load spc file")?;
+    /// Loads a song. If `overrides` gives a length, it's played for that long.
+    /// Otherwise if its loop is known (from xid6 tags, `overrides` or
+    /// `detected_loop`), it's played for `Settings::loop_count` loops. Failing
+    /// that, the length comes from the tags, then `settings`.
+    pub fn new(
+        path: &Path,
+        settings: &Settings,
+        overrides: &TrackOverrides,
+        detected_loop: Option<LoopInfo>,
+    ) -> Result<SpcPlayer> {
+        let spc = archive::load_spc(path)?;
 
         let mut apu = Apu::from_spc(&spc);
         // Most SPC's have crap in the echo buffer on startup, so while it's not technically correct, we'll clear that.
@@ -86,99 +189,1267 @@ impl SpcPlayer {
         //  think we're OK to do it too :)
         apu.clear_echo_buffer();
 
-        let end_state: Option<SpcEndState> = if let Some(ref id666_tag) = spc.id666_tag {
-            let fade_out_sample =
-                id666_tag.seconds_to_play_before_fading_out * (SAMPLE_RATE as i32);
-            let end_sample =
-                fade_out_sample + id666_tag.fade_out_length * (SAMPLE_RATE as i32) / 1000;
-            Some(SpcEndState {
-                sample_pos: 0,
-                fade_out_sample: fade_out_sample,
-                end_sample: end_sample,
-            })
-        } else {
-            None
+        let tags = SpcTags::from_spc(&spc);
+        let loop_info = tags.loop_info.or(detected_loop);
+        // An M3U loop length replaces the loop's length, but not its intro.
+        let loop_info = match overrides.loop_ms.filter(|&ms| ms > 0) {
+            Some(loop_ms) => Some(LoopInfo {
+                intro_ms: loop_info.map_or(0, |info| info.intro_ms),
+                loop_ms,
+            }),
+            None => loop_info,
+        };
+
+        let default_length_ms = match settings.default_length_secs {
+            0 => None,
+            secs => Some(secs * 1000),
         };
+        let fade_ms = overrides
+            .fade_ms
+            .or(tags.fade_ms)
+            .unwrap_or(settings.default_fade_ms);
+        let ends_after_loops = overrides.length_ms.is_none() && loop_info.is_some();
+        let end_state = overrides
+            .length_ms
+            .or(tags.length_ms)
+            .or(default_length_ms)
+            .map(|length_ms| SpcEndState::new(length_ms, fade_ms));
 
-        Ok(SpcPlayer {
+        let mut player = SpcPlayer {
             path: path.to_owned(),
             spc,
             apu,
             end_state,
+            volume: 1.0,
+            amplification: tags.amplification,
+            replay_gain: 1.0,
+            position: 0,
+            loop_info,
+            ends_after_loops,
+            loop_count: settings.loop_count,
+            fade_ms,
+            pending: vec![],
+            silence_threshold: 0,
+            silence_frames: 0,
+            silent_frames: 0,
+        };
+        if ends_after_loops {
+            player.set_loop_count(settings.loop_count);
+        }
+        player.apply_settings(settings);
+        if settings.trim_leading_silence {
+            player.trim_leading_silence();
+        }
+        Ok(player)
+    }
+
+    /// Applies the settings which can change during playback.
+    pub fn apply_settings(&mut self, settings: &Settings) {
+        self.volume = settings.volume;
+        self.silence_threshold = db_to_amplitude(settings.silence_threshold_db);
+        self.silence_frames = settings.silence_secs as usize * SAMPLE_RATE;
+
+        let dsp = self.apu.dsp.as_mut().unwrap();
+        dsp.set_resampling_mode(settings.interpolation);
+        dsp.set_remove_surround(settings.remove_surround);
+
+        if self.ends_after_loops && self.loop_count != settings.loop_count {
+            self.set_loop_count(settings.loop_count);
+        }
+    }
+
+    /// Moves the end of the song to after `loop_count` loops, keeping the
+    /// current position.
+    fn set_loop_count(&mut self, loop_count: u32) {
+        self.loop_count = loop_count;
+        self.end_state = match self.loop_info {
+            Some(info) if loop_count > 0 => {
+                let mut end_state = SpcEndState::new(info.length_ms(loop_count), self.fade_ms);
+                end_state.sample_pos = self.position.min(i32::MAX as u64) as i32;
+                Some(end_state)
+            }
+            _ => None,
+        };
+    }
+
+    /// Sets the gain which normalizes the song's loudness.
+    pub fn set_replay_gain_db(&mut self, gain_db: f64) {
+        self.replay_gain = 10f64.powf(gain_db / 20.0) as f32;
+    }
+
+    /// Skips silence at the start of the song. The skipped time counts towards
+    /// the song's length.
+    fn trim_leading_silence(&mut self) {
+        let mut block = [0; TRIM_BLOCK_FRAMES * 2];
+        while self.position < MAX_TRIM_FRAMES {
+            self.apu.render_interleaved(&mut block);
+            if block
+                .iter()
+                .any(|&sample| (sample as i32).abs() > self.silence_threshold)
+            {
+                self.pending = block.to_vec();
+                break;
+            }
+            self.position += TRIM_BLOCK_FRAMES as u64;
+        }
+
+        if let Some(end_state) = &mut self.end_state {
+            end_state.sample_pos = self.position as i32;
+        }
+    }
+
+    /// Returns whether `out` finishes a stretch of silence long enough to end
+    /// the song.
+    ///
+    /// Echo can leave gaps as long as the echo delay between repeats of the
+    /// last note, so silence must last longer than that.
+    fn detect_silence(&mut self, out: &[i16]) -> bool {
+        if self.silence_frames == 0 {
+            return false;
+        }
+        if out
+            .iter()
+            .any(|&sample| (sample as i32).abs() > self.silence_threshold)
+        {
+            self.silent_frames = 0;
+            return false;
+        }
+        self.silent_frames += out.len() / 2;
+
+        let dsp = self.apu.dsp.as_ref().unwrap();
+        // A held note at zero volume can get louder again, so only notes
+        // which have been released and faded out count.
+        let keyed_off = dsp
+            .voices
+            .iter()
+            .all(|voice| voice.envelope.is_releasing() && voice.envelope.level == 0);
+        let echo_frames = (dsp.get_register_value(EDL) & 0x0f) as usize * 16 * SAMPLE_RATE / 1000;
+
+        let mut required = self.silence_frames;
+        if keyed_off {
+            required = required.min(KEYED_OFF_SILENCE_FRAMES);
+        }
+        required = required.max(echo_frames + SAMPLE_RATE / 10);
+        self.silent_frames >= required
+    }
+
+    /// Time played so far, including skipped leading silence.
+    pub fn position_ms(&self) -> u32 {
+        (self.position * 1000 / SAMPLE_RATE as u64) as u32
+    }
+
+    /// Which time through the loop is playing, counting from 1, or 0 during
+    /// the intro. None if the song's loop isn't known.
+    pub fn current_loop(&self) -> Option<u32> {
+        let info = self.loop_info?;
+        Some(match self.position_ms().checked_sub(info.intro_ms) {
+            Some(looped_ms) => 1 + looped_ms / info.loop_ms.max(1),
+            None => 0,
         })
     }
 
+    /// Loops to play before fading out, if the song ends after a number of
+    /// loops rather than at a fixed length. 0 means forever.
+    pub fn loop_count(&self) -> Option<u32> {
+        if self.ends_after_loops {
+            Some(self.loop_count)
+        } else {
+            None
+        }
+    }
+
+    /// Whether playback has reached the end of the song's length and fade.
+    pub fn is_finished(&self) -> bool {
+        matches!(&self.end_state, Some(end_state) if end_state.is_finished())
+    }
+
+    /// Number of frames left before the end of the fade, or None if the song
+    /// plays forever.
+    pub fn remaining_frames(&self) -> Option<usize> {
+        self.end_state.as_ref().map(SpcEndState::remaining_samples)
+    }
+
+    pub fn tags(&self) -> SpcTags {
+        SpcTags::from_spc(&self.spc)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
     pub fn get_spc_info(&self) -> String {
         get_spc_info(&self.path, &self.spc)
     }
 
+    /// Replaces the sample `sources` play from now on, as for
+    /// `brr::replace_sample()`. Returns the sample's address.
+    pub fn replace_sample(&mut self, sources: &[u8], sample: &EncodedSample) -> Result<u16> {
+        brr::replace_sample(&mut self.apu, sources, sample)
+    }
+
+    /// Captures the current playback position as an SPC file, keeping the
+    /// original file's tags.
+    pub fn to_spc(&self) -> Spc {
+        let mut spc = self.apu.to_spc();
+        spc.id666_tag = self.spc.id666_tag.clone();
+        spc.xid6_tag = self.spc.xid6_tag.clone();
+        spc
+    }
+
+    /// Returns the gain of the next frame, including the fade, and advances
+    /// to the frame after. None past the end of the song.
+    fn next_frame_gain(&mut self) -> Option<f32> {
+        let mut gain = self.volume * self.amplification * self.replay_gain;
+        if let Some(end_state) = &mut self.end_state {
+            if end_state.is_finished() {
+                return None;
+            }
+            gain *= end_state.next_gain();
+        }
+        Some(gain)
+    }
+
+    /// Fills `out` with the APU's output, starting with any audio rendered
+    /// while skipping leading silence.
+    fn render_apu(&mut self, out: &mut [i16]) {
+        let pending = self.pending.len().min(out.len());
+        out[..pending].copy_from_slice(&self.pending[..pending]);
+        self.pending.drain(..pending);
+        if pending < out.len() {
+            self.apu.render_interleaved(&mut out[pending..]);
+        }
+        self.position += (out.len() / 2) as u64;
+    }
+
+    /// Renders the song as the APU outputs it, before any gain, up to where
+    /// it starts fading out. Returns the number of frames written to the
+    /// start of `out`, which is fewer than it holds only at the fade.
+    pub fn render_unfaded(&mut self, out: &mut [i16]) -> FramesWritten {
+        let mut frames = out.len() / 2;
+        if let Some(end_state) = &mut self.end_state {
+            let before_fade = (end_state.fade_out_sample - end_state.sample_pos).max(0);
+            frames = frames.min(before_fade as usize);
+            end_state.sample_pos += frames as i32;
+        }
+        self.render_apu(&mut out[..frames * 2]);
+        frames
+    }
+
+    /// Linear gain from the xid6 tags, applied by `render()`.
+    pub fn amplification(&self) -> f32 {
+        self.amplification
+    }
+
+    /// Fills `out` with interleaved stereo audio, padding with silence past
+    /// the end of the song. Returns the number of frames before the end.
     pub fn render(&mut self, out: &mut [i16]) -> FramesWritten {
-        self.apu.render_interleaved(&mut *out);
-        // TODO handle pausing and fade out
-        out.len() / 2
+        if self.is_finished() {
+            out.fill(0);
+            return 0;
+        }
+
+        self.render_apu(out);
+        let silent = self.detect_silence(out);
+        // TODO handle pausing
+
+        let mut frames_written = 0;
+        for frame in out.chunks_exact_mut(2) {
+            let gain = match self.next_frame_gain() {
+                Some(gain) => gain,
+                None => {
+                    frame.fill(0);
+                    continue;
+                }
+            };
+            for sample in frame {
+                *sample = (*sample as f32 * gain) as i16;
+            }
+            frames_written += 1;
+        }
+
+        if silent {
+            self.end_state = Some(SpcEndState::new(0, 0));
+        }
+        frames_written
     }
+
+    /// Renders the rest of the song, including its fade, and finishes `sink`.
+    /// Fails if the song plays forever.
+    pub fn render_to(&mut self, sink: &mut dyn AudioSink) -> Result<()> {
+        if self.remaining_frames().is_none() {
+            bail!(
+                "{} plays forever, so it can't be rendered",
+                self.path.display()
+            );
+        }
+        let mut buffer = vec![0; SAMPLE_RATE * 2];
+        while !self.is_finished() {
+            let frames = self.render(&mut buffer);
+            sink.write(&buffer[..frames * 2])?;
+        }
+        sink.finish()
+    }
+
+    /// Renders the rest of the song as `STEM_CHANNELS` interleaved channels:
+    /// each voice in stereo, then each voice with its echo, then the echo.
+    /// Voices are before the song's main volume. The song ends at its length
+    /// rather than after silence. Fails if the song plays forever, or if
+    /// leading silence was skipped, as it's skipped without stems.
+    pub fn render_stems_to(&mut self, sink: &mut dyn AudioSink) -> Result<()> {
+        if self.remaining_frames().is_none() {
+            bail!(
+                "{} plays forever, so it can't be rendered",
+                self.path.display()
+            );
+        }
+        if !self.pending.is_empty() {
+            bail!("Stems can't be rendered after skipping leading silence");
+        }
+
+        self.apu.dsp.as_mut().unwrap().set_stem_capture(true);
+        let mut buffer = vec![0; SAMPLE_RATE * 2];
+        let mut out = vec![];
+        while !self.is_finished() {
+            self.apu.render_interleaved(&mut buffer);
+            let stem_samples = self.apu.dsp.as_mut().unwrap().take_stem_samples();
+            self.position += stem_samples.len() as u64;
+
+            out.clear();
+            for stem_sample in &stem_samples {
+                let gain = match self.next_frame_gain() {
+                    Some(gain) => gain,
+                    None => break,
+                };
+                let mut push = |left: i16, right: i16| {
+                    out.push((left as f32 * gain) as i16);
+                    out.push((right as f32 * gain) as i16);
+                };
+                for &(left, right) in &stem_sample.voices {
+                    push(left, right);
+                }
+                for (voice, echo) in stem_sample.voices.iter().zip(&stem_sample.voice_echoes) {
+                    push(
+                        voice.0.saturating_add(echo.0),
+                        voice.1.saturating_add(echo.1),
+                    );
+                }
+                push(stem_sample.echo.0, stem_sample.echo.1);
+            }
+            sink.write(&out)?;
+        }
+        self.apu.dsp.as_mut().unwrap().set_stem_capture(false);
+        sink.finish()
+    }
+
+    /// Renders the rest of the song without keeping the audio, logging the
+    /// DSP register writes for converting to MIDI. Fails if the song plays
+    /// forever, or if leading silence was skipped, as its writes weren't
+    /// logged.
+    pub fn render_register_writes(&mut self) -> Result<RegisterLog> {
+        if self.remaining_frames().is_none() {
+            bail!(
+                "{} plays forever, so it can't be rendered",
+                self.path.display()
+            );
+        }
+        if !self.pending.is_empty() {
+            bail!("MIDI can't be rendered after skipping leading silence");
+        }
+
+        let dsp = self.apu.dsp.as_mut().unwrap();
+        let initial = dsp.get_state();
+        let start = dsp.samples_rendered();
+        dsp.set_register_logging(true);
+        let mut buffer = vec![0; SAMPLE_RATE * 2];
+        let mut length = 0;
+        while !self.is_finished() {
+            length += self.render(&mut buffer) as u64;
+        }
+        let dsp = self.apu.dsp.as_mut().unwrap();
+        let mut writes = dsp.take_register_writes();
+        dsp.set_register_logging(false);
+        for write in &mut writes {
+            write.sample -= start;
+        }
+        Ok(RegisterLog {
+            initial,
+            writes,
+            length,
+        })
+    }
+
+    /// Renders the intro and one time through the loop, without fading, for
+    /// looping seamlessly. Fails if the song's loop isn't known.
+    pub fn render_loop(&mut self) -> Result<(Vec<i16>, LoopPoints)> {
+        let info = match self.loop_info {
+            Some(info) if info.loop_ms > 0 => info,
+            _ => bail!("{} has no known loop", self.path.display()),
+        };
+        let ms_to_frames = |ms: u32| (ms as u64 * SAMPLE_RATE as u64 / 1000) as usize;
+        // Skipped leading silence shortens the intro.
+        let start = ms_to_frames(info.intro_ms).saturating_sub(self.position as usize);
+        let length = ms_to_frames(info.loop_ms);
+
+        self.end_state = None;
+        self.silence_frames = 0;
+        let needed = loops::seamless_frames_needed(start, length);
+        let mut sink = MemorySink::default();
+        let mut buffer = vec![0; SAMPLE_RATE * 2];
+        while sink.samples.len() < needed * 2 {
+            self.render(&mut buffer);
+            sink.write(&buffer)?;
+        }
+        Ok(loops::make_seamless(sink.samples, start, length))
+    }
+}
+
+/// Timing for a test song: `length_ms` and then a `fade_ms` fade, whatever
+/// its tags say.
+#[cfg(test)]
+pub fn test_overrides(length_ms: u32, fade_ms: u32) -> TrackOverrides {
+    TrackOverrides {
+        length_ms: Some(length_ms),
+        fade_ms: Some(fade_ms),
+        loop_ms: None,
+    }
+}
+
+/// Loads a test song with default settings, timed as by `test_overrides()`.
+#[cfg(test)]
+pub fn test_player(path: &str, length_ms: u32, fade_ms: u32) -> Result<SpcPlayer> {
+    SpcPlayer::new(
+        Path::new(path),
+        &Settings::default(),
+        &test_overrides(length_ms, fade_ms),
+        None,
+    )
+}
+
+/// Lists the names of output devices, for `Settings::output_device`.
+pub fn output_device_names() -> Result<Vec<String>> {
+    let host = cpal::default_host();
+    let devices = host
+        .output_devices()
+        .context("error while querying devices")?;
+    Ok(devices.filter_map(|device| device.name().ok()).collect())
+}
+
+/// Frames before the end of a song at which to ask for the next one, so it
+/// can be loaded before it's needed.
+const QUEUE_AHEAD_FRAMES: usize = 3 * SAMPLE_RATE;
+
+/// Sent from the audio thread to tell the UI what happened.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PlaybackEvent {
+    /// The current song is about to end, and no song is queued.
+    NeedNext,
+    /// The queued song started playing.
+    Started,
+    /// The current song ended, and no song was queued.
+    Stopped,
+    /// The current song went back to the start of its loop.
+    Looped,
+}
+
+/// A song being faded out while the next one starts.
+struct Crossfade {
+    outgoing: SpcPlayer,
+    /// Frames of the incoming song played so far.
+    pos: usize,
+    len: usize,
 }
 
-pub struct AudioHandle {
-    stream: cpal::Stream,
+/// What the output stream plays. Shared between the UI and audio threads.
+pub struct Playback {
+    current: Option<SpcPlayer>,
+    /// Starts when `current` ends, or overlaps its end if `crossfade_frames`
+    /// is nonzero.
+    next: Option<SpcPlayer>,
+    crossfade: Option<Crossfade>,
+    crossfade_frames: usize,
+    /// Whether `PlaybackEvent::NeedNext` was sent for the current song.
+    asked_for_next: bool,
+    events: Vec<PlaybackEvent>,
+    /// Scratch space for mixing the outgoing song of a crossfade.
+    mix_buffer: Vec<i16>,
+    /// Whether to play silence without advancing.
+    paused: bool,
+    /// Samples being auditioned, mixed over the songs even while paused.
+    sampler: Option<Sampler>,
 }
 
-impl AudioHandle {
-    pub fn new(mut spc: SpcPlayer) -> Result<AudioHandle> {
-        let host = cpal::default_host();
+impl Playback {
+    fn new() -> Playback {
+        Playback {
+            current: None,
+            next: None,
+            crossfade: None,
+            crossfade_frames: 0,
+            asked_for_next: false,
+            events: vec![],
+            mix_buffer: vec![],
+            paused: false,
+            sampler: None,
+        }
+    }
+
+    pub fn current(&self) -> Option<&SpcPlayer> {
+        self.current.as_ref()
+    }
+
+    pub fn current_mut(&mut self) -> Option<&mut SpcPlayer> {
+        self.current.as_mut()
+    }
+
+    pub fn sampler_mut(&mut self) -> Option<&mut Sampler> {
+        self.sampler.as_mut()
+    }
 
-        let device = host
+    pub fn set_sampler(&mut self, sampler: Option<Sampler>) {
+        self.sampler = sampler;
+    }
+
+    /// Applies the settings which can change during playback.
+    pub fn apply_settings(&mut self, settings: &Settings) {
+        self.crossfade_frames = (settings.crossfade_ms as u64 * SAMPLE_RATE as u64 / 1000) as usize;
+        for player in self.current.iter_mut().chain(&mut self.next) {
+            player.apply_settings(settings);
+        }
+        if let Some(crossfade) = &mut self.crossfade {
+            crossfade.outgoing.apply_settings(settings);
+        }
+    }
+
+    /// Starts the next song, crossfading with the current one over `fade_len`
+    /// frames if nonzero.
+    fn advance(&mut self, fade_len: usize) {
+        let outgoing = std::mem::replace(&mut self.current, self.next.take());
+        self.crossfade = match outgoing {
+            Some(outgoing) if fade_len > 0 => Some(Crossfade {
+                outgoing,
+                pos: 0,
+                len: fade_len,
+            }),
+            _ => None,
+        };
+        self.asked_for_next = false;
+        self.events.push(PlaybackEvent::Started);
+    }
+
+    /// Fills `out` with interleaved stereo audio.
+    fn render(&mut self, out: &mut [i16]) {
+        self.render_songs(out);
+        if let Some(sampler) = &mut self.sampler {
+            sampler.mix_into(out);
+        }
+    }
+
+    fn render_songs(&mut self, out: &mut [i16]) {
+        if self.paused {
+            out.fill(0);
+            return;
+        }
+
+        let frames = out.len() / 2;
+        let mut pos = 0;
+
+        while pos < frames {
+            let remaining = match &self.current {
+                Some(current) => current.remaining_frames(),
+                None => {
+                    out[pos * 2..].fill(0);
+                    break;
+                }
+            };
+
+            if let Some(remaining) = remaining {
+                if self.next.is_none()
+                    && !self.asked_for_next
+                    && remaining <= QUEUE_AHEAD_FRAMES + self.crossfade_frames
+                {
+                    self.asked_for_next = true;
+                    self.events.push(PlaybackEvent::NeedNext);
+                }
+                // Start the crossfade exactly crossfade_frames before the end.
+                if self.next.is_some()
+                    && self.crossfade_frames > 0
+                    && remaining <= self.crossfade_frames
+                {
+                    self.advance(remaining);
+                    continue;
+                }
+            }
+            let current = self.current.as_mut().unwrap();
+
+            // Stop where the next song should start.
+            let mut chunk = frames - pos;
+            if let (Some(remaining), true) = (remaining, self.next.is_some()) {
+                chunk = chunk.min(remaining.saturating_sub(self.crossfade_frames).max(1));
+            }
+
+            let chunk_out = &mut out[pos * 2..(pos + chunk) * 2];
+            let old_loop = current.current_loop();
+            let frames_written = current.render(chunk_out);
+            if current.current_loop() != old_loop {
+                self.events.push(PlaybackEvent::Looped);
+            }
+
+            if let Some(crossfade) = &mut self.crossfade {
+                let mix = &mut self.mix_buffer;
+                mix.resize(chunk_out.len(), 0);
+                crossfade.outgoing.render(mix);
+
+                for (frame, mix_frame) in chunk_out.chunks_exact_mut(2).zip(mix.chunks_exact(2)) {
+                    // The outgoing song has already faded out by itself.
+                    let gain = (crossfade.pos as f32 / crossfade.len as f32).min(1.0);
+                    for (sample, &mix_sample) in frame.iter_mut().zip(mix_frame) {
+                        let incoming = *sample as f32 * gain;
+                        *sample = (incoming + mix_sample as f32)
+                            .clamp(i16::MIN as f32, i16::MAX as f32)
+                            as i16;
+                    }
+                    crossfade.pos += 1;
+                }
+                if crossfade.pos >= crossfade.len {
+                    self.crossfade = None;
+                }
+            }
+
+            let current = self.current.as_ref().unwrap();
+            if current.is_finished() {
+                pos += frames_written;
+                if self.next.is_some() {
+                    // Gapless: the next song starts on the following frame.
+                    self.advance(0);
+                } else {
+                    self.current = None;
+                    self.events.push(PlaybackEvent::Stopped);
+                }
+            } else {
+                pos += chunk;
+            }
+        }
+    }
+}
+
+/// Finds the output device chosen in `settings` and a stereo 16-bit config at
+/// the SNES's sample rate.
+pub fn open_device(settings: &Settings) -> Result<(cpal::Device, cpal::StreamConfig)> {
+    let host = cpal::default_host();
+
+    // Fall back to the default device if the chosen one was unplugged.
+    let chosen_device = match &settings.output_device {
+        Some(name) => host
+            .output_devices()
+            .context("error while querying devices")?
+            .find(|device| device.name().ok().as_ref() == Some(name)),
+        None => None,
+    };
+    let device = match chosen_device {
+        Some(device) => device,
+        None => host
             .default_output_device()
-            .context("no input device available")?;
-
-        let supported_config: cpal::SupportedStreamConfig = {
-            let supported_config_ranges: Vec<cpal::SupportedStreamConfigRange> = device
-                .supported_output_configs()
-                .context("error while querying configs")?
-                .collect();
-
-            let range: cpal::SupportedStreamConfigRange = supported_config_ranges
-                .into_iter()
-                .find(|range| {
-                    range.channels() == 2 && range.sample_format() == cpal::SampleFormat::I16
-                })
-                .context("no supported config found")?;
-
-            let min = range.min_sample_rate().0;
-            let max = range.max_sample_rate().0;
-            if !(min <= 32000 && 32000 <= max) {
-                bail!(
-                    "invalid sampling range {} to {} does not include 32000",
-                    min,
-                    max,
-                );
+            .context("no input device available")?,
+    };
+
+    let supported_config: cpal::SupportedStreamConfig = {
+        let supported_config_ranges: Vec<cpal::SupportedStreamConfigRange> = device
+            .supported_output_configs()
+            .context("error while querying configs")?
+            .collect();
+
+        let range: cpal::SupportedStreamConfigRange = supported_config_ranges
+            .into_iter()
+            .find(|range| range.channels() == 2 && range.sample_format() == cpal::SampleFormat::I16)
+            .context("no supported config found")?;
+
+        let min = range.min_sample_rate().0;
+        let max = range.max_sample_rate().0;
+        if !(min <= 32000 && 32000 <= max) {
+            bail!(
+                "invalid sampling range {} to {} does not include 32000",
+                min,
+                max,
+            );
+        }
+
+        range.with_sample_rate(cpal::SampleRate(32000))
+    };
+
+    // For some reason, converting SupportedStreamConfig into StreamConfig
+    // (SupportedStreamConfig::config())
+    // throws away buffer_size and replaces with BufferSize::Default.
+    let mut config: cpal::StreamConfig = supported_config.into();
+    if let Some(buffer_size) = settings.buffer_size {
+        config.buffer_size = cpal::BufferSize::Fixed(buffer_size);
+    }
+
+    Ok((device, config))
+}
+
+/// An output stream which stays open between songs, so they can be played
+/// back to back without gaps.
+pub struct AudioOutput {
+    playback: Arc<Mutex<Playback>>,
+    /// Dropping the stream closes the device.
+    _stream: cpal::Stream,
+}
+
+impl AudioOutput {
+    /// Opens the output device chosen in `settings` and starts playing
+    /// silence. `notify` is called on the audio thread whenever a
+    /// `PlaybackEvent` is ready to be read with `take_events()`.
+    pub fn new(settings: &Settings, notify: Box<dyn Fn() + Send>) -> Result<AudioOutput> {
+        let mut playback = Playback::new();
+        playback.apply_settings(settings);
+        let playback = Arc::new(Mutex::new(playback));
+
+        let (device, config) = open_device(settings)?;
+        let err_fn = |err| eprintln!("an error occurred on the input audio stream: {}", err);
+
+        let stream = {
+            let playback = playback.clone();
+            device
+                .build_output_stream(
+                    &config,
+                    move |data, _info| {
+                        let mut playback = playback.lock().unwrap();
+                        playback.render(data);
+                        if !playback.events.is_empty() {
+                            notify();
+                        }
+                    },
+                    err_fn,
+                )
+                .context("Error building output stream")?
+        };
+        stream.play().context("Error playing audio device")?;
+
+        Ok(AudioOutput {
+            playback,
+            _stream: stream,
+        })
+    }
+
+    /// Locks the playback state shared with the audio thread. Don't hold the
+    /// guard for long, or playback will stutter.
+    pub fn lock(&self) -> MutexGuard<'_, Playback> {
+        self.playback.lock().unwrap()
+    }
+
+    /// Plays a song immediately, discarding any queued song.
+    pub fn play(&self, player: SpcPlayer) {
+        let mut playback = self.lock();
+        playback.current = Some(player);
+        playback.next = None;
+        playback.crossfade = None;
+        playback.asked_for_next = false;
+    }
+
+    /// Plays a song once the current one ends.
+    pub fn queue(&self, player: SpcPlayer) {
+        self.lock().next = Some(player);
+    }
+
+    /// Discards the queued song. If the current song is near its end,
+    /// `PlaybackEvent::NeedNext` will be sent again. Returns false if there
+    /// was no song queued, eg. because it already started.
+    pub fn clear_queue(&self) -> bool {
+        let mut playback = self.lock();
+        playback.asked_for_next = false;
+        playback.next.take().is_some()
+    }
+
+    pub fn stop(&self) {
+        let mut playback = self.lock();
+        playback.current = None;
+        playback.next = None;
+        playback.crossfade = None;
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.lock().paused = paused;
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.lock().current.is_some()
+    }
+
+    pub fn take_events(&self) -> Vec<PlaybackEvent> {
+        std::mem::take(&mut self.lock().events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spc::{Emulator, Id666Tag, Xid6Tag, TICKS_PER_SECOND};
+
+    const FERRIS_PATH: &str = "3rdparty/snes-apu/test/ferris-nu.spc";
+    const SMASHIT_PATH: &str = "3rdparty/snes-apu/test/smashit.spc";
+    /// Playback is rendered in chunks which don't divide songs' lengths, so
+    /// they end mid-chunk.
+    const CHUNK_FRAMES: usize = 999;
+
+    fn render_song(path: &str, length_ms: u32) -> Result<Vec<i16>> {
+        let mut sink = MemorySink::default();
+        test_player(path, length_ms, 0)?.render_to(&mut sink)?;
+        Ok(sink.samples)
+    }
+
+    /// Plays `first`, queueing `second` when asked for the next song, until
+    /// playback stops. Returns the audio, and the events other than `Looped`
+    /// with the frame each one's chunk started on.
+    fn play_two(
+        playback: &mut Playback,
+        first: SpcPlayer,
+        second: SpcPlayer,
+    ) -> (Vec<i16>, Vec<(usize, PlaybackEvent)>) {
+        playback.current = Some(first);
+        let mut second = Some(second);
+        let mut out = vec![];
+        let mut events = vec![];
+        let mut chunk = vec![0; CHUNK_FRAMES * 2];
+        while !events
+            .iter()
+            .any(|&(_, event)| event == PlaybackEvent::Stopped)
+        {
+            let start = out.len() / 2;
+            playback.render(&mut chunk);
+            out.extend_from_slice(&chunk);
+            for event in std::mem::take(&mut playback.events) {
+                if event == PlaybackEvent::NeedNext {
+                    playback.next = second.take();
+                }
+                if event != PlaybackEvent::Looped {
+                    events.push((start, event));
+                }
             }
+        }
+        (out, events)
+    }
+
+    /// The start of the chunk `frame` is rendered in.
+    fn chunk_start(frame: usize) -> usize {
+        frame / CHUNK_FRAMES * CHUNK_FRAMES
+    }
+
+    #[test]
+    fn test_gapless() -> Result<()> {
+        let first = render_song(FERRIS_PATH, 4000)?;
+        let second = render_song(SMASHIT_PATH, 2000)?;
+        let mut playback = Playback::new();
+        let (out, events) = play_two(
+            &mut playback,
+            test_player(FERRIS_PATH, 4000, 0)?,
+            test_player(SMASHIT_PATH, 2000, 0)?,
+        );
+
+        // The second song starts on the frame after the first ends.
+        let end = first.len();
+        assert_eq!(end, 4 * SAMPLE_RATE * 2);
+        assert!(out[..end] == first[..]);
+        assert!(out[end..end + second.len()] == second[..]);
+        assert!(out[end + second.len()..].iter().all(|&sample| sample == 0));
+
+        // The next song is asked for once QUEUE_AHEAD_FRAMES are left.
+        let need_next = end / 2 - QUEUE_AHEAD_FRAMES;
+        let end = end / 2;
+        assert_eq!(
+            events,
+            [
+                (
+                    chunk_start(need_next + CHUNK_FRAMES - 1),
+                    PlaybackEvent::NeedNext
+                ),
+                (chunk_start(end), PlaybackEvent::Started),
+                // The second song is short enough to ask for the next at once.
+                (chunk_start(end), PlaybackEvent::NeedNext),
+                (chunk_start(end + second.len() / 2), PlaybackEvent::Stopped),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_crossfade() -> Result<()> {
+        let first = render_song(FERRIS_PATH, 2000)?;
+        let second = render_song(SMASHIT_PATH, 2000)?;
+        let mut playback = Playback::new();
+        let fade_frames = SAMPLE_RATE / 2;
+        playback.crossfade_frames = fade_frames;
+        let (out, events) = play_two(
+            &mut playback,
+            test_player(FERRIS_PATH, 2000, 0)?,
+            test_player(SMASHIT_PATH, 2000, 0)?,
+        );
+
+        // The crossfade starts exactly crossfade_frames before the end.
+        let start = first.len() / 2 - fade_frames;
+        assert!(out[..start * 2] == first[..start * 2]);
+        let faded: Vec<i16> = (0..fade_frames * 2)
+            .map(|i| {
+                let gain = ((i / 2) as f32 / fade_frames as f32).min(1.0);
+                (second[i] as f32 * gain + first[start * 2 + i] as f32)
+                    .clamp(i16::MIN as f32, i16::MAX as f32) as i16
+            })
+            .collect();
+        let end = start + second.len() / 2;
+        assert!(out[start * 2..(start + fade_frames) * 2] == faded[..]);
+        assert!(out[(start + fade_frames) * 2..end * 2] == second[fade_frames * 2..]);
+        assert!(out[end * 2..].iter().all(|&sample| sample == 0));
+
+        assert_eq!(
+            events,
+            [
+                (0, PlaybackEvent::NeedNext),
+                (chunk_start(start), PlaybackEvent::Started),
+                (chunk_start(start), PlaybackEvent::NeedNext),
+                (chunk_start(end), PlaybackEvent::Stopped),
+            ]
+        );
+        Ok(())
+    }
+
+    fn id666(length_secs: i32, fade_ms: i32) -> Id666Tag {
+        Id666Tag {
+            song_title: String::new(),
+            game_title: String::new(),
+            dumper_name: String::new(),
+            comments: String::new(),
+            date_dumped: String::new(),
+            seconds_to_play_before_fading_out: length_secs,
+            fade_out_length: fade_ms,
+            artist_name: String::new(),
+            default_channel_disables: 0,
+            dumping_emulator: Emulator::Unknown,
+        }
+    }
 
-            range.with_sample_rate(cpal::SampleRate(32000))
+    /// Saves a copy of a test song with the given tags, named after `name`.
+    fn save_tagged(name: &str, id666: Option<Id666Tag>, xid6: Option<Xid6Tag>) -> Result<PathBuf> {
+        let mut spc = Spc::load(FERRIS_PATH)?;
+        spc.id666_tag = id666;
+        spc.xid6_tag = xid6;
+        let path =
+            std::env::temp_dir().join(format!("spcplay-{}-{}.spc", name, std::process::id()));
+        spc.save(&path)?;
+        Ok(path)
+    }
+
+    fn secs(secs: f64) -> Option<usize> {
+        Some((secs * SAMPLE_RATE as f64) as usize)
+    }
+
+    fn render_secs(player: &mut SpcPlayer, secs: usize) {
+        let mut buffer = vec![0; SAMPLE_RATE * 2];
+        for _ in 0..secs {
+            player.render(&mut buffer);
+        }
+    }
+
+    #[test]
+    fn test_loop_count() -> Result<()> {
+        let path = save_tagged("loop-count", None, None)?;
+        let mut settings = Settings {
+            loop_count: 2,
+            default_fade_ms: 1000,
+            ..Settings::default()
         };
+        let info = LoopInfo {
+            intro_ms: 1000,
+            loop_ms: 2000,
+        };
+        let mut player = SpcPlayer::new(&path, &settings, &TrackOverrides::default(), Some(info))?;
+        std::fs::remove_file(&path)?;
 
-        // For some reason, converting SupportedStreamConfig into StreamConfig
-        // (SupportedStreamConfig::config())
-        // throws away buffer_size and replaces with BufferSize::Default.
-        let config: cpal::StreamConfig = supported_config.into();
+        // The intro, two loops and the fade.
+        assert_eq!(player.remaining_frames(), secs(6.0));
+        assert_eq!(player.loop_count(), Some(2));
+        assert_eq!(player.current_loop(), Some(0));
+        render_secs(&mut player, 1);
+        assert_eq!(player.current_loop(), Some(1));
+        render_secs(&mut player, 2);
+        assert_eq!(player.current_loop(), Some(2));
 
-        let err_fn = |err| eprintln!("an error occurred on the input audio stream: {}", err);
+        // Changing the loop count moves the end, but not the position.
+        settings.loop_count = 3;
+        player.apply_settings(&settings);
+        assert_eq!(player.position_ms(), 3000);
+        assert_eq!(player.current_loop(), Some(2));
+        assert_eq!(player.loop_count(), Some(3));
+        assert_eq!(player.remaining_frames(), secs(8.0 - 3.0));
+
+        settings.loop_count = 0;
+        player.apply_settings(&settings);
+        assert_eq!(player.loop_count(), Some(0));
+        assert_eq!(player.remaining_frames(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_timing_precedence() -> Result<()> {
+        let settings = Settings {
+            default_length_secs: 60,
+            default_fade_ms: 4000,
+            loop_count: 2,
+            ..Settings::default()
+        };
+        let detected = Some(LoopInfo {
+            intro_ms: 1000,
+            loop_ms: 2000,
+        });
+        let xid6 = Xid6Tag {
+            intro_length: Some(TICKS_PER_SECOND * 2),
+            loop_length: Some(TICKS_PER_SECOND * 10),
+            ..Xid6Tag::default()
+        };
+        let untagged = save_tagged("untagged", None, None)?;
+        let id666_tagged = save_tagged("id666", Some(id666(90, 5000)), None)?;
+        let xid6_tagged = save_tagged("xid6", Some(id666(90, 5000)), Some(xid6))?;
+        let no_overrides = TrackOverrides::default();
+        let remaining = |path: &PathBuf,
+                         settings: &Settings,
+                         overrides: &TrackOverrides,
+                         detected_loop: Option<LoopInfo>|
+         -> Result<(Option<usize>, Option<u32>)> {
+            let player = SpcPlayer::new(path, settings, overrides, detected_loop)?;
+            Ok((player.remaining_frames(), player.loop_count()))
+        };
+
+        // Settings, when nothing else gives a length.
+        assert_eq!(
+            remaining(&untagged, &settings, &no_overrides, None)?,
+            (secs(64.0), None)
+        );
+        let forever = Settings {
+            default_length_secs: 0,
+            ..settings.clone()
+        };
+        assert_eq!(
+            remaining(&untagged, &forever, &no_overrides, None)?,
+            (None, None)
+        );
+        // Tags beat settings.
+        assert_eq!(
+            remaining(&id666_tagged, &settings, &no_overrides, None)?,
+            (secs(95.0), None)
+        );
+        // A detected loop beats the tags' length, but not their fade.
+        assert_eq!(
+            remaining(&id666_tagged, &settings, &no_overrides, detected)?,
+            (secs(5.0 + 5.0), Some(2))
+        );
+        // An xid6 loop beats a detected loop.
+        assert_eq!(
+            remaining(&xid6_tagged, &settings, &no_overrides, detected)?,
+            (secs(22.0 + 5.0), Some(2))
+        );
+        // An override loop length keeps the intro.
+        let loop_override = TrackOverrides {
+            loop_ms: Some(4000),
+            fade_ms: Some(1000),
+            ..TrackOverrides::default()
+        };
+        assert_eq!(
+            remaining(&xid6_tagged, &settings, &loop_override, detected)?,
+            (secs(10.0 + 1.0), Some(2))
+        );
+        // An override length beats everything.
+        assert_eq!(
+            remaining(
+                &xid6_tagged,
+                &settings,
+                &test_overrides(30_000, 1000),
+                detected
+            )?,
+            (secs(31.0), None)
+        );
+
+        for path in [untagged, id666_tagged, xid6_tagged] {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// Waits `outer` * 256 loops, about 0.75 ms per `outer`, or 0.2 s when 0.
+    fn delay(outer: u8) -> [u8; 10] {
+        // MOV Y,#outer; MOV X,#0; DEC X; BNE -3; DEC Y; BNE -6
+        [0x8d, outer, 0xcd, 0x00, 0x1d, 0xd0, 0xfd, 0xdc, 0xd0, 0xfa]
+    }
+
+    /// Writes `value` to DSP register `address`.
+    fn write_dsp(address: u8, value: u8) -> [u8; 6] {
+        // MOV $f2,#address; MOV $f3,#value
+        [0x8f, address, 0xf2, 0x8f, value, 0xf3]
+    }
+
+    /// Saves a song which starts with the DSP registers `regs`, and then runs
+    /// `program` and stops. Voice 0 is set up to play a loud square wave at
+    /// full volume, without echo.
+    fn save_test_song(name: &str, regs: &[(u8, u8)], program: &[&[u8]]) -> Result<PathBuf> {
+        let mut spc = Spc::load(FERRIS_PATH)?;
+        spc.id666_tag = None;
+        spc.xid6_tag = None;
+        spc.ram = [0; 0x10000];
+        spc.regs = [0; 128];
+        let (pc, a, x, y, psw, sp) = (0x0200, 0, 0, 0, 0, 0xef);
+        spc.pc = pc;
+        spc.a = a;
+        spc.x = x;
+        spc.y = y;
+        spc.psw = psw;
+        spc.sp = sp;
+
+        let mut code: Vec<u8> = program.concat();
+        // BRA -2
+        code.extend_from_slice(&[0x2f, 0xfe]);
+        spc.ram[pc as usize..pc as usize + code.len()].copy_from_slice(&code);
+        // Source 0 starts and loops at $0400.
+        spc.ram[0x0300..0x0304].copy_from_slice(&[0x00, 0x04, 0x00, 0x04]);
+        // One looping BRR block of a square wave.
+        spc.ram[0x0400] = 0xb3;
+        spc.ram[0x0401..0x0405].fill(0x77);
+        spc.ram[0x0405..0x0409].fill(0x99);
+
+        let voice = [
+            (0x00, 0x7f),
+            (0x01, 0x7f),
+            (0x03, 0x10),
+            (0x04, 0x00),
+            (0x05, 0x8f),
+            (0x06, 0xe0),
+        ];
+        let global = [(0x0c, 0x7f), (0x1c, 0x7f), (0x5d, 0x03), (0x6c, 0x20)];
+        for &(address, value) in voice.iter().chain(&global).chain(regs) {
+            spc.regs[address as usize] = value;
+        }
+
+        let path =
+            std::env::temp_dir().join(format!("spcplay-{}-{}.spc", name, std::process::id()));
+        spc.save(&path)?;
+        Ok(path)
+    }
+
+    fn load_test_song(path: &Path, settings: &Settings) -> Result<SpcPlayer> {
+        let player = SpcPlayer::new(path, settings, &test_overrides(20_000, 0), None)?;
+        std::fs::remove_file(path)?;
+        Ok(player)
+    }
+
+    /// Plays the rest of the song in short chunks, returning its audio.
+    fn play_to_end(player: &mut SpcPlayer) -> Vec<i16> {
+        let mut out = vec![];
+        let mut chunk = [0; SAMPLE_RATE / 100 * 2];
+        while !player.is_finished() {
+            let frames = player.render(&mut chunk);
+            out.extend_from_slice(&chunk[..frames * 2]);
+        }
+        out
+    }
+
+    fn is_audible(frame: &[i16]) -> bool {
+        frame.iter().any(|&sample| sample.abs() > 32)
+    }
+
+    #[test]
+    fn test_silence_ends_song() -> Result<()> {
+        let settings = Settings {
+            silence_secs: 4,
+            ..Settings::default()
+        };
+        // A note held at zero gain is silent, but isn't keyed off.
+        let path = save_test_song("held", &[(0x05, 0x00), (0x07, 0x00), (0x4c, 0x01)], &[])?;
+        let mut player = load_test_song(&path, &settings)?;
+        let out = play_to_end(&mut player);
+        assert_eq!(out.len(), 4 * SAMPLE_RATE * 2);
+        assert!(!out.chunks_exact(2).any(is_audible));
+        Ok(())
+    }
+
+    #[test]
+    fn test_keyed_off_silence_ends_song() -> Result<()> {
+        let settings = Settings {
+            silence_secs: 10,
+            ..Settings::default()
+        };
+        let path = save_test_song(
+            "keyed-off",
+            &[(0x4c, 0x01)],
+            &[&delay(0), &write_dsp(0x5c, 0x01)],
+        )?;
+        let mut player = load_test_song(&path, &settings)?;
+        let out = play_to_end(&mut player);
 
-        let stream = device
-            .build_output_stream(
-                &config,
-                move |data, _info| {
-                    spc.render(data);
-                },
-                err_fn,
-            )
-            .context("Error building output stream")?;
+        let audible_frames = out.chunks_exact(2).rposition(is_audible).unwrap() + 1;
+        assert!(audible_frames > SAMPLE_RATE / 8 && audible_frames < SAMPLE_RATE / 4);
+        let end = out.len() / 2;
+        assert!(end >= audible_frames + KEYED_OFF_SILENCE_FRAMES);
+        assert!(end < audible_frames + KEYED_OFF_SILENCE_FRAMES + SAMPLE_RATE / 100);
+        Ok(())
+    }
+
+    #[test]
+    fn test_echo_tail_delays_end() -> Result<()> {
+        let settings = Settings {
+            silence_secs: 10,
+            ..Settings::default()
+        };
+        // A short note, and then echo repeating it every 240ms.
+        let echo = [
+            (0x2c, 0x7f),
+            (0x3c, 0x7f),
+            (0x0d, 0x40),
+            (0x0f, 0x7f),
+            (0x4c, 0x01),
+            (0x4d, 0x01),
+            (0x6c, 0x00),
+            (0x6d, 0x80),
+            (EDL, 0x0f),
+        ];
+        let path = save_test_song("echo", &echo, &[&delay(0x10), &write_dsp(0x5c, 0x01)])?;
+        let mut player = load_test_song(&path, &settings)?;
+        let out = play_to_end(&mut player);
 
-        Ok(AudioHandle { stream })
+        // The song doesn't end in the gap between the note and its echo.
+        let frames: Vec<&[i16]> = out.chunks_exact(2).collect();
+        assert!(frames[..SAMPLE_RATE / 100]
+            .iter()
+            .any(|frame| is_audible(frame)));
+        assert!(!frames[SAMPLE_RATE / 10..SAMPLE_RATE / 5]
+            .iter()
+            .any(|frame| is_audible(frame)));
+        let audible_frames = frames.iter().rposition(|frame| is_audible(frame)).unwrap() + 1;
+        assert!(audible_frames > SAMPLE_RATE);
+        let end = frames.len();
+        assert!(end >= audible_frames + KEYED_OFF_SILENCE_FRAMES);
+        assert!(end < audible_frames + KEYED_OFF_SILENCE_FRAMES + SAMPLE_RATE / 100);
+        Ok(())
     }
 
-    pub fn play(&self) -> Result<()> {
-        self.stream.play().context("Error playing audio device")?;
+    #[test]
+    fn test_trim_leading_silence() -> Result<()> {
+        let settings = Settings {
+            trim_leading_silence: true,
+            ..Settings::default()
+        };
+        let path = save_test_song("trim", &[], &[&delay(0), &write_dsp(0x4c, 0x01)])?;
+        let mut player = SpcPlayer::new(&path, &settings, &test_overrides(5000, 0), None)?;
+        std::fs::remove_file(&path)?;
+
+        // The skipped silence counts towards the song's length.
+        let skipped = player.position_ms();
+        assert!(skipped > 150 && skipped < 250);
+        let skipped_frames = player.position as usize;
+        assert_eq!(skipped_frames % TRIM_BLOCK_FRAMES, 0);
+        assert_eq!(
+            player.remaining_frames(),
+            Some(5 * SAMPLE_RATE - skipped_frames)
+        );
+
+        let out = play_to_end(&mut player);
+        assert_eq!(out.len() / 2, 5 * SAMPLE_RATE - skipped_frames);
+        assert!(is_audible(&out[..TRIM_BLOCK_FRAMES * 2]));
         Ok(())
     }
 }
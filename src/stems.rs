@@ -0,0 +1,176 @@
+//! Exporting each voice of a song as its own track, for transcribing and
+//! remixing.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use snes_apu::dsp::dsp::NUM_VOICES;
+
+use crate::sink::AudioSink;
+use crate::spcplay::STEM_CHANNELS;
+
+/// Names of the stereo stems rendered by `SpcPlayer::render_stems_to()`, in
+/// order.
+pub fn stem_names() -> Vec<String> {
+    let mut names: Vec<String> = (1..=NUM_VOICES)
+        .map(|voice| format!("voice {}", voice))
+        .collect();
+    names.extend((1..=NUM_VOICES).map(|voice| format!("voice {} with echo", voice)));
+    names.push("echo".to_owned());
+    names
+}
+
+/// Where to write each stem, next to `output`: `song.wav` becomes
+/// `song - voice 1.wav` and so on.
+pub fn stem_paths(output: &Path) -> Vec<PathBuf> {
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = match output.extension() {
+        Some(extension) => extension.to_string_lossy(),
+        None => "wav".into(),
+    };
+    stem_names()
+        .iter()
+        .map(|name| output.with_file_name(format!("{} - {}.{}", stem, name, extension)))
+        .collect()
+}
+
+/// Splits interleaved stems into a stereo sink for each.
+pub struct SplitSink {
+    sinks: Vec<Box<dyn AudioSink>>,
+    buffer: Vec<i16>,
+}
+
+impl SplitSink {
+    /// Takes a sink for each of `stem_names()`.
+    pub fn new(sinks: Vec<Box<dyn AudioSink>>) -> SplitSink {
+        assert_eq!(sinks.len() * 2, STEM_CHANNELS);
+        SplitSink {
+            sinks,
+            buffer: vec![],
+        }
+    }
+}
+
+impl AudioSink for SplitSink {
+    fn write(&mut self, samples: &[i16]) -> Result<()> {
+        for (i, sink) in self.sinks.iter_mut().enumerate() {
+            self.buffer.clear();
+            for frame in samples.chunks_exact(STEM_CHANNELS) {
+                self.buffer.extend_from_slice(&frame[i * 2..i * 2 + 2]);
+            }
+            sink.write(&self.buffer)?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        for sink in &mut self.sinks {
+            sink.finish()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::MemorySink;
+    use crate::spcplay::test_player;
+    use snes_apu::apu::Apu;
+    use snes_apu::dsp::dsp::SAMPLE_RATE;
+    use spc::Spc;
+
+    const SPC_PATH: &str = "3rdparty/snes-apu/test/smashit.spc";
+
+    #[test]
+    fn test_stem_paths() {
+        let paths = stem_paths(Path::new("out/song.flac"));
+        assert_eq!(paths.len() * 2, STEM_CHANNELS);
+        assert_eq!(paths[0], Path::new("out/song - voice 1.flac"));
+        assert_eq!(paths[8], Path::new("out/song - voice 1 with echo.flac"));
+        assert_eq!(paths[16], Path::new("out/song - echo.flac"));
+    }
+
+    #[test]
+    fn test_render_stems() -> Result<()> {
+        let mut mix = MemorySink::default();
+        test_player(SPC_PATH, 3000, 0)?.render_to(&mut mix)?;
+        let mut stems = MemorySink::default();
+        test_player(SPC_PATH, 3000, 0)?.render_stems_to(&mut stems)?;
+        assert_eq!(stems.samples.len(), mix.samples.len() / 2 * STEM_CHANNELS);
+        assert!(stems.samples.iter().any(|&sample| sample != 0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_stems_add_up_to_mix() -> Result<()> {
+        let mut apu = Apu::from_spc(&Spc::load(SPC_PATH)?);
+        apu.clear_echo_buffer();
+        let mut mix = vec![0; SAMPLE_RATE * 2];
+        apu.render_interleaved(&mut mix);
+
+        // The song doesn't use echo, so turn it on once the song has set up
+        // the DSP, with its buffer in unused RAM.
+        let dsp = apu.dsp.as_mut().unwrap();
+        let registers = [
+            (0x6d, 0xf4), // ESA
+            (0x7d, 0x01), // EDL
+            (0x0d, 0x50), // EFB
+            (0x0f, 0x40), // FIR
+            (0x1f, 0x20),
+            (0x2c, 0x30), // EVOL
+            (0x3c, 0xd0),
+            (0x4d, 0x0f), // EON
+            (0x6c, 0x00), // FLG
+        ];
+        for &(address, value) in &registers {
+            dsp.set_register(address, value);
+        }
+        let main_volumes = [0x0c, 0x1c].map(|address| dsp.get_register_value(address) as i8 as i32);
+        // Audio already rendered into the output buffer has no stems.
+        let skip = dsp.output_buffer.get_sample_count() as usize;
+        dsp.set_stem_capture(true);
+        apu.clear_echo_buffer();
+
+        let mut mixed = vec![];
+        for _ in 0..2 {
+            apu.render_interleaved(&mut mix);
+            mixed.extend_from_slice(&mix);
+        }
+        let dsp = apu.dsp.as_mut().unwrap();
+        for &(address, value) in &registers {
+            assert_eq!(dsp.get_register_value(address), value);
+        }
+        let stem_samples = dsp.take_stem_samples();
+
+        let mut max_echo_error = 0;
+        for (stem_sample, mixed) in stem_samples.iter().zip(mixed.chunks_exact(2).skip(skip)) {
+            let stereo = |(left, right): (i16, i16)| [left as i32, right as i32];
+            for channel in 0..2 {
+                let voices: i32 = stem_sample
+                    .voices
+                    .iter()
+                    .map(|&voice| stereo(voice)[channel])
+                    .sum();
+                let echo = stereo(stem_sample.echo)[channel];
+                let expected = ((voices * main_volumes[channel]) >> 7) + echo;
+                assert_eq!(mixed[channel] as i32, expected.clamp(-32768, 32767));
+
+                // Each voice's echo runs separately, so rounding differs.
+                let voice_echoes: i32 = stem_sample
+                    .voice_echoes
+                    .iter()
+                    .map(|&echo| stereo(echo)[channel])
+                    .sum();
+                max_echo_error = max_echo_error.max((voice_echoes - echo).abs());
+            }
+        }
+        assert!(stem_samples.iter().any(|sample| sample.echo != (0, 0)));
+        // Voices with echo off have none of it.
+        assert!(stem_samples
+            .iter()
+            .all(|sample| sample.voice_echoes[4..].iter().all(|&echo| echo == (0, 0))));
+        assert!(max_echo_error < 16, "echo error {}", max_echo_error);
+        Ok(())
+    }
+}
@@ -0,0 +1,650 @@
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use snes_apu::dsp::dsp::SAMPLE_RATE;
+
+use crate::library::SpcTags;
+use crate::loops::LoopPoints;
+use crate::sink::AudioSink;
+
+const CHANNELS: u16 = 2;
+/// Offset of the RIFF chunk's size, which is filled in by `finish()`.
+const RIFF_SIZE_OFFSET: u64 = 4;
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+/// Needed for more than two channels. The real format is given by a GUID.
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xfffe;
+/// The rest of the GUID after the format tag, shared by PCM and float.
+const KSDATAFORMAT_SUBTYPE: [u8; 14] = [
+    0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xaa, 0x00, 0x38, 0x9b, 0x71,
+];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SampleFormat {
+    Int16,
+    Float32,
+}
+
+impl SampleFormat {
+    fn bytes(self) -> u16 {
+        match self {
+            SampleFormat::Int16 => 2,
+            SampleFormat::Float32 => 4,
+        }
+    }
+}
+
+/// Writes a chunk, padded to an even length.
+pub fn write_chunk(w: &mut impl Write, id: &[u8; 4], body: &[u8]) -> Result<()> {
+    w.write_all(id)?;
+    w.write_all(&(body.len() as u32).to_le_bytes())?;
+    w.write_all(body)?;
+    if body.len() % 2 == 1 {
+        w.write_all(&[0])?;
+    }
+    Ok(())
+}
+
+/// A RIFF INFO list, which most players read.
+fn info_list(tags: &SpcTags) -> Vec<u8> {
+    let mut list = b"INFO".to_vec();
+    let fields = [
+        (b"INAM", &tags.title),
+        (b"IPRD", &tags.game),
+        (b"IART", &tags.artist),
+        (b"ICMT", &tags.comments),
+    ];
+    for (id, text) in fields.iter() {
+        if !text.is_empty() {
+            let mut body = text.as_bytes().to_vec();
+            body.push(0);
+            write_chunk(&mut list, id, &body).unwrap();
+        }
+    }
+    write_chunk(&mut list, b"ISFT", b"spcplay-rs\0").unwrap();
+    list
+}
+
+/// UTF-16 with a byte order mark, as ID3 text encoding 1.
+fn utf16(text: &str) -> Vec<u8> {
+    let mut bytes = vec![0xff, 0xfe];
+    for unit in text.encode_utf16() {
+        bytes.extend_from_slice(&unit.to_le_bytes());
+    }
+    bytes
+}
+
+/// An ID3v2.3 tag, for players which ignore INFO lists.
+fn id3_tag(tags: &SpcTags) -> Vec<u8> {
+    fn frame(frames: &mut Vec<u8>, id: &[u8; 4], body: &[u8]) {
+        frames.extend_from_slice(id);
+        frames.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        frames.extend_from_slice(&[0, 0]);
+        frames.extend_from_slice(body);
+    }
+
+    let mut frames = vec![];
+    let fields = [
+        (b"TIT2", &tags.title),
+        (b"TALB", &tags.game),
+        (b"TPE1", &tags.artist),
+    ];
+    for (id, text) in fields.iter() {
+        if !text.is_empty() {
+            let mut body = vec![1];
+            body.extend(utf16(text));
+            frame(&mut frames, id, &body);
+        }
+    }
+    if !tags.comments.is_empty() {
+        // Language, then an empty description.
+        let mut body = vec![1];
+        body.extend_from_slice(b"eng");
+        body.extend(utf16(""));
+        body.extend_from_slice(&[0, 0]);
+        body.extend(utf16(&tags.comments));
+        frame(&mut frames, b"COMM", &body);
+    }
+
+    // The size is stored 7 bits per byte.
+    let size = frames.len() as u32;
+    let mut tag = b"ID3\x03\x00\x00".to_vec();
+    tag.extend((0..4).rev().map(|i| ((size >> (i * 7)) & 0x7f) as u8));
+    tag.extend(frames);
+    tag
+}
+
+/// A sampler chunk marking a forward loop, which samplers and game engines
+/// read to repeat the audio.
+fn sampler_chunk(points: LoopPoints) -> Vec<u8> {
+    let mut smpl = vec![];
+    let mut field = |value: u32| smpl.extend_from_slice(&value.to_le_bytes());
+    // Manufacturer and product.
+    field(0);
+    field(0);
+    // Nanoseconds per frame, then MIDI note and pitch fraction.
+    field(1_000_000_000 / SAMPLE_RATE as u32);
+    field(60);
+    field(0);
+    // SMPTE format and offset.
+    field(0);
+    field(0);
+    // One loop, and no sampler-specific data.
+    field(1);
+    field(0);
+    // Cue point ID and loop type, then the first and last frames of the
+    // loop, a fraction, and 0 to repeat forever.
+    field(0);
+    field(0);
+    field(points.start as u32);
+    field((points.end() - 1) as u32);
+    field(0);
+    field(0);
+    smpl
+}
+
+/// Cue points at each marker's frame, and an associated data list labelling
+/// them, which audio editors show as markers.
+fn marker_chunks(markers: &[(u64, String)]) -> (Vec<u8>, Vec<u8>) {
+    let mut cue = (markers.len() as u32).to_le_bytes().to_vec();
+    let mut adtl = b"adtl".to_vec();
+    for (i, (frame, label)) in markers.iter().enumerate() {
+        let id = i as u32 + 1;
+        // ID, position, then the data chunk, chunk and block starts, and
+        // the frame within the block.
+        cue.extend_from_slice(&id.to_le_bytes());
+        cue.extend_from_slice(&(*frame as u32).to_le_bytes());
+        cue.extend_from_slice(b"data");
+        cue.extend_from_slice(&0u32.to_le_bytes());
+        cue.extend_from_slice(&0u32.to_le_bytes());
+        cue.extend_from_slice(&(*frame as u32).to_le_bytes());
+
+        let mut labl = id.to_le_bytes().to_vec();
+        labl.extend_from_slice(label.as_bytes());
+        labl.push(0);
+        write_chunk(&mut adtl, b"labl", &labl).unwrap();
+    }
+    (cue, adtl)
+}
+
+/// Writes audio at the SNES's sample rate to a WAV file, tagged with the
+/// song's title, game, artist and comments. Audio is stereo unless the writer
+/// is made `with_channels()`.
+pub struct WavWriter<W: Write + Seek> {
+    w: W,
+    format: SampleFormat,
+    channels: u16,
+    /// Offset of the fact chunk's frame count, for float files.
+    fact_offset: Option<u64>,
+    /// Offset of the data chunk's size.
+    data_size_offset: u64,
+    data_len: u32,
+    loop_points: Option<LoopPoints>,
+    markers: Vec<(u64, String)>,
+}
+
+fn create_file(path: &Path) -> Result<BufWriter<File>> {
+    let file =
+        File::create(path).with_context(|| format!("Could not create {}", path.display()))?;
+    Ok(BufWriter::new(file))
+}
+
+impl WavWriter<BufWriter<File>> {
+    pub fn create(path: &Path, format: SampleFormat, tags: &SpcTags) -> Result<Self> {
+        WavWriter::new(create_file(path)?, format, tags)
+    }
+
+    pub fn create_with_channels(
+        path: &Path,
+        format: SampleFormat,
+        tags: &SpcTags,
+        channels: u16,
+    ) -> Result<Self> {
+        WavWriter::with_channels(create_file(path)?, format, tags, channels)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    /// Writes the header and tags, with sizes to be filled in by `finish()`.
+    pub fn new(w: W, format: SampleFormat, tags: &SpcTags) -> Result<Self> {
+        WavWriter::with_channels(w, format, tags, CHANNELS)
+    }
+
+    /// Like `new()`, but for interleaved samples with any number of channels,
+    /// eg. stems.
+    pub fn with_channels(
+        mut w: W,
+        format: SampleFormat,
+        tags: &SpcTags,
+        channels: u16,
+    ) -> Result<Self> {
+        let block_align = channels * format.bytes();
+
+        w.write_all(b"RIFF")?;
+        w.write_all(&0u32.to_le_bytes())?;
+        w.write_all(b"WAVE")?;
+
+        let mut fmt = vec![];
+        let format_tag = match format {
+            SampleFormat::Int16 => WAVE_FORMAT_PCM,
+            SampleFormat::Float32 => WAVE_FORMAT_IEEE_FLOAT,
+        };
+        let extensible = channels > CHANNELS;
+        if extensible {
+            fmt.extend_from_slice(&WAVE_FORMAT_EXTENSIBLE.to_le_bytes());
+        } else {
+            fmt.extend_from_slice(&format_tag.to_le_bytes());
+        }
+        fmt.extend_from_slice(&channels.to_le_bytes());
+        fmt.extend_from_slice(&(SAMPLE_RATE as u32).to_le_bytes());
+        fmt.extend_from_slice(&(SAMPLE_RATE as u32 * block_align as u32).to_le_bytes());
+        fmt.extend_from_slice(&block_align.to_le_bytes());
+        fmt.extend_from_slice(&(format.bytes() * 8).to_le_bytes());
+        if extensible {
+            // Valid bits, then a channel mask of 0 as the channels aren't
+            // speakers, then the format's GUID.
+            fmt.extend_from_slice(&22u16.to_le_bytes());
+            fmt.extend_from_slice(&(format.bytes() * 8).to_le_bytes());
+            fmt.extend_from_slice(&0u32.to_le_bytes());
+            fmt.extend_from_slice(&format_tag.to_le_bytes());
+            fmt.extend_from_slice(&KSDATAFORMAT_SUBTYPE);
+        } else if format == SampleFormat::Float32 {
+            // Formats other than PCM have an extension, even if it's empty.
+            fmt.extend_from_slice(&0u16.to_le_bytes());
+        }
+        write_chunk(&mut w, b"fmt ", &fmt)?;
+
+        // Formats other than PCM also need a frame count.
+        let mut fact_offset = None;
+        if format == SampleFormat::Float32 {
+            fact_offset = Some(w.stream_position()? + 8);
+            write_chunk(&mut w, b"fact", &0u32.to_le_bytes())?;
+        }
+
+        write_chunk(&mut w, b"LIST", &info_list(tags))?;
+        write_chunk(&mut w, b"id3 ", &id3_tag(tags))?;
+
+        w.write_all(b"data")?;
+        let data_size_offset = w.stream_position()?;
+        w.write_all(&0u32.to_le_bytes())?;
+
+        Ok(WavWriter {
+            w,
+            format,
+            channels,
+            fact_offset,
+            data_size_offset,
+            data_len: 0,
+            loop_points: None,
+            markers: vec![],
+        })
+    }
+
+    /// Marks a loop, written after the audio by `finish()`.
+    pub fn set_loop(&mut self, points: LoopPoints) {
+        self.loop_points = Some(points);
+    }
+
+    /// Adds labelled markers at the given frames, eg. the start of each song,
+    /// written after the audio by `finish()`.
+    pub fn set_markers(&mut self, markers: Vec<(u64, String)>) {
+        self.markers = markers;
+    }
+}
+
+impl<W: Write + Seek> AudioSink for WavWriter<W> {
+    /// Writes interleaved stereo samples.
+    fn write(&mut self, samples: &[i16]) -> Result<()> {
+        for &sample in samples {
+            match self.format {
+                SampleFormat::Int16 => self.w.write_all(&sample.to_le_bytes())?,
+                SampleFormat::Float32 => {
+                    self.w.write_all(&(sample as f32 / 32768.0).to_le_bytes())?
+                }
+            }
+        }
+        self.data_len = (samples.len() as u64 * self.format.bytes() as u64)
+            .checked_add(self.data_len as u64)
+            .filter(|&len| len < u32::MAX as u64)
+            .context("WAV file is too long")? as u32;
+        Ok(())
+    }
+
+    /// Writes the loop and markers, if any, and fills in the sizes in the
+    /// header.
+    fn finish(&mut self) -> Result<()> {
+        if let Some(points) = self.loop_points {
+            write_chunk(&mut self.w, b"smpl", &sampler_chunk(points))?;
+        }
+        if !self.markers.is_empty() {
+            let (cue, adtl) = marker_chunks(&self.markers);
+            write_chunk(&mut self.w, b"cue ", &cue)?;
+            write_chunk(&mut self.w, b"LIST", &adtl)?;
+        }
+        let end = self.w.stream_position()?;
+        self.w.seek(SeekFrom::Start(RIFF_SIZE_OFFSET))?;
+        self.w.write_all(&(end as u32 - 8).to_le_bytes())?;
+        if let Some(fact_offset) = self.fact_offset {
+            let frames = self.data_len / (self.channels * self.format.bytes()) as u32;
+            self.w.seek(SeekFrom::Start(fact_offset))?;
+            self.w.write_all(&frames.to_le_bytes())?;
+        }
+        self.w.seek(SeekFrom::Start(self.data_size_offset))?;
+        self.w.write_all(&self.data_len.to_le_bytes())?;
+        self.w.seek(SeekFrom::Start(end))?;
+        self.w.flush()?;
+        Ok(())
+    }
+}
+
+/// Audio read from a WAV file by `read()`.
+#[derive(Clone, Debug)]
+pub struct WavFile {
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// Interleaved samples, converted to 16-bit.
+    pub samples: Vec<i16>,
+    /// From a sampler chunk's first loop, in frames.
+    pub loop_points: Option<LoopPoints>,
+    /// The MIDI note the audio plays at its sample rate, from a sampler
+    /// chunk.
+    pub unity_note: Option<u8>,
+}
+
+impl WavFile {
+    /// Mixes the channels down to one.
+    pub fn mono(&self) -> Vec<i16> {
+        let channels = self.channels as usize;
+        self.samples
+            .chunks_exact(channels)
+            .map(|frame| (frame.iter().map(|&x| x as i32).sum::<i32>() / channels as i32) as i16)
+            .collect()
+    }
+}
+
+/// Reads a PCM or float WAV file, with its loop if it has one.
+pub fn read(path: &Path) -> Result<WavFile> {
+    let data = std::fs::read(path).with_context(|| format!("Could not read {}", path.display()))?;
+    parse(&data).with_context(|| format!("Could not read {}", path.display()))
+}
+
+fn parse(data: &[u8]) -> Result<WavFile> {
+    let u16_at = |pos: usize| u16::from_le_bytes([data[pos], data[pos + 1]]);
+    let u32_at =
+        |pos: usize| u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]);
+    if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        bail!("Not a WAV file");
+    }
+
+    let mut format = None;
+    let mut body = None;
+    let mut loop_points = None;
+    let mut unity_note = None;
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let id = &data[pos..pos + 4];
+        let len = u32_at(pos + 4) as usize;
+        let start = pos + 8;
+        // Some writers leave the data chunk's size too long.
+        let end = start.saturating_add(len).min(data.len());
+        match id {
+            b"fmt " if end - start >= 16 => {
+                let mut tag = u16_at(start);
+                if tag == WAVE_FORMAT_EXTENSIBLE && end - start >= 26 {
+                    tag = u16_at(start + 24);
+                }
+                format = Some((
+                    tag,
+                    u16_at(start + 2),
+                    u32_at(start + 4),
+                    u16_at(start + 14),
+                ));
+            }
+            b"data" => body = Some(&data[start..end]),
+            b"smpl" if end - start >= 36 => {
+                unity_note = Some(u32_at(start + 12).min(127) as u8);
+                if u32_at(start + 28) > 0 && end - start >= 60 {
+                    let first = u32_at(start + 44) as u64;
+                    let last = u32_at(start + 48) as u64;
+                    if last >= first {
+                        loop_points = Some(LoopPoints {
+                            start: first,
+                            length: last - first + 1,
+                        });
+                    }
+                }
+            }
+            _ => {}
+        }
+        pos = end + len % 2;
+    }
+
+    let (tag, channels, sample_rate, bits) = format.context("No format chunk")?;
+    let body = body.context("No audio")?;
+    if channels == 0 || sample_rate == 0 {
+        bail!("Invalid format");
+    }
+    let samples: Vec<i16> = match (tag, bits) {
+        (WAVE_FORMAT_PCM, 8) => body.iter().map(|&x| (x as i16 - 128) << 8).collect(),
+        (WAVE_FORMAT_PCM, 16) => body
+            .chunks_exact(2)
+            .map(|x| i16::from_le_bytes([x[0], x[1]]))
+            .collect(),
+        (WAVE_FORMAT_PCM, 24) => body
+            .chunks_exact(3)
+            .map(|x| i16::from_le_bytes([x[1], x[2]]))
+            .collect(),
+        (WAVE_FORMAT_PCM, 32) => body
+            .chunks_exact(4)
+            .map(|x| i16::from_le_bytes([x[2], x[3]]))
+            .collect(),
+        (WAVE_FORMAT_IEEE_FLOAT, 32) => body
+            .chunks_exact(4)
+            .map(|x| {
+                let x = f32::from_le_bytes([x[0], x[1], x[2], x[3]]);
+                (x * 32768.0).round().clamp(-32768.0, 32767.0) as i16
+            })
+            .collect(),
+        _ => bail!("Unsupported format: {}-bit, format {}", bits, tag),
+    };
+    let frames = samples.len() as u64 / channels as u64;
+    Ok(WavFile {
+        sample_rate,
+        channels,
+        samples,
+        loop_points: loop_points.filter(|points: &LoopPoints| points.end() <= frames),
+        unity_note,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+    use std::io::Cursor;
+
+    fn tags() -> SpcTags {
+        SpcTags {
+            title: "Title".to_owned(),
+            game: "Gäme".to_owned(),
+            artist: "Artist".to_owned(),
+            comments: "Odd".to_owned(),
+            ..SpcTags::default()
+        }
+    }
+
+    fn write(format: SampleFormat, channels: u16, samples: &[i16]) -> Result<Vec<u8>> {
+        let mut wav = WavWriter::with_channels(Cursor::new(vec![]), format, &tags(), channels)?;
+        wav.write(samples)?;
+        wav.finish()?;
+        Ok(wav.w.into_inner())
+    }
+
+    /// Splits a RIFF file or list body into its chunks' IDs and bodies.
+    fn chunks(mut data: &[u8]) -> Vec<(&[u8], &[u8])> {
+        let mut chunks = vec![];
+        while !data.is_empty() {
+            let len = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+            chunks.push((&data[..4], &data[8..8 + len]));
+            data = &data[(8 + len + len % 2).min(data.len())..];
+        }
+        chunks
+    }
+
+    fn chunk<'a>(chunks: &[(&[u8], &'a [u8])], id: &[u8]) -> &'a [u8] {
+        chunks
+            .iter()
+            .find(|(chunk_id, _)| *chunk_id == id)
+            .unwrap()
+            .1
+    }
+
+    #[test]
+    fn test_tags() -> Result<()> {
+        let bytes = write(SampleFormat::Int16, 2, &[1, 2, 3, 4])?;
+        assert_eq!(&bytes[..4], b"RIFF");
+        assert_eq!(&bytes[8..12], b"WAVE");
+        let riff = chunks(&bytes[12..]);
+        let ids: Vec<&[u8]> = riff.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, [b"fmt ", b"LIST", b"id3 ", b"data"]);
+
+        let list = chunk(&riff, b"LIST");
+        assert_eq!(&list[..4], b"INFO");
+        let info = chunks(&list[4..]);
+        let expected: [(&[u8], &[u8]); 5] = [
+            (b"INAM", b"Title\0"),
+            (b"IPRD", "Gäme\0".as_bytes()),
+            // Odd lengths are padded.
+            (b"IART", b"Artist\0"),
+            (b"ICMT", b"Odd\0"),
+            (b"ISFT", b"spcplay-rs\0"),
+        ];
+        assert_eq!(info, expected);
+
+        let id3 = chunk(&riff, b"id3 ");
+        assert_eq!(&id3[..6], b"ID3\x03\x00\x00");
+        let size = id3[6..10]
+            .iter()
+            .fold(0, |size, &byte| size << 7 | byte as usize);
+        assert_eq!(size, id3.len() - 10);
+        let mut frames = vec![];
+        let mut rest = &id3[10..];
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes(rest[4..8].try_into()?) as usize;
+            frames.push((&rest[..4], &rest[10..10 + len]));
+            rest = &rest[10 + len..];
+        }
+        let text = |text: &str| [vec![1], utf16(text)].concat();
+        let comment = [
+            vec![1],
+            b"eng".to_vec(),
+            utf16(""),
+            vec![0, 0],
+            utf16("Odd"),
+        ]
+        .concat();
+        let expected: [(&[u8], &[u8]); 4] = [
+            (b"TIT2", &text("Title")),
+            (b"TALB", &text("Gäme")),
+            (b"TPE1", &text("Artist")),
+            (b"COMM", &comment),
+        ];
+        assert_eq!(frames, expected);
+        assert_eq!(&utf16("ä")[..], [0xff, 0xfe, 0xe4, 0x00]);
+
+        let wav = parse(&bytes)?;
+        assert_eq!((wav.channels, wav.sample_rate), (2, SAMPLE_RATE as u32));
+        assert_eq!(wav.samples, [1, 2, 3, 4]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_untagged() -> Result<()> {
+        let mut wav = WavWriter::new(
+            Cursor::new(vec![]),
+            SampleFormat::Int16,
+            &SpcTags::default(),
+        )?;
+        wav.finish()?;
+        let bytes = wav.w.into_inner();
+        let riff = chunks(&bytes[12..]);
+        assert_eq!(
+            chunks(&chunk(&riff, b"LIST")[4..]),
+            [(&b"ISFT"[..], &b"spcplay-rs\0"[..])]
+        );
+        assert_eq!(chunk(&riff, b"id3 "), b"ID3\x03\x00\x00\x00\x00\x00\x00");
+        assert!(chunk(&riff, b"data").is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_float32() -> Result<()> {
+        let samples = [0, 16384, -32768, 32767, -1, 1];
+        let bytes = write(SampleFormat::Float32, 2, &samples)?;
+        let riff = chunks(&bytes[12..]);
+        let ids: Vec<&[u8]> = riff.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, [b"fmt ", b"fact", b"LIST", b"id3 ", b"data"]);
+
+        // WAVE_FORMAT_IEEE_FLOAT, with an empty extension.
+        let fmt = chunk(&riff, b"fmt ");
+        let mut expected = vec![];
+        for field in [3u16, 2] {
+            expected.extend_from_slice(&field.to_le_bytes());
+        }
+        for field in [SAMPLE_RATE as u32, SAMPLE_RATE as u32 * 8] {
+            expected.extend_from_slice(&field.to_le_bytes());
+        }
+        for field in [8u16, 32, 0] {
+            expected.extend_from_slice(&field.to_le_bytes());
+        }
+        assert_eq!(fmt, &expected[..]);
+        assert_eq!(chunk(&riff, b"fact"), 3u32.to_le_bytes());
+
+        let data: Vec<f32> = chunk(&riff, b"data")
+            .chunks_exact(4)
+            .map(|x| f32::from_le_bytes(x.try_into().unwrap()))
+            .collect();
+        assert_eq!(
+            data,
+            [
+                0.0,
+                0.5,
+                -1.0,
+                32767.0 / 32768.0,
+                -1.0 / 32768.0,
+                1.0 / 32768.0
+            ]
+        );
+        assert_eq!(parse(&bytes)?.samples, samples);
+        Ok(())
+    }
+
+    #[test]
+    fn test_float32_extensible() -> Result<()> {
+        let samples: Vec<i16> = (0..12).map(|i| i * 1000).collect();
+        let bytes = write(SampleFormat::Float32, 6, &samples)?;
+        let riff = chunks(&bytes[12..]);
+
+        let fmt = chunk(&riff, b"fmt ");
+        assert_eq!(fmt.len(), 40);
+        assert_eq!(
+            u16::from_le_bytes(fmt[..2].try_into()?),
+            WAVE_FORMAT_EXTENSIBLE
+        );
+        assert_eq!(u16::from_le_bytes(fmt[2..4].try_into()?), 6);
+        assert_eq!(u16::from_le_bytes(fmt[12..14].try_into()?), 24);
+        // Extension size, valid bits and channel mask, then the float GUID.
+        assert_eq!(fmt[16..24], [22, 0, 32, 0, 0, 0, 0, 0]);
+        assert_eq!(fmt[24..26], WAVE_FORMAT_IEEE_FLOAT.to_le_bytes());
+        assert_eq!(fmt[26..], KSDATAFORMAT_SUBTYPE);
+        assert_eq!(chunk(&riff, b"fact"), 2u32.to_le_bytes());
+
+        let wav = parse(&bytes)?;
+        assert_eq!(wav.channels, 6);
+        assert_eq!(wav.samples, samples);
+        Ok(())
+    }
+}